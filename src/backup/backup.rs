@@ -1,21 +1,252 @@
-use crate::backup_sets::backup_set::create_empty_set;
-use crate::dhcopy::copy_folder::copy_folder;
-use crate::test_helpers::test_helpers::create_tmp_folder;
-use chrono::Utc;
+#[cfg(test)]
+use crate::backup_sets::backup_set::list_sets;
+use crate::backup_sets::backup_set::{
+	clean_up_temp_sets, create_empty_set, temp_set_folder, SetInProgress, SET_METADATA_FILES,
+};
+use crate::backup_sets::compression_report::{compression_report, print_compression_report};
+use crate::backup_sets::duplicates::{
+	find_duplicates, link_duplicates, print_duplicates_report, without_linked_copies,
+};
+use crate::backup_sets::encrypted_names::encrypt_set_names;
+use crate::backup_sets::hash_catalog::HashCatalog;
+#[cfg(test)]
+use crate::backup_sets::latest::latest_set;
+use crate::backup_sets::latest::update_latest;
+use crate::backup_sets::lock::lock_destination;
+#[cfg(test)]
+use crate::backup_sets::manifest::MANIFEST_FILE_NAME;
+use crate::backup_sets::manifest::{write_encrypted_manifest, write_manifest_to, ManifestEntry};
+use crate::backup_sets::retention::{enforce_space_limits, prune_sets, RetentionPolicy};
+use crate::backup_sets::seal::{seal_set, Sealed};
+use crate::backup_sets::set_metadata::{
+	finish_metadata, read_metadata, write_metadata, write_metadata_to, SetMetadata, SetStats,
+};
+use crate::backup_sets::set_namer::{sanitize, SetNameTemplate, SetTimezone};
+use crate::backup_sets::trash::empty_trash;
+use crate::chunk_store::chunked_set::write_chunked_set;
+use crate::chunk_store::chunker::{store_chunk_sizes, ChunkSizes};
+use crate::chunk_store::store::ChunkStore;
+use crate::dhcopy::archive::{write_archive, ArchiveLayout};
+use crate::dhcopy::codec::Codec;
+use crate::dhcopy::compression::Compression;
+use crate::dhcopy::copy_folder::{copy_folder, CopyOrder, CopyStats, EarlierCopies, FileIssue};
+use crate::dhcopy::dictionary::{train_dictionary, write_dictionary};
+use crate::dhcopy::encryption::{parse_recipients, PassphraseParams, SetKey};
+use crate::error::{BackupError, Context, Operation};
+use crate::progress::{self, Stage};
+use crate::space::estimate::{check_free_space, estimate_backup_size};
+use crate::space::usage::total_stats;
+use crate::storage::backend::StorageBackend;
+use crate::storage::local::{LocalSource, LocalStorage};
+use crate::storage::source::SourceBackend;
+#[cfg(test)]
+use crate::test_helpers::test_helpers::{backup_folder, create_tmp_folder};
+use age::secrecy::SecretString;
+use chrono::{TimeDelta, Utc};
+use std::collections::{BTreeMap, HashSet};
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
 
+#[cfg(test)]
 const DEEP_PATH: &str = "thats/deep";
+#[cfg(test)]
+const THE_TEXT: &str = "backmeup susie";
+#[cfg(test)]
 const BACKUP_FOLDER_NAME: &str = "backups";
 
+#[derive(Clone, Debug, Default)]
+pub struct BackupOptions {
+	/// Sets outside this policy are pruned after a successful backup,
+	/// or before starting if the destination is short of space.
+	/// Its space limits include the new set.
+	pub retention: RetentionPolicy,
+	/// Move pruned sets to the trash, where they stay until the next backup
+	pub trash: bool,
+	pub set_name_template: SetNameTemplate,
+	/// Timezone for the timestamp in set names
+	pub timezone: SetTimezone,
+	/// Wait for another backup to the same destination to finish rather than failing
+	pub wait_lock: bool,
+	/// Make the set read-only once it's complete, see [seal_set]
+	pub seal: bool,
+	/// Tags to record in the new set's metadata
+	pub tags: Vec<String>,
+	/// How to store file contents, see [Compression]
+	pub compression: Compression,
+	/// Write the set as a single archive, compressed as a whole, rather than
+	/// as a copy of each file. Much faster on destinations that are slow with
+	/// many small files, like network shares.
+	pub archive: bool,
+	/// Split the archive into volumes of this many bytes, e.g. to fit FAT32
+	/// or optical media
+	pub volume_size: Option<u64>,
+	/// Store file contents in the destination's chunk store, so each set
+	/// only takes space for what changed since earlier chunked sets
+	pub chunked: bool,
+	/// Sizes to cut chunks to, recorded by the first chunked backup to the
+	/// destination. Later ones must ask for the same or leave it unset.
+	pub chunk_sizes: Option<ChunkSizes>,
+	/// Keep a separate copy of each file with the same contents as another
+	/// in the set or in an earlier set, rather than hard-linking them
+	/// together. For filesystems or tools where link counts matter.
+	pub copy_duplicates: bool,
+	/// Encrypt the set to these age recipients, each an `age1...` public
+	/// key or a file of them. Restoring needs a matching identity.
+	pub encrypt_to: Vec<String>,
+	/// Encrypt the set under this passphrase, alone or as well as to
+	/// recipients. The key is derived with Argon2id, see [PassphraseParams].
+	pub passphrase: Option<SecretString>,
+	/// Encrypt the manifest of an encrypted set as well, so the digests
+	/// don't give away which files it holds
+	pub encrypt_manifest: bool,
+	/// Store the files of an encrypted set under names derived from their
+	/// paths and the set's key, leaving the real names only in the
+	/// manifest, which is encrypted too. Empty folders aren't kept.
+	pub encrypt_names: bool,
+	/// Compress files against a zstd dictionary trained on the small files
+	/// in the sources, kept in the set for reading them back. Much smaller
+	/// sets for sources of many small similar files, like source code or
+	/// JSON. Needs zstd compression.
+	pub zstd_dictionary: bool,
+	/// If the backup's cancelled, keep what was copied as an incomplete
+	/// set rather than removing it
+	pub keep_cancelled: bool,
+	/// Stop starting files once the backup's been going this long, keeping
+	/// what was copied as a resumable set the next backup carries on from
+	pub max_duration: Option<TimeDelta>,
+	/// Files and folders in the sources copied before the rest, in this
+	/// order, so they're in the set if it runs out of time
+	pub priority: Vec<String>,
+}
+
+impl BackupOptions {
+	/// The options that differ from the defaults, for recording in set metadata
+	pub fn describe(&self) -> BTreeMap<String, String> {
+		let mut options = BTreeMap::new();
+		let retention = &self.retention;
+		let counts = [
+			("keep_last", retention.last),
+			("keep_daily", retention.daily),
+			("keep_weekly", retention.weekly),
+			("keep_monthly", retention.monthly),
+		];
+		for (name, count) in counts.into_iter().filter(|(_, count)| *count > 0) {
+			options.insert(name.to_string(), count.to_string());
+		}
+		if let Some(within) = retention.within {
+			options.insert(
+				"keep_within".to_string(),
+				format!("{}h", within.num_hours()),
+			);
+		}
+		if let Some(max_space) = retention.max_space {
+			options.insert("max_space".to_string(), max_space.to_string());
+		}
+		if let Some(min_free) = retention.min_free {
+			options.insert("min_free".to_string(), min_free.to_string());
+		}
+		if !retention.keep_tagged.is_empty() {
+			options.insert("keep_tagged".to_string(), retention.keep_tagged.join(","));
+		}
+		if self.trash {
+			options.insert("trash".to_string(), "true".to_string());
+		}
+		if self.set_name_template != SetNameTemplate::default() {
+			options.insert(
+				"set_name_template".to_string(),
+				self.set_name_template.to_string(),
+			);
+		}
+		if self.seal {
+			options.insert("seal".to_string(), "true".to_string());
+		}
+		if self.compression != Compression::None {
+			options.insert("compression".to_string(), self.compression.to_string());
+		}
+		if self.archive {
+			options.insert("archive".to_string(), "true".to_string());
+		}
+		if let Some(volume_size) = self.volume_size {
+			options.insert("volume_size".to_string(), volume_size.to_string());
+		}
+		if self.chunked {
+			options.insert("chunked".to_string(), "true".to_string());
+		}
+		if let Some(chunk_sizes) = self.chunk_sizes {
+			options.insert("chunk_sizes".to_string(), chunk_sizes.to_string());
+		}
+		if self.copy_duplicates {
+			options.insert("copy_duplicates".to_string(), "true".to_string());
+		}
+		if !self.encrypt_to.is_empty() {
+			options.insert("encrypt_to".to_string(), self.encrypt_to.join(","));
+		}
+		if self.encrypt_manifest {
+			options.insert("encrypt_manifest".to_string(), "true".to_string());
+		}
+		if self.encrypt_names {
+			options.insert("encrypt_names".to_string(), "true".to_string());
+		}
+		if self.zstd_dictionary {
+			options.insert("zstd_dictionary".to_string(), "true".to_string());
+		}
+		if let Some(max_duration) = self.max_duration {
+			options.insert(
+				"max_duration".to_string(),
+				format!("{}s", max_duration.num_seconds()),
+			);
+		}
+		if self.timezone != SetTimezone::Utc {
+			options.insert("timezone".to_string(), self.timezone.to_string());
+		}
+		options
+	}
+}
+
+/// What [run] backs up, and where to
+#[derive(Clone, Debug, Default)]
+pub struct BackupRequest {
+	/// Local folders, copied into one set
+	pub sources: Vec<String>,
+	/// The local folder sets are kept in
+	pub destination: String,
+	pub options: BackupOptions,
+}
+
+/// The set a backup wrote, and how it went
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackupReport {
+	pub set_name: String,
+	pub set_folder: PathBuf,
+	/// The sources as backed up, symlinks resolved
+	pub sources: Vec<PathBuf>,
+	pub stats: SetStats,
+	/// Files deleted or changed while they were copied
+	pub issues: Vec<FileIssue>,
+}
+
+/// Backs up the request's sources into a new set in its destination.
+/// Several sources are each copied into a subfolder labelled from its
+/// path, e.g. `home-alice` for `/home/alice`, the labels being recorded in
+/// the set's metadata. A single source is copied into the root of the set,
+/// and is refused if that would clash with the set's own files.
+pub fn run(request: &BackupRequest) -> Result<BackupReport, BackupError> {
+	let sources: Vec<&str> = request.sources.iter().map(String::as_str).collect();
+	Ok(backup_from(
+		&LocalSource,
+		&sources,
+		&request.destination,
+		&request.options,
+	)?)
+}
+
+/// Backs up a folder with the default options, returning the new set's name
+#[deprecated(note = "use backup::run, which takes options and reports how it went")]
 pub fn backup(source: &str, dest: &str) -> io::Result<String> {
-	fs::create_dir_all(dest)?;
-	let set_name = create_empty_set(dest, || Utc::now())?;
-	let dest_folder = Path::new(dest).join(&set_name);
-	println!("backing up {} into {:?}", source, dest_folder);
-	copy_folder(source, dest_folder.to_str().unwrap())?;
-	Ok(set_name)
+	Ok(backup_sources(&[source], dest, &BackupOptions::default())?)
 }
 
 #[test]
@@ -24,7 +255,7 @@ fn test_backup() -> io::Result<()> {
 	let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
 
 	// smoke test
-	let set_name = backup(&source, &dest)?;
+	let set_name = backup_folder(&source, &dest, &BackupOptions::default())?;
 
 	// Just a quick check that deeply nested file is copied.
 	// All other edge cases are tested in unit tests.
@@ -37,14 +268,41 @@ fn test_backup() -> io::Result<()> {
 		"test file should be copied to backup folder"
 	);
 
+	let set_folder = Path::new(&dest).join(&set_name);
+	assert!(
+		set_folder.join(MANIFEST_FILE_NAME).exists(),
+		"manifest should be written to the set"
+	);
+	let metadata = read_metadata(&set_folder)?;
+	assert_eq!(Path::new(&metadata.source), fs::canonicalize(&source)?);
+	assert_eq!(metadata.stats.map(|stats| stats.files), Some(1));
+	assert_eq!(latest_set(&dest)?, Some(set_name));
+
 	// cleanup
 	let _ = fs::remove_dir_all(&source);
 	Ok(())
 }
 
 #[test]
-fn test_backup_non_existent_path() {
-	// todo
+fn test_backup_non_existent_path() -> io::Result<()> {
+	let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+	let missing_source = Path::new(&dest).join("not-here");
+
+	let result = backup_folder(
+		missing_source.to_str().unwrap(),
+		&dest,
+		&BackupOptions::default(),
+	);
+
+	match result.unwrap_err() {
+		BackupError::SourceMissing { path } => assert_eq!(path, missing_source),
+		e => panic!("expected a missing source, got {:?}", e),
+	}
+	assert!(
+		list_sets(&dest)?.is_empty(),
+		"no set should be created for a missing source"
+	);
+	Ok(())
 }
 
 #[test]
@@ -54,7 +312,11 @@ fn test_creates_destination_folder() -> io::Result<()> {
 
 	let non_existent_destination = Path::new(&dest).join("to-be-created");
 
-	backup(&source, non_existent_destination.to_str().unwrap())?;
+	backup_folder(
+		&source,
+		non_existent_destination.to_str().unwrap(),
+		&BackupOptions::default(),
+	)?;
 
 	let dir = fs::read_dir(&non_existent_destination)?;
 	assert!(dir.count() > 0, "destination folder should be copied");
@@ -64,6 +326,7 @@ fn test_creates_destination_folder() -> io::Result<()> {
 	Ok(())
 }
 
+#[cfg(test)]
 fn create_source() -> io::Result<String> {
 	let source = create_tmp_folder("orig")?;
 
@@ -71,8 +334,1096 @@ fn create_source() -> io::Result<String> {
 	fs::create_dir_all(&folder_path)?;
 
 	let test_file_name = folder_path.join("testfile.txt");
-	let the_text = "backmeup susie";
-	fs::write(test_file_name, the_text)?;
+	fs::write(test_file_name, THE_TEXT)?;
 
 	Ok(source)
 }
+
+/// Like [run], returning only the new set's name
+pub fn backup_sources(
+	sources: &[&str],
+	dest: &str,
+	options: &BackupOptions,
+) -> Result<String, BackupError> {
+	Ok(backup_from(&LocalSource, sources, dest, options)?.set_name)
+}
+
+/// Backs up sources read through the backend, like folders on an SSH
+/// server or snapshots of local ones, into a local destination. Only sets
+/// of copied files can be made this way, as archived, chunked and
+/// dictionary compressed sets read their sources directly.
+pub fn backup_from_remote(
+	source_backend: &dyn SourceBackend,
+	sources: &[&str],
+	dest: &str,
+	options: &BackupOptions,
+) -> Result<String, BackupError> {
+	if options.archive || options.chunked || options.zstd_dictionary {
+		return Err(io::Error::new(
+			io::ErrorKind::Unsupported,
+			"archived, chunked and dictionary compressed sets need local sources read directly",
+		)
+		.into());
+	}
+	Ok(backup_from(source_backend, sources, dest, options)?.set_name)
+}
+
+fn backup_from(
+	source_backend: &dyn SourceBackend,
+	sources: &[&str],
+	dest: &str,
+	options: &BackupOptions,
+) -> io::Result<BackupReport> {
+	progress::start();
+	check_sources(sources)?;
+	let recipients = parse_recipients(&options.encrypt_to)?;
+	let encrypted = !recipients.is_empty() || options.passphrase.is_some();
+	if encrypted && (options.archive || options.chunked) {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			"archived and chunked sets can't be encrypted",
+		));
+	}
+	if options.zstd_dictionary
+		&& (options.archive
+			|| options.chunked
+			|| !options.compression.same_format(Compression::ZSTD))
+	{
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			"a zstd dictionary needs zstd compression, and isn't used for archived or chunked sets",
+		));
+	}
+	if options.chunk_sizes.is_some() && !options.chunked {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			"chunk sizes only apply to chunked sets",
+		));
+	}
+	if options.encrypt_manifest && !encrypted {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			"only encrypted sets can have an encrypted manifest",
+		));
+	}
+	if options.encrypt_names && !encrypted {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			"only encrypted sets can have encrypted names",
+		));
+	}
+	// only plain sets can link to the files a resumable set copied
+	if options.max_duration.is_some()
+		&& (options.archive
+			|| options.chunked
+			|| encrypted
+			|| options.zstd_dictionary
+			|| options.copy_duplicates)
+	{
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			"a time limit needs a set of plain copied files, without encryption, a dictionary or copied duplicates",
+		));
+	}
+	let deadline = options
+		.max_duration
+		.map(|max_duration| Instant::now() + max_duration.to_std().unwrap_or_default());
+	let absolute_sources = sources
+		.iter()
+		.map(|source| canonical_source(source_backend, source))
+		.collect::<Result<Vec<_>, _>>()?;
+	check_root_names(source_backend, &absolute_sources)?;
+	let orders = copy_orders(
+		source_backend,
+		&absolute_sources,
+		&options.priority,
+		deadline,
+	)?;
+	fs::create_dir_all(dest).context(Operation::Creating, Path::new(dest))?;
+	let _lock = lock_destination(dest, options.wait_lock)?;
+	clean_up_temp_sets(dest)?;
+	// sets pruned by earlier runs have had their grace period
+	empty_trash(dest)?;
+	// other sets' files are encrypted with other keys, or compressed
+	// against other dictionaries
+	let catalog = match options.archive
+		|| options.chunked
+		|| options.copy_duplicates
+		|| encrypted
+		|| options.zstd_dictionary
+	{
+		true => None,
+		false => Some(HashCatalog::load(dest, options.compression)?),
+	};
+	let mut total = 0;
+	let mut required = 0;
+	for (source, folder) in sources.iter().zip(source_folders(&absolute_sources)) {
+		let estimate = estimate_backup_size(
+			source_backend,
+			Path::new(source),
+			Path::new(&folder),
+			catalog.as_ref(),
+		)?;
+		total += estimate.total;
+		// unchanged files are linked to earlier copies, taking no more space
+		required += estimate.new;
+	}
+	enforce_space_limits(dest, &options.retention, required)?;
+	preflight_space_check(dest, required, options).context(Operation::Writing, Path::new(dest))?;
+	let chunk_sizes = match options.chunked {
+		true => store_chunk_sizes(Path::new(dest), options.chunk_sizes)?,
+		false => ChunkSizes::default(),
+	};
+	let started_at = Utc::now();
+	let first_source = source_backend.describe(&absolute_sources[0]);
+	// archived and chunked sets, the set's key and sealing still write
+	// to the local filesystem directly
+	let backend = LocalStorage;
+	let set_name = create_empty_set(
+		&backend,
+		dest,
+		&options.set_name_template,
+		options.timezone,
+		&first_source,
+		|| started_at,
+	)?;
+	let in_progress = SetInProgress::new(&backend, dest, &set_name, options.keep_cancelled);
+	let dest_folder = temp_set_folder(dest, &set_name);
+	let mut metadata = SetMetadata::new(&first_source, started_at, options.describe());
+	metadata.tags = options.tags.iter().cloned().collect();
+	metadata.compression = options.compression;
+	metadata.archive = options.archive;
+	metadata.volume_size = options.volume_size.filter(|_| options.archive);
+	metadata.chunked = options.chunked;
+	metadata.encrypted = encrypted;
+	metadata.encrypted_names = options.encrypt_names;
+	let key = match encrypted {
+		true => Some(SetKey::generate()),
+		false => None,
+	};
+	if let Some(key) = &key {
+		if !recipients.is_empty() {
+			key.write_locked(&dest_folder, &recipients)?;
+		}
+		if let Some(passphrase) = &options.passphrase {
+			let params = PassphraseParams::generate();
+			key.write_with_passphrase(&dest_folder, passphrase, &params)?;
+			metadata.passphrase_kdf = Some(params);
+		}
+	}
+	let dictionary = match options.zstd_dictionary {
+		true => train_dictionary(sources)?,
+		false => None,
+	};
+	if let Some(dictionary) = &dictionary {
+		write_dictionary(&dest_folder, dictionary, key.as_ref())?;
+		metadata.zstd_dictionary = true;
+	}
+	let labelled_sources = label_sources(source_backend, sources, &absolute_sources, &mut metadata);
+	write_metadata_to(&backend, &dest_folder, &metadata)?;
+	progress::start_copying(total);
+	let (stats, manifest, deduplicated_bytes, issues) = if options.archive {
+		let layout = ArchiveLayout {
+			compression: options.compression,
+			volume_size: metadata.volume_size,
+		};
+		let (stats, files) = write_archive(&dest_folder, &labelled_sources, layout)?;
+		let manifest: Vec<ManifestEntry> = files
+			.into_iter()
+			.map(|file| ManifestEntry {
+				path: file.path,
+				size: file.size,
+				digest: file.digest,
+			})
+			.collect();
+		write_manifest_to(&backend, &dest_folder, &manifest)?;
+		(stats, manifest, 0, Vec::new())
+	} else if options.chunked {
+		let store = ChunkStore::new(Path::new(dest), options.compression);
+		let (stats, chunk_stats, files) =
+			write_chunked_set(&store, chunk_sizes, &dest_folder, &labelled_sources)?;
+		let manifest: Vec<ManifestEntry> = files
+			.into_iter()
+			.map(|file| ManifestEntry {
+				path: file.path,
+				size: file.size,
+				digest: file.digest,
+			})
+			.collect();
+		write_manifest_to(&backend, &dest_folder, &manifest)?;
+		(
+			stats,
+			manifest,
+			stats.bytes - chunk_stats.new_bytes,
+			Vec::new(),
+		)
+	} else {
+		// files are copied under their real names, then moved once the
+		// manifest has them
+		let codec = Codec {
+			compression: options.compression,
+			key,
+			encrypted_names: false,
+			dictionary: dictionary.map(Arc::new),
+		};
+		let CopiedSources {
+			stats,
+			manifest,
+			linked,
+			issues,
+		} = copy_sources(
+			source_backend,
+			&backend,
+			&dest_folder,
+			&labelled_sources,
+			&codec,
+			catalog.as_ref(),
+			&orders,
+		)?;
+		progress::set_stage(Stage::Finishing);
+		if stats.linked_bytes > 0 {
+			println!(
+				"{} bytes already in earlier sets were hard-linked to the copies there",
+				stats.linked_bytes
+			);
+		}
+		match &codec.key {
+			Some(key) if options.encrypt_manifest || options.encrypt_names => {
+				write_encrypted_manifest(&backend, &dest_folder, &manifest, key)?
+			}
+			_ => write_manifest_to(&backend, &dest_folder, &manifest)?,
+		}
+		let mut deduplicated_bytes = stats.linked_bytes;
+		if !options.copy_duplicates {
+			// copies linked to an earlier set are already counted
+			let groups = without_linked_copies(find_duplicates(&manifest), &linked);
+			deduplicated_bytes += link_duplicates(&dest_folder, &groups, &codec)?;
+		}
+		if codec.compression != Compression::None {
+			print_compression_report(&compression_report(&dest_folder, &manifest, &codec)?);
+		}
+		if options.encrypt_names {
+			let codec = Codec {
+				encrypted_names: true,
+				..codec
+			};
+			encrypt_set_names(&dest_folder, &manifest, &codec)?;
+		}
+		(stats, manifest, deduplicated_bytes, issues)
+	};
+	progress::set_stage(Stage::Finishing);
+	let set_stats = SetStats {
+		deduplicated_bytes,
+		..stats.into()
+	};
+	if stats.out_of_time {
+		return keep_resumable(in_progress, &dest_folder, &set_name, set_stats);
+	}
+	finish_metadata(&dest_folder, Utc::now(), set_stats)?;
+	in_progress.finalize()?;
+	if options.seal {
+		if let Sealed::ReadOnly(e) = seal_set(&Path::new(dest).join(&set_name))? {
+			eprintln!(
+				"warning: set {} is only read-only, not immutable: {}",
+				set_name, e
+			);
+		}
+	}
+	update_latest(dest, &set_name)?;
+	print_deduplication(dest, &set_stats)?;
+	// duplicates already share space in chunked sets, and in plain sets
+	// unless they were kept as copies
+	if options.archive || options.copy_duplicates {
+		print_duplicates_report(&find_duplicates(&manifest));
+	}
+	// the size estimate can be off if the source changed while copying
+	if let Err(e) = prune_sets(dest, &options.retention, options.trash) {
+		match e.kind() {
+			io::ErrorKind::StorageFull => {
+				eprintln!("warning: backup is outside space limits: {}", e)
+			}
+			_ => return Err(e.into()),
+		}
+	}
+	Ok(BackupReport {
+		set_folder: Path::new(dest).join(&set_name),
+		set_name,
+		sources: absolute_sources,
+		stats: set_stats,
+		issues,
+	})
+}
+
+/// How each source is copied: its priority paths first and until the
+/// deadline. Priority paths have to be in one of the sources.
+fn copy_orders(
+	source_backend: &dyn SourceBackend,
+	absolute_sources: &[PathBuf],
+	priority: &[String],
+	deadline: Option<Instant>,
+) -> io::Result<Vec<CopyOrder>> {
+	let mut orders = vec![
+		CopyOrder {
+			priority: Vec::new(),
+			deadline,
+		};
+		absolute_sources.len()
+	];
+	for path in priority {
+		let absolute = source_backend.canonicalize(Path::new(path))?;
+		let found = absolute_sources.iter().enumerate().find_map(|(i, source)| {
+			let relative = absolute.strip_prefix(source).ok()?;
+			Some((i, relative.to_path_buf()))
+		});
+		let Some((i, relative)) = found else {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				format!("the priority path {} isn't in any of the sources", path),
+			));
+		};
+		orders[i].priority.push(relative);
+	}
+	Ok(orders)
+}
+
+/// Keeps a set that ran out of time under its real name, unfinished but
+/// marked resumable, for the next backup to link its files rather than
+/// copy them again
+fn keep_resumable(
+	in_progress: SetInProgress,
+	set_folder: &Path,
+	set_name: &str,
+	stats: SetStats,
+) -> io::Result<BackupReport> {
+	let mut metadata = read_metadata(set_folder)?;
+	metadata.stats = Some(stats);
+	metadata.resumable = true;
+	write_metadata(set_folder, &metadata)?;
+	in_progress.finalize()?;
+	Err(io::Error::new(
+		io::ErrorKind::TimedOut,
+		format!(
+			"ran out of time after copying {} files ({} bytes), kept as set {} for the next backup to carry on from",
+			stats.files, stats.bytes, set_name
+		),
+	))
+}
+
+/// The source's absolute path, failing with [BackupError::SourceMissing]
+/// if there's nothing there
+pub(crate) fn canonical_source(
+	source_backend: &dyn SourceBackend,
+	source: &str,
+) -> Result<PathBuf, BackupError> {
+	match source_backend.canonicalize(Path::new(source)) {
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Err(BackupError::SourceMissing {
+			path: PathBuf::from(source),
+		}),
+		result => result.context(Operation::Reading, Path::new(source)),
+	}
+}
+
+/// A single source is copied into the root of the set, next to the files
+/// the set keeps about itself, so it can't have anything there with the
+/// same name as one of them
+pub(crate) fn check_root_names(
+	source_backend: &dyn SourceBackend,
+	absolute_sources: &[PathBuf],
+) -> io::Result<()> {
+	let [source] = absolute_sources else {
+		return Ok(());
+	};
+	let clash = source_backend
+		.list(source)?
+		.into_iter()
+		.find(|entry| SET_METADATA_FILES.contains(&entry.name.to_string_lossy().as_ref()));
+	match clash {
+		Some(entry) => Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			format!(
+				"{} has {} in its root, which is the name of one of the set's own files; back up the folder above it, or back it up with another source so it gets a subfolder of its own",
+				source_backend.describe(source),
+				entry.name.to_string_lossy()
+			),
+		)),
+		None => Ok(()),
+	}
+}
+
+pub(crate) fn check_sources(sources: &[&str]) -> io::Result<()> {
+	match sources.is_empty() {
+		true => Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			"no sources to back up",
+		)),
+		false => Ok(()),
+	}
+}
+
+/// Pairs each source with the subfolder of the set it's copied into,
+/// recording the labels in the metadata. A single source goes in the root
+/// of the set, under an empty label.
+pub(crate) fn label_sources<'a>(
+	source_backend: &dyn SourceBackend,
+	sources: &[&'a str],
+	absolute_sources: &[PathBuf],
+	metadata: &mut SetMetadata,
+) -> Vec<(String, &'a str)> {
+	let labels = source_folders(absolute_sources);
+	if absolute_sources.len() > 1 {
+		metadata.sources = labels
+			.iter()
+			.zip(absolute_sources)
+			.map(|(label, source)| (label.clone(), source_backend.describe(source)))
+			.collect();
+	}
+	labels.into_iter().zip(sources.iter().copied()).collect()
+}
+
+/// The subfolder of the set each source goes in, a single source going in
+/// the set itself
+fn source_folders(absolute_sources: &[PathBuf]) -> Vec<String> {
+	match absolute_sources.len() {
+		1 => vec![String::new()],
+		_ => source_labels(absolute_sources),
+	}
+}
+
+/// What [copy_sources] copied, with paths within the set
+pub(crate) struct CopiedSources {
+	pub stats: CopyStats,
+	pub manifest: Vec<ManifestEntry>,
+	/// Files hard-linked to an earlier set's copy
+	pub linked: HashSet<String>,
+	pub issues: Vec<FileIssue>,
+}
+
+/// Copies each source into its subfolder of the set, returning the
+/// manifest. Files are hashed while copying, so nothing is read back from
+/// the set. Sources are copied in the order given for each, those with
+/// priority paths first, until the deadline.
+pub(crate) fn copy_sources(
+	source_backend: &dyn SourceBackend,
+	backend: &dyn StorageBackend,
+	set_folder: &Path,
+	labelled_sources: &[(String, &str)],
+	codec: &Codec,
+	catalog: Option<&HashCatalog>,
+	orders: &[CopyOrder],
+) -> io::Result<CopiedSources> {
+	let mut stats = CopyStats::default();
+	let mut manifest = Vec::new();
+	let mut linked = HashSet::new();
+	let mut issues = Vec::new();
+	let unordered = CopyOrder::default();
+	let mut ordered: Vec<_> = labelled_sources
+		.iter()
+		.enumerate()
+		.map(|(i, source)| (source, orders.get(i).unwrap_or(&unordered)))
+		.collect();
+	ordered.sort_by_key(|(_, order)| order.priority.is_empty());
+	for ((label, source), order) in ordered {
+		if stats.out_of_time {
+			break;
+		}
+		let source_folder = set_folder.join(label);
+		println!("backing up {} into {:?}", source, source_folder);
+		if !label.is_empty() {
+			backend.create_dir(&source_folder)?;
+			stats.folders += 1;
+		}
+		let (source_stats, files, source_issues) = copy_folder(
+			source_backend,
+			backend,
+			source,
+			source_folder.to_str().unwrap(),
+			codec,
+			catalog.map(|catalog| EarlierCopies {
+				catalog,
+				within: Path::new(label),
+			}),
+			order,
+		)?;
+		stats.add(source_stats);
+		let in_set = |path: String| Path::new(label).join(path).to_string_lossy().into_owned();
+		issues.extend(source_issues.into_iter().map(|issue| FileIssue {
+			path: in_set(issue.path),
+			..issue
+		}));
+		for file in files {
+			let path = in_set(file.path);
+			if file.linked {
+				linked.insert(path.clone());
+			}
+			manifest.push(ManifestEntry {
+				path,
+				size: file.size,
+				digest: file.digest,
+			});
+		}
+	}
+	Ok(CopiedSources {
+		stats,
+		manifest,
+		linked,
+		issues,
+	})
+}
+
+/// Says how much of the new set, and of all sets so far, took no new space
+fn print_deduplication(dest: &str, stats: &SetStats) -> io::Result<()> {
+	println!(
+		"deduplicated {} of {} bytes, {} bytes newly stored",
+		stats.deduplicated_bytes,
+		stats.bytes,
+		stats.bytes.saturating_sub(stats.deduplicated_bytes)
+	);
+	let total = total_stats(dest)?;
+	println!(
+		"{} of {} bytes deduplicated across all sets",
+		total.deduplicated_bytes, total.bytes
+	);
+	Ok(())
+}
+
+/// Subfolder names for sources backed up together, from their whole paths
+/// so `/home/alice/docs` and `/srv/docs` don't clash
+fn source_labels(sources: &[PathBuf]) -> Vec<String> {
+	let mut labels: Vec<String> = Vec::new();
+	for source in sources {
+		let path = source.to_string_lossy();
+		let trimmed = path.trim_matches(['/', '\\']);
+		let base = match trimmed {
+			"" => "root".to_string(),
+			_ => sanitize(&trimmed.replace(['/', '\\'], "-")),
+		};
+		let mut label = base.clone();
+		let mut sequence = 2;
+		while labels.contains(&label) {
+			label = format!("{}-{}", base, sequence);
+			sequence += 1;
+		}
+		labels.push(label);
+	}
+	labels
+}
+
+/// Fails before anything is copied if the source won't fit, rather than
+/// running out of space part way through. Pruning by the retention policy
+/// happens early if that would help.
+fn preflight_space_check(dest: &str, required: u64, options: &BackupOptions) -> io::Result<()> {
+	match check_free_space(Path::new(dest), required) {
+		Err(e) if e.kind() == io::ErrorKind::StorageFull && options.retention.has_keep_rules() => {
+			println!("{}, pruning before backing up", e);
+			// trashing wouldn't free anything
+			prune_sets(dest, &options.retention, false)?;
+			check_free_space(Path::new(dest), required)
+		}
+		result => result,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup::restore::restore_set;
+	use crate::backup_sets::backup_set::{list_resumable_sets, list_sets};
+	use crate::backup_sets::manifest::MANIFEST_FILE_NAME;
+	use crate::backup_sets::set_metadata::{read_metadata, METADATA_FILE_NAME};
+	use crate::backup_sets::verify::verify_set;
+	use crate::chunk_store::store::CHUNKS_FOLDER;
+	use crate::dhcopy::dictionary::DICTIONARY_FILE_NAME;
+	use crate::dhcopy::encryption::Keyring;
+	use crate::space::max_space::SpaceLimit;
+	use crate::space::usage::folder_usage;
+	use crate::test_helpers::test_helpers::{
+		backup_folder, create_tmp_folder, file_contents_matches,
+	};
+	use std::fs::File;
+	use std::time::{Duration, SystemTime};
+
+	#[test]
+	fn test_run_reports_set() -> io::Result<()> {
+		let source = create_source()?;
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+
+		let report = run(&BackupRequest {
+			sources: vec![source.clone()],
+			destination: dest.clone(),
+			options: BackupOptions::default(),
+		})?;
+
+		assert_eq!(report.set_folder, Path::new(&dest).join(&report.set_name));
+		assert_eq!(report.sources, vec![fs::canonicalize(&source)?]);
+		assert_eq!(report.stats.files, 1);
+		assert_eq!(report.stats.bytes, THE_TEXT.len() as u64);
+		assert!(report.issues.is_empty());
+		assert_eq!(read_metadata(&report.set_folder)?.stats, Some(report.stats));
+		Ok(())
+	}
+
+	#[test]
+	#[allow(deprecated)]
+	fn test_old_backup_signature_still_works() -> io::Result<()> {
+		let source = create_source()?;
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+
+		let set_name = backup(&source, &dest)?;
+
+		assert!(Path::new(&dest)
+			.join(set_name)
+			.join(DEEP_PATH)
+			.join("testfile.txt")
+			.exists());
+		Ok(())
+	}
+
+	#[test]
+	fn test_backup_several_sources() -> io::Result<()> {
+		let first = create_source()?;
+		let second = create_source()?;
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+
+		let set_name = backup_sources(&[&first, &second], &dest, &BackupOptions::default())?;
+
+		let metadata = read_metadata(&Path::new(&dest).join(&set_name))?;
+		assert_eq!(metadata.sources.len(), 2);
+		for (label, source) in &metadata.sources {
+			assert!(Path::new(&dest)
+				.join(&set_name)
+				.join(label)
+				.join(DEEP_PATH)
+				.join("testfile.txt")
+				.exists());
+			assert_eq!(label, &source_labels(&[PathBuf::from(source)])[0]);
+		}
+		assert_eq!(metadata.stats.map(|stats| stats.files), Some(2));
+		Ok(())
+	}
+
+	#[test]
+	fn test_archive_set_verifies_and_restores() -> io::Result<()> {
+		let first = create_source()?;
+		let second = create_source()?;
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+		let options = BackupOptions {
+			compression: Compression::ZSTD,
+			archive: true,
+			..Default::default()
+		};
+
+		let set_name = backup_sources(&[&first, &second], &dest, &options)?;
+
+		let set_folder = Path::new(&dest).join(&set_name);
+		assert!(set_folder.join("dhb-archive.tar.zst").exists());
+		assert!(!set_folder.join(DEEP_PATH).exists());
+		let result = verify_set(&dest, &set_name, &Keyring::default())?;
+		assert!(result.is_ok());
+		assert_eq!(result.checked, 2);
+
+		let target = create_tmp_folder("restored")?;
+		let stats = restore_set(&dest, &set_name, &target, &[], &Keyring::default())?;
+		assert_eq!(stats.files, 2);
+		let label = read_metadata(&set_folder)?
+			.sources
+			.into_keys()
+			.next()
+			.unwrap();
+		assert!(Path::new(&target)
+			.join(label)
+			.join(DEEP_PATH)
+			.join("testfile.txt")
+			.exists());
+		Ok(())
+	}
+
+	#[test]
+	fn test_chunked_sets_share_chunks() -> io::Result<()> {
+		let source = create_source()?;
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+		let options = BackupOptions {
+			chunked: true,
+			..Default::default()
+		};
+
+		let first = backup_folder(&source, &dest, &options)?;
+		let chunks = || -> io::Result<usize> {
+			let mut count = 0;
+			for entry in fs::read_dir(Path::new(&dest).join(CHUNKS_FOLDER))? {
+				let entry = entry?;
+				if entry.file_type()?.is_dir() {
+					count += fs::read_dir(entry.path())?.count();
+				}
+			}
+			Ok(count)
+		};
+		let stored = chunks()?;
+		let second = backup_folder(&source, &dest, &options)?;
+
+		assert_eq!(list_sets(&dest)?, vec![first.clone(), second.clone()]);
+		assert_eq!(chunks()?, stored, "nothing new to store");
+		let set_folder = Path::new(&dest).join(&second);
+		assert!(!set_folder.join(DEEP_PATH).exists());
+		assert!(verify_set(&dest, &second, &Keyring::default())?.is_ok());
+		let stats = read_metadata(&set_folder)?.stats.unwrap();
+		assert_eq!(stats.deduplicated_bytes, stats.bytes);
+		let total = total_stats(&dest)?;
+		assert_eq!(total.deduplicated_bytes * 2, total.bytes);
+
+		let target = create_tmp_folder("restored")?;
+		let stats = restore_set(&dest, &first, &target, &[], &Keyring::default())?;
+		assert_eq!(stats.files, 1);
+		assert!(file_contents_matches(
+			&Path::new(&source)
+				.join(DEEP_PATH)
+				.join("testfile.txt")
+				.to_string_lossy(),
+			&Path::new(&target)
+				.join(DEEP_PATH)
+				.join("testfile.txt")
+				.to_string_lossy()
+		)?);
+		Ok(())
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_links_unchanged_files_to_earlier_set() -> io::Result<()> {
+		use std::os::unix::fs::MetadataExt;
+
+		let source = create_source()?;
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+		let first = backup_folder(&source, &dest, &BackupOptions::default())?;
+		let second = backup_folder(&source, &dest, &BackupOptions::default())?;
+		let copied = backup_folder(
+			&source,
+			&dest,
+			&BackupOptions {
+				copy_duplicates: true,
+				..Default::default()
+			},
+		)?;
+
+		let inode = |set: &str| -> io::Result<u64> {
+			let path = Path::new(&dest)
+				.join(set)
+				.join(DEEP_PATH)
+				.join("testfile.txt");
+			Ok(fs::metadata(path)?.ino())
+		};
+		assert_eq!(inode(&first)?, inode(&second)?);
+		assert_ne!(inode(&first)?, inode(&copied)?);
+		let stats = read_metadata(&Path::new(&dest).join(&second))?.stats;
+		assert_eq!(stats.map(|stats| stats.files), Some(1));
+		Ok(())
+	}
+
+	#[test]
+	fn test_counts_duplicates_linked_to_earlier_set_once() -> io::Result<()> {
+		let source = create_tmp_folder("orig")?;
+		for name in ["one", "two"] {
+			fs::write(Path::new(&source).join(name), [7u8; 5000])?;
+		}
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+		let first = backup_folder(&source, &dest, &BackupOptions::default())?;
+		verify_set(&dest, &first, &Keyring::default())?;
+
+		let second = backup_folder(&source, &dest, &BackupOptions::default())?;
+
+		let stats = read_metadata(&Path::new(&dest).join(&second))?
+			.stats
+			.unwrap();
+		assert_eq!(stats.bytes, 10_000);
+		assert_eq!(stats.deduplicated_bytes, 10_000);
+		Ok(())
+	}
+
+	#[test]
+	fn test_encrypted_set_needs_identity() -> io::Result<()> {
+		let source = create_source()?;
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+		let owner = age::x25519::Identity::generate();
+		let options = BackupOptions {
+			compression: Compression::ZSTD,
+			encrypt_to: vec![owner.to_public().to_string()],
+			encrypt_manifest: true,
+			..Default::default()
+		};
+
+		let set_name = backup_folder(&source, &dest, &options)?;
+
+		let set_folder = Path::new(&dest).join(&set_name);
+		let stored = set_folder.join(DEEP_PATH).join("testfile.txt.zst.age");
+		assert!(stored.exists());
+		assert!(!fs::read(&stored)?
+			.windows(THE_TEXT.len())
+			.any(|window| window == THE_TEXT.as_bytes()));
+		assert!(!set_folder.join(MANIFEST_FILE_NAME).exists());
+		let err = verify_set(&dest, &set_name, &Keyring::default()).err();
+		assert_eq!(err.map(|e| e.kind()), Some(io::ErrorKind::PermissionDenied));
+
+		let keys = Keyring::new(vec![Box::new(owner)]);
+		let result = verify_set(&dest, &set_name, &keys)?;
+		assert!(result.is_ok());
+		assert_eq!(result.checked, 1);
+		let target = create_tmp_folder("restored")?;
+		restore_set(&dest, &set_name, &target, &[], &keys)?;
+		assert_eq!(
+			fs::read_to_string(Path::new(&target).join(DEEP_PATH).join("testfile.txt"))?,
+			THE_TEXT
+		);
+		assert_eq!(
+			fs::read_dir(&target)?.count(),
+			1,
+			"only the backed up files"
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_passphrase_set_needs_passphrase() -> io::Result<()> {
+		let source = create_source()?;
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+		let options = BackupOptions {
+			passphrase: Some("backmeup susie".to_string().into()),
+			..Default::default()
+		};
+
+		let set_name = backup_folder(&source, &dest, &options)?;
+
+		let set_folder = Path::new(&dest).join(&set_name);
+		assert!(set_folder.join(DEEP_PATH).join("testfile.txt.age").exists());
+		let wrong = Keyring::default().with_passphrase(Some("susie".to_string().into()));
+		let err = verify_set(&dest, &set_name, &wrong).err();
+		assert_eq!(err.map(|e| e.kind()), Some(io::ErrorKind::PermissionDenied));
+
+		let keys = Keyring::default().with_passphrase(options.passphrase.clone());
+		assert!(verify_set(&dest, &set_name, &keys)?.is_ok());
+		let target = create_tmp_folder("restored")?;
+		restore_set(&dest, &set_name, &target, &[], &keys)?;
+		assert_eq!(
+			fs::read_to_string(Path::new(&target).join(DEEP_PATH).join("testfile.txt"))?,
+			THE_TEXT
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_encrypted_names() -> io::Result<()> {
+		let source = create_source()?;
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+		let owner = age::x25519::Identity::generate();
+		let options = BackupOptions {
+			encrypt_to: vec![owner.to_public().to_string()],
+			encrypt_names: true,
+			..Default::default()
+		};
+
+		let set_name = backup_folder(&source, &dest, &options)?;
+
+		let set_folder = Path::new(&dest).join(&set_name);
+		assert!(!set_folder.join(DEEP_PATH).exists());
+		assert!(!set_folder.join(MANIFEST_FILE_NAME).exists());
+		let keys = Keyring::new(vec![Box::new(owner)]);
+		let result = verify_set(&dest, &set_name, &keys)?;
+		assert!(result.is_ok());
+		assert_eq!(result.checked, 1);
+		let target = create_tmp_folder("restored")?;
+		restore_set(&dest, &set_name, &target, &[], &keys)?;
+		assert_eq!(
+			fs::read_to_string(Path::new(&target).join(DEEP_PATH).join("testfile.txt"))?,
+			THE_TEXT
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_zstd_dictionary() -> io::Result<()> {
+		let source = create_tmp_folder("orig")?;
+		for i in 0..300 {
+			fs::write(
+				Path::new(&source).join(format!("{}.json", i)),
+				format!(r#"{{"id": {}, "name": "susie", "status": "backed up"}}"#, i),
+			)?;
+		}
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+		let options = BackupOptions {
+			compression: Compression::ZSTD,
+			zstd_dictionary: true,
+			..Default::default()
+		};
+
+		let set_name = backup_folder(&source, &dest, &options)?;
+
+		let set_folder = Path::new(&dest).join(&set_name);
+		assert!(read_metadata(&set_folder)?.zstd_dictionary);
+		assert!(set_folder.join(DICTIONARY_FILE_NAME).exists());
+		let result = verify_set(&dest, &set_name, &Keyring::default())?;
+		assert!(result.is_ok());
+		assert_eq!(result.checked, 300);
+		let target = create_tmp_folder("restored")?;
+		restore_set(&dest, &set_name, &target, &[], &Keyring::default())?;
+		assert_eq!(
+			fs::read_to_string(Path::new(&target).join("7.json"))?,
+			r#"{"id": 7, "name": "susie", "status": "backed up"}"#
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_source_labels() {
+		let sources = [
+			PathBuf::from("/home/alice"),
+			PathBuf::from("/etc"),
+			PathBuf::from("/"),
+			PathBuf::from("/home/alice"),
+		];
+		assert_eq!(
+			source_labels(&sources),
+			vec!["home-alice", "etc", "root", "home-alice-2"]
+		);
+	}
+
+	#[test]
+	fn test_refuses_source_with_set_file_names() -> io::Result<()> {
+		let source = create_source()?;
+		fs::write(Path::new(&source).join(METADATA_FILE_NAME), THE_TEXT)?;
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+
+		let err = backup_folder(&source, &dest, &BackupOptions::default()).unwrap_err();
+
+		assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+		assert!(list_sets(&dest)?.is_empty());
+
+		// in a subfolder of its own it's backed up like any other file
+		let other = create_source()?;
+		let set_name = backup_sources(&[&source, &other], &dest, &BackupOptions::default())?;
+		let metadata = read_metadata(&Path::new(&dest).join(&set_name))?;
+		let canonical = fs::canonicalize(&source)?;
+		let (label, _) = metadata
+			.sources
+			.iter()
+			.find(|(_, path)| Path::new(path) == canonical)
+			.unwrap();
+		let copied = Path::new(&dest)
+			.join(&set_name)
+			.join(label)
+			.join(METADATA_FILE_NAME);
+		assert_eq!(fs::read_to_string(copied)?, THE_TEXT);
+		Ok(())
+	}
+
+	#[test]
+	fn test_max_space_includes_new_set() -> io::Result<()> {
+		let source = create_source()?;
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+		let old_set = Path::new(&dest).join("dhb-set-20000101-000000");
+		let previous_set = Path::new(&dest).join("dhb-set-20000102-000000");
+		for set in [&old_set, &previous_set] {
+			fs::create_dir_all(set)?;
+			fs::write(set.join("data"), [0u8; 1000])?;
+		}
+		let options = BackupOptions {
+			// room for the previous set and the new one with its metadata, but not the old set as well
+			retention: RetentionPolicy {
+				max_space: Some(SpaceLimit::Bytes(1600)),
+				..Default::default()
+			},
+			..Default::default()
+		};
+
+		backup_folder(&source, &dest, &options)?;
+
+		assert!(!old_set.exists(), "old set should make way for the new one");
+		assert!(previous_set.exists(), "latest set should always be kept");
+		Ok(())
+	}
+
+	#[test]
+	fn test_unchanged_backup_needs_no_room() -> io::Result<()> {
+		let source = create_tmp_folder("orig")?;
+		let data = Path::new(&source).join("data");
+		fs::write(&data, [0u8; 10_000])?;
+		// so it's seen to be unchanged without reading it
+		let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+		File::options()
+			.write(true)
+			.open(&data)?
+			.set_modified(an_hour_ago)?;
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+		let first = backup_folder(&source, &dest, &BackupOptions::default())?;
+		let used = folder_usage(Path::new(&dest))?.total;
+		let options = BackupOptions {
+			// room for another set's metadata, but not another copy
+			retention: RetentionPolicy {
+				max_space: Some(SpaceLimit::Bytes(used + 5_000)),
+				..Default::default()
+			},
+			..Default::default()
+		};
+
+		let second = backup_folder(&source, &dest, &options)?;
+
+		assert_eq!(list_sets(&dest)?, vec![first, second]);
+		Ok(())
+	}
+
+	#[test]
+	fn test_carries_on_after_running_out_of_time() -> io::Result<()> {
+		let source = create_source()?;
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+		let limited = BackupOptions {
+			max_duration: Some(TimeDelta::zero()),
+			..Default::default()
+		};
+
+		let err = backup_folder(&source, &dest, &limited).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+		let sets = list_sets(&dest)?;
+		assert_eq!(list_resumable_sets(&dest)?, sets);
+		let metadata = read_metadata(&Path::new(&dest).join(&sets[0]))?;
+		assert!(metadata.finished_at.is_none());
+
+		let outside = BackupOptions {
+			priority: vec![dest.clone()],
+			..limited
+		};
+		let err = backup_folder(&source, &dest, &outside).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+		// the resumable set is pruned once a backup completes, as any
+		// incomplete set is
+		let retained = BackupOptions {
+			retention: RetentionPolicy {
+				last: 5,
+				..Default::default()
+			},
+			..Default::default()
+		};
+		let set_name = backup_folder(&source, &dest, &retained)?;
+		assert_eq!(list_sets(&dest)?, vec![set_name]);
+		Ok(())
+	}
+
+	#[test]
+	fn test_backup_through_source_backend() -> io::Result<()> {
+		let source = create_source()?;
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+
+		let set_name = backup_from_remote(&LocalSource, &[&source], &dest, &Default::default())?;
+
+		let metadata = read_metadata(&Path::new(&dest).join(&set_name))?;
+		assert_eq!(
+			Path::new(&metadata.source),
+			fs::canonicalize(&source)?.as_path()
+		);
+		assert!(verify_set(&dest, &set_name, &Keyring::default())?.is_ok());
+		let options = BackupOptions {
+			chunked: true,
+			..Default::default()
+		};
+		let err = backup_from_remote(&LocalSource, &[&source], &dest, &options).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+		Ok(())
+	}
+}
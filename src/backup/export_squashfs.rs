@@ -0,0 +1,104 @@
+use crate::backup::restore::restore_set;
+use crate::backup_sets::set_metadata::read_metadata;
+use crate::dhcopy::copy_folder::CopyStats;
+use crate::dhcopy::encryption::Keyring;
+use crate::error::BackupError;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+/// Tool from squashfs-tools that builds the image
+const MKSQUASHFS: &str = "mksquashfs";
+
+/// Writes a set's files into a new SquashFS image at `output`, which can be
+/// loop-mounted read-only to browse or check the set. The set is restored
+/// into a staging folder beside `output` and handed to `mksquashfs`, so
+/// that needs installing and the staging needs as much space as the set
+/// restored. Every file is dated with when the backup started.
+pub fn export_squashfs(
+	dest: &str,
+	set_name: &str,
+	output: &str,
+	keys: &Keyring,
+) -> Result<CopyStats, BackupError> {
+	// never overwrite, as restore won't
+	if Path::new(output).exists() {
+		return Err(io::Error::new(
+			io::ErrorKind::AlreadyExists,
+			format!("{} already exists", output),
+		)
+		.into());
+	}
+	let staging = format!("{}.dhb-staging", output);
+	let result = restore_set(dest, set_name, &staging, &[], keys).and_then(|stats| {
+		make_image(dest, set_name, &staging, output)?;
+		Ok(stats)
+	});
+	if Path::new(&staging).exists() {
+		fs::remove_dir_all(&staging)?;
+	}
+	result
+}
+
+fn make_image(dest: &str, set_name: &str, staging: &str, output: &str) -> io::Result<()> {
+	let mut command = Command::new(MKSQUASHFS);
+	command.args([staging, output, "-noappend", "-no-progress"]);
+	if let Ok(metadata) = read_metadata(&Path::new(dest).join(set_name)) {
+		command.args(["-all-time", &metadata.started_at.timestamp().to_string()]);
+	}
+	let status = command.status().map_err(|e| match e.kind() {
+		io::ErrorKind::NotFound => io::Error::new(
+			io::ErrorKind::NotFound,
+			format!("{} not found, install squashfs-tools", MKSQUASHFS),
+		),
+		_ => e,
+	})?;
+	match status.success() {
+		true => Ok(()),
+		false => Err(io::Error::other(format!(
+			"{} failed, {}",
+			MKSQUASHFS, status
+		))),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup::backup::BackupOptions;
+	use crate::test_helpers::test_helpers::{backup_folder, create_tmp_folder};
+
+	#[test]
+	fn test_exports_squashfs() -> io::Result<()> {
+		let source = create_tmp_folder("orig")?;
+		fs::create_dir_all(Path::new(&source).join("deep"))?;
+		fs::write(
+			Path::new(&source).join("deep/testfile.txt"),
+			"backmeup susie",
+		)?;
+		let dest = create_tmp_folder("backups")?;
+		let set_name = backup_folder(&source, &dest, &BackupOptions::default())?;
+		let export = create_tmp_folder("export")?;
+		let output = Path::new(&export).join("set.sqfs");
+		let output = output.to_str().unwrap();
+
+		match export_squashfs(&dest, &set_name, output, &Keyring::default()) {
+			Ok(stats) => {
+				assert_eq!(stats.files, 1);
+				assert!(Path::new(output).is_file());
+			}
+			// squashfs-tools isn't installed everywhere the tests run
+			Err(e) => {
+				assert_eq!(e.kind(), io::ErrorKind::NotFound);
+				assert!(e.to_string().contains(MKSQUASHFS));
+			}
+		}
+		assert_eq!(
+			fs::read_dir(&export)?.count(),
+			usize::from(Path::new(output).exists()),
+			"the staging folder should be gone"
+		);
+		Ok(())
+	}
+}
@@ -0,0 +1,106 @@
+use crate::backup::set_entries::{for_each_set_entry, SetEntry};
+use crate::backup_sets::set_metadata::read_metadata;
+use crate::dhcopy::copy_folder::CopyStats;
+use crate::dhcopy::encryption::Keyring;
+use crate::error::BackupError;
+use chrono::{Datelike, Timelike};
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, DateTime, ZipWriter};
+
+/// Writes a set's files into a new zip file at `output`, decompressed and
+/// decrypted as for a restore, so it can be opened with anything that
+/// reads zips. Every entry is dated with when the backup started.
+pub fn export_zip(
+	dest: &str,
+	set_name: &str,
+	output: &str,
+	keys: &Keyring,
+) -> Result<CopyStats, BackupError> {
+	let set_folder = Path::new(dest).join(set_name);
+	if !set_folder.is_dir() {
+		return Err(BackupError::set_missing(dest, set_name));
+	}
+	let mut options = SimpleFileOptions::default()
+		.compression_method(CompressionMethod::Deflated)
+		// sizes aren't known until each file has been read, so every entry
+		// gets zip64 fields in case it's over 4GB
+		.large_file(true);
+	if let Ok(metadata) = read_metadata(&set_folder) {
+		let started = metadata.started_at;
+		// zips before 1980 can't be dated, and the default is 1980 anyway
+		if let Ok(time) = DateTime::from_date_and_time(
+			started.year().try_into().unwrap_or(0),
+			started.month() as u8,
+			started.day() as u8,
+			started.hour() as u8,
+			started.minute() as u8,
+			started.second() as u8,
+		) {
+			options = options.last_modified_time(time);
+		}
+	}
+	// never overwrite, as restore won't
+	let mut zip = ZipWriter::new(BufWriter::new(File::create_new(output)?));
+	let mut stats = CopyStats::default();
+	for_each_set_entry(&set_folder, keys, |entry| {
+		match entry {
+			SetEntry::Folder(path) => {
+				zip.add_directory(path, options)?;
+				stats.folders += 1;
+			}
+			SetEntry::File(path, contents) => {
+				zip.start_file(path, options)?;
+				stats.bytes += io::copy(contents, &mut zip)?;
+				stats.files += 1;
+			}
+		}
+		Ok(())
+	})?;
+	zip.finish().map_err(io::Error::from)?;
+	Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup::backup::BackupOptions;
+	use crate::dhcopy::compression::Compression;
+	use crate::test_helpers::test_helpers::{backup_folder, create_tmp_folder};
+	use std::fs;
+	use std::io::Read;
+	use zip::ZipArchive;
+
+	#[test]
+	fn test_exports_zip() -> io::Result<()> {
+		let source = create_tmp_folder("orig")?;
+		fs::create_dir_all(Path::new(&source).join("deep/empty"))?;
+		fs::write(
+			Path::new(&source).join("deep/testfile.txt"),
+			"backmeup susie",
+		)?;
+		let dest = create_tmp_folder("backups")?;
+		let options = BackupOptions {
+			compression: Compression::ZSTD,
+			..Default::default()
+		};
+		let set_name = backup_folder(&source, &dest, &options)?;
+		let output = Path::new(&create_tmp_folder("export")?).join("set.zip");
+		let output = output.to_str().unwrap();
+
+		let stats = export_zip(&dest, &set_name, output, &Keyring::default())?;
+
+		assert_eq!((stats.files, stats.folders), (1, 2));
+		let mut zip = ZipArchive::new(File::open(output)?)?;
+		assert!(zip.by_name("deep/empty/")?.is_dir());
+		let mut contents = String::new();
+		zip.by_name("deep/testfile.txt")?
+			.read_to_string(&mut contents)?;
+		assert_eq!(contents, "backmeup susie");
+		let err = export_zip(&dest, &set_name, output, &Keyring::default()).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+		Ok(())
+	}
+}
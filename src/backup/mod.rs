@@ -1 +1,12 @@
+#[allow(clippy::module_inception)]
 pub mod backup;
+pub mod export_squashfs;
+pub mod export_zip;
+pub mod push;
+pub mod remote;
+pub mod replicate;
+pub mod restore;
+pub mod run;
+pub mod set_entries;
+
+pub use self::backup::{run, BackupReport, BackupRequest};
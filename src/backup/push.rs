@@ -0,0 +1,267 @@
+use crate::backup_sets::manifest::read_manifest;
+use crate::backup_sets::set_metadata::{set_storage, SetStorage, METADATA_FILE_NAME};
+use crate::chunk_store::store::CHUNKS_FOLDER;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+const RSYNC: &str = "rsync";
+
+// Lives in the destination alongside the sets, holding the last set pushed
+// to each target, which the next push links unchanged files against
+const PUSHED_FILE: &str = ".dhb-pushed.toml";
+
+/// What of a set is sent to the target, as paths within the set
+#[derive(Debug, Default, PartialEq)]
+pub struct PushPlan {
+	/// Files the set last pushed has with the same contents, which the
+	/// target links to its copy there instead of receiving them again
+	pub unchanged: Vec<String>,
+	/// Every other file and folder
+	pub changed: Vec<String>,
+}
+
+/// Copies a complete set to an rsync target, anything rsync takes as a
+/// destination: `host:/backups`, `rsync://host/module` or a local folder.
+/// Files the manifest shows unchanged since the set last pushed there are
+/// hard-linked to that set on the target, so only changed files are sent.
+/// A chunked set's chunks are pushed first, and the set's metadata last so
+/// a set cut short on the target doesn't look finished. Uploads are kept
+/// under `upload_limit` bytes a second if given. Needs rsync.
+pub fn push_set(
+	dest: &str,
+	set_name: &str,
+	target: &str,
+	upload_limit: Option<u64>,
+) -> io::Result<PushPlan> {
+	let set_folder = Path::new(dest).join(set_name);
+	if !set_folder.is_dir() {
+		return Err(io::Error::new(
+			io::ErrorKind::NotFound,
+			format!("no set named {} in {}", set_name, dest),
+		));
+	}
+	// the target only has what the last push left it
+	let previous = last_pushed(dest, target)?
+		.filter(|previous| previous != set_name && Path::new(dest).join(previous).is_dir());
+	let plan = plan_push(dest, set_name, previous.as_deref())?;
+	let target = target.trim_end_matches('/');
+	let remote_set = format!("{}/{}/", target, set_name);
+	let local_set = format!("{}/", set_folder.display());
+	// rsync counts in KiB a second
+	let bwlimit = upload_limit.map(|limit| format!("--bwlimit={}", (limit / 1024).max(1)));
+
+	if set_storage(&set_folder)?.chunked {
+		let chunks = Path::new(dest).join(CHUNKS_FOLDER);
+		let local_chunks = format!("{}/", chunks.display());
+		let remote_chunks = format!("{}/{}/", target, CHUNKS_FOLDER);
+		// chunk names are their digest, so rsync skips those already there
+		let mut args = vec!["-a"];
+		args.extend(bwlimit.as_deref());
+		args.extend([local_chunks.as_str(), remote_chunks.as_str()]);
+		run_rsync(&args, None)?;
+	}
+	let link_dest = previous.map(|previous| format!("--link-dest=../{}", previous));
+	let mut args = vec!["-a", "--from0", "--files-from=-"];
+	args.extend(bwlimit.as_deref());
+	args.extend(link_dest.as_deref());
+	if !plan.unchanged.is_empty() {
+		// sizes are enough to find them as the manifest already matched them
+		let args = [&args[..], &["--size-only", &local_set, &remote_set]].concat();
+		run_rsync(&args, Some(&plan.unchanged))?;
+	}
+	let (metadata, changed): (Vec<String>, Vec<String>) = plan
+		.changed
+		.iter()
+		.cloned()
+		.partition(|path| path == METADATA_FILE_NAME);
+	args.extend([local_set.as_str(), remote_set.as_str()]);
+	for files in [changed, metadata] {
+		if !files.is_empty() {
+			run_rsync(&args, Some(&files))?;
+		}
+	}
+	record_pushed(dest, target, set_name)?;
+	Ok(plan)
+}
+
+/// Splits the set's files by whether `previous` holds them with the same
+/// contents, going by both manifests. Sets whose files are stored
+/// differently, like archived or encrypted ones, share nothing.
+pub fn plan_push(dest: &str, set_name: &str, previous: Option<&str>) -> io::Result<PushPlan> {
+	let set_folder = Path::new(dest).join(set_name);
+	let unchanged = match previous {
+		Some(previous) => unchanged_files(&set_folder, &Path::new(dest).join(previous))?,
+		None => HashSet::new(),
+	};
+	let mut files = Vec::new();
+	list_files(&set_folder, Path::new(""), &mut files)?;
+	let (mut unchanged, changed): (Vec<String>, Vec<String>) =
+		files.into_iter().partition(|path| unchanged.contains(path));
+	unchanged.sort();
+	Ok(PushPlan { unchanged, changed })
+}
+
+fn unchanged_files(set_folder: &Path, previous_folder: &Path) -> io::Result<HashSet<String>> {
+	let storage = set_storage(set_folder)?;
+	let previous_storage = set_storage(previous_folder)?;
+	let plain = |storage: &SetStorage| {
+		!(storage.archive || storage.chunked || storage.encrypted || storage.zstd_dictionary)
+	};
+	if !plain(&storage)
+		|| !plain(&previous_storage)
+		|| !storage
+			.compression
+			.same_format(previous_storage.compression)
+	{
+		return Ok(HashSet::new());
+	}
+	let (entries, previous_entries) =
+		match (read_manifest(set_folder), read_manifest(previous_folder)) {
+			(Ok(entries), Ok(previous_entries)) => (entries, previous_entries),
+			(Err(e), _) | (_, Err(e)) if e.kind() == io::ErrorKind::NotFound => {
+				return Ok(HashSet::new())
+			}
+			(Err(e), _) | (_, Err(e)) => return Err(e),
+		};
+	let previous_entries: HashSet<(String, String)> = previous_entries.into_iter().collect();
+	Ok(entries
+		.into_iter()
+		.filter(|entry| previous_entries.contains(entry))
+		.map(|(_, path)| {
+			storage
+				.compression
+				.stored_path(Path::new(&path))
+				.to_string_lossy()
+				.into_owned()
+		})
+		.collect())
+}
+
+/// Every file and folder under `folder`, folders before what's in them
+fn list_files(folder: &Path, relative: &Path, files: &mut Vec<String>) -> io::Result<()> {
+	let mut children = fs::read_dir(folder)?.collect::<io::Result<Vec<_>>>()?;
+	children.sort_by_key(|entry| entry.file_name());
+	for entry in children {
+		let relative_path = relative.join(entry.file_name());
+		files.push(relative_path.to_string_lossy().into_owned());
+		if entry.file_type()?.is_dir() {
+			list_files(&entry.path(), &relative_path, files)?;
+		}
+	}
+	Ok(())
+}
+
+/// Runs rsync, giving it the files to send on its standard input
+fn run_rsync(args: &[&str], files: Option<&[String]>) -> io::Result<()> {
+	let mut command = Command::new(RSYNC);
+	command.args(args);
+	if files.is_some() {
+		command.stdin(Stdio::piped());
+	}
+	let mut child = command.spawn().map_err(|e| match e.kind() {
+		io::ErrorKind::NotFound => io::Error::new(
+			io::ErrorKind::NotFound,
+			format!("{} not found, install it to push sets", RSYNC),
+		),
+		_ => e,
+	})?;
+	if let (Some(files), Some(mut stdin)) = (files, child.stdin.take()) {
+		for file in files {
+			stdin.write_all(file.as_bytes())?;
+			stdin.write_all(b"\0")?;
+		}
+	}
+	let status = child.wait()?;
+	match status.success() {
+		true => Ok(()),
+		false => Err(io::Error::other(format!("{} failed, {}", RSYNC, status))),
+	}
+}
+
+/// The last set pushed to the target, if any
+pub fn last_pushed(dest: &str, target: &str) -> io::Result<Option<String>> {
+	Ok(read_pushed(dest)?.remove(target.trim_end_matches('/')))
+}
+
+fn record_pushed(dest: &str, target: &str, set_name: &str) -> io::Result<()> {
+	let mut pushed = read_pushed(dest)?;
+	pushed.insert(target.to_string(), set_name.to_string());
+	let contents = toml::to_string(&pushed).map_err(io::Error::other)?;
+	fs::write(Path::new(dest).join(PUSHED_FILE), contents)
+}
+
+fn read_pushed(dest: &str) -> io::Result<BTreeMap<String, String>> {
+	match fs::read_to_string(Path::new(dest).join(PUSHED_FILE)) {
+		Ok(contents) => {
+			toml::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+		}
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+		Err(e) => Err(e),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup::backup::BackupOptions;
+	use crate::backup_sets::manifest::MANIFEST_FILE_NAME;
+	use crate::test_helpers::test_helpers::{backup_folder, create_tmp_folder};
+
+	#[test]
+	fn test_plans_unchanged_files() -> io::Result<()> {
+		let source = create_tmp_folder("orig")?;
+		fs::create_dir(Path::new(&source).join("thats"))?;
+		fs::write(Path::new(&source).join("thats/same.txt"), "backmeup susie")?;
+		fs::write(Path::new(&source).join("changed.txt"), "backmeup sammy")?;
+		let dest = create_tmp_folder("backups")?;
+		let first = backup_folder(&source, &dest, &BackupOptions::default())?;
+		fs::write(Path::new(&source).join("changed.txt"), "backmeup stevie")?;
+		let second = backup_folder(&source, &dest, &BackupOptions::default())?;
+
+		let plan = plan_push(&dest, &second, Some(&first))?;
+
+		assert_eq!(plan.unchanged, vec!["thats/same.txt"]);
+		for path in [
+			"changed.txt",
+			"thats",
+			MANIFEST_FILE_NAME,
+			METADATA_FILE_NAME,
+		] {
+			assert!(
+				plan.changed.iter().any(|changed| changed == path),
+				"{}",
+				path
+			);
+		}
+		assert!(plan_push(&dest, &second, None)?.unchanged.is_empty());
+		Ok(())
+	}
+
+	#[test]
+	fn test_pushes_to_folder() -> io::Result<()> {
+		let source = create_tmp_folder("orig")?;
+		fs::write(Path::new(&source).join("testfile.txt"), "backmeup susie")?;
+		let dest = create_tmp_folder("backups")?;
+		let set_name = backup_folder(&source, &dest, &BackupOptions::default())?;
+		let target = create_tmp_folder("replica")?;
+
+		match push_set(&dest, &set_name, &target, None) {
+			Ok(_) => {
+				let pushed = Path::new(&target).join(&set_name);
+				assert!(pushed.join("testfile.txt").is_file());
+				assert!(pushed.join(METADATA_FILE_NAME).is_file());
+				assert_eq!(last_pushed(&dest, &target)?, Some(set_name));
+			}
+			// rsync isn't installed everywhere the tests run
+			Err(e) => {
+				assert_eq!(e.kind(), io::ErrorKind::NotFound);
+				assert!(e.to_string().contains(RSYNC));
+				assert_eq!(last_pushed(&dest, &target)?, None);
+			}
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,174 @@
+use crate::backup::backup::{
+	canonical_source, check_root_names, check_sources, copy_sources, label_sources, BackupOptions,
+	CopiedSources,
+};
+use crate::backup_sets::backup_set::{create_empty_set, temp_set_folder, SetInProgress};
+use crate::backup_sets::manifest::write_manifest_to;
+use crate::backup_sets::set_metadata::{write_metadata_to, SetMetadata, SetStats};
+use crate::dhcopy::codec::Codec;
+use crate::error::BackupError;
+use crate::progress::{self, Stage};
+use crate::space::estimate::estimate_size;
+use crate::storage::backend::StorageBackend;
+use crate::storage::local::LocalSource;
+use chrono::Utc;
+use std::io;
+use std::path::Path;
+
+/// Backs up into a destination reached only through the backend, like an
+/// SFTP server. Sets are copied file by file, compressed if asked, and
+/// renamed to their real name once complete as with local destinations.
+///
+/// Everything that needs the destination's filesystem isn't available:
+/// archived, chunked, encrypted, dictionary compressed and sealed sets,
+/// retention, hard-linking to earlier sets, the destination lock and
+/// `latest`. Concurrent backups still can't claim the same set name, and
+/// sets left under their temporary name by a failed backup stay there
+/// until removed by hand, though cancelled backups tidy up after
+/// themselves.
+pub fn backup_to_remote(
+	backend: &dyn StorageBackend,
+	sources: &[&str],
+	dest: &str,
+	options: &BackupOptions,
+) -> Result<String, BackupError> {
+	check_sources(sources)?;
+	check_remote_options(options)?;
+	progress::start();
+	let started_at = Utc::now();
+	let absolute_sources = sources
+		.iter()
+		.map(|source| canonical_source(&LocalSource, source))
+		.collect::<Result<Vec<_>, _>>()?;
+	check_root_names(&LocalSource, &absolute_sources)?;
+	let first_source = absolute_sources[0].to_string_lossy();
+	let set_name = create_empty_set(
+		backend,
+		dest,
+		&options.set_name_template,
+		options.timezone,
+		&first_source,
+		|| started_at,
+	)?;
+	let in_progress = SetInProgress::new(backend, dest, &set_name, options.keep_cancelled);
+	let dest_folder = temp_set_folder(dest, &set_name);
+	let mut metadata = SetMetadata::new(&first_source, started_at, options.describe());
+	metadata.tags = options.tags.iter().cloned().collect();
+	metadata.compression = options.compression;
+	let labelled_sources = label_sources(&LocalSource, sources, &absolute_sources, &mut metadata);
+	write_metadata_to(backend, &dest_folder, &metadata)?;
+
+	let codec = Codec::from(options.compression);
+	let mut total = 0;
+	for source in &absolute_sources {
+		total += estimate_size(&LocalSource, source)?;
+	}
+	progress::start_copying(total);
+	let CopiedSources {
+		stats, manifest, ..
+	} = copy_sources(
+		&LocalSource,
+		backend,
+		&dest_folder,
+		&labelled_sources,
+		&codec,
+		None,
+		&[],
+	)?;
+	progress::set_stage(Stage::Finishing);
+	write_manifest_to(backend, &dest_folder, &manifest)?;
+	// written again rather than updated in place, as the first copy can't
+	// be read back cheaply
+	metadata.finished_at = Some(Utc::now());
+	metadata.stats = Some(SetStats::from(stats));
+	write_metadata_to(backend, &dest_folder, &metadata)?;
+	in_progress.finalize()?;
+	println!(
+		"backed up {} files, {} bytes into {}",
+		stats.files,
+		stats.bytes,
+		Path::new(dest).join(&set_name).display()
+	);
+	Ok(set_name)
+}
+
+fn check_remote_options(options: &BackupOptions) -> io::Result<()> {
+	let unsupported = [
+		("archived sets", options.archive),
+		("chunked sets", options.chunked),
+		(
+			"encryption",
+			!options.encrypt_to.is_empty() || options.passphrase.is_some(),
+		),
+		("zstd dictionaries", options.zstd_dictionary),
+		("sealing", options.seal),
+		("retention", !options.retention.is_unlimited()),
+		("trash", options.trash),
+		("time limits", options.max_duration.is_some()),
+	];
+	match unsupported.iter().find(|(_, used)| *used) {
+		Some((name, _)) => Err(io::Error::new(
+			io::ErrorKind::Unsupported,
+			format!("remote destinations don't support {}", name),
+		)),
+		None => Ok(()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup::restore::restore_set;
+	use crate::backup_sets::backup_set::list_sets;
+	use crate::backup_sets::set_metadata::read_metadata;
+	use crate::backup_sets::verify::verify_set;
+	use crate::dhcopy::compression::Compression;
+	use crate::dhcopy::encryption::Keyring;
+	use crate::storage::local::LocalStorage;
+	use crate::test_helpers::test_helpers::{create_tmp_folder, file_contents_matches};
+	use std::fs;
+
+	#[test]
+	fn test_backs_up_through_backend() -> io::Result<()> {
+		let source = create_tmp_folder("orig")?;
+		fs::create_dir(Path::new(&source).join("thats"))?;
+		fs::write(
+			Path::new(&source).join("thats/testfile.txt"),
+			"backmeup susie",
+		)?;
+		let dest = create_tmp_folder("backups")?;
+		let options = BackupOptions {
+			compression: Compression::ZSTD,
+			..Default::default()
+		};
+
+		let set_name = backup_to_remote(&LocalStorage, &[&source], &dest, &options)?;
+
+		assert_eq!(list_sets(&dest)?, vec![set_name.clone()]);
+		let metadata = read_metadata(&Path::new(&dest).join(&set_name))?;
+		assert!(metadata.finished_at.is_some());
+		assert_eq!(metadata.stats.unwrap().files, 1);
+		assert!(verify_set(&dest, &set_name, &Keyring::default())?.is_ok());
+		let target = create_tmp_folder("restored")?;
+		restore_set(&dest, &set_name, &target, &[], &Keyring::default())?;
+		assert!(file_contents_matches(
+			&format!("{}/thats/testfile.txt", source),
+			&format!("{}/thats/testfile.txt", target)
+		)?);
+		Ok(())
+	}
+
+	#[test]
+	fn test_refuses_local_only_options() -> io::Result<()> {
+		let source = create_tmp_folder("orig")?;
+		let dest = create_tmp_folder("backups")?;
+		let options = BackupOptions {
+			chunked: true,
+			..Default::default()
+		};
+		let err = backup_to_remote(&LocalStorage, &[&source], &dest, &options).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+		assert!(list_sets(&dest)?.is_empty());
+		Ok(())
+	}
+}
@@ -0,0 +1,224 @@
+use crate::backup_sets::backup_set::{finalize_set, temp_set_folder};
+use crate::backup_sets::set_metadata::set_storage;
+use crate::chunk_store::chunker::CHUNK_SIZES_FILE_NAME;
+use crate::chunk_store::store::CHUNKS_FOLDER;
+use crate::storage::backend::StorageBackend;
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// What replicating a set copied
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReplicateStats {
+	pub files: u64,
+	pub bytes: u64,
+	/// Of the files, chunks the replica's chunk store didn't have yet
+	pub chunks: u64,
+	/// Of the bytes, those an interrupted replication had already sent,
+	/// which weren't sent again
+	pub resumed: u64,
+}
+
+/// Copies a complete set as it's stored, compressed or encrypted files
+/// and all, into another destination reached through the backend, e.g. a
+/// second disk or an SFTP server. The copy is written under a temporary
+/// name and renamed once complete, as backups are. A chunked set's chunks
+/// that the replica doesn't have yet are copied first. An interrupted
+/// replication carries on where it stopped, keeping the files it finished
+/// and sending only the rest of one it was part way through.
+pub fn replicate_set(
+	dest: &str,
+	set_name: &str,
+	backend: &dyn StorageBackend,
+	replica: &str,
+) -> io::Result<ReplicateStats> {
+	let set_folder = Path::new(dest).join(set_name);
+	if !set_folder.is_dir() {
+		return Err(io::Error::new(
+			io::ErrorKind::NotFound,
+			format!("no set named {} in {}", set_name, dest),
+		));
+	}
+	if backend.exists(&Path::new(replica).join(set_name))? {
+		return Err(io::Error::new(
+			io::ErrorKind::AlreadyExists,
+			format!("{} already has a set named {}", replica, set_name),
+		));
+	}
+	let mut stats = ReplicateStats::default();
+	backend.create_dir_all(Path::new(replica))?;
+	if set_storage(&set_folder)?.chunked {
+		let chunks = Path::new(dest).join(CHUNKS_FOLDER);
+		copy_new_chunks(
+			backend,
+			&chunks,
+			&Path::new(replica).join(CHUNKS_FOLDER),
+			&mut stats,
+		)?;
+	}
+	// any left by an earlier replication that failed part way is resumed,
+	// sets never changing once finished
+	let temp_folder = temp_set_folder(replica, set_name);
+	create_dir_if_missing(backend, &temp_folder)?;
+	copy_folder_as_stored(backend, &set_folder, &temp_folder, &mut stats)?;
+	finalize_set(backend, replica, set_name)?;
+	Ok(stats)
+}
+
+fn copy_folder_as_stored(
+	backend: &dyn StorageBackend,
+	folder: &Path,
+	replica_folder: &Path,
+	stats: &mut ReplicateStats,
+) -> io::Result<()> {
+	let mut children = fs::read_dir(folder)?.collect::<io::Result<Vec<_>>>()?;
+	children.sort_by_key(|entry| entry.file_name());
+	for entry in children {
+		let replica_path = replica_folder.join(entry.file_name());
+		if entry.file_type()?.is_dir() {
+			create_dir_if_missing(backend, &replica_path)?;
+			copy_folder_as_stored(backend, &entry.path(), &replica_path, stats)?;
+		} else {
+			copy_file_as_stored(backend, &entry.path(), &replica_path, stats)?;
+			stats.files += 1;
+		}
+	}
+	Ok(())
+}
+
+/// Copies the chunk store's files the replica is missing. Each is written
+/// under a temporary name first, so one cut short isn't taken for a chunk
+/// the replica has, but is resumed.
+fn copy_new_chunks(
+	backend: &dyn StorageBackend,
+	folder: &Path,
+	replica_folder: &Path,
+	stats: &mut ReplicateStats,
+) -> io::Result<()> {
+	backend.create_dir_all(replica_folder)?;
+	for entry in fs::read_dir(folder)? {
+		let entry = entry?;
+		let name = entry.file_name().to_string_lossy().into_owned();
+		let replica_path = replica_folder.join(&name);
+		if entry.file_type()?.is_dir() {
+			copy_new_chunks(backend, &entry.path(), &replica_path, stats)?;
+		} else if !name.ends_with(".tmp") && !backend.exists(&replica_path)? {
+			let temp_path = replica_folder.join(format!("{}.tmp", name));
+			copy_file_as_stored(backend, &entry.path(), &temp_path, stats)?;
+			backend.rename(&temp_path, &replica_path)?;
+			stats.files += 1;
+			if name != CHUNK_SIZES_FILE_NAME {
+				stats.chunks += 1;
+			}
+		}
+	}
+	Ok(())
+}
+
+fn create_dir_if_missing(backend: &dyn StorageBackend, path: &Path) -> io::Result<()> {
+	match backend.create_dir(path) {
+		Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+		result => result,
+	}
+}
+
+/// Copies a file, or what's missing from the end of the replica's copy if
+/// an earlier replication got part way through it
+fn copy_file_as_stored(
+	backend: &dyn StorageBackend,
+	path: &Path,
+	replica_path: &Path,
+	stats: &mut ReplicateStats,
+) -> io::Result<()> {
+	let mut file = File::open(path)?;
+	let size = file.metadata()?.len();
+	let sent = match backend.file_size(replica_path) {
+		Ok(sent) if sent <= size => Some(sent),
+		// not a copy of this file, so start again
+		Ok(_) => None,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+		Err(e) => return Err(e),
+	};
+	if sent == Some(size) {
+		stats.bytes += size;
+		stats.resumed += size;
+		return Ok(());
+	}
+	let mut writer = match sent {
+		Some(sent) if sent > 0 => {
+			file.seek(SeekFrom::Start(sent))?;
+			stats.bytes += sent;
+			stats.resumed += sent;
+			backend.append_file(replica_path)?
+		}
+		_ => backend.create_file(replica_path)?,
+	};
+	stats.bytes += io::copy(&mut file, &mut writer)?;
+	writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup::backup::BackupOptions;
+	use crate::backup_sets::backup_set::list_sets;
+	use crate::backup_sets::verify::verify_set;
+	use crate::dhcopy::encryption::Keyring;
+	use crate::storage::local::LocalStorage;
+	use crate::test_helpers::test_helpers::{backup_folder, create_tmp_folder};
+
+	#[test]
+	fn test_replicates_sets() -> io::Result<()> {
+		let source = create_tmp_folder("orig")?;
+		fs::create_dir(Path::new(&source).join("thats"))?;
+		fs::write(
+			Path::new(&source).join("thats/testfile.txt"),
+			"backmeup susie",
+		)?;
+		let dest = create_tmp_folder("backups")?;
+		let replica = create_tmp_folder("replica")?;
+		let options = BackupOptions {
+			chunked: true,
+			..Default::default()
+		};
+		let first = backup_folder(&source, &dest, &options)?;
+		let stats = replicate_set(&dest, &first, &LocalStorage, &replica)?;
+		assert_eq!(stats.chunks, 1);
+
+		fs::write(Path::new(&source).join("another.txt"), "backmeup sammy")?;
+		let second = backup_folder(&source, &dest, &options)?;
+		let stats = replicate_set(&dest, &second, &LocalStorage, &replica)?;
+
+		// only the new file's chunk
+		assert_eq!(stats.chunks, 1);
+		assert_eq!(list_sets(&replica)?, vec![first.clone(), second.clone()]);
+		for set in [&first, &second] {
+			assert!(verify_set(&replica, set, &Keyring::default())?.is_ok());
+		}
+		let err = replicate_set(&dest, &first, &LocalStorage, &replica).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+		Ok(())
+	}
+
+	#[test]
+	fn test_resumes_interrupted_replication() -> io::Result<()> {
+		let source = create_tmp_folder("orig")?;
+		fs::write(Path::new(&source).join("finished.txt"), "backmeup susie")?;
+		fs::write(Path::new(&source).join("cut_short.txt"), "backmeup sammy")?;
+		let dest = create_tmp_folder("backups")?;
+		let replica = create_tmp_folder("replica")?;
+		let set_name = backup_folder(&source, &dest, &BackupOptions::default())?;
+		replicate_set(&dest, &set_name, &LocalStorage, &replica)?;
+		// as an interrupted replication would have left it
+		let temp_folder = temp_set_folder(&replica, &set_name);
+		fs::rename(Path::new(&replica).join(&set_name), &temp_folder)?;
+		fs::write(temp_folder.join("cut_short.txt"), "backm")?;
+
+		let stats = replicate_set(&dest, &set_name, &LocalStorage, &replica)?;
+
+		assert!(stats.resumed >= "backmeup susie".len() as u64 + 5);
+		assert!(stats.resumed < stats.bytes);
+		assert!(verify_set(&replica, &set_name, &Keyring::default())?.is_ok());
+		Ok(())
+	}
+}
@@ -0,0 +1,355 @@
+use crate::backup_sets::backup_set::SET_METADATA_FILES;
+use crate::backup_sets::last_known_good::last_known_good;
+use crate::backup_sets::manifest::read_set_manifest;
+use crate::backup_sets::set_metadata::set_storage;
+use crate::chunk_store::chunked_set::{checked_path, unpack_chunked_set};
+use crate::chunk_store::store::ChunkStore;
+use crate::dhcopy::archive::{read_index, unpack_archive, ArchivedFile};
+use crate::dhcopy::codec::Codec;
+use crate::dhcopy::compression::Compression;
+use crate::dhcopy::copy_folder::CopyStats;
+use crate::dhcopy::encryption::Keyring;
+use crate::dhcopy::hashing_reader::HashingReader;
+use crate::error::BackupError;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// The set restored when none is named: the last known good one, so a
+/// restore doesn't bring back files from a set that's since been damaged
+pub fn default_restore_set(dest: &str) -> Result<String, BackupError> {
+	match last_known_good(dest)? {
+		Some(set_name) if Path::new(dest).join(&set_name).is_dir() => Ok(set_name),
+		_ => Err(io::Error::new(
+			io::ErrorKind::NotFound,
+			format!(
+				"no set in {} has been verified, name the set to restore",
+				dest
+			),
+		)
+		.into()),
+	}
+}
+
+/// Copies a set's files back out to `target`, decompressing and decrypting
+/// them as the set needs. Encrypted sets are unlocked with the keyring. The target must be empty or not exist yet, so nothing is
+/// overwritten. Given `paths`, only those files and folders are restored,
+/// and for sets archived in volumes only the volumes holding them are read.
+/// Copied files are checked against the manifest as they're restored,
+/// failing with [BackupError::ChecksumMismatch] at the first that's damaged.
+pub fn restore_set(
+	dest: &str,
+	set_name: &str,
+	target: &str,
+	paths: &[String],
+	keys: &Keyring,
+) -> Result<CopyStats, BackupError> {
+	let set_folder = Path::new(dest).join(set_name);
+	if !set_folder.is_dir() {
+		return Err(BackupError::set_missing(dest, set_name));
+	}
+	let storage = set_storage(&set_folder)?;
+	// fail before touching the target if the set can't be unlocked
+	let codec = storage.codec(&set_folder, keys)?;
+	let target = Path::new(target);
+	if target.exists() && fs::read_dir(target)?.next().is_some() {
+		return Err(io::Error::new(
+			io::ErrorKind::AlreadyExists,
+			format!(
+				"{} isn't empty, restore into an empty folder",
+				target.display()
+			),
+		)
+		.into());
+	}
+	fs::create_dir_all(target)?;
+	println!(
+		"restoring set {} ({}) into {}",
+		set_name,
+		storage,
+		target.display()
+	);
+	let paths: Vec<&str> = paths.iter().map(|path| path.trim_matches('/')).collect();
+	let stats = match storage.archive_layout() {
+		Some(layout) => {
+			let (start, files) = match paths.is_empty() {
+				true => (0, None),
+				false => {
+					let index = read_index(&set_folder)?;
+					let wanted: Vec<&ArchivedFile> = index
+						.iter()
+						.filter(|file| is_wanted(&file.path, &paths))
+						.collect();
+					let start = wanted.iter().map(|file| file.start).min().unwrap_or(0);
+					(start, Some(wanted.len() as u64))
+				}
+			};
+			unpack_archive(&set_folder, layout, target, start, files, |path| {
+				is_wanted(path, &paths)
+			})?
+		}
+		None if storage.chunked => {
+			let store = ChunkStore::for_set(&set_folder, storage.compression);
+			unpack_chunked_set(&store, &set_folder, target, |path| {
+				is_wanted(path, &paths) || leads_to_wanted(path, &paths)
+			})?
+		}
+		None if storage.encrypted_names => {
+			restore_by_manifest(&set_folder, target, &paths, &codec)?
+		}
+		None => {
+			let digests = read_set_manifest(&set_folder, &codec)?
+				.into_iter()
+				.map(|(digest, path)| (path, digest))
+				.collect();
+			let restore = Restore {
+				paths: &paths,
+				codec: &codec,
+				digests: &digests,
+			};
+			restore_folder(&restore, &set_folder, target, "")?
+		}
+	};
+	if !paths.is_empty() && stats.files == 0 && stats.folders == 0 {
+		return Err(io::Error::new(
+			io::ErrorKind::NotFound,
+			format!("nothing in set {} matches {}", set_name, paths.join(", ")),
+		)
+		.into());
+	}
+	Ok(stats)
+}
+
+/// Whether the path in the set is one of `paths` or inside one, or all
+/// paths are wanted if none are given
+fn is_wanted(path: &str, paths: &[&str]) -> bool {
+	paths.is_empty()
+		|| paths.iter().any(|wanted| {
+			path == *wanted
+				|| path
+					.strip_prefix(wanted)
+					.is_some_and(|rest| rest.starts_with('/'))
+		})
+}
+
+/// Whether the folder at `path` holds one of `paths`, so it's needed to
+/// restore them
+fn leads_to_wanted(path: &str, paths: &[&str]) -> bool {
+	paths.iter().any(|wanted| {
+		wanted
+			.strip_prefix(path)
+			.is_some_and(|rest| rest.starts_with('/'))
+	})
+}
+
+/// What's restored from a set of copied files and how, the same for all of
+/// its folders
+struct Restore<'a> {
+	paths: &'a [&'a str],
+	codec: &'a Codec,
+	/// The manifest's digest of each file, by path
+	digests: &'a HashMap<String, String>,
+}
+
+fn restore_folder(
+	restore: &Restore,
+	from: &Path,
+	into: &Path,
+	relative: &str,
+) -> io::Result<CopyStats> {
+	let Restore { paths, codec, .. } = *restore;
+	let mut stats = CopyStats::default();
+	for entry in fs::read_dir(from)? {
+		let entry = entry?;
+		let name = entry.file_name();
+		if relative.is_empty() && SET_METADATA_FILES.contains(&name.to_string_lossy().as_ref()) {
+			continue;
+		}
+		let is_dir = entry.file_type()?.is_dir();
+		let name = match is_dir {
+			true => name,
+			false => match codec.original_name(&name) {
+				Some(original) => original,
+				None => continue,
+			},
+		};
+		let path = match relative {
+			"" => name.to_string_lossy().into_owned(),
+			_ => format!("{}/{}", relative, name.to_string_lossy()),
+		};
+		if is_dir {
+			if !is_wanted(&path, paths) && !leads_to_wanted(&path, paths) {
+				continue;
+			}
+			let folder = into.join(&name);
+			fs::create_dir(&folder)?;
+			stats.folders += 1;
+			stats.add(restore_folder(restore, &entry.path(), &folder, &path)?);
+		} else if is_wanted(&path, paths) {
+			stats.bytes += restore_file(
+				codec.reader(&entry.path())?,
+				&into.join(name),
+				restore.digests.get(&path),
+			)?;
+			stats.files += 1;
+		}
+	}
+	Ok(stats)
+}
+
+/// Restores a set whose real paths are only in its manifest, reading each
+/// file from where the codec says it's stored
+fn restore_by_manifest(
+	set_folder: &Path,
+	target: &Path,
+	paths: &[&str],
+	codec: &Codec,
+) -> io::Result<CopyStats> {
+	let mut stats = CopyStats::default();
+	let mut folders = HashSet::new();
+	for (digest, path) in read_set_manifest(set_folder, codec)? {
+		if !is_wanted(&path, paths) {
+			continue;
+		}
+		let relative = checked_path(&path)?;
+		for folder in relative.ancestors().skip(1) {
+			if !folder.as_os_str().is_empty() && folders.insert(folder.to_path_buf()) {
+				stats.folders += 1;
+			}
+		}
+		let file = target.join(relative);
+		if let Some(parent) = file.parent() {
+			fs::create_dir_all(parent)?;
+		}
+		let reader = codec.reader(&codec.stored_file(set_folder, &path))?;
+		stats.bytes += restore_file(reader, &file, Some(&digest))?;
+		stats.files += 1;
+	}
+	Ok(stats)
+}
+
+/// Writes out a file read from the set, checking it has the digest the
+/// manifest gives for it, if any
+fn restore_file(reader: impl Read, file: &Path, expected: Option<&String>) -> io::Result<u64> {
+	let mut reader = HashingReader::new(reader);
+	let bytes = Compression::None.write(&mut reader, file)?;
+	let (actual, _) = reader.finish();
+	match expected {
+		Some(expected) if *expected != actual => Err(BackupError::ChecksumMismatch {
+			path: file.to_path_buf(),
+			expected: expected.clone(),
+			actual,
+		}
+		.into()),
+		_ => Ok(bytes),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup::backup::BackupOptions;
+	use crate::backup_sets::last_known_good::record_last_known_good;
+	use crate::test_helpers::test_helpers::{backup_folder, create_tmp_folder};
+
+	const THE_TEXT: &str = "backmeup susie";
+
+	#[test]
+	fn test_restores_compressed_set() -> io::Result<()> {
+		let source = create_tmp_folder("orig")?;
+		fs::create_dir_all(Path::new(&source).join("deep"))?;
+		fs::write(Path::new(&source).join("deep/testfile.txt"), THE_TEXT)?;
+		let dest = create_tmp_folder("backups")?;
+		let options = BackupOptions {
+			compression: Compression::ZSTD,
+			..Default::default()
+		};
+		let set_name = backup_folder(&source, &dest, &options)?;
+		let target = create_tmp_folder("restored")?;
+
+		let stats = restore_set(&dest, &set_name, &target, &[], &Keyring::default())?;
+
+		assert_eq!(stats.files, 1);
+		assert_eq!(
+			fs::read_to_string(Path::new(&target).join("deep/testfile.txt"))?,
+			THE_TEXT
+		);
+		assert!(!Path::new(&target).join("dhb-set.toml").exists());
+		Ok(())
+	}
+
+	#[test]
+	fn test_restores_chosen_paths() -> io::Result<()> {
+		let source = create_tmp_folder("orig")?;
+		fs::create_dir_all(Path::new(&source).join("deep"))?;
+		fs::write(Path::new(&source).join("deep/wanted.txt"), THE_TEXT)?;
+		fs::write(Path::new(&source).join("other.txt"), THE_TEXT)?;
+		let dest = create_tmp_folder("backups")?;
+		let set_name = backup_folder(&source, &dest, &BackupOptions::default())?;
+		let target = create_tmp_folder("restored")?;
+
+		let stats = restore_set(
+			&dest,
+			&set_name,
+			&target,
+			&["deep/wanted.txt".to_string()],
+			&Keyring::default(),
+		)?;
+
+		assert_eq!(stats.files, 1);
+		assert!(Path::new(&target).join("deep/wanted.txt").exists());
+		assert!(!Path::new(&target).join("other.txt").exists());
+		Ok(())
+	}
+
+	#[test]
+	fn test_defaults_to_last_known_good_set() -> io::Result<()> {
+		let source = create_tmp_folder("orig")?;
+		let dest = create_tmp_folder("backups")?;
+		let set_name = backup_folder(&source, &dest, &BackupOptions::default())?;
+		assert!(default_restore_set(&dest).is_err());
+
+		record_last_known_good(&dest, &set_name)?;
+
+		assert_eq!(default_restore_set(&dest)?, set_name);
+		Ok(())
+	}
+
+	#[test]
+	fn test_detects_damaged_files() -> io::Result<()> {
+		let source = create_tmp_folder("orig")?;
+		fs::write(Path::new(&source).join("testfile.txt"), THE_TEXT)?;
+		let dest = create_tmp_folder("backups")?;
+		let set_name = backup_folder(&source, &dest, &BackupOptions::default())?;
+		fs::write(
+			Path::new(&dest).join(&set_name).join("testfile.txt"),
+			"backmeup susan",
+		)?;
+		let target = create_tmp_folder("restored")?;
+
+		let err = restore_set(&dest, &set_name, &target, &[], &Keyring::default()).unwrap_err();
+
+		match err {
+			BackupError::ChecksumMismatch { path, .. } => {
+				assert_eq!(path, Path::new(&target).join("testfile.txt"))
+			}
+			e => panic!("expected a checksum mismatch, got {:?}", e),
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_refuses_non_empty_target() -> io::Result<()> {
+		let source = create_tmp_folder("orig")?;
+		let dest = create_tmp_folder("backups")?;
+		let set_name = backup_folder(&source, &dest, &BackupOptions::default())?;
+		let target = create_tmp_folder("restored")?;
+		fs::write(Path::new(&target).join("mine.txt"), THE_TEXT)?;
+
+		let err = restore_set(&dest, &set_name, &target, &[], &Keyring::default()).unwrap_err();
+
+		assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+		Ok(())
+	}
+}
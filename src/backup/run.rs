@@ -0,0 +1,453 @@
+use crate::backup::backup::{backup_from_remote, BackupOptions};
+use crate::backup::push::push_set;
+use crate::backup::remote::backup_to_remote;
+use crate::backup::replicate::replicate_set;
+use crate::backup::{self, BackupRequest};
+use crate::backup_sets::backup_set::list_sets_by_status;
+use crate::backup_sets::last_known_good::last_known_good;
+use crate::backup_sets::lock::lock_destination;
+use crate::config::remotes::Destination;
+use crate::config::sources::SourceConfig;
+use crate::docker::{Docker, Quiesce};
+use crate::error::BackupError;
+use crate::hooks::{run_hook, HookFailure};
+use crate::logging::{log_report, LogTarget};
+use crate::notify::{notify, notify_started, publish_progress, NotifyConfig, RunReport};
+use crate::progress::{self, Stage};
+use crate::snapshot::{SnapshotKind, SnapshotSource};
+use crate::status::history::RunHistory;
+use crate::storage::local::LocalStorage;
+use crate::storage::sftp::{SftpLocation, SftpStorage};
+use crate::storage::throttle::ThrottledStorage;
+use chrono::{DateTime, Utc};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// What's done to the sources before they're backed up
+pub struct Preparation<'a> {
+	/// Run first, failing the run if it fails
+	pub pre_backup: Option<&'a str>,
+	/// Sources with settings in the config, which may have commands to run
+	/// first
+	pub before_backup: Vec<(&'a str, &'a SourceConfig)>,
+	pub snapshot: Option<SnapshotKind>,
+	/// Running containers using these Docker volumes are paused or stopped
+	/// until the set's written
+	pub containers: Option<(Quiesce, &'a [String])>,
+}
+
+/// What a backup run does besides writing the set
+pub struct FollowUps<'a> {
+	pub push_to: Option<&'a str>,
+	pub replicas: &'a [(&'a str, Destination)],
+	pub upload_limit: Option<u64>,
+	pub notify: &'a NotifyConfig,
+	/// Run last, told how the run went
+	pub post_backup: Option<&'a str>,
+	/// Which try this is, when the daemon retries failed runs
+	pub attempt: Option<u32>,
+	/// The system log the outcome is sent to, if output is going there
+	pub log_target: Option<LogTarget>,
+}
+
+/// Backs up the sources to the destination as one run: the commands and
+/// snapshots before, the set itself, then pushing and replicating it, the
+/// command after, notifications and the run history. Returns the run's
+/// report once the set's written, which lists anything after it that
+/// failed and files deleted or changed while they were copied, or why the
+/// set wasn't written. This is [crate::backup::run] with everything around
+/// it that the CLI does.
+pub fn run_backup(
+	sources: &[&str],
+	destination_name: &str,
+	destination: &Destination,
+	preparation: &Preparation,
+	options: &BackupOptions,
+	follow_ups: &FollowUps,
+) -> Result<RunReport, BackupError> {
+	let FollowUps {
+		push_to,
+		replicas,
+		upload_limit,
+		..
+	} = *follow_ups;
+	let remote_source = sources
+		.iter()
+		.find(|source| SftpLocation::is_sftp_url(source) || SftpLocation::is_scp_style(source));
+	check_run(sources, destination, preparation, follow_ups, remote_source)?;
+	let started_at = Utc::now();
+	progress::start();
+	let history = RunHistory::open_default();
+	let _current_run = history
+		.as_ref()
+		.ok()
+		.and_then(|history| history.start_run(destination_name).ok());
+	for e in notify_started(follow_ups.notify, destination_name) {
+		eprintln!("Notifying failed: {}", e);
+	}
+	let publishing_progress = publish_progress(follow_ups.notify, destination_name);
+	let mut warnings = Vec::new();
+	let sources_env = sources.join("\n");
+	let hook_env = [
+		("DHB_SOURCES", sources_env.as_str()),
+		("DHB_DESTINATION", destination_name),
+	];
+	let prepared = match preparation.pre_backup {
+		Some(command) => {
+			println!("running the command before the backup");
+			run_hook(command, &hook_env).map_err(|e| {
+				io::Error::new(
+					e.kind(),
+					format!("the command before the backup failed: {}", e),
+				)
+			})
+		}
+		None => Ok(()),
+	};
+	let prepared =
+		prepared.and_then(|()| run_before_backup(&preparation.before_backup, &mut warnings));
+	let docker = Docker::from_env();
+	// from here on the containers have to be resumed whatever happens
+	let mut quiesced = None;
+	let prepared = prepared.and_then(|()| match preparation.containers {
+		Some((mode, volumes)) => docker
+			.quiesce(mode, volumes)
+			.map(|containers| quiesced = Some(containers)),
+		None => Ok(()),
+	});
+	let result = prepared.and_then(|()| match destination {
+		Destination::Sftp(location) => {
+			SftpStorage::connect(location).and_then(|storage| match upload_limit {
+				Some(limit) => Ok(backup_to_remote(
+					&ThrottledStorage::new(&storage, limit),
+					sources,
+					&location.path,
+					options,
+				)?),
+				None => Ok(backup_to_remote(
+					&storage,
+					sources,
+					&location.path,
+					options,
+				)?),
+			})
+		}
+		Destination::Local(destination) => {
+			if Path::new(destination).exists() {
+				warn_if_newest_unverified(destination);
+			}
+			match remote_source {
+				Some(source) => sftp_location(source).and_then(|location| {
+					let storage = SftpStorage::connect(&location)?;
+					Ok(backup_from_remote(
+						&storage,
+						&[&location.path],
+						destination,
+						options,
+					)?)
+				}),
+				None => match preparation.snapshot {
+					Some(kind) => SnapshotSource::take(kind, sources).and_then(|snapshots| {
+						Ok(backup_from_remote(
+							&snapshots,
+							&snapshots.sources(),
+							destination,
+							options,
+						)?)
+					}),
+					None => {
+						let request = BackupRequest {
+							sources: sources.iter().map(|source| source.to_string()).collect(),
+							destination: destination.clone(),
+							options: options.clone(),
+						};
+						match backup::run(&request) {
+							Ok(report) => {
+								warnings.extend(report.issues.iter().map(ToString::to_string));
+								Ok(report.set_name)
+							}
+							Err(e) => Err(e.into()),
+						}
+					}
+				},
+			}
+		}
+	});
+	let local_folder = match destination {
+		Destination::Local(folder) => Some(folder.as_str()),
+		Destination::Sftp(_) => None,
+	};
+	for e in quiesced.iter_mut().flat_map(|quiesced| quiesced.resume()) {
+		let warning = format!("Resuming containers failed: {}", e);
+		eprintln!("{}", warning);
+		warnings.push(warning);
+	}
+	if let Ok(set_name) = &result {
+		println!("Backup successful");
+		if let Some(folder) = local_folder {
+			if let Some(target) = push_to {
+				progress::set_stage(Stage::Pushing);
+				if let Err(e) = push(folder, set_name, target, upload_limit) {
+					let warning = format!("Push to {} failed: {}", target, e);
+					eprintln!("{}", warning);
+					warnings.push(warning);
+				}
+			}
+			if !replicas.is_empty() {
+				progress::set_stage(Stage::Replicating);
+				warnings.extend(replicate(folder, set_name, replicas, upload_limit));
+			}
+		}
+	}
+	let mut report = RunReport::new(destination_name, local_folder, started_at, &result);
+	report.warnings = warnings;
+	report.attempt = follow_ups.attempt;
+	if let Some(command) = follow_ups.post_backup {
+		let status = report.event().to_string();
+		let mut env = hook_env.to_vec();
+		env.push(("DHB_STATUS", &status));
+		if let Some(set_name) = &report.set_name {
+			env.push(("DHB_SET", set_name));
+		}
+		println!("running the command after the backup");
+		if let Err(e) = run_hook(command, &env) {
+			let warning = format!("The command after the backup failed: {}", e);
+			eprintln!("{}", warning);
+			report.warnings.push(warning);
+		}
+	}
+	// so no progress follows the news of how it finished
+	drop(publishing_progress);
+	for e in notify(follow_ups.notify, &report) {
+		eprintln!("Notifying failed: {}", e);
+	}
+	if let Err(e) = history.and_then(|history| history.record(&report)) {
+		eprintln!("Recording the run failed: {}", e);
+	}
+	if let Some(target) = follow_ups.log_target {
+		if let Err(e) = log_report(target, &report) {
+			eprintln!("Logging the outcome failed: {}", e);
+		}
+	}
+	match result {
+		Ok(_) => Ok(report),
+		Err(e) => Err(e.into()),
+	}
+}
+
+/// Records and notifies a run that couldn't start backing up, as when the
+/// destination isn't there, like any other failed run
+pub fn report_unstarted(
+	destination_name: &str,
+	started_at: DateTime<Utc>,
+	error: &io::Error,
+	notify_config: &NotifyConfig,
+	attempt: Option<u32>,
+) {
+	let result = Err(io::Error::new(error.kind(), error.to_string()));
+	let mut report = RunReport::new(destination_name, None, started_at, &result);
+	report.attempt = attempt;
+	for e in notify(notify_config, &report) {
+		eprintln!("Notifying failed: {}", e);
+	}
+	if let Err(e) = RunHistory::open_default().and_then(|history| history.record(&report)) {
+		eprintln!("Recording the run failed: {}", e);
+	}
+}
+
+/// Fails a run asking for things that don't go together, before anything
+/// is done
+fn check_run(
+	sources: &[&str],
+	destination: &Destination,
+	preparation: &Preparation,
+	follow_ups: &FollowUps,
+	remote_source: Option<&&str>,
+) -> io::Result<()> {
+	let invalid = |message: &str| Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+	let remote_destination = matches!(destination, Destination::Sftp(_));
+	if preparation.snapshot.is_some() && (remote_source.is_some() || remote_destination) {
+		return invalid(
+			"snapshots are only taken of local sources backed up to a local destination",
+		);
+	}
+	if remote_destination
+		&& (follow_ups.push_to.is_some()
+			|| !follow_ups.replicas.is_empty()
+			|| remote_source.is_some())
+	{
+		return invalid("remote destinations need local sources and can't be pushed or replicated");
+	}
+	if remote_source.is_some() && sources.len() > 1 {
+		return invalid("a remote source has to be the only source");
+	}
+	Ok(())
+}
+
+/// Runs the commands the config gives to run before sources are backed
+/// up. One failing fails the run, unless its source's config says only to
+/// warn.
+fn run_before_backup(
+	sources: &[(&str, &SourceConfig)],
+	warnings: &mut Vec<String>,
+) -> io::Result<()> {
+	for (source, config) in sources {
+		let Some(command) = &config.before_backup else {
+			continue;
+		};
+		println!("running the command before backing up {}", source);
+		if let Err(e) = run_hook(command, &[("DHB_SOURCE", source)]) {
+			let e = io::Error::new(
+				e.kind(),
+				format!("the command before backing up {} failed: {}", source, e),
+			);
+			match config.on_failure {
+				HookFailure::Abort => return Err(e),
+				HookFailure::Warn => {
+					eprintln!("Warning: {}", e);
+					warnings.push(e.to_string());
+				}
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Parses an sftp:// URL or scp style [user@]host:path
+fn sftp_location(location: &str) -> io::Result<SftpLocation> {
+	let parsed = match SftpLocation::is_sftp_url(location) {
+		true => location.parse(),
+		false => SftpLocation::parse_scp_style(location),
+	};
+	parsed.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn push(destination: &str, set: &str, target: &str, upload_limit: Option<u64>) -> io::Result<()> {
+	let _lock = lock_destination(destination, false)?;
+	let plan = push_set(destination, set, target, upload_limit)?;
+	println!(
+		"Pushed {} to {}, {} unchanged files linked and {} files and folders sent",
+		set,
+		target,
+		plan.unchanged.len(),
+		plan.changed.len()
+	);
+	Ok(())
+}
+
+/// Copies the set to each replica in turn, reporting how each went.
+/// Returns the failures.
+fn replicate(
+	destination: &str,
+	set: &str,
+	replicas: &[(&str, Destination)],
+	upload_limit: Option<u64>,
+) -> Vec<String> {
+	let _lock = match lock_destination(destination, false) {
+		Ok(lock) => lock,
+		Err(e) => {
+			let failure = format!("Replication failed: {}", e);
+			eprintln!("{}", failure);
+			return vec![failure];
+		}
+	};
+	let mut failures = Vec::new();
+	for (name, replica) in replicas {
+		let result = match replica {
+			Destination::Local(path) => fs::create_dir_all(path)
+				.and_then(|_| Ok(lock_destination(path, false)?))
+				.and_then(|_lock| replicate_set(destination, set, &LocalStorage, path)),
+			Destination::Sftp(location) => {
+				SftpStorage::connect(location).and_then(|storage| match upload_limit {
+					Some(limit) => replicate_set(
+						destination,
+						set,
+						&ThrottledStorage::new(&storage, limit),
+						&location.path,
+					),
+					None => replicate_set(destination, set, &storage, &location.path),
+				})
+			}
+		};
+		match result {
+			Ok(stats) => {
+				println!(
+					"Replicated {} to {}, {} files ({} bytes) copied including {} new chunks",
+					set, name, stats.files, stats.bytes, stats.chunks
+				);
+				if stats.resumed > 0 {
+					println!(
+						"  resumed an interrupted replication, {} bytes were already there",
+						stats.resumed
+					);
+				}
+			}
+			Err(e) => {
+				let failure = format!("Replication to {} failed: {}", name, e);
+				eprintln!("{}", failure);
+				failures.push(failure);
+			}
+		}
+	}
+	failures
+}
+
+fn warn_if_newest_unverified(destination: &str) {
+	let newest = list_sets_by_status(destination).map(|mut sets| sets.complete.pop());
+	let (Ok(Some(newest)), Ok(last_good)) = (newest, last_known_good(destination)) else {
+		return;
+	};
+	if last_good.as_deref() != Some(newest.as_str()) {
+		match last_good {
+			Some(last_good) => eprintln!(
+				"warning: newest set {} has not been verified, last known good set is {}",
+				newest, last_good
+			),
+			None => eprintln!(
+				"warning: newest set {} has not been verified, no set has been verified yet",
+				newest
+			),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	#[test]
+	fn test_refuses_snapshot_of_remote_source() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let preparation = Preparation {
+			pre_backup: None,
+			before_backup: Vec::new(),
+			snapshot: Some(SnapshotKind::Btrfs),
+			containers: None,
+		};
+		let follow_ups = FollowUps {
+			push_to: None,
+			replicas: &[],
+			upload_limit: None,
+			notify: &NotifyConfig::default(),
+			post_backup: None,
+			attempt: None,
+			log_target: None,
+		};
+
+		let err = run_backup(
+			&["susie@example.com:/home/susie"],
+			&dest,
+			&Destination::Local(dest.clone()),
+			&preparation,
+			&BackupOptions::default(),
+			&follow_ups,
+		)
+		.unwrap_err();
+
+		assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+		assert_eq!(fs::read_dir(&dest)?.count(), 0, "nothing should be written");
+		Ok(())
+	}
+}
@@ -1,24 +1,380 @@
-use crate::backup_sets::set_namer::generate_name;
-use chrono::Utc;
+use crate::backup_sets::last_known_good::clear_last_known_good;
+use crate::backup_sets::latest::{latest_set, remove_latest, update_latest};
+use crate::backup_sets::lock::lock_destination;
+use crate::backup_sets::manifest::{ENCRYPTED_MANIFEST_FILE_NAME, MANIFEST_FILE_NAME};
+use crate::backup_sets::pin::{is_pinned, PIN_FILE_NAME};
+use crate::backup_sets::seal::remove_set;
+use crate::backup_sets::set_metadata::{read_metadata, METADATA_FILE_NAME};
+use crate::backup_sets::set_namer::{SetName, SetNameTemplate, SetTimezone, SET_PREFIX};
+use crate::backup_sets::verify_catalog::{read_catalog, write_catalog};
+use crate::cancel::{is_cancelled, set_stops_cleanly};
+use crate::dhcopy::dictionary::DICTIONARY_FILE_NAME;
+use crate::dhcopy::encryption::{PASSPHRASE_KEY_FILE_NAME, SET_KEY_FILE_NAME};
+use crate::error::BackupError;
+use crate::space::usage::folder_usage;
+use crate::storage::backend::StorageBackend;
+use crate::storage::local::LocalStorage;
+use chrono::{DateTime, Utc};
 use std::fs;
-use std::path::Path;
+use std::io;
+use std::path::{Path, PathBuf};
 
+#[cfg(test)]
 const BACKUP_FOLDER_NAME: &str = "backups";
 
-pub fn create_empty_set<F>(dest: &str, get_time: F) -> Result<String, std::io::Error>
+/// Files this tool keeps in the root of a set, as opposed to backed up data
+pub const SET_METADATA_FILES: [&str; 7] = [
+	MANIFEST_FILE_NAME,
+	ENCRYPTED_MANIFEST_FILE_NAME,
+	METADATA_FILE_NAME,
+	PIN_FILE_NAME,
+	SET_KEY_FILE_NAME,
+	PASSPHRASE_KEY_FILE_NAME,
+	DICTIONARY_FILE_NAME,
+];
+
+// New sets are written under this prefix and only renamed to their real
+// name once complete, so a partial set never looks like a normal one
+const TEMP_SET_PREFIX: &str = ".tmp-";
+
+/// Creates a new empty set folder under its temporary name, see
+/// [temp_set_folder], and returns the name it will have once finalized.
+/// If a set with the same name already exists, e.g. from a backup started
+/// in the same second, a `_2`, `_3`... suffix is added rather than sharing
+/// the folder.
+pub fn create_empty_set<F>(
+	backend: &dyn StorageBackend,
+	dest: &str,
+	template: &SetNameTemplate,
+	timezone: SetTimezone,
+	source: &str,
+	get_time: F,
+) -> Result<String, std::io::Error>
 where
 	F: Fn() -> chrono::DateTime<Utc>,
 {
-	let set_name = generate_name(get_time);
-	let dir_path = Path::new(dest).join(&set_name);
-	fs::create_dir_all(&dir_path)?;
+	let base_name = template.render(get_time(), timezone, source);
+	backend.create_dir_all(Path::new(dest))?;
+	let mut set_name = base_name.clone();
+	for sequence in 2.. {
+		// create_dir fails if the folder exists, so concurrent backups can't both claim it
+		let created = match backend.exists(&Path::new(dest).join(&set_name))? {
+			true => Err(io::ErrorKind::AlreadyExists.into()),
+			false => backend.create_dir(&temp_set_folder(dest, &set_name)),
+		};
+		match created {
+			Ok(()) => break,
+			Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+				set_name = format!("{}_{}", base_name, sequence);
+			}
+			Err(e) => return Err(e),
+		}
+	}
 	Ok(set_name)
 }
 
+/// Where a set is written before it's finalized
+pub fn temp_set_folder(dest: &str, set_name: &str) -> PathBuf {
+	Path::new(dest).join(format!("{}{}", TEMP_SET_PREFIX, set_name))
+}
+
+/// Gives a completed set its real name, in one rename
+pub fn finalize_set(backend: &dyn StorageBackend, dest: &str, set_name: &str) -> io::Result<()> {
+	backend.rename(
+		&temp_set_folder(dest, set_name),
+		&Path::new(dest).join(set_name),
+	)
+}
+
+/// A set being written under its temporary name, see [create_empty_set].
+/// If the backup's cancelled before it's finalized, it's removed on being
+/// dropped, or kept as an incomplete set if asked. Sets left by other
+/// failures stay under the temporary name for [clean_up_temp_sets].
+pub struct SetInProgress<'a> {
+	backend: &'a dyn StorageBackend,
+	dest: &'a str,
+	set_name: &'a str,
+	keep_if_cancelled: bool,
+	finalized: bool,
+}
+
+impl<'a> SetInProgress<'a> {
+	pub fn new(
+		backend: &'a dyn StorageBackend,
+		dest: &'a str,
+		set_name: &'a str,
+		keep_if_cancelled: bool,
+	) -> SetInProgress<'a> {
+		set_stops_cleanly(true);
+		SetInProgress {
+			backend,
+			dest,
+			set_name,
+			keep_if_cancelled,
+			finalized: false,
+		}
+	}
+
+	/// Gives the completed set its real name, see [finalize_set]
+	pub fn finalize(mut self) -> io::Result<()> {
+		finalize_set(self.backend, self.dest, self.set_name)?;
+		self.finalized = true;
+		Ok(())
+	}
+
+	/// Removes what's been written so far, or keeps it under the set's real
+	/// name, where its metadata shows it as incomplete
+	fn stop(&self) -> io::Result<()> {
+		let folder = temp_set_folder(self.dest, self.set_name);
+		match self.keep_if_cancelled {
+			true => {
+				finalize_set(self.backend, self.dest, self.set_name)?;
+				println!("kept set {} as it was, incomplete", self.set_name);
+			}
+			false => {
+				self.backend.remove_dir_all(&folder)?;
+				println!("removed set {}, as it wasn't finished", self.set_name);
+			}
+		}
+		Ok(())
+	}
+}
+
+impl Drop for SetInProgress<'_> {
+	fn drop(&mut self) {
+		set_stops_cleanly(false);
+		if self.finalized || !is_cancelled() {
+			return;
+		}
+		if let Err(e) = self.stop() {
+			eprintln!(
+				"Tidying up the cancelled set {} failed: {}",
+				self.set_name, e
+			);
+		}
+	}
+}
+
+/// Sets found under their temporary names by [clean_up_temp_sets]
+#[derive(Debug, Default, PartialEq)]
+pub struct TempSetCleanup {
+	/// Finished sets whose backup crashed before renaming them, now renamed
+	pub adopted: Vec<String>,
+	/// Sets whose backup never finished, now deleted
+	pub removed: Vec<String>,
+}
+
+/// Tidies up sets left under their temporary names by backups that crashed.
+/// Sets whose metadata shows the copy finished are given their real name,
+/// the rest are deleted. Only safe while holding the destination lock.
+pub fn clean_up_temp_sets(dest: &str) -> Result<TempSetCleanup, BackupError> {
+	let mut cleanup = TempSetCleanup::default();
+	for entry in fs::read_dir(dest)? {
+		let entry = entry?;
+		let name = entry.file_name().to_string_lossy().into_owned();
+		let (true, Some(set_name)) = (
+			entry.file_type()?.is_dir(),
+			name.strip_prefix(TEMP_SET_PREFIX),
+		) else {
+			continue;
+		};
+		let finished = read_metadata(&entry.path()).is_ok_and(|m| m.finished_at.is_some());
+		if finished && !Path::new(dest).join(set_name).exists() {
+			println!(
+				"adopting set {}, finished by a backup that stopped before renaming it",
+				set_name
+			);
+			finalize_set(&LocalStorage, dest, set_name)?;
+			cleanup.adopted.push(set_name.to_string());
+		} else {
+			let size = folder_usage(&entry.path())?.exclusive;
+			println!(
+				"removing unfinished set {} left by a backup that stopped, freeing {} bytes",
+				set_name, size
+			);
+			remove_set(&entry.path())?;
+			cleanup.removed.push(set_name.to_string());
+		}
+	}
+	Ok(cleanup)
+}
+
+/// A set in the destination as seen by tools built on this crate
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackupSet {
+	pub name: String,
+	pub path: PathBuf,
+	/// When the backup started, from the set's name, see [SetName]
+	pub created_at: DateTime<Utc>,
+	/// Bytes the set takes up, counting files hard linked within it once
+	pub size: u64,
+	pub status: SetStatus,
+}
+
+impl BackupSet {
+	/// The sets in the destination, oldest first. Sizes are worked out by
+	/// walking each set, so this can be slow for large destinations.
+	pub fn list(dest: &str) -> Result<Vec<BackupSet>, BackupError> {
+		list_set_names(dest)?
+			.into_iter()
+			.map(|set_name| {
+				let path = Path::new(dest).join(set_name.as_str());
+				Ok(BackupSet {
+					size: folder_usage(&path)?.total,
+					status: set_status(dest, set_name.as_str())?,
+					created_at: set_name.time(),
+					name: set_name.to_string(),
+					path,
+				})
+			})
+			.collect()
+	}
+
+	/// Deletes the set from `dest`, the destination it was listed from,
+	/// refusing pinned sets, sets still being written and anything that isn't
+	/// a set directly inside `dest`. Takes the destination lock, so fails with
+	/// `WouldBlock` while a backup is running.
+	/// Sealed sets are unsealed first, and the verify catalog, last known
+	/// good set and latest link are updated to match.
+	pub fn delete(&self, dest: &str) -> Result<(), BackupError> {
+		let _lock = lock_destination(dest, false)?;
+		let refuse = |reason: &str| {
+			Err(BackupError::from(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				format!("can't delete {}: {}", self.path.display(), reason),
+			)))
+		};
+		// resolves symlinks and `..`, so nothing outside the destination can be reached
+		let set_path = fs::canonicalize(&self.path)?;
+		if set_path.parent() != Some(fs::canonicalize(dest)?.as_path())
+			|| set_path.file_name() != Some(self.name.as_ref())
+		{
+			return refuse(&format!("it isn't a set in {}", dest));
+		}
+		let sets = list_sets_by_status(dest)?;
+		if !sets.complete.contains(&self.name) && !sets.abandoned.contains(&self.name) {
+			return match list_sets(dest)?.contains(&self.name) {
+				true => refuse("its backup may still be running"),
+				false => refuse(&format!("it isn't a set in {}", dest)),
+			};
+		}
+		if is_pinned(dest, &self.name) {
+			return refuse("it is pinned");
+		}
+
+		remove_set(&set_path)?;
+		clear_last_known_good(dest, &self.name)?;
+		let catalog = read_catalog(dest)?;
+		if catalog.contains_key(&self.name) {
+			write_catalog(dest, &catalog)?;
+		}
+		if latest_set(dest)?.as_deref() == Some(self.name.as_str()) {
+			match list_sets_by_status(dest)?.complete.pop() {
+				Some(newest) => update_latest(dest, &newest)?,
+				None => remove_latest(dest)?,
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Names of the sets in the destination, oldest first, see [SetName].
+/// Sets either have the default name prefix or, if named from a template, metadata.
+pub fn list_sets(dest: &str) -> Result<Vec<String>, BackupError> {
+	Ok(list_set_names(dest)?
+		.into_iter()
+		.map(|set| set.to_string())
+		.collect())
+}
+
+fn list_set_names(dest: &str) -> io::Result<Vec<SetName>> {
+	let mut sets = Vec::new();
+	for entry in fs::read_dir(dest)? {
+		let entry = entry?;
+		let name = entry.file_name().to_string_lossy().into_owned();
+		if !entry.file_type()?.is_dir() || name.starts_with('.') {
+			continue;
+		}
+		let is_set = name.starts_with(SET_PREFIX) || entry.path().join(METADATA_FILE_NAME).exists();
+		if is_set {
+			sets.push(SetName::from_folder(dest, &name)?);
+		}
+	}
+	sets.sort();
+	Ok(sets)
+}
+
+/// Incomplete sets left by backups that ran out of time, oldest first.
+/// Their files can be used as if from a complete set, as the manifest only
+/// lists those copied.
+pub fn list_resumable_sets(dest: &str) -> Result<Vec<String>, BackupError> {
+	let mut resumable = Vec::new();
+	for set_name in list_set_names(dest)? {
+		let metadata = read_metadata(&Path::new(dest).join(set_name.as_str()));
+		if metadata.is_ok_and(|metadata| metadata.resumable && metadata.finished_at.is_none()) {
+			resumable.push(set_name.as_str().to_string());
+		}
+	}
+	Ok(resumable)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SetStatus {
+	Complete,
+	/// The backup never finished, e.g. the copy failed or the machine went down
+	Incomplete,
+}
+
+/// Whether the backup that made a set finished, going by its metadata.
+/// Sets from before metadata was written count as complete.
+pub fn set_status(dest: &str, set_name: &str) -> Result<SetStatus, BackupError> {
+	match read_metadata(&Path::new(dest).join(set_name)) {
+		Ok(metadata) if metadata.finished_at.is_some() => Ok(SetStatus::Complete),
+		Ok(_) => Ok(SetStatus::Incomplete),
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(SetStatus::Complete),
+		// metadata cut off part way through writing
+		Err(e) if e.kind() == io::ErrorKind::InvalidData => Ok(SetStatus::Incomplete),
+		Err(e) => Err(e.into()),
+	}
+}
+
+pub struct SetsByStatus {
+	pub complete: Vec<String>,
+	/// Incomplete sets older than the newest complete set. Newer incomplete
+	/// sets may still be being written so are in neither list.
+	pub abandoned: Vec<String>,
+}
+
+/// Sets in the destination, oldest first, split by whether they finished
+pub fn list_sets_by_status(dest: &str) -> Result<SetsByStatus, BackupError> {
+	let mut sets = SetsByStatus {
+		complete: Vec::new(),
+		abandoned: Vec::new(),
+	};
+	let mut incomplete = Vec::new();
+	for set in list_sets(dest)? {
+		match set_status(dest, &set)? {
+			SetStatus::Complete => {
+				sets.abandoned.append(&mut incomplete);
+				sets.complete.push(set);
+			}
+			SetStatus::Incomplete => incomplete.push(set),
+		}
+	}
+	Ok(sets)
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
-	use crate::test_helpers::test_helpers::{create_tmp_folder, time_fixer};
+	use crate::backup_sets::pin::pin_set;
+	use crate::backup_sets::seal::seal_set;
+	use crate::backup_sets::set_metadata::{
+		finish_metadata, write_metadata, SetMetadata, SetStats,
+	};
+	use crate::backup_sets::set_namer::generate_name;
+	use crate::test_helpers::test_helpers::{create_set, create_tmp_folder, time_fixer};
+	use chrono::TimeZone;
 	use std::fs;
 	use std::path::Path;
 
@@ -31,12 +387,238 @@ mod tests {
 		let expected_set_name = generate_name(&time_fixer);
 
 		// act
-		let actual_set_name = create_empty_set(&dest, &time_fixer).unwrap();
+		let actual_set_name = create_empty_set(
+			&LocalStorage,
+			&dest,
+			&SetNameTemplate::default(),
+			SetTimezone::Utc,
+			"/home",
+			&time_fixer,
+		)
+		.unwrap();
 
 		// assert
 		assert_eq!(expected_set_name, actual_set_name);
 
+		assert!(
+			temp_set_folder(&dest, &actual_set_name).exists(),
+			"set folder should be created under its temporary name"
+		);
+		assert!(list_sets(&dest).unwrap().is_empty());
+
+		finalize_set(&LocalStorage, &dest, &actual_set_name).unwrap();
 		let dir_path = Path::new(&dest).join(&actual_set_name);
-		assert!(dir_path.exists(), "set folder should be created");
+		assert!(dir_path.exists(), "set folder should be renamed");
+	}
+
+	#[test]
+	fn test_stops_sets_in_progress() -> io::Result<()> {
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+		for (set_name, keep) in [
+			("dhb-set-20250101-000000", false),
+			("dhb-set-20250102-000000", true),
+		] {
+			fs::create_dir(temp_set_folder(&dest, set_name))?;
+			let set = SetInProgress::new(&LocalStorage, &dest, set_name, keep);
+			set.stop()?;
+		}
+		assert!(!temp_set_folder(&dest, "dhb-set-20250101-000000").exists());
+		assert_eq!(list_sets(&dest)?, vec!["dhb-set-20250102-000000"]);
+		Ok(())
+	}
+
+	#[test]
+	fn test_removes_temp_sets() -> io::Result<()> {
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+		let set_name = create_empty_set(
+			&LocalStorage,
+			&dest,
+			&SetNameTemplate::default(),
+			SetTimezone::Utc,
+			"/home",
+			time_fixer(),
+		)?;
+
+		let cleanup = clean_up_temp_sets(&dest)?;
+
+		assert_eq!(cleanup.removed, vec![set_name.clone()]);
+		assert!(!temp_set_folder(&dest, &set_name).exists());
+		Ok(())
+	}
+
+	#[test]
+	fn test_adopts_finished_temp_sets() -> io::Result<()> {
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+		let set_name = create_empty_set(
+			&LocalStorage,
+			&dest,
+			&SetNameTemplate::default(),
+			SetTimezone::Utc,
+			"/home",
+			time_fixer(),
+		)?;
+		let temp_folder = temp_set_folder(&dest, &set_name);
+		write_metadata(
+			&temp_folder,
+			&SetMetadata::new("/home", Utc::now(), Default::default()),
+		)?;
+		finish_metadata(&temp_folder, Utc::now(), SetStats::default())?;
+
+		let cleanup = clean_up_temp_sets(&dest)?;
+
+		assert_eq!(cleanup.adopted, vec![set_name.clone()]);
+		assert_eq!(list_sets(&dest)?, vec![set_name]);
+		Ok(())
+	}
+
+	#[test]
+	fn test_creation_in_same_second() {
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME).unwrap();
+		let time_fixer = time_fixer();
+		let create = || {
+			create_empty_set(
+				&LocalStorage,
+				&dest,
+				&SetNameTemplate::default(),
+				SetTimezone::Utc,
+				"/home",
+				&time_fixer,
+			)
+			.unwrap()
+		};
+
+		let first = create();
+		let second = create();
+		finalize_set(&LocalStorage, &dest, &second).unwrap();
+		let third = create();
+		finalize_set(&LocalStorage, &dest, &first).unwrap();
+		finalize_set(&LocalStorage, &dest, &third).unwrap();
+
+		assert_eq!(second, format!("{}_2", first));
+		assert_eq!(third, format!("{}_3", first));
+		assert_eq!(list_sets(&dest).unwrap(), vec![first, second, third]);
+	}
+
+	#[test]
+	fn test_list_sets() {
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME).unwrap();
+		fs::create_dir_all(Path::new(&dest).join("dhb-set-20010203-140506")).unwrap();
+		fs::create_dir_all(Path::new(&dest).join("dhb-set-20000101-000000")).unwrap();
+		fs::create_dir_all(Path::new(&dest).join("not-a-set")).unwrap();
+		fs::create_dir_all(Path::new(&dest).join("unrelated-20000601-000000")).unwrap();
+		let renamed_set = Path::new(&dest).join("before-the-move");
+		fs::create_dir_all(&renamed_set).unwrap();
+		fs::write(renamed_set.join(METADATA_FILE_NAME), "").unwrap();
+		fs::File::open(&renamed_set)
+			.unwrap()
+			.set_modified(Utc.with_ymd_and_hms(2000, 3, 1, 0, 0, 0).unwrap().into())
+			.unwrap();
+		fs::write(Path::new(&dest).join("dhb-set-file"), "").unwrap();
+		let templated_set = Path::new(&dest).join("aardvark-20000601-000000");
+		fs::create_dir_all(&templated_set).unwrap();
+		fs::write(templated_set.join(METADATA_FILE_NAME), "").unwrap();
+
+		let sets = list_sets(&dest).unwrap();
+
+		assert_eq!(
+			sets,
+			vec![
+				"dhb-set-20000101-000000",
+				"before-the-move",
+				"aardvark-20000601-000000",
+				"dhb-set-20010203-140506"
+			]
+		);
+	}
+
+	#[test]
+	fn test_list_sets_by_status() -> io::Result<()> {
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+		create_set(&dest, "dhb-set-20000101-000000", SetStatus::Incomplete)?;
+		create_set(&dest, "dhb-set-20000102-000000", SetStatus::Complete)?;
+		fs::create_dir_all(Path::new(&dest).join("dhb-set-20000103-000000"))?;
+		create_set(&dest, "dhb-set-20000104-000000", SetStatus::Incomplete)?;
+
+		let sets = list_sets_by_status(&dest)?;
+
+		assert_eq!(
+			sets.complete,
+			vec!["dhb-set-20000102-000000", "dhb-set-20000103-000000"]
+		);
+		assert_eq!(sets.abandoned, vec!["dhb-set-20000101-000000"]);
+		Ok(())
+	}
+
+	#[test]
+	fn test_lists_backup_sets() -> io::Result<()> {
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+		create_set(&dest, "dhb-set-20000101-000000", SetStatus::Complete)?;
+		fs::write(
+			Path::new(&dest).join("dhb-set-20000101-000000/data"),
+			"1234567890",
+		)?;
+		create_set(&dest, "dhb-set-20000102-000000", SetStatus::Incomplete)?;
+
+		let sets = BackupSet::list(&dest)?;
+
+		let names: Vec<&str> = sets.iter().map(|set| set.name.as_str()).collect();
+		assert_eq!(
+			names,
+			vec!["dhb-set-20000101-000000", "dhb-set-20000102-000000"]
+		);
+		assert_eq!(
+			sets[0].path,
+			Path::new(&dest).join("dhb-set-20000101-000000")
+		);
+		assert_eq!(
+			sets[0].created_at,
+			Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap()
+		);
+		assert!(sets[0].size > sets[1].size);
+		assert_eq!(sets[0].status, SetStatus::Complete);
+		assert_eq!(sets[1].status, SetStatus::Incomplete);
+		Ok(())
+	}
+
+	#[test]
+	fn test_delete_set() -> io::Result<()> {
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+		create_set(&dest, "dhb-set-20000101-000000", SetStatus::Complete)?;
+		create_set(&dest, "dhb-set-20000102-000000", SetStatus::Complete)?;
+		update_latest(&dest, "dhb-set-20000102-000000")?;
+		seal_set(&Path::new(&dest).join("dhb-set-20000102-000000"))?;
+		let sets = BackupSet::list(&dest)?;
+
+		sets[1].delete(&dest)?;
+
+		assert_eq!(list_sets(&dest)?, vec!["dhb-set-20000101-000000"]);
+		assert_eq!(
+			latest_set(&dest)?.as_deref(),
+			Some("dhb-set-20000101-000000")
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_refuses_to_delete() -> io::Result<()> {
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+		create_set(&dest, "dhb-set-20000101-000000", SetStatus::Complete)?;
+		create_set(&dest, "dhb-set-20000102-000000", SetStatus::Incomplete)?;
+		pin_set(&dest, "dhb-set-20000101-000000")?;
+		let sets = BackupSet::list(&dest)?;
+		let elsewhere = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+		let outside = BackupSet {
+			name: "dhb-set-20000101-000000".to_string(),
+			path: Path::new(&dest).join("..").join(&elsewhere),
+			..sets[0].clone()
+		};
+
+		for set in [&sets[0], &sets[1], &outside] {
+			let err = set.delete(&dest).unwrap_err();
+			assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", err);
+		}
+		assert_eq!(list_sets(&dest)?.len(), 2);
+		assert!(Path::new(&elsewhere).exists());
+		Ok(())
 	}
 }
@@ -0,0 +1,236 @@
+use crate::backup_sets::backup_set::{list_sets, set_status, SetStatus, SET_METADATA_FILES};
+use crate::backup_sets::last_known_good::clear_last_known_good;
+use crate::backup_sets::manifest::generate_manifest;
+use crate::backup_sets::pin::is_pinned;
+use crate::backup_sets::seal::{is_sealed, unseal_set, while_unsealed};
+use crate::backup_sets::set_metadata::{read_metadata, set_storage, write_metadata, SetStats};
+use crate::error::BackupError;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Merges the sets from `first` to `last` inclusive into `last`, which
+/// must be the newer of the two. Files already in `last` win; otherwise the
+/// newest older set holding a path provides it, so files that have since
+/// been deleted from the source are kept. The older sets are then removed
+/// and their names returned. Pinned and incomplete sets can't be merged away.
+pub fn compact_sets(dest: &str, first: &str, last: &str) -> Result<Vec<String>, BackupError> {
+	let sets = list_sets(dest)?;
+	let position = |set_name: &str| {
+		sets.iter()
+			.position(|set| set == set_name)
+			.ok_or_else(|| BackupError::set_missing(dest, set_name))
+	};
+	let (first_index, last_index) = (position(first)?, position(last)?);
+	if first_index >= last_index {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			format!("{} must be older than {} to compact them", first, last),
+		)
+		.into());
+	}
+	let range = &sets[first_index..=last_index];
+	for set in range {
+		if set_status(dest, set)? == SetStatus::Incomplete {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				format!("can't compact incomplete set {}", set),
+			)
+			.into());
+		}
+	}
+	let (merged, _) = range.split_at(range.len() - 1);
+	if let Some(pinned) = merged.iter().find(|set| is_pinned(dest, set)) {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			format!("can't compact pinned set {} into {}", pinned, last),
+		)
+		.into());
+	}
+
+	let target = Path::new(dest).join(last);
+	let storage = set_storage(&target)?;
+	for set in range {
+		let set_storage = set_storage(&Path::new(dest).join(set))?;
+		if set_storage.archive {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				format!("can't compact {}, it's stored as an archive", set),
+			)
+			.into());
+		}
+		if set_storage.encrypted {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				format!("can't compact {}, it's encrypted with its own key", set),
+			)
+			.into());
+		}
+		if set_storage.zstd_dictionary {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				format!(
+					"can't compact {}, it's compressed with its own dictionary",
+					set
+				),
+			)
+			.into());
+		}
+		if set_storage.chunked {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				format!("can't compact {}, its files are in the chunk store", set),
+			)
+			.into());
+		}
+		if !set_storage.compression.same_format(storage.compression) {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				format!(
+					"can't compact {} into {}, their files are compressed differently",
+					set, last
+				),
+			)
+			.into());
+		}
+	}
+	while_unsealed(&target, || {
+		let mut added = SetStats::default();
+		for set in merged.iter().rev() {
+			println!("merging set {} into {}", set, last);
+			let set_folder = Path::new(dest).join(set);
+			// files are moved out of the older sets
+			if is_sealed(&set_folder) {
+				unseal_set(&set_folder)?;
+			}
+			merge_folder(&set_folder, &target, true, &mut added)?;
+			fs::remove_dir_all(&set_folder)?;
+			clear_last_known_good(dest, set)?;
+		}
+		generate_manifest(&target)?;
+		// the set has new contents so needs verifying again
+		clear_last_known_good(dest, last)?;
+		record_compaction(&target, merged, added)
+	})?;
+	Ok(merged.to_vec())
+}
+
+/// Moves whatever in `from` isn't already in `into` across
+fn merge_folder(from: &Path, into: &Path, is_root: bool, added: &mut SetStats) -> io::Result<()> {
+	for entry in fs::read_dir(from)? {
+		let entry = entry?;
+		let name = entry.file_name();
+		if is_root && SET_METADATA_FILES.contains(&name.to_string_lossy().as_ref()) {
+			continue;
+		}
+		let target = into.join(&name);
+		if entry.file_type()?.is_dir() {
+			if !target.exists() {
+				fs::create_dir(&target)?;
+				added.folders += 1;
+			} else if !target.is_dir() {
+				// a newer set has a file where this has a folder
+				continue;
+			}
+			merge_folder(&entry.path(), &target, false, added)?;
+		} else if fs::symlink_metadata(&target).is_err() {
+			added.bytes += entry.metadata()?.len();
+			added.files += 1;
+			fs::rename(entry.path(), &target)?;
+		}
+	}
+	Ok(())
+}
+
+fn record_compaction(target: &Path, merged: &[String], added: SetStats) -> io::Result<()> {
+	let mut metadata = match read_metadata(target) {
+		Ok(metadata) => metadata,
+		// sets from before metadata was written have nothing to update
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+		Err(e) => return Err(e),
+	};
+	metadata.compacted_from.extend(merged.iter().cloned());
+	if let Some(stats) = metadata.stats.as_mut() {
+		stats.files += added.files;
+		stats.folders += added.folders;
+		stats.bytes += added.bytes;
+	}
+	write_metadata(target, &metadata)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup_sets::manifest::read_manifest;
+	use crate::backup_sets::pin::pin_set;
+	use crate::test_helpers::test_helpers::{create_set, create_tmp_folder};
+
+	const OLDEST_SET: &str = "dhb-set-20000101-000000";
+	const MIDDLE_SET: &str = "dhb-set-20000102-000000";
+	const NEWEST_SET: &str = "dhb-set-20000103-000000";
+
+	fn create_sets() -> io::Result<String> {
+		let dest = create_tmp_folder("backups")?;
+		for (set, contents) in [
+			(OLDEST_SET, "old"),
+			(MIDDLE_SET, "middle"),
+			(NEWEST_SET, "new"),
+		] {
+			create_set(&dest, set, SetStatus::Complete)?;
+			fs::create_dir_all(Path::new(&dest).join(set).join("docs"))?;
+			fs::write(Path::new(&dest).join(set).join("docs/notes.txt"), contents)?;
+		}
+		fs::write(
+			Path::new(&dest).join(OLDEST_SET).join("docs/deleted.txt"),
+			"old",
+		)?;
+		fs::write(
+			Path::new(&dest).join(MIDDLE_SET).join("deleted.txt"),
+			"middle",
+		)?;
+		Ok(dest)
+	}
+
+	#[test]
+	fn test_keeps_newest_version_of_each_file() -> io::Result<()> {
+		let dest = create_sets()?;
+
+		let merged = compact_sets(&dest, OLDEST_SET, NEWEST_SET)?;
+
+		assert_eq!(merged, vec![OLDEST_SET, MIDDLE_SET]);
+		assert_eq!(list_sets(&dest)?, vec![NEWEST_SET]);
+		let target = Path::new(&dest).join(NEWEST_SET);
+		assert_eq!(fs::read_to_string(target.join("docs/notes.txt"))?, "new");
+		assert_eq!(fs::read_to_string(target.join("docs/deleted.txt"))?, "old");
+		assert_eq!(fs::read_to_string(target.join("deleted.txt"))?, "middle");
+		assert_eq!(read_manifest(&target)?.len(), 3);
+		let metadata = read_metadata(&target)?;
+		assert_eq!(metadata.compacted_from, vec![OLDEST_SET, MIDDLE_SET]);
+		assert_eq!(metadata.stats.map(|stats| stats.files), Some(2));
+		Ok(())
+	}
+
+	#[test]
+	fn test_compacts_a_range() -> io::Result<()> {
+		let dest = create_sets()?;
+
+		compact_sets(&dest, OLDEST_SET, MIDDLE_SET)?;
+
+		assert_eq!(list_sets(&dest)?, vec![MIDDLE_SET, NEWEST_SET]);
+		Ok(())
+	}
+
+	#[test]
+	fn test_refuses_pinned_and_backwards_ranges() -> io::Result<()> {
+		let dest = create_sets()?;
+		pin_set(&dest, MIDDLE_SET)?;
+
+		let pinned = compact_sets(&dest, OLDEST_SET, NEWEST_SET).unwrap_err();
+		let backwards = compact_sets(&dest, NEWEST_SET, OLDEST_SET).unwrap_err();
+
+		assert_eq!(pinned.kind(), io::ErrorKind::InvalidInput);
+		assert_eq!(backwards.kind(), io::ErrorKind::InvalidInput);
+		assert_eq!(list_sets(&dest)?.len(), 3);
+		Ok(())
+	}
+}
@@ -0,0 +1,150 @@
+use crate::backup_sets::manifest::ManifestEntry;
+use crate::dhcopy::codec::Codec;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// How many extensions and files the report lists
+const REPORT_LENGTH: usize = 10;
+
+/// Space some files take before and after compression
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompressionRatio {
+	/// The extension, with its dot, or the file's path
+	pub name: String,
+	pub files: u64,
+	pub original_bytes: u64,
+	pub stored_bytes: u64,
+}
+
+impl CompressionRatio {
+	/// Stored size as a fraction of the original, 1 or more for files that
+	/// don't compress
+	pub fn ratio(&self) -> f64 {
+		match self.original_bytes {
+			0 => 1.0,
+			original => self.stored_bytes as f64 / original as f64,
+		}
+	}
+}
+
+/// Where the space of a compressed set goes, each list biggest first
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompressionReport {
+	pub by_extension: Vec<CompressionRatio>,
+	pub largest_files: Vec<CompressionRatio>,
+}
+
+/// Compares the size of each file in the manifest with the size it's
+/// stored at in the set
+pub fn compression_report(
+	set_folder: &Path,
+	manifest: &[ManifestEntry],
+	codec: &Codec,
+) -> io::Result<CompressionReport> {
+	let mut by_extension: HashMap<String, CompressionRatio> = HashMap::new();
+	let mut files = Vec::new();
+	for entry in manifest {
+		let stored_bytes = fs::metadata(codec.stored_file(set_folder, &entry.path))?.len();
+		let extension = match Path::new(&entry.path).extension() {
+			Some(extension) => format!(".{}", extension.to_string_lossy().to_lowercase()),
+			None => "(none)".to_string(),
+		};
+		let group = by_extension
+			.entry(extension.clone())
+			.or_insert_with(|| CompressionRatio {
+				name: extension,
+				..Default::default()
+			});
+		group.files += 1;
+		group.original_bytes += entry.size;
+		group.stored_bytes += stored_bytes;
+		files.push(CompressionRatio {
+			name: entry.path.clone(),
+			files: 1,
+			original_bytes: entry.size,
+			stored_bytes,
+		});
+	}
+	let mut by_extension: Vec<CompressionRatio> = by_extension.into_values().collect();
+	for list in [&mut by_extension, &mut files] {
+		list.sort_by(|a, b| {
+			b.stored_bytes
+				.cmp(&a.stored_bytes)
+				.then_with(|| a.name.cmp(&b.name))
+		});
+		list.truncate(REPORT_LENGTH);
+	}
+	Ok(CompressionReport {
+		by_extension,
+		largest_files: files,
+	})
+}
+
+pub fn print_compression_report(report: &CompressionReport) {
+	if report.largest_files.is_empty() {
+		return;
+	}
+	println!("compression by extension:");
+	for group in &report.by_extension {
+		println!(
+			"  {} {} files, {} bytes stored in {} ({:.0}%)",
+			group.name,
+			group.files,
+			group.original_bytes,
+			group.stored_bytes,
+			group.ratio() * 100.0
+		);
+	}
+	println!("largest stored files:");
+	for file in &report.largest_files {
+		println!(
+			"  {} {} bytes stored in {} ({:.0}%)",
+			file.name,
+			file.original_bytes,
+			file.stored_bytes,
+			file.ratio() * 100.0
+		);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup_sets::manifest::hash_set_files;
+	use crate::dhcopy::compression::Compression;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	#[test]
+	fn test_reports_by_extension() -> io::Result<()> {
+		let set_folder = create_tmp_folder("set")?;
+		let set_path = Path::new(&set_folder);
+		let codec = Codec::from(Compression::ZSTD);
+		let text = "backmeup susie ".repeat(1000);
+		let noise: Vec<u8> = (0..10_000).map(|_| rand::random()).collect();
+		for (path, contents) in [
+			("notes.txt", text.as_bytes()),
+			("more notes.TXT", text.as_bytes()),
+			("photo.jpg", &noise[..]),
+		] {
+			codec.write(&mut &contents[..], &codec.stored_path(&set_path.join(path)))?;
+		}
+		let manifest = hash_set_files(set_path, &codec)?;
+
+		let report = compression_report(set_path, &manifest, &codec)?;
+
+		let names: Vec<&str> = report
+			.by_extension
+			.iter()
+			.map(|group| group.name.as_str())
+			.collect();
+		assert_eq!(names, vec![".jpg", ".txt"]);
+		assert_eq!(report.by_extension[1].files, 2);
+		assert!(report.by_extension[0].ratio() > 0.99);
+		assert!(report.by_extension[1].ratio() < 0.1);
+		assert_eq!(report.largest_files[0].name, "photo.jpg");
+		assert_eq!(report.largest_files.len(), 3);
+		Ok(())
+	}
+}
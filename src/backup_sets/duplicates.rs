@@ -0,0 +1,194 @@
+use crate::backup_sets::manifest::ManifestEntry;
+use crate::dhcopy::codec::Codec;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Files in a set that have identical contents
+pub struct DuplicateGroup {
+	pub digest: String,
+	pub size: u64,
+	pub paths: Vec<String>,
+}
+
+impl DuplicateGroup {
+	/// Space that would be saved by keeping only one copy
+	pub fn reclaimable_bytes(&self) -> u64 {
+		self.size * (self.paths.len() as u64 - 1)
+	}
+}
+
+pub fn find_duplicates(entries: &[ManifestEntry]) -> Vec<DuplicateGroup> {
+	let mut by_digest: BTreeMap<&str, DuplicateGroup> = BTreeMap::new();
+	// empty files all share a digest but don't cost anything, so aren't worth reporting
+	for entry in entries.iter().filter(|entry| entry.size > 0) {
+		by_digest
+			.entry(&entry.digest)
+			.or_insert_with(|| DuplicateGroup {
+				digest: entry.digest.clone(),
+				size: entry.size,
+				paths: Vec::new(),
+			})
+			.paths
+			.push(entry.path.clone());
+	}
+
+	let mut groups: Vec<DuplicateGroup> = by_digest
+		.into_values()
+		.filter(|group| group.paths.len() > 1)
+		.collect();
+	groups.sort_by_key(|group| Reverse(group.reclaimable_bytes()));
+	groups
+}
+
+/// Leaves out of each group all but one of the copies already hard-linked to
+/// an earlier set's copy, putting that one first so the others are linked to
+/// it, and drops groups with nothing left to link. What's then reclaimable
+/// is only what the set newly stores.
+pub fn without_linked_copies(
+	groups: Vec<DuplicateGroup>,
+	linked: &HashSet<String>,
+) -> Vec<DuplicateGroup> {
+	groups
+		.into_iter()
+		.filter_map(|mut group| {
+			let (mut kept, unlinked): (Vec<String>, Vec<String>) = group
+				.paths
+				.into_iter()
+				.partition(|path| linked.contains(path));
+			kept.truncate(1);
+			kept.extend(unlinked);
+			group.paths = kept;
+			(group.paths.len() > 1).then_some(group)
+		})
+		.collect()
+}
+
+/// Replaces every copy but the first in each group with a hard link to the
+/// first, returning the bytes saved. Each copy is swapped for its link in one
+/// rename, so a path never goes missing if this is interrupted.
+pub fn link_duplicates(
+	set_folder: &Path,
+	groups: &[DuplicateGroup],
+	codec: &Codec,
+) -> io::Result<u64> {
+	let mut saved = 0;
+	for group in groups {
+		let original = codec.stored_path(&set_folder.join(&group.paths[0]));
+		for path in &group.paths[1..] {
+			let copy = codec.stored_path(&set_folder.join(path));
+			let mut link = copy.clone().into_os_string();
+			link.push(".dhb-link");
+			fs::hard_link(&original, &link)?;
+			fs::rename(&link, &copy)?;
+		}
+		saved += group.reclaimable_bytes();
+	}
+	if saved > 0 {
+		println!("hard-linked duplicate files, {} bytes saved", saved);
+	}
+	Ok(saved)
+}
+
+pub fn print_duplicates_report(groups: &[DuplicateGroup]) {
+	if groups.is_empty() {
+		return;
+	}
+	println!("duplicates:");
+	for group in groups {
+		println!(
+			"  {} copies of {} bytes ({} bytes reclaimable), sha256 {}:",
+			group.paths.len(),
+			group.size,
+			group.reclaimable_bytes(),
+			group.digest
+		);
+		for path in &group.paths {
+			println!("    {}", path);
+		}
+	}
+	let total: u64 = groups.iter().map(DuplicateGroup::reclaimable_bytes).sum();
+	println!("  total reclaimable: {} bytes", total);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	fn entry(path: &str, size: u64, digest: &str) -> ManifestEntry {
+		ManifestEntry {
+			path: path.to_string(),
+			size,
+			digest: digest.to_string(),
+		}
+	}
+
+	#[test]
+	fn test_finds_duplicates() {
+		let entries = vec![
+			entry("a.txt", 10, "aaa"),
+			entry("b.txt", 20, "bbb"),
+			entry("copy/a.txt", 10, "aaa"),
+			entry("copy/again/a.txt", 10, "aaa"),
+			entry("empty1", 0, "zzz"),
+			entry("empty2", 0, "zzz"),
+		];
+
+		let groups = find_duplicates(&entries);
+
+		assert_eq!(
+			groups.len(),
+			1,
+			"only non-empty duplicates should be reported"
+		);
+		assert_eq!(
+			groups[0].paths,
+			vec!["a.txt", "copy/a.txt", "copy/again/a.txt"]
+		);
+		assert_eq!(groups[0].reclaimable_bytes(), 20);
+	}
+
+	#[test]
+	fn test_leaves_out_linked_copies() {
+		let entries = vec![
+			entry("a.txt", 10, "aaa"),
+			entry("b.txt", 20, "bbb"),
+			entry("copy/a.txt", 10, "aaa"),
+			entry("copy/again/a.txt", 10, "aaa"),
+			entry("copy/b.txt", 20, "bbb"),
+		];
+		let linked =
+			HashSet::from(["a.txt", "copy/again/a.txt", "b.txt", "copy/b.txt"].map(String::from));
+
+		let groups = without_linked_copies(find_duplicates(&entries), &linked);
+
+		assert_eq!(groups.len(), 1);
+		assert_eq!(groups[0].paths, vec!["a.txt", "copy/a.txt"]);
+		assert_eq!(groups[0].reclaimable_bytes(), 10);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_links_duplicates() -> io::Result<()> {
+		use std::os::unix::fs::MetadataExt;
+
+		let set_folder = create_tmp_folder("set")?;
+		let set_path = Path::new(&set_folder);
+		fs::create_dir(set_path.join("copy"))?;
+		fs::write(set_path.join("a.txt"), "backmeup susie")?;
+		fs::write(set_path.join("copy/a.txt"), "backmeup susie")?;
+		let groups = find_duplicates(&[entry("a.txt", 14, "aaa"), entry("copy/a.txt", 14, "aaa")]);
+
+		let saved = link_duplicates(set_path, &groups, &Codec::default())?;
+
+		assert_eq!(saved, 14);
+		let original = fs::metadata(set_path.join("a.txt"))?;
+		let copy = fs::metadata(set_path.join("copy/a.txt"))?;
+		assert_eq!(original.ino(), copy.ino());
+		assert_eq!(fs::read_dir(set_path.join("copy"))?.count(), 1);
+		Ok(())
+	}
+}
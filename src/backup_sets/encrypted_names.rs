@@ -0,0 +1,107 @@
+use crate::backup_sets::backup_set::SET_METADATA_FILES;
+use crate::backup_sets::manifest::ManifestEntry;
+use crate::dhcopy::codec::{Codec, ENCRYPTED_NAMES_FOLDER};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Moves every file of a freshly written set from its real path to the one
+/// `codec` derives for it, then removes the folders left behind, so only
+/// the encrypted manifest says what the files are. Empty folders go too,
+/// as the manifest only lists files.
+pub fn encrypt_set_names(
+	set_folder: &Path,
+	entries: &[ManifestEntry],
+	codec: &Codec,
+) -> io::Result<()> {
+	let mut folders = Vec::new();
+	for entry in fs::read_dir(set_folder)? {
+		let entry = entry?;
+		let name = entry.file_name();
+		if entry.file_type()?.is_dir() && name != ENCRYPTED_NAMES_FOLDER {
+			list_folders(&entry.path(), &mut folders)?;
+		}
+	}
+	for entry in entries {
+		let stored = codec.stored_file(set_folder, &entry.path);
+		if let Some(parent) = stored.parent() {
+			fs::create_dir_all(parent)?;
+		}
+		fs::rename(codec.stored_path(&set_folder.join(&entry.path)), stored)?;
+	}
+	// deepest first, so each folder is empty by the time it's removed
+	for folder in folders.iter().rev() {
+		fs::remove_dir(folder)?;
+	}
+	let leftover = fs::read_dir(set_folder)?
+		.filter_map(Result::ok)
+		.find(|entry| {
+			let name = entry.file_name();
+			name != ENCRYPTED_NAMES_FOLDER
+				&& !SET_METADATA_FILES.contains(&name.to_string_lossy().as_ref())
+		});
+	match leftover {
+		Some(entry) => Err(io::Error::other(format!(
+			"{} wasn't in the manifest, so its name can't be encrypted",
+			entry.path().display()
+		))),
+		None => Ok(()),
+	}
+}
+
+/// Lists `folder` and every folder in it, each before its contents
+fn list_folders(folder: &Path, folders: &mut Vec<PathBuf>) -> io::Result<()> {
+	folders.push(folder.to_path_buf());
+	for entry in fs::read_dir(folder)? {
+		let entry = entry?;
+		if entry.file_type()?.is_dir() {
+			list_folders(&entry.path(), folders)?;
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup_sets::manifest::hash_set_files;
+	use crate::dhcopy::compression::Compression;
+	use crate::dhcopy::encryption::SetKey;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+	use std::io::Read;
+
+	#[test]
+	fn test_hides_names() -> io::Result<()> {
+		let set_folder = create_tmp_folder("set")?;
+		let set_path = Path::new(&set_folder);
+		fs::create_dir_all(set_path.join("secret plans/empty"))?;
+		let codec = Codec {
+			compression: Compression::ZSTD,
+			key: Some(SetKey::generate()),
+			encrypted_names: false,
+			dictionary: None,
+		};
+		codec.write(
+			&mut "backmeup susie".as_bytes(),
+			&codec.stored_path(&set_path.join("secret plans/world domination.txt")),
+		)?;
+		let entries = hash_set_files(set_path, &codec)?;
+
+		let codec = Codec {
+			encrypted_names: true,
+			..codec
+		};
+		encrypt_set_names(set_path, &entries, &codec)?;
+
+		let names: Vec<_> = fs::read_dir(set_path)?
+			.map(|entry| entry.map(|entry| entry.file_name()))
+			.collect::<io::Result<_>>()?;
+		assert_eq!(names, vec![ENCRYPTED_NAMES_FOLDER]);
+		let mut contents = String::new();
+		codec
+			.reader(&codec.stored_file(set_path, "secret plans/world domination.txt"))?
+			.read_to_string(&mut contents)?;
+		assert_eq!(contents, "backmeup susie");
+		Ok(())
+	}
+}
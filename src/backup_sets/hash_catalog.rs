@@ -0,0 +1,219 @@
+use crate::backup_sets::backup_set::{list_resumable_sets, list_sets_by_status};
+use crate::backup_sets::last_known_good::last_known_good;
+use crate::backup_sets::manifest::read_manifest;
+use crate::backup_sets::set_metadata::{read_metadata, set_storage};
+use crate::dhcopy::compression::Compression;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How long before a set started a file must have last changed to be taken
+/// as unchanged since, as filesystems keep modification times coarser than
+/// the clock, FAT to two seconds
+const MODIFIED_MARGIN: Duration = Duration::from_secs(2);
+
+/// Where a copy of each file contents can be found in the destination's
+/// last known good, newest complete and resumable sets, going by their
+/// manifests, so new sets can hard-link to it instead of keeping another
+/// copy
+#[derive(Debug, Default)]
+pub struct HashCatalog {
+	files: HashMap<String, PathBuf>,
+	/// What was copied to each path within a set
+	paths: HashMap<String, EarlierCopy>,
+	compression: Compression,
+}
+
+/// A file as an earlier set copied it
+#[derive(Clone, Debug, PartialEq)]
+pub struct EarlierCopy {
+	pub stored: PathBuf,
+	/// SHA-256 of the original contents, in hex
+	pub digest: String,
+	/// When the set started, so before the file was copied
+	started_at: SystemTime,
+}
+
+impl HashCatalog {
+	/// Catalogs those of the sets that are plain sets stored in the same
+	/// format as `compression`. The last known good set's copies win, as
+	/// they've been checked since they were written, then the newest
+	/// complete set's, then those in resumable sets, newest first. Sets
+	/// without a manifest are skipped.
+	pub fn load(dest: &str, compression: Compression) -> io::Result<Self> {
+		let mut files = HashMap::new();
+		let mut paths = HashMap::new();
+		let mut sets: Vec<String> = last_known_good(dest)?.into_iter().collect();
+		if let Some(newest) = list_sets_by_status(dest)?.complete.pop() {
+			if !sets.contains(&newest) {
+				sets.push(newest);
+			}
+		}
+		// so a backup that ran out of time isn't copied all over again
+		sets.extend(list_resumable_sets(dest)?.into_iter().rev());
+		for set in &sets {
+			let set_folder = Path::new(dest).join(set);
+			if !set_folder.is_dir() {
+				continue;
+			}
+			let storage = set_storage(&set_folder)?;
+			// encrypted sets each have their own key, and sets with a
+			// dictionary their own dictionary, so can't share files
+			if storage.archive
+				|| storage.chunked
+				|| storage.encrypted
+				|| storage.zstd_dictionary
+				|| !storage.compression.same_format(compression)
+			{
+				continue;
+			}
+			let entries = match read_manifest(&set_folder) {
+				Ok(entries) => entries,
+				Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+				Err(e) => return Err(e),
+			};
+			let started_at = SystemTime::from(read_metadata(&set_folder)?.started_at);
+			for (digest, path) in entries {
+				let stored = compression.stored_path(&set_folder.join(&path));
+				files
+					.entry(digest.clone())
+					.or_insert_with(|| stored.clone());
+				paths.entry(path).or_insert(EarlierCopy {
+					stored,
+					digest,
+					started_at,
+				});
+			}
+		}
+		Ok(HashCatalog {
+			files,
+			paths,
+			compression,
+		})
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.files.is_empty()
+	}
+
+	/// A stored copy of the contents with this digest
+	pub fn find(&self, digest: &str) -> Option<&Path> {
+		self.files.get(digest).map(PathBuf::as_path)
+	}
+
+	/// The earlier copy of the file at `path` within the set, if the file
+	/// hasn't changed since, going by when it was last modified and, for
+	/// uncompressed sets, its size. Files without a modification time are
+	/// taken to have changed.
+	pub fn unchanged(
+		&self,
+		path: &str,
+		size: u64,
+		modified: Option<SystemTime>,
+	) -> Option<&EarlierCopy> {
+		let earlier = self.paths.get(path)?;
+		if modified.is_none_or(|modified| modified + MODIFIED_MARGIN >= earlier.started_at) {
+			return None;
+		}
+		if self.compression == Compression::None
+			&& !fs::metadata(&earlier.stored).is_ok_and(|stored| stored.len() == size)
+		{
+			return None;
+		}
+		Some(earlier)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup_sets::last_known_good::record_last_known_good;
+	use crate::backup_sets::manifest::generate_manifest;
+	use crate::backup_sets::set_metadata::{read_metadata, write_metadata};
+	use crate::backup_sets::SetStatus;
+	use crate::test_helpers::test_helpers::{create_set, create_tmp_folder};
+	use std::fs;
+
+	const THE_DIGEST: &str = "d4c6dd316c319e25f5599ba149dfaafd1127bdd1a152b6e148217e5860b57ba7";
+
+	#[test]
+	fn test_finds_files_in_complete_sets() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		for (set, status) in [
+			("dhb-set-20010101-000000", SetStatus::Complete),
+			("dhb-set-20010102-000000", SetStatus::Complete),
+			("dhb-set-20010103-000000", SetStatus::Incomplete),
+		] {
+			create_set(&dest, set, status)?;
+			let set_folder = Path::new(&dest).join(set);
+			fs::write(set_folder.join("testfile.txt"), "backmeup susie")?;
+			generate_manifest(&set_folder)?;
+		}
+		let in_set = |set: &str| Path::new(&dest).join(set).join("testfile.txt");
+
+		let catalog = HashCatalog::load(&dest, Compression::None)?;
+		assert_eq!(
+			catalog.find(THE_DIGEST),
+			Some(in_set("dhb-set-20010102-000000").as_path())
+		);
+
+		// the last known good set wins over the newest
+		record_last_known_good(&dest, "dhb-set-20010101-000000")?;
+		let catalog = HashCatalog::load(&dest, Compression::None)?;
+		assert_eq!(
+			catalog.find(THE_DIGEST),
+			Some(in_set("dhb-set-20010101-000000").as_path())
+		);
+		assert!(HashCatalog::load(&dest, Compression::ZSTD)?.is_empty());
+		Ok(())
+	}
+
+	#[test]
+	fn test_finds_unchanged_files() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		create_set(&dest, "dhb-set-20010101-000000", SetStatus::Complete)?;
+		let set_folder = Path::new(&dest).join("dhb-set-20010101-000000");
+		fs::write(set_folder.join("testfile.txt"), "backmeup susie")?;
+		generate_manifest(&set_folder)?;
+		let catalog = HashCatalog::load(&dest, Compression::None)?;
+		let started_at = SystemTime::from(read_metadata(&set_folder)?.started_at);
+		let before = Some(started_at - Duration::from_secs(60));
+		let size = "backmeup susie".len() as u64;
+
+		let earlier = catalog.unchanged("testfile.txt", size, before).unwrap();
+		assert_eq!(earlier.stored, set_folder.join("testfile.txt"));
+		assert_eq!(earlier.digest, THE_DIGEST);
+		// changed as the set started, or no time to go by
+		let during = started_at - Duration::from_secs(1);
+		assert!(catalog
+			.unchanged("testfile.txt", size, Some(during))
+			.is_none());
+		assert!(catalog.unchanged("testfile.txt", size, None).is_none());
+		assert!(catalog
+			.unchanged("testfile.txt", size + 1, before)
+			.is_none());
+		assert!(catalog.unchanged("other.txt", size, before).is_none());
+		Ok(())
+	}
+
+	#[test]
+	fn test_finds_files_in_resumable_sets() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let set_folder = Path::new(&dest).join("dhb-set-20010101-000000");
+		create_set(&dest, "dhb-set-20010101-000000", SetStatus::Incomplete)?;
+		fs::write(set_folder.join("copied.txt"), "backmeup susie")?;
+		generate_manifest(&set_folder)?;
+		assert!(HashCatalog::load(&dest, Compression::None)?.is_empty());
+
+		let mut metadata = read_metadata(&set_folder)?;
+		metadata.resumable = true;
+		write_metadata(&set_folder, &metadata)?;
+		assert_eq!(
+			HashCatalog::load(&dest, Compression::None)?.find(THE_DIGEST),
+			Some(set_folder.join("copied.txt").as_path())
+		);
+		Ok(())
+	}
+}
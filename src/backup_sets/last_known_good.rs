@@ -0,0 +1,59 @@
+use crate::backup_sets::set_namer::parse_name;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Lives in the destination alongside the sets, holding the name of the newest fully verified set
+const LAST_KNOWN_GOOD_FILE: &str = ".dhb-last-known-good";
+
+pub fn last_known_good(dest: &str) -> io::Result<Option<String>> {
+	match fs::read_to_string(Path::new(dest).join(LAST_KNOWN_GOOD_FILE)) {
+		Ok(contents) => Ok(Some(contents.trim().to_string())),
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+		Err(e) => Err(e),
+	}
+}
+
+/// Records a set that passed verification, unless a newer set is already known to be good
+pub fn record_last_known_good(dest: &str, set_name: &str) -> io::Result<()> {
+	if let Some(current) = last_known_good(dest)? {
+		if parse_name(&current) > parse_name(set_name) {
+			return Ok(());
+		}
+	}
+	fs::write(Path::new(dest).join(LAST_KNOWN_GOOD_FILE), set_name)
+}
+
+/// Forgets a set that failed verification if it was the last known good one
+pub fn clear_last_known_good(dest: &str, set_name: &str) -> io::Result<()> {
+	if last_known_good(dest)?.as_deref() == Some(set_name) {
+		fs::remove_file(Path::new(dest).join(LAST_KNOWN_GOOD_FILE))?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	const OLDER_SET: &str = "dhb-set-20000101-000000";
+	const NEWER_SET: &str = "dhb-set-20010203-140506";
+
+	#[test]
+	fn test_only_moves_forward() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		assert_eq!(last_known_good(&dest)?, None);
+
+		record_last_known_good(&dest, NEWER_SET)?;
+		record_last_known_good(&dest, OLDER_SET)?;
+		assert_eq!(last_known_good(&dest)?.as_deref(), Some(NEWER_SET));
+
+		clear_last_known_good(&dest, OLDER_SET)?;
+		assert_eq!(last_known_good(&dest)?.as_deref(), Some(NEWER_SET));
+
+		clear_last_known_good(&dest, NEWER_SET)?;
+		assert_eq!(last_known_good(&dest)?, None);
+		Ok(())
+	}
+}
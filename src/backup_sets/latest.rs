@@ -0,0 +1,88 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Points at the newest complete set so scripts don't need to parse set names.
+// Windows can't reliably create symlinks without extra privileges, so gets a text file instead.
+#[cfg(unix)]
+pub const LATEST_NAME: &str = "latest";
+#[cfg(not(unix))]
+pub const LATEST_NAME: &str = "latest.txt";
+
+const LATEST_TEMP_NAME: &str = ".dhb-latest.tmp";
+
+/// Points `latest` at the given set. The link is built under a temporary
+/// name and renamed over the old one, so readers never see it missing.
+pub fn update_latest(dest: &str, set_name: &str) -> io::Result<()> {
+	let temp = Path::new(dest).join(LATEST_TEMP_NAME);
+	match fs::remove_file(&temp) {
+		Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+		_ => {}
+	}
+	write_latest(&temp, set_name)?;
+	fs::rename(temp, Path::new(dest).join(LATEST_NAME))
+}
+
+/// Removes `latest`, for when there are no complete sets left to point at
+pub fn remove_latest(dest: &str) -> io::Result<()> {
+	match fs::remove_file(Path::new(dest).join(LATEST_NAME)) {
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+		result => result,
+	}
+}
+
+/// The set `latest` points at, if any
+pub fn latest_set(dest: &str) -> io::Result<Option<String>> {
+	match read_latest(&Path::new(dest).join(LATEST_NAME)) {
+		Ok(set_name) => Ok(Some(set_name)),
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+		Err(e) => Err(e),
+	}
+}
+
+#[cfg(unix)]
+fn write_latest(path: &Path, set_name: &str) -> io::Result<()> {
+	// relative, so the link still works if the destination is mounted elsewhere
+	std::os::unix::fs::symlink(set_name, path)
+}
+
+#[cfg(unix)]
+fn read_latest(path: &Path) -> io::Result<String> {
+	Ok(fs::read_link(path)?.to_string_lossy().into_owned())
+}
+
+#[cfg(not(unix))]
+fn write_latest(path: &Path, set_name: &str) -> io::Result<()> {
+	fs::write(path, set_name)
+}
+
+#[cfg(not(unix))]
+fn read_latest(path: &Path) -> io::Result<String> {
+	Ok(fs::read_to_string(path)?.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	#[test]
+	fn test_update_latest() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		fs::create_dir_all(Path::new(&dest).join("dhb-set-20000101-000000"))?;
+		fs::create_dir_all(Path::new(&dest).join("dhb-set-20010203-140506"))?;
+		assert_eq!(latest_set(&dest)?, None);
+
+		update_latest(&dest, "dhb-set-20000101-000000")?;
+		update_latest(&dest, "dhb-set-20010203-140506")?;
+
+		assert_eq!(
+			latest_set(&dest)?.as_deref(),
+			Some("dhb-set-20010203-140506")
+		);
+		assert!(!Path::new(&dest).join(LATEST_TEMP_NAME).exists());
+		#[cfg(unix)]
+		assert!(Path::new(&dest).join(LATEST_NAME).join(".").is_dir());
+		Ok(())
+	}
+}
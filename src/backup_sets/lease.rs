@@ -0,0 +1,289 @@
+use crate::backup_sets::set_namer::current_hostname;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+// Kept alongside the lock file, which only keeps out other processes on
+// the same machine, as advisory locks can't be relied on across network
+// filesystems
+const LEASE_FILE_NAME: &str = ".dhb-lease";
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+// A lease not renewed for this long was held by a machine that went down
+// or lost the network, and can be taken over
+const STALE_AFTER: TimeDelta = TimeDelta::minutes(5);
+
+/// Who holds a destination's lease, as recorded in its lease file
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LeaseHolder {
+	pub hostname: String,
+	pub pid: u32,
+	pub acquired_at: DateTime<Utc>,
+	/// Brought up to date every minute while the lease is held
+	pub renewed_at: DateTime<Utc>,
+}
+
+impl LeaseHolder {
+	fn is_stale(&self, now: DateTime<Utc>) -> bool {
+		now - self.renewed_at > STALE_AFTER
+	}
+
+	fn same_holder(&self, other: &LeaseHolder) -> bool {
+		self.hostname == other.hostname
+			&& self.pid == other.pid
+			&& self.acquired_at == other.acquired_at
+	}
+}
+
+/// A lease on a destination shared between machines, e.g. a NAS export
+/// several back up to, so one machine's pruning or gc never runs during
+/// another's backup. It's renewed in the background while held and
+/// given up when dropped.
+pub struct Lease {
+	path: PathBuf,
+	holder: LeaseHolder,
+	renewer: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+/// Takes the destination's lease, failing with `WouldBlock` if another
+/// machine holds it unless `wait` is set. Only one process on this machine
+/// should try at a time, so this is done holding the destination's lock.
+pub fn take_lease(dest: &str, wait: bool) -> io::Result<Lease> {
+	let now = Utc::now();
+	let holder = LeaseHolder {
+		hostname: current_hostname(),
+		pid: std::process::id(),
+		acquired_at: now,
+		renewed_at: now,
+	};
+	let path = Path::new(dest).join(LEASE_FILE_NAME);
+	let mut waiting = false;
+	loop {
+		match try_take(&path, &holder, Utc::now()) {
+			Err(e) if e.kind() == io::ErrorKind::WouldBlock && wait => {
+				if !waiting {
+					println!("waiting: {}", e);
+					waiting = true;
+				}
+				thread::sleep(HEARTBEAT_INTERVAL / 2);
+			}
+			result => break result?,
+		}
+	}
+	let mut lease = Lease {
+		path,
+		holder,
+		renewer: None,
+	};
+	lease.start_renewing();
+	Ok(lease)
+}
+
+/// Claims the lease at `path` for `holder`, taking it over from a holder
+/// that's stopped renewing it
+fn try_take(path: &Path, holder: &LeaseHolder, now: DateTime<Utc>) -> io::Result<()> {
+	match read_lease(path)? {
+		Some(Ok(current)) => {
+			// the lock lets one process on a machine through at a time, so
+			// a lease from this machine was left by one that's gone
+			if current.hostname != holder.hostname && !current.is_stale(now) {
+				return Err(io::Error::new(
+					io::ErrorKind::WouldBlock,
+					format!(
+						"{} (process {}) is using {}, last seen {}",
+						current.hostname,
+						current.pid,
+						path.parent().unwrap_or(path).display(),
+						current.renewed_at
+					),
+				));
+			}
+			println!(
+				"taking over the lease from {} (process {}), last renewed {}",
+				current.hostname, current.pid, current.renewed_at
+			);
+			set_aside(path, Some(&current))?;
+		}
+		// a lease is written as it's created, so one that can't be read
+		// is only worth waiting for if it's new
+		Some(Err(modified)) if now - modified <= STALE_AFTER => {
+			return Err(io::Error::new(
+				io::ErrorKind::WouldBlock,
+				format!("another machine is taking the lease {}", path.display()),
+			));
+		}
+		Some(Err(_)) => set_aside(path, None)?,
+		None => {}
+	}
+	let mut file = match OpenOptions::new().write(true).create_new(true).open(path) {
+		Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+			return Err(io::Error::new(
+				io::ErrorKind::WouldBlock,
+				format!("another machine took the lease {} first", path.display()),
+			))
+		}
+		result => result?,
+	};
+	file.write_all(&serde_json::to_vec(holder)?)?;
+	file.sync_all()
+}
+
+/// The lease at `path`, or when it was last modified if it can't be read
+fn read_lease(path: &Path) -> io::Result<Option<Result<LeaseHolder, DateTime<Utc>>>> {
+	let contents = match fs::read(path) {
+		Ok(contents) => contents,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+		Err(e) => return Err(e),
+	};
+	match serde_json::from_slice(&contents) {
+		Ok(holder) => Ok(Some(Ok(holder))),
+		Err(_) => {
+			let modified = fs::metadata(path)?
+				.modified()
+				.unwrap_or(SystemTime::UNIX_EPOCH);
+			Ok(Some(Err(modified.into())))
+		}
+	}
+}
+
+/// Moves a stale lease out of the way. Renaming it first means only one
+/// machine taking it over can succeed, and the one that does checks it
+/// moved the lease it found stale rather than one just taken.
+fn set_aside(path: &Path, expected: Option<&LeaseHolder>) -> io::Result<()> {
+	let aside = path.with_extension(format!(
+		"stale-{}-{}",
+		current_hostname(),
+		std::process::id()
+	));
+	match fs::rename(path, &aside) {
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+		result => result?,
+	}
+	let moved = read_lease(&aside)?.and_then(Result::ok);
+	let unchanged = match (expected, &moved) {
+		(Some(expected), Some(moved)) => expected.same_holder(moved),
+		(None, moved) => moved.is_none(),
+		(Some(_), None) => false,
+	};
+	if !unchanged {
+		fs::rename(&aside, path)?;
+		return Err(io::Error::new(
+			io::ErrorKind::WouldBlock,
+			format!(
+				"another machine took over the lease {} first",
+				path.display()
+			),
+		));
+	}
+	fs::remove_file(aside)
+}
+
+impl Lease {
+	fn start_renewing(&mut self) {
+		let (stop, stopped) = mpsc::channel();
+		let path = self.path.clone();
+		let mut holder = self.holder.clone();
+		let renewer = thread::spawn(move || {
+			while stopped.recv_timeout(HEARTBEAT_INTERVAL) == Err(RecvTimeoutError::Timeout) {
+				holder.renewed_at = Utc::now();
+				if let Err(e) = renew(&path, &holder) {
+					eprintln!(
+						"warning: renewing the lease {} failed: {}",
+						path.display(),
+						e
+					);
+				}
+			}
+		});
+		self.renewer = Some((stop, renewer));
+	}
+}
+
+/// Rewrites the lease with a new time, if it's still `holder`'s
+fn renew(path: &Path, holder: &LeaseHolder) -> io::Result<()> {
+	match read_lease(path)? {
+		Some(Ok(current)) if current.same_holder(holder) => {}
+		Some(Ok(current)) => {
+			return Err(io::Error::other(format!(
+				"{} (process {}) took it over",
+				current.hostname, current.pid
+			)))
+		}
+		_ => return Err(io::Error::other("it's gone")),
+	}
+	// renamed over the lease so it's never seen part written
+	let renewed = path.with_extension(format!("renew-{}", holder.pid));
+	fs::write(&renewed, serde_json::to_vec(holder)?)?;
+	fs::rename(renewed, path)
+}
+
+impl Drop for Lease {
+	fn drop(&mut self) {
+		if let Some((stop, renewer)) = self.renewer.take() {
+			drop(stop);
+			let _ = renewer.join();
+		}
+		if let Ok(Some(Ok(current))) = read_lease(&self.path) {
+			if current.same_holder(&self.holder) {
+				let _ = fs::remove_file(&self.path);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	fn holder(hostname: &str, renewed_at: DateTime<Utc>) -> LeaseHolder {
+		LeaseHolder {
+			hostname: hostname.to_string(),
+			pid: 42,
+			acquired_at: renewed_at - TimeDelta::hours(1),
+			renewed_at,
+		}
+	}
+
+	#[test]
+	fn test_takes_over_only_stale_leases() -> io::Result<()> {
+		let dest = create_tmp_folder("lease")?;
+		let path = Path::new(&dest).join(LEASE_FILE_NAME);
+		let now = Utc::now();
+		let laptop = holder("laptop", now - TimeDelta::minutes(1));
+		try_take(&path, &laptop, now - TimeDelta::minutes(1))?;
+
+		let other = holder("desktop", now);
+		let err = try_take(&path, &other, now).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+		assert!(err.to_string().contains("laptop"), "{}", err);
+
+		let later = now + STALE_AFTER;
+		try_take(&path, &other, later)?;
+		assert_eq!(read_lease(&path)?, Some(Ok(other)));
+		Ok(())
+	}
+
+	#[test]
+	fn test_gives_up_lease_when_dropped() -> io::Result<()> {
+		let dest = create_tmp_folder("lease")?;
+		let path = Path::new(&dest).join(LEASE_FILE_NAME);
+		// left by a process on this machine that crashed
+		fs::write(
+			&path,
+			serde_json::to_vec(&holder(&current_hostname(), Utc::now()))?,
+		)?;
+
+		let lease = take_lease(&dest, false)?;
+		assert_eq!(read_lease(&path)?, Some(Ok(lease.holder.clone())));
+		drop(lease);
+		assert!(!path.exists());
+		Ok(())
+	}
+}
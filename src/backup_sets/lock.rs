@@ -0,0 +1,186 @@
+use crate::backup_sets::lease::{take_lease, Lease};
+use crate::error::{BackupError, Context, Operation};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Held by whatever is changing the destination, so two backups or a backup
+// and a prune don't delete sets from under each other
+const LOCK_FILE_NAME: &str = ".dhb-lock";
+
+/// Exclusive lock on a destination, released when dropped
+pub struct DestinationLock {
+	file: File,
+	_lease: Lease,
+}
+
+impl Drop for DestinationLock {
+	fn drop(&mut self) {
+		// an empty lock file shows the last holder finished cleanly
+		let _ = self.file.set_len(0);
+	}
+}
+
+/// Locks the destination, failing with [BackupError::DestinationLocked] if
+/// something else holds it unless `wait` is set. The lock is advisory and goes away with
+/// the process, so a crashed backup never leaves it stuck; its lock file is
+/// just reused, with a note that whatever held it last didn't finish.
+/// The destination's [Lease] is taken too, keeping out other machines
+/// sharing it.
+pub fn lock_destination(dest: &str, wait: bool) -> Result<DestinationLock, BackupError> {
+	let path = Path::new(dest).join(LOCK_FILE_NAME);
+	let mut file = OpenOptions::new()
+		.create(true)
+		.truncate(false)
+		.read(true)
+		.write(true)
+		.open(&path)
+		.context(Operation::Writing, &path)?;
+	match try_lock(&file) {
+		Err(e) if e.kind() == io::ErrorKind::WouldBlock && wait => {
+			println!("waiting for another backup of {} to finish", dest);
+			lock(&file)?;
+		}
+		Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+			return Err(BackupError::DestinationLocked {
+				path: PathBuf::from(dest),
+			});
+		}
+		result => result?,
+	}
+	let lease = take_lease(dest, wait)?;
+	let mut previous_holder = String::new();
+	file.read_to_string(&mut previous_holder)?;
+	if !previous_holder.trim().is_empty() {
+		println!(
+			"process {} stopped without releasing its lock on {}, cleaning up after it",
+			previous_holder.trim(),
+			dest
+		);
+	}
+	// only for people wondering who holds the lock
+	file.set_len(0)?;
+	file.seek(SeekFrom::Start(0))?;
+	writeln!(file, "{}", std::process::id())?;
+	Ok(DestinationLock {
+		file,
+		_lease: lease,
+	})
+}
+
+#[cfg(unix)]
+fn try_lock(file: &File) -> io::Result<()> {
+	flock(file, libc::LOCK_EX | libc::LOCK_NB)
+}
+
+#[cfg(unix)]
+fn lock(file: &File) -> io::Result<()> {
+	flock(file, libc::LOCK_EX)
+}
+
+#[cfg(unix)]
+fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+	use std::os::unix::io::AsRawFd;
+
+	loop {
+		// SAFETY: the descriptor stays open for as long as `file` is borrowed
+		if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+			return Ok(());
+		}
+		let e = io::Error::last_os_error();
+		if e.kind() != io::ErrorKind::Interrupted {
+			return Err(e);
+		}
+	}
+}
+
+#[cfg(windows)]
+fn try_lock(file: &File) -> io::Result<()> {
+	use windows_sys::Win32::Storage::FileSystem::LOCKFILE_FAIL_IMMEDIATELY;
+
+	lock_file_ex(file, LOCKFILE_FAIL_IMMEDIATELY)
+}
+
+#[cfg(windows)]
+fn lock(file: &File) -> io::Result<()> {
+	lock_file_ex(file, 0)
+}
+
+#[cfg(windows)]
+fn lock_file_ex(file: &File, flags: u32) -> io::Result<()> {
+	use std::os::windows::io::AsRawHandle;
+	use windows_sys::Win32::Foundation::ERROR_LOCK_VIOLATION;
+	use windows_sys::Win32::Storage::FileSystem::{LockFileEx, LOCKFILE_EXCLUSIVE_LOCK};
+	use windows_sys::Win32::System::IO::OVERLAPPED;
+
+	// SAFETY: the handle stays open for as long as `file` is borrowed, and
+	// an all-zero OVERLAPPED locks from the start of the file
+	let locked = unsafe {
+		let mut overlapped: OVERLAPPED = std::mem::zeroed();
+		LockFileEx(
+			file.as_raw_handle(),
+			LOCKFILE_EXCLUSIVE_LOCK | flags,
+			0,
+			u32::MAX,
+			u32::MAX,
+			&mut overlapped,
+		)
+	};
+	if locked != 0 {
+		return Ok(());
+	}
+	let e = io::Error::last_os_error();
+	match e.raw_os_error() {
+		Some(code) if code == ERROR_LOCK_VIOLATION as i32 => Err(io::ErrorKind::WouldBlock.into()),
+		_ => Err(e),
+	}
+}
+
+// without a lock, two backups could prune sets from under each other, and
+// the lease would let a second process on this machine take over
+#[cfg(not(any(unix, windows)))]
+fn try_lock(_file: &File) -> io::Result<()> {
+	Err(io::Error::new(
+		io::ErrorKind::Unsupported,
+		"locking the destination is not supported on this platform",
+	))
+}
+
+#[cfg(not(any(unix, windows)))]
+fn lock(file: &File) -> io::Result<()> {
+	try_lock(file)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	#[test]
+	fn test_second_lock_fails_until_first_released() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+
+		let first = lock_destination(&dest, false)?;
+		let second = lock_destination(&dest, false);
+		assert_eq!(
+			second.err().map(|e| e.kind()),
+			Some(io::ErrorKind::WouldBlock)
+		);
+
+		drop(first);
+		assert!(lock_destination(&dest, false).is_ok());
+		Ok(())
+	}
+
+	#[test]
+	fn test_released_lock_leaves_empty_file() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+
+		let lock = lock_destination(&dest, false)?;
+		assert!(!std::fs::read_to_string(Path::new(&dest).join(LOCK_FILE_NAME))?.is_empty());
+		drop(lock);
+
+		assert!(std::fs::read_to_string(Path::new(&dest).join(LOCK_FILE_NAME))?.is_empty());
+		Ok(())
+	}
+}
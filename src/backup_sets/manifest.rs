@@ -0,0 +1,203 @@
+use crate::backup_sets::backup_set::SET_METADATA_FILES;
+use crate::backup_sets::set_metadata::set_storage;
+use crate::dhcopy::codec::Codec;
+use crate::dhcopy::encryption::SetKey;
+use crate::storage::backend::StorageBackend;
+use crate::storage::local::LocalStorage;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+// Same format as `sha256sum`, so a set can be checked with `sha256sum -c` without this tool.
+// For compressed sets it lists the original names and contents, so the files
+// need decompressing first.
+pub const MANIFEST_FILE_NAME: &str = "dhb-manifest.sha256";
+
+// Encrypted sets can keep their manifest encrypted too, in place of the
+// plain one, so the digests don't give away what's in the set
+pub const ENCRYPTED_MANIFEST_FILE_NAME: &str = "dhb-manifest.sha256.age";
+
+pub struct ManifestEntry {
+	/// Path relative to the root of the set
+	pub path: String,
+	pub size: u64,
+	pub digest: String,
+}
+
+pub fn generate_manifest(set_folder: &Path) -> io::Result<Vec<ManifestEntry>> {
+	let codec = Codec::from(set_storage(set_folder)?.compression);
+	let entries = hash_set_files(set_folder, &codec)?;
+	write_manifest(set_folder, &entries)?;
+	Ok(entries)
+}
+
+/// Hashes the original contents of every file in the set
+pub fn hash_set_files(set_folder: &Path, codec: &Codec) -> io::Result<Vec<ManifestEntry>> {
+	let mut entries = Vec::new();
+	hash_folder(set_folder, Path::new(""), codec, &mut entries)?;
+	Ok(entries)
+}
+
+/// Reads back the `(digest, path)` pairs of a set's manifest
+pub fn read_manifest(set_folder: &Path) -> io::Result<Vec<(String, String)>> {
+	parse_manifest(&fs::read_to_string(set_folder.join(MANIFEST_FILE_NAME))?)
+}
+
+/// Reads back a set's manifest, decrypting it if it's encrypted
+pub fn read_set_manifest(set_folder: &Path, codec: &Codec) -> io::Result<Vec<(String, String)>> {
+	let encrypted = set_folder.join(ENCRYPTED_MANIFEST_FILE_NAME);
+	match &codec.key {
+		Some(key) if encrypted.exists() => {
+			let mut contents = String::new();
+			key.decrypt(File::open(encrypted)?)?
+				.read_to_string(&mut contents)?;
+			parse_manifest(&contents)
+		}
+		_ => read_manifest(set_folder),
+	}
+}
+
+fn parse_manifest(contents: &str) -> io::Result<Vec<(String, String)>> {
+	contents
+		.lines()
+		.map(|line| {
+			line.split_once("  ")
+				.map(|(digest, path)| (digest.to_string(), path.to_string()))
+				.ok_or_else(|| {
+					io::Error::new(
+						io::ErrorKind::InvalidData,
+						format!("malformed manifest line: {}", line),
+					)
+				})
+		})
+		.collect()
+}
+
+pub fn hash_file(path: &Path) -> io::Result<(String, u64)> {
+	hash_reader(&mut File::open(path)?)
+}
+
+/// Hashes the original contents of a file in a set, given its path in the manifest
+pub fn hash_stored_file(set_folder: &Path, path: &str, codec: &Codec) -> io::Result<(String, u64)> {
+	let stored = codec.stored_file(set_folder, path);
+	hash_reader(&mut codec.reader(&stored)?)
+}
+
+pub fn hash_reader(reader: &mut (impl Read + ?Sized)) -> io::Result<(String, u64)> {
+	let mut hasher = Sha256::new();
+	let size = io::copy(reader, &mut hasher)?;
+	Ok((hex_digest(hasher), size))
+}
+
+/// The digest in the lowercase hex the manifest uses
+pub fn hex_digest(hasher: Sha256) -> String {
+	hasher
+		.finalize()
+		.iter()
+		.map(|byte| format!("{:02x}", byte))
+		.collect()
+}
+
+fn hash_folder(
+	root: &Path,
+	relative: &Path,
+	codec: &Codec,
+	entries: &mut Vec<ManifestEntry>,
+) -> io::Result<()> {
+	let mut children = fs::read_dir(root.join(relative))?.collect::<io::Result<Vec<_>>>()?;
+	children.sort_by_key(|entry| entry.file_name());
+
+	for entry in children {
+		if relative.as_os_str().is_empty()
+			&& SET_METADATA_FILES.contains(&entry.file_name().to_string_lossy().as_ref())
+		{
+			continue;
+		}
+		if entry.file_type()?.is_dir() {
+			hash_folder(root, &relative.join(entry.file_name()), codec, entries)?;
+		} else {
+			// anything without the extension wasn't written by the backup
+			let Some(name) = codec.original_name(&entry.file_name()) else {
+				continue;
+			};
+			let relative_path = relative.join(name);
+			let (digest, size) = hash_reader(&mut codec.reader(&entry.path())?)?;
+			entries.push(ManifestEntry {
+				path: relative_path.to_string_lossy().into_owned(),
+				size,
+				digest,
+			});
+		}
+	}
+	Ok(())
+}
+
+pub fn write_manifest(set_folder: &Path, entries: &[ManifestEntry]) -> io::Result<()> {
+	write_manifest_to(&LocalStorage, set_folder, entries)
+}
+
+/// Like [write_manifest], into a set in the backend
+pub fn write_manifest_to(
+	backend: &dyn StorageBackend,
+	set_folder: &Path,
+	entries: &[ManifestEntry],
+) -> io::Result<()> {
+	let mut out = BufWriter::new(backend.create_file(&set_folder.join(MANIFEST_FILE_NAME))?);
+	write_entries(&mut out, entries)?;
+	out.flush()
+}
+
+/// Writes the manifest encrypted to the set's key, instead of a plain one
+pub fn write_encrypted_manifest(
+	backend: &dyn StorageBackend,
+	set_folder: &Path,
+	entries: &[ManifestEntry],
+	key: &SetKey,
+) -> io::Result<()> {
+	let file = BufWriter::new(backend.create_file(&set_folder.join(ENCRYPTED_MANIFEST_FILE_NAME))?);
+	let mut out = key.encrypt(file)?;
+	write_entries(&mut out, entries)?;
+	out.finish()?.flush()
+}
+
+fn write_entries(out: &mut impl Write, entries: &[ManifestEntry]) -> io::Result<()> {
+	for entry in entries {
+		writeln!(out, "{}  {}", entry.digest, entry.path)?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	const THE_TEXT: &str = "backmeup susie";
+	const THE_DIGEST: &str = "d4c6dd316c319e25f5599ba149dfaafd1127bdd1a152b6e148217e5860b57ba7";
+
+	#[test]
+	fn test_generates_manifest() -> io::Result<()> {
+		let set_folder = create_tmp_folder("set")?;
+		let set_path = Path::new(&set_folder);
+		fs::create_dir_all(set_path.join("deep"))?;
+		fs::write(set_path.join("deep").join("testfile.txt"), THE_TEXT)?;
+
+		let entries = generate_manifest(set_path)?;
+
+		assert_eq!(entries.len(), 1);
+		assert_eq!(entries[0].path, "deep/testfile.txt");
+		assert_eq!(entries[0].size, THE_TEXT.len() as u64);
+		assert_eq!(entries[0].digest, THE_DIGEST);
+
+		let manifest = fs::read_to_string(set_path.join(MANIFEST_FILE_NAME))?;
+		assert_eq!(manifest, format!("{}  deep/testfile.txt\n", THE_DIGEST));
+
+		let read_back = read_manifest(set_path)?;
+		assert_eq!(
+			read_back,
+			vec![(THE_DIGEST.to_string(), "deep/testfile.txt".to_string())]
+		);
+		Ok(())
+	}
+}
@@ -0,0 +1,174 @@
+use crate::backup_sets::backup_set::list_sets;
+use crate::backup_sets::manifest::{generate_manifest, MANIFEST_FILE_NAME};
+use crate::backup_sets::seal::while_unsealed;
+use crate::backup_sets::set_metadata::{
+	read_metadata, write_metadata, SetMetadata, SetStats, SET_FORMAT_VERSION,
+};
+use crate::backup_sets::set_namer::SetName;
+use crate::error::BackupError;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Brings sets written by older versions up to the current layout,
+/// returning the names of the sets that changed. Sets from before
+/// metadata and manifests existed get both, with what can be recovered
+/// from the set itself. Sets written by a newer version are left alone.
+pub fn migrate_sets(dest: &str) -> Result<Vec<String>, BackupError> {
+	let mut migrated = Vec::new();
+	for set in list_sets(dest)? {
+		let set_folder = Path::new(dest).join(&set);
+		let changed = match read_metadata(&set_folder) {
+			Ok(metadata) if metadata.format_version > SET_FORMAT_VERSION => {
+				eprintln!(
+					"warning: set {} has format version {}, newer than this version supports ({})",
+					set, metadata.format_version, SET_FORMAT_VERSION
+				);
+				false
+			}
+			Ok(metadata) if metadata.format_version < SET_FORMAT_VERSION => {
+				while_unsealed(&set_folder, || {
+					write_metadata(
+						&set_folder,
+						&SetMetadata {
+							format_version: SET_FORMAT_VERSION,
+							..metadata
+						},
+					)
+				})?;
+				true
+			}
+			Ok(_) => false,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => {
+				while_unsealed(&set_folder, || migrate_legacy_set(dest, &set))?;
+				true
+			}
+			Err(e) => return Err(e.into()),
+		};
+		if changed {
+			println!("migrated set {}", set);
+			migrated.push(set);
+		}
+	}
+	Ok(migrated)
+}
+
+/// Sets from before metadata was written were only made once the copy
+/// finished, so count as complete. Where they came from wasn't recorded.
+fn migrate_legacy_set(dest: &str, set: &str) -> io::Result<()> {
+	let set_folder = Path::new(dest).join(set);
+	let manifest = if set_folder.join(MANIFEST_FILE_NAME).exists() {
+		None
+	} else {
+		Some(generate_manifest(&set_folder)?)
+	};
+	let started_at = SetName::from_folder(dest, set)?.time();
+	let stats = match manifest {
+		Some(entries) => Some(SetStats {
+			files: entries.len() as u64,
+			folders: count_folders(&set_folder)?,
+			bytes: entries.iter().map(|entry| entry.size).sum(),
+			deduplicated_bytes: 0,
+		}),
+		None => None,
+	};
+	write_metadata(
+		&set_folder,
+		&SetMetadata {
+			format_version: SET_FORMAT_VERSION,
+			source: String::new(),
+			sources: BTreeMap::new(),
+			hostname: String::new(),
+			tool_version: env!("CARGO_PKG_VERSION").to_string(),
+			started_at,
+			finished_at: Some(started_at),
+			options: BTreeMap::new(),
+			stats,
+			compression: Default::default(),
+			archive: false,
+			volume_size: None,
+			chunked: false,
+			encrypted: false,
+			encrypted_names: false,
+			zstd_dictionary: false,
+			passphrase_kdf: None,
+			tags: Default::default(),
+			note: None,
+			compacted_from: Vec::new(),
+			resumable: false,
+		},
+	)
+}
+
+fn count_folders(folder: &Path) -> io::Result<u64> {
+	let mut count = 0;
+	for entry in fs::read_dir(folder)? {
+		let entry = entry?;
+		if entry.file_type()?.is_dir() {
+			count += 1 + count_folders(&entry.path())?;
+		}
+	}
+	Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup_sets::backup_set::SetStatus;
+	use crate::backup_sets::manifest::read_manifest;
+	use crate::test_helpers::test_helpers::{create_set, create_tmp_folder};
+
+	const LEGACY_SET: &str = "dhb-set-20000101-000000";
+	const CURRENT_SET: &str = "dhb-set-20000102-000000";
+
+	#[test]
+	fn test_migrates_legacy_sets() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let legacy = Path::new(&dest).join(LEGACY_SET);
+		fs::create_dir_all(legacy.join("docs"))?;
+		fs::write(legacy.join("docs/notes.txt"), "1234567890")?;
+		create_set(&dest, CURRENT_SET, SetStatus::Complete)?;
+
+		let migrated = migrate_sets(&dest)?;
+
+		assert_eq!(migrated, vec![LEGACY_SET]);
+		assert_eq!(read_manifest(&legacy)?.len(), 1);
+		let metadata = read_metadata(&legacy)?;
+		assert_eq!(metadata.format_version, SET_FORMAT_VERSION);
+		assert_eq!(metadata.finished_at, Some(metadata.started_at));
+		assert_eq!(
+			metadata.stats,
+			Some(SetStats {
+				files: 1,
+				folders: 1,
+				bytes: 10,
+				deduplicated_bytes: 0,
+			})
+		);
+		assert!(migrate_sets(&dest)?.is_empty());
+		Ok(())
+	}
+
+	#[test]
+	fn test_leaves_newer_sets_alone() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		create_set(&dest, CURRENT_SET, SetStatus::Complete)?;
+		let set_folder = Path::new(&dest).join(CURRENT_SET);
+		let metadata = read_metadata(&set_folder)?;
+		write_metadata(
+			&set_folder,
+			&SetMetadata {
+				format_version: SET_FORMAT_VERSION + 1,
+				..metadata
+			},
+		)?;
+
+		assert!(migrate_sets(&dest)?.is_empty());
+		assert_eq!(
+			read_metadata(&set_folder)?.format_version,
+			SET_FORMAT_VERSION + 1
+		);
+		Ok(())
+	}
+}
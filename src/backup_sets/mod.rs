@@ -1,2 +1,24 @@
 pub mod backup_set;
+pub mod compact;
+pub mod compression_report;
+pub mod duplicates;
+pub mod encrypted_names;
+pub mod hash_catalog;
+pub mod last_known_good;
+pub mod latest;
+pub mod lease;
+pub mod lock;
+pub mod manifest;
+pub mod migrate;
+pub mod pin;
+pub mod rekey;
+pub mod retention;
+pub mod seal;
+pub mod set_metadata;
 pub mod set_namer;
+pub mod tags;
+pub mod trash;
+pub mod verify;
+pub mod verify_catalog;
+
+pub use backup_set::{BackupSet, SetStatus};
@@ -0,0 +1,71 @@
+use crate::backup_sets::seal::while_unsealed;
+use crate::error::BackupError;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Marker file in the root of a set that protects it from being pruned
+pub const PIN_FILE_NAME: &str = "dhb-pinned";
+
+pub fn pin_set(dest: &str, set_name: &str) -> Result<(), BackupError> {
+	let folder = set_folder(dest, set_name)?;
+	Ok(while_unsealed(&folder, || {
+		fs::write(folder.join(PIN_FILE_NAME), "")
+	})?)
+}
+
+pub fn unpin_set(dest: &str, set_name: &str) -> Result<(), BackupError> {
+	let folder = set_folder(dest, set_name)?;
+	if !folder.join(PIN_FILE_NAME).exists() {
+		return Ok(());
+	}
+	Ok(while_unsealed(&folder, || {
+		fs::remove_file(folder.join(PIN_FILE_NAME))
+	})?)
+}
+
+pub fn is_pinned(dest: &str, set_name: &str) -> bool {
+	Path::new(dest).join(set_name).join(PIN_FILE_NAME).exists()
+}
+
+fn set_folder(dest: &str, set_name: &str) -> Result<PathBuf, BackupError> {
+	let folder = Path::new(dest).join(set_name);
+	if !folder.is_dir() {
+		return Err(BackupError::set_missing(dest, set_name));
+	}
+	Ok(folder)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+	use std::io;
+
+	const SET_NAME: &str = "dhb-set-20010203-140506";
+
+	#[test]
+	fn test_pin_and_unpin() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		fs::create_dir_all(Path::new(&dest).join(SET_NAME))?;
+
+		pin_set(&dest, SET_NAME)?;
+		assert!(is_pinned(&dest, SET_NAME));
+
+		unpin_set(&dest, SET_NAME)?;
+		assert!(!is_pinned(&dest, SET_NAME));
+		Ok(())
+	}
+
+	#[test]
+	fn test_pin_missing_set() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let error = pin_set(&dest, SET_NAME).unwrap_err();
+		assert_eq!(error.kind(), io::ErrorKind::NotFound);
+		assert!(
+			matches!(&error, BackupError::SetMissing { set_name, .. } if set_name == SET_NAME),
+			"{:?}",
+			error
+		);
+		Ok(())
+	}
+}
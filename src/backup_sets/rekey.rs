@@ -0,0 +1,145 @@
+use crate::backup_sets::backup_set::list_sets_by_status;
+use crate::backup_sets::seal::while_unsealed;
+use crate::backup_sets::set_metadata::{read_metadata, write_metadata};
+use crate::dhcopy::encryption::{
+	Keyring, PassphraseParams, PASSPHRASE_KEY_FILE_NAME, SET_KEY_FILE_NAME,
+};
+use crate::error::BackupError;
+use age::secrecy::SecretString;
+use age::x25519;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Who can unlock a set after rekeying: the recipients, the passphrase or both
+pub struct NewLocks<'a> {
+	pub recipients: &'a [x25519::Recipient],
+	pub passphrase: Option<&'a SecretString>,
+}
+
+/// Locks the key of an encrypted set to new recipients and passphrase,
+/// replacing the old ones. The files are encrypted with the set's own key,
+/// which doesn't change, so none of them are rewritten.
+pub fn rekey_set(
+	dest: &str,
+	set_name: &str,
+	keys: &Keyring,
+	locks: &NewLocks,
+) -> Result<(), BackupError> {
+	if locks.recipients.is_empty() && locks.passphrase.is_none() {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			"give recipients or a passphrase to lock the set with",
+		)
+		.into());
+	}
+	let set_folder = Path::new(dest).join(set_name);
+	if !set_folder.is_dir() {
+		return Err(BackupError::set_missing(dest, set_name));
+	}
+	let mut metadata = read_metadata(&set_folder)?;
+	if !metadata.encrypted {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			format!("set {} isn't encrypted", set_name),
+		)
+		.into());
+	}
+	let key = keys.unlock(&set_folder, metadata.passphrase_kdf.as_ref())?;
+	Ok(while_unsealed(&set_folder, || {
+		match locks.recipients.is_empty() {
+			true => remove_if_present(&set_folder.join(SET_KEY_FILE_NAME))?,
+			false => key.write_locked(&set_folder, locks.recipients)?,
+		}
+		metadata.passphrase_kdf = match locks.passphrase {
+			Some(passphrase) => {
+				let params = PassphraseParams::generate();
+				key.write_with_passphrase(&set_folder, passphrase, &params)?;
+				Some(params)
+			}
+			None => {
+				remove_if_present(&set_folder.join(PASSPHRASE_KEY_FILE_NAME))?;
+				None
+			}
+		};
+		write_metadata(&set_folder, &metadata)
+	})?)
+}
+
+/// Rekeys every complete encrypted set, returning their names. Stops at the
+/// first set the keyring can't unlock, leaving the ones before it rekeyed.
+pub fn rekey_sets(
+	dest: &str,
+	keys: &Keyring,
+	locks: &NewLocks,
+) -> Result<Vec<String>, BackupError> {
+	let mut rekeyed = Vec::new();
+	for set_name in list_sets_by_status(dest)?.complete {
+		if read_metadata(&Path::new(dest).join(&set_name)).is_ok_and(|metadata| metadata.encrypted)
+		{
+			rekey_set(dest, &set_name, keys, locks)?;
+			println!("rekeyed {}", set_name);
+			rekeyed.push(set_name);
+		}
+	}
+	Ok(rekeyed)
+}
+
+fn remove_if_present(path: &Path) -> io::Result<()> {
+	match fs::remove_file(path) {
+		Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+		_ => Ok(()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup_sets::backup_set::SetStatus;
+	use crate::backup_sets::set_metadata::update_metadata;
+	use crate::dhcopy::encryption::SetKey;
+	use crate::test_helpers::test_helpers::{create_set, create_tmp_folder};
+	use std::io::{Read, Write};
+
+	const SET_NAME: &str = "dhb-set-20010203-140506";
+
+	#[test]
+	fn test_rekeys_to_passphrase() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		create_set(&dest, SET_NAME, SetStatus::Complete)?;
+		let set_folder = Path::new(&dest).join(SET_NAME);
+		let owner = x25519::Identity::generate();
+		let key = SetKey::generate();
+		key.write_locked(&set_folder, &[owner.to_public()])?;
+		update_metadata(&dest, SET_NAME, |metadata| metadata.encrypted = true)?;
+		let mut writer = key.encrypt(Vec::new())?;
+		writer.write_all(b"backmeup susie")?;
+		let encrypted = writer.finish()?;
+
+		let passphrase = SecretString::from("correct horse".to_string());
+		let owner_keys = Keyring::new(vec![Box::new(owner)]);
+		let rekeyed = rekey_sets(
+			&dest,
+			&owner_keys,
+			&NewLocks {
+				recipients: &[],
+				passphrase: Some(&passphrase),
+			},
+		)?;
+
+		assert_eq!(rekeyed, vec![SET_NAME.to_string()]);
+		assert!(!set_folder.join(SET_KEY_FILE_NAME).exists());
+		let params = read_metadata(&set_folder)?.passphrase_kdf;
+		let err = owner_keys.unlock(&set_folder, params.as_ref()).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+		let unlocked = Keyring::default()
+			.with_passphrase(Some(passphrase))
+			.unlock(&set_folder, params.as_ref())?;
+		let mut decrypted = String::new();
+		unlocked
+			.decrypt(&encrypted[..])?
+			.read_to_string(&mut decrypted)?;
+		assert_eq!(decrypted, "backmeup susie");
+		Ok(())
+	}
+}
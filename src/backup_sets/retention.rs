@@ -0,0 +1,538 @@
+use crate::backup_sets::backup_set::list_sets_by_status;
+use crate::backup_sets::last_known_good::last_known_good;
+use crate::backup_sets::pin::is_pinned;
+use crate::backup_sets::seal::remove_set;
+use crate::backup_sets::set_namer::{generate_name, parse_name};
+use crate::backup_sets::tags::set_tags;
+use crate::backup_sets::trash::move_to_trash;
+use crate::error::BackupError;
+use crate::space::max_space::{make_room, SpaceLimit};
+use crate::space::min_free::make_free_space;
+use chrono::{DateTime, Datelike, TimeDelta, Utc};
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+/// Which sets to keep, combining count, age and space rules. In order of precedence:
+///
+/// 1. Pinned sets, sets tagged with any of `keep_tagged` and the newest `last`
+///    sets (and always the newest set) are kept.
+/// 2. Otherwise sets are kept if created `within` the given time, or by
+///    grandfather-father-son retention of the newest set from each of the last
+///    `daily` days, `weekly` ISO weeks and `monthly` months that have sets.
+///    Sets kept by none of these are pruned.
+/// 3. If the destination still uses more than `max_space`, or leaves less than
+///    `min_free` on the disk, the oldest sets not protected by 1. are deleted
+///    until it doesn't, even if 2. would keep them.
+///
+/// A policy with no keep rules prunes nothing by age, and one with no rules at
+/// all applies no retention.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RetentionPolicy {
+	pub last: usize,
+	pub within: Option<TimeDelta>,
+	pub daily: usize,
+	pub weekly: usize,
+	pub monthly: usize,
+	/// Cap on the space used by all sets in the destination
+	pub max_space: Option<SpaceLimit>,
+	/// Space to always leave free on the destination filesystem
+	pub min_free: Option<SpaceLimit>,
+	pub keep_tagged: Vec<String>,
+}
+
+impl RetentionPolicy {
+	pub fn is_unlimited(&self) -> bool {
+		!self.has_keep_rules() && self.max_space.is_none() && self.min_free.is_none()
+	}
+
+	/// Whether the set must be kept whatever the other rules say, being
+	/// pinned or tagged with one of `keep_tagged`
+	pub fn protects(&self, dest: &str, set_name: &str) -> bool {
+		is_pinned(dest, set_name)
+			|| (!self.keep_tagged.is_empty()
+				&& set_tags(dest, set_name)
+					.is_ok_and(|tags| self.keep_tagged.iter().any(|tag| tags.contains(tag))))
+	}
+
+	/// Whether any count or age rules are set
+	pub fn has_keep_rules(&self) -> bool {
+		self.last > 0
+			|| self.within.is_some()
+			|| self.daily > 0
+			|| self.weekly > 0
+			|| self.monthly > 0
+	}
+
+	/// Picks which of the given sets fall outside the policy as of `now`.
+	/// Age comes from the set name rather than the filesystem, so survives
+	/// copying the destination elsewhere. Sets whose names can't be parsed are
+	/// never selected.
+	pub fn sets_to_prune(&self, sets: &[String], now: DateTime<Utc>) -> Vec<String> {
+		if !self.has_keep_rules() {
+			return Vec::new();
+		}
+
+		let mut dated: Vec<(DateTime<Utc>, &String)> = sets
+			.iter()
+			.filter_map(|set| parse_name(set).map(|time| (time, set)))
+			.collect();
+		dated.sort_by_key(|(time, _)| Reverse(*time));
+
+		let mut keep: BTreeSet<&String> = dated
+			.iter()
+			.take(self.last.max(1))
+			.map(|(_, set)| *set)
+			.collect();
+		if let Some(within) = self.within {
+			keep.extend(
+				dated
+					.iter()
+					.filter(|(time, _)| now - *time <= within)
+					.map(|(_, set)| *set),
+			);
+		}
+		keep_newest_per_period(&dated, self.daily, &mut keep, |time| {
+			(time.year(), time.ordinal())
+		});
+		keep_newest_per_period(&dated, self.weekly, &mut keep, |time| {
+			(time.iso_week().year(), time.iso_week().week())
+		});
+		keep_newest_per_period(&dated, self.monthly, &mut keep, |time| {
+			(time.year(), time.month())
+		});
+
+		dated
+			.iter()
+			.rev()
+			.filter(|(_, set)| !keep.contains(set))
+			.map(|(_, set)| (*set).clone())
+			.collect()
+	}
+}
+
+/// Deletes the sets in the destination that fall outside the policy's keep
+/// rules, returning their names, then enforces its space limits as for
+/// [enforce_space_limits].
+/// Only complete sets count towards the policy; abandoned incomplete sets are
+/// pruned first whenever there is a policy.
+/// With `trash` sets pruned by age are moved to the trash instead of being deleted outright.
+/// Pinned sets are never deleted, and the last known good set isn't pruned
+/// by age, so there's always a verified set to restore.
+pub fn prune_sets(
+	dest: &str,
+	policy: &RetentionPolicy,
+	trash: bool,
+) -> Result<Vec<String>, BackupError> {
+	if policy.is_unlimited() {
+		return Ok(Vec::new());
+	}
+	let sets = list_sets_by_status(dest)?;
+	let mut to_prune = sets.abandoned;
+	to_prune.extend(policy.sets_to_prune(&sets.complete, Utc::now()));
+
+	let last_good = last_known_good(dest)?;
+	let mut pruned = Vec::new();
+	for set in to_prune {
+		if policy.protects(dest, &set) {
+			println!("keeping pinned or tagged set {}", set);
+			continue;
+		}
+		if last_good.as_deref() == Some(set.as_str()) {
+			println!("keeping last known good set {}", set);
+			continue;
+		}
+		if trash {
+			println!("moving set {} to trash", set);
+			move_to_trash(dest, &set)?;
+		} else {
+			println!("pruning set {}", set);
+			remove_set(&Path::new(dest).join(&set))?;
+		}
+		pruned.push(set);
+	}
+	pruned.extend(enforce_space_limits(dest, policy, 0)?);
+	Ok(pruned)
+}
+
+/// Deletes the oldest sets until `required` more bytes fit within the
+/// policy's `max_space` and `min_free`, returning their names. The newest
+/// `last` sets and protected sets are kept even if that means failing with
+/// [BackupError::DestinationFull].
+pub fn enforce_space_limits(
+	dest: &str,
+	policy: &RetentionPolicy,
+	required: u64,
+) -> Result<Vec<String>, BackupError> {
+	let mut deleted = Vec::new();
+	if let Some(max_space) = policy.max_space {
+		let max_space = max_space.resolve(Path::new(dest))?;
+		deleted.extend(make_room(dest, max_space, required, policy)?);
+	}
+	if let Some(min_free) = policy.min_free {
+		let min_free = min_free.resolve(Path::new(dest))?;
+		deleted.extend(make_free_space(dest, min_free, required, policy)?);
+	}
+	Ok(deleted)
+}
+
+/// One hypothetical backup in a [simulate]d timeline
+#[derive(Debug, PartialEq)]
+pub struct SimulatedRun {
+	pub time: DateTime<Utc>,
+	pub new_set: String,
+	/// Sets the policy would prune after this backup
+	pub pruned: Vec<String>,
+}
+
+/// Plays out `runs` backups, the first at `start` and then one every
+/// `interval`, on top of the existing `sets`, and what the policy would
+/// prune after each. Sets for which `is_pinned` holds are never pruned.
+pub fn simulate<F>(
+	policy: &RetentionPolicy,
+	sets: &[String],
+	start: DateTime<Utc>,
+	interval: TimeDelta,
+	runs: usize,
+	is_pinned: F,
+) -> Vec<SimulatedRun>
+where
+	F: Fn(&str) -> bool,
+{
+	let mut sets = sets.to_vec();
+	(0..runs)
+		.map(|run| {
+			let time = start + interval * run as i32;
+			let new_set = generate_name(|| time);
+			sets.push(new_set.clone());
+			let pruned: Vec<String> = policy
+				.sets_to_prune(&sets, time)
+				.into_iter()
+				.filter(|set| !is_pinned(set))
+				.collect();
+			sets.retain(|set| !pruned.contains(set));
+			SimulatedRun {
+				time,
+				new_set,
+				pruned,
+			}
+		})
+		.collect()
+}
+
+// `dated` must be newest first
+fn keep_newest_per_period<'a, P, F>(
+	dated: &[(DateTime<Utc>, &'a String)],
+	count: usize,
+	keep: &mut BTreeSet<&'a String>,
+	period: F,
+) where
+	P: PartialEq,
+	F: Fn(&DateTime<Utc>) -> P,
+{
+	let mut last_period = None;
+	let mut kept = 0;
+	for (time, set) in dated {
+		if kept == count {
+			break;
+		}
+		let this_period = Some(period(time));
+		if this_period != last_period {
+			keep.insert(*set);
+			kept += 1;
+			last_period = this_period;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup_sets::backup_set::SetStatus;
+	use crate::backup_sets::last_known_good::record_last_known_good;
+	use crate::backup_sets::pin::pin_set;
+	use crate::backup_sets::set_namer::generate_name;
+	use crate::backup_sets::tags::tag_set;
+	use crate::backup_sets::trash::trash_folder;
+	use crate::test_helpers::test_helpers::{create_set, create_tmp_folder};
+	use chrono::TimeZone;
+	use std::fs;
+	use std::io;
+
+	fn set_at(year: i32, month: u32, day: u32, hour: u32) -> String {
+		generate_name(|| time_at(year, month, day, hour))
+	}
+
+	fn time_at(year: i32, month: u32, day: u32, hour: u32) -> DateTime<Utc> {
+		Utc.with_ymd_and_hms(year, month, day, hour, 0, 0).unwrap()
+	}
+
+	#[test]
+	fn test_grandfather_father_son() {
+		let sets = vec![
+			set_at(2024, 1, 15, 1),
+			set_at(2024, 2, 20, 1),
+			set_at(2024, 3, 4, 1),
+			set_at(2024, 3, 10, 1),
+			set_at(2024, 3, 11, 1),
+			set_at(2024, 3, 11, 13),
+			"holiday-photos".to_string(),
+		];
+		let policy = RetentionPolicy {
+			daily: 1,
+			weekly: 2,
+			monthly: 2,
+			..Default::default()
+		};
+
+		let pruned = policy.sets_to_prune(&sets, time_at(2024, 3, 12, 0));
+
+		// daily keeps the 11th at 13:00, weekly adds the 10th as the newest of
+		// the previous week, monthly adds the 20th of February
+		assert_eq!(
+			pruned,
+			vec![
+				set_at(2024, 1, 15, 1),
+				set_at(2024, 3, 4, 1),
+				set_at(2024, 3, 11, 1),
+			]
+		);
+	}
+
+	#[test]
+	fn test_keep_last() {
+		let sets = vec![
+			set_at(2024, 3, 11, 1),
+			set_at(2024, 3, 11, 2),
+			set_at(2024, 3, 11, 3),
+		];
+		let policy = RetentionPolicy {
+			last: 2,
+			daily: 1,
+			..Default::default()
+		};
+
+		let pruned = policy.sets_to_prune(&sets, time_at(2024, 3, 12, 0));
+
+		assert_eq!(pruned, vec![set_at(2024, 3, 11, 1)]);
+	}
+
+	#[test]
+	fn test_keep_within() {
+		let sets = vec![
+			set_at(2024, 1, 1, 0),
+			set_at(2024, 3, 1, 0),
+			set_at(2024, 3, 11, 0),
+		];
+		let policy = RetentionPolicy {
+			within: Some(TimeDelta::days(30)),
+			..Default::default()
+		};
+
+		let pruned = policy.sets_to_prune(&sets, time_at(2024, 3, 12, 0));
+
+		assert_eq!(pruned, vec![set_at(2024, 1, 1, 0)]);
+	}
+
+	#[test]
+	fn test_always_keeps_newest_set() {
+		let sets = vec![set_at(2024, 1, 1, 0), set_at(2024, 1, 2, 0)];
+		let policy = RetentionPolicy {
+			within: Some(TimeDelta::days(1)),
+			..Default::default()
+		};
+
+		let pruned = policy.sets_to_prune(&sets, time_at(2024, 3, 12, 0));
+
+		assert_eq!(pruned, vec![set_at(2024, 1, 1, 0)]);
+	}
+
+	#[test]
+	fn test_unlimited_policy_keeps_everything() {
+		let sets = vec![set_at(2024, 1, 15, 1), set_at(2024, 2, 20, 1)];
+		assert!(RetentionPolicy::default()
+			.sets_to_prune(&sets, time_at(2024, 3, 12, 0))
+			.is_empty());
+	}
+
+	#[test]
+	fn test_simulate() {
+		let sets = vec![set_at(2024, 3, 1, 0), set_at(2024, 3, 2, 0)];
+		let policy = RetentionPolicy {
+			last: 3,
+			..Default::default()
+		};
+
+		let runs = simulate(
+			&policy,
+			&sets,
+			time_at(2024, 3, 3, 0),
+			TimeDelta::days(1),
+			3,
+			|set| set == set_at(2024, 3, 2, 0),
+		);
+
+		assert_eq!(runs.len(), 3);
+		assert_eq!(runs[0].new_set, set_at(2024, 3, 3, 0));
+		assert!(runs[0].pruned.is_empty());
+		assert_eq!(runs[1].pruned, vec![set_at(2024, 3, 1, 0)]);
+		// the pinned set stays, pushing nothing else out
+		assert!(runs[2].pruned.is_empty());
+	}
+
+	#[test]
+	fn test_prune_sets() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let old_set = set_at(2024, 1, 15, 1);
+		let new_set = set_at(2024, 2, 20, 1);
+		fs::create_dir_all(Path::new(&dest).join(&old_set))?;
+		fs::create_dir_all(Path::new(&dest).join(&new_set))?;
+		let policy = RetentionPolicy {
+			daily: 1,
+			..Default::default()
+		};
+
+		let pruned = prune_sets(&dest, &policy, false)?;
+
+		assert_eq!(pruned, vec![old_set.clone()]);
+		assert!(!Path::new(&dest).join(&old_set).exists());
+		assert!(Path::new(&dest).join(&new_set).exists());
+		Ok(())
+	}
+
+	#[test]
+	fn test_prune_to_trash() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let old_set = set_at(2024, 1, 15, 1);
+		let new_set = set_at(2024, 2, 20, 1);
+		fs::create_dir_all(Path::new(&dest).join(&old_set))?;
+		fs::create_dir_all(Path::new(&dest).join(&new_set))?;
+		let policy = RetentionPolicy {
+			last: 1,
+			..Default::default()
+		};
+
+		prune_sets(&dest, &policy, true)?;
+
+		assert!(!Path::new(&dest).join(&old_set).exists());
+		assert!(trash_folder(&dest).join(&old_set).exists());
+		Ok(())
+	}
+
+	#[test]
+	fn test_incomplete_sets_dont_count() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let old_set = set_at(2024, 1, 15, 1);
+		let failed_set = set_at(2024, 2, 20, 1);
+		let new_set = set_at(2024, 3, 1, 1);
+		create_set(&dest, &old_set, SetStatus::Complete)?;
+		create_set(&dest, &failed_set, SetStatus::Incomplete)?;
+		create_set(&dest, &new_set, SetStatus::Complete)?;
+		let policy = RetentionPolicy {
+			last: 2,
+			..Default::default()
+		};
+
+		let pruned = prune_sets(&dest, &policy, false)?;
+
+		assert_eq!(pruned, vec![failed_set]);
+		assert!(Path::new(&dest).join(&old_set).exists());
+		Ok(())
+	}
+
+	#[test]
+	fn test_space_limits_override_age_rules() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let sets = [
+			set_at(2024, 1, 15, 1),
+			set_at(2024, 2, 20, 1),
+			set_at(2024, 3, 1, 1),
+		];
+		for set in &sets {
+			fs::create_dir_all(Path::new(&dest).join(set))?;
+			fs::write(Path::new(&dest).join(set).join("data"), "1234567890")?;
+		}
+		record_last_known_good(&dest, &sets[0])?;
+		let policy = RetentionPolicy {
+			last: 2,
+			within: Some(TimeDelta::days(100_000)),
+			max_space: Some(SpaceLimit::Bytes(15)),
+			..Default::default()
+		};
+
+		let err = prune_sets(&dest, &policy, false).unwrap_err();
+
+		// the oldest set goes despite keep within, the newest two stay despite max space
+		assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+		assert!(
+			matches!(err, BackupError::DestinationFull { .. }),
+			"{:?}",
+			err
+		);
+		assert!(!Path::new(&dest).join(&sets[0]).exists());
+		assert!(Path::new(&dest).join(&sets[1]).exists());
+		assert_eq!(last_known_good(&dest)?, None);
+		Ok(())
+	}
+
+	#[test]
+	fn test_keeps_last_known_good_set() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let old_set = set_at(2024, 1, 15, 1);
+		let new_set = set_at(2024, 2, 20, 1);
+		fs::create_dir_all(Path::new(&dest).join(&old_set))?;
+		fs::create_dir_all(Path::new(&dest).join(&new_set))?;
+		record_last_known_good(&dest, &old_set)?;
+		let policy = RetentionPolicy {
+			last: 1,
+			..Default::default()
+		};
+
+		let pruned = prune_sets(&dest, &policy, false)?;
+
+		assert!(pruned.is_empty());
+		assert!(Path::new(&dest).join(&old_set).exists());
+		Ok(())
+	}
+
+	#[test]
+	fn test_never_prunes_sets_tagged_to_keep() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let old_set = set_at(2024, 1, 15, 1);
+		let new_set = set_at(2024, 2, 20, 1);
+		create_set(&dest, &old_set, SetStatus::Complete)?;
+		create_set(&dest, &new_set, SetStatus::Complete)?;
+		tag_set(&dest, &old_set, &["milestone".to_string()])?;
+		let policy = RetentionPolicy {
+			last: 1,
+			keep_tagged: vec!["milestone".to_string()],
+			..Default::default()
+		};
+
+		let pruned = prune_sets(&dest, &policy, false)?;
+
+		assert!(pruned.is_empty());
+		assert!(Path::new(&dest).join(&old_set).exists());
+		Ok(())
+	}
+
+	#[test]
+	fn test_never_prunes_pinned_sets() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let old_set = set_at(2024, 1, 15, 1);
+		let new_set = set_at(2024, 2, 20, 1);
+		fs::create_dir_all(Path::new(&dest).join(&old_set))?;
+		fs::create_dir_all(Path::new(&dest).join(&new_set))?;
+		pin_set(&dest, &old_set)?;
+		let policy = RetentionPolicy {
+			last: 1,
+			..Default::default()
+		};
+
+		let pruned = prune_sets(&dest, &policy, false)?;
+
+		assert!(pruned.is_empty());
+		assert!(Path::new(&dest).join(&old_set).exists());
+		Ok(())
+	}
+}
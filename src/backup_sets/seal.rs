@@ -0,0 +1,298 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// How far [seal_set] got
+#[derive(Debug)]
+pub enum Sealed {
+	/// Read-only and with the immutable attribute, which only root can undo
+	Immutable,
+	/// Only read-only, which the backup user can undo, as setting the
+	/// immutable attribute failed. This is why.
+	ReadOnly(io::Error),
+}
+
+/// Makes every file and folder in a completed set read-only and, where it
+/// can, immutable (as with chattr +i). The set's root is sealed last, so a
+/// sealed root means the whole set is sealed. Immutable folders can't be
+/// renamed, so sets are sealed once they have their final name.
+///
+/// Only root can set or clear the immutable attribute, and only on Linux
+/// filesystems that have it. Otherwise the set is just read-only, which
+/// stops mistakes and casual damage but not an attacker with the backup
+/// user's rights who knows to chmod first. Immutable files can't be
+/// hard-linked either, so later sets copy unchanged files again rather
+/// than linking to them.
+pub fn seal_set(set_folder: &Path) -> io::Result<Sealed> {
+	let mut sealed = Sealed::Immutable;
+	seal_tree(set_folder, &mut sealed)?;
+	Ok(sealed)
+}
+
+/// Undoes [seal_set], which for an immutable set needs root. The root is
+/// unsealed last, so an interrupted unseal is finished by the next one.
+/// Files hard-linked into other sets stay read-only: permissions belong to
+/// the file rather than the link, so making them writable would unseal
+/// them in every set sharing them. They do lose the immutable attribute,
+/// as otherwise they couldn't be deleted from this set.
+pub fn unseal_set(set_folder: &Path) -> io::Result<()> {
+	for entry in fs::read_dir(set_folder)? {
+		let entry = entry?;
+		let file_type = entry.file_type()?;
+		if file_type.is_dir() {
+			unseal_set(&entry.path())?;
+		} else if file_type.is_file() {
+			let path = entry.path();
+			clear_immutable(&path)?;
+			if !is_shared(&entry.metadata()?) {
+				set_writable(&path, true)?;
+			}
+		}
+	}
+	clear_immutable(set_folder)?;
+	set_writable(set_folder, true)
+}
+
+pub fn is_sealed(set_folder: &Path) -> bool {
+	immutable::is_immutable(set_folder)
+		|| fs::metadata(set_folder).is_ok_and(|metadata| metadata.permissions().readonly())
+}
+
+/// Runs `change` with the set unsealed, sealing it again afterwards if it was sealed
+pub fn while_unsealed<T, F>(set_folder: &Path, change: F) -> io::Result<T>
+where
+	F: FnOnce() -> io::Result<T>,
+{
+	if !is_sealed(set_folder) {
+		return change();
+	}
+	unseal_set(set_folder)?;
+	let result = change();
+	seal_set(set_folder)?;
+	result
+}
+
+/// Deletes a set, unsealing it first if needed. Files it shares with other
+/// sets are left read-only, as deleting a link only needs its folder to be
+/// writable.
+pub fn remove_set(set_folder: &Path) -> io::Result<()> {
+	if is_sealed(set_folder) {
+		unseal_set(set_folder)?;
+	}
+	fs::remove_dir_all(set_folder)
+}
+
+fn seal_tree(folder: &Path, sealed: &mut Sealed) -> io::Result<()> {
+	for entry in fs::read_dir(folder)? {
+		let entry = entry?;
+		let file_type = entry.file_type()?;
+		if file_type.is_dir() {
+			seal_tree(&entry.path(), sealed)?;
+		} else if file_type.is_file() {
+			seal_path(&entry.path(), sealed)?;
+		}
+		// symlinks are left alone, changing them would change what they point at
+	}
+	seal_path(folder, sealed)
+}
+
+/// Seals a file or folder, trying the immutable attribute until it fails
+/// once
+fn seal_path(path: &Path, sealed: &mut Sealed) -> io::Result<()> {
+	// shared with a set that was sealed already
+	if immutable::is_immutable(path) {
+		return Ok(());
+	}
+	set_writable(path, false)?;
+	if let Sealed::Immutable = sealed {
+		if let Err(e) = immutable::set_immutable(path, true) {
+			*sealed = Sealed::ReadOnly(e);
+		}
+	}
+	Ok(())
+}
+
+fn clear_immutable(path: &Path) -> io::Result<()> {
+	if !immutable::is_immutable(path) {
+		return Ok(());
+	}
+	immutable::set_immutable(path, false).map_err(|e| {
+		io::Error::new(
+			e.kind(),
+			format!(
+				"{} is immutable, which only root can undo: {}",
+				path.display(),
+				e
+			),
+		)
+	})
+}
+
+#[cfg(target_os = "linux")]
+mod immutable {
+	use std::fs::{File, OpenOptions};
+	use std::io;
+	use std::os::unix::fs::OpenOptionsExt;
+	use std::os::unix::io::AsRawFd;
+	use std::path::Path;
+
+	// from linux/fs.h, which libc doesn't have
+	const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+
+	fn open(path: &Path) -> io::Result<File> {
+		OpenOptions::new()
+			.read(true)
+			.custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW)
+			.open(path)
+	}
+
+	// the kernel reads and writes an int, whatever the ioctl's name says
+	fn flags(file: &File) -> io::Result<libc::c_int> {
+		let mut flags: libc::c_int = 0;
+		if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		Ok(flags)
+	}
+
+	pub fn is_immutable(path: &Path) -> bool {
+		open(path)
+			.and_then(|file| flags(&file))
+			.is_ok_and(|flags| flags & FS_IMMUTABLE_FL != 0)
+	}
+
+	pub fn set_immutable(path: &Path, immutable: bool) -> io::Result<()> {
+		let file = open(path)?;
+		let flags = flags(&file)?;
+		let changed = match immutable {
+			true => flags | FS_IMMUTABLE_FL,
+			false => flags & !FS_IMMUTABLE_FL,
+		};
+		if changed != flags
+			&& unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &changed) } < 0
+		{
+			return Err(io::Error::last_os_error());
+		}
+		Ok(())
+	}
+}
+
+#[cfg(not(target_os = "linux"))]
+mod immutable {
+	use std::io;
+	use std::path::Path;
+
+	pub fn is_immutable(_path: &Path) -> bool {
+		false
+	}
+
+	pub fn set_immutable(_path: &Path, _immutable: bool) -> io::Result<()> {
+		Err(io::Error::new(
+			io::ErrorKind::Unsupported,
+			"only Linux has an immutable attribute to set",
+		))
+	}
+}
+
+/// Whether the file has links elsewhere, e.g. in an earlier set it was
+/// linked to instead of being copied again
+#[cfg(unix)]
+fn is_shared(metadata: &fs::Metadata) -> bool {
+	use std::os::unix::fs::MetadataExt;
+
+	metadata.nlink() > 1
+}
+
+// there's no stable way to count links elsewhere; read-only files can't be
+// deleted on Windows, so shared ones are made writable to delete them
+#[cfg(not(unix))]
+fn is_shared(_metadata: &fs::Metadata) -> bool {
+	false
+}
+
+#[cfg(unix)]
+fn set_writable(path: &Path, writable: bool) -> io::Result<()> {
+	use std::os::unix::fs::PermissionsExt;
+
+	let mut permissions = fs::metadata(path)?.permissions();
+	let mode = permissions.mode();
+	permissions.set_mode(match writable {
+		true => mode | 0o200,
+		false => mode & !0o222,
+	});
+	fs::set_permissions(path, permissions)
+}
+
+#[cfg(not(unix))]
+fn set_writable(path: &Path, writable: bool) -> io::Result<()> {
+	let mut permissions = fs::metadata(path)?.permissions();
+	// only unix has the world-writable problem this lint is about
+	#[allow(clippy::permissions_set_readonly_false)]
+	permissions.set_readonly(!writable);
+	fs::set_permissions(path, permissions)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	#[test]
+	fn test_seal_then_remove() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let set_folder = Path::new(&dest).join("dhb-set-20000101-000000");
+		fs::create_dir_all(set_folder.join("docs"))?;
+		fs::write(set_folder.join("docs/notes.txt"), "1234567890")?;
+
+		let sealed = seal_set(&set_folder)?;
+
+		assert!(is_sealed(&set_folder));
+		if let Sealed::Immutable = sealed {
+			assert!(fs::write(set_folder.join("docs/notes.txt"), "changed").is_err());
+			assert!(fs::remove_file(set_folder.join("docs/notes.txt")).is_err());
+		}
+		assert!(fs::metadata(set_folder.join("docs"))?
+			.permissions()
+			.readonly());
+		assert!(fs::metadata(set_folder.join("docs/notes.txt"))?
+			.permissions()
+			.readonly());
+
+		while_unsealed(&set_folder, || {
+			fs::write(set_folder.join("docs/more.txt"), "12345")
+		})?;
+		assert!(is_sealed(&set_folder));
+		assert!(fs::metadata(set_folder.join("docs/more.txt"))?
+			.permissions()
+			.readonly());
+
+		remove_set(&set_folder)?;
+		assert!(!set_folder.exists());
+		Ok(())
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_removing_set_keeps_linked_files_sealed() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let first = Path::new(&dest).join("dhb-set-20000101-000000");
+		fs::create_dir_all(&first)?;
+		fs::write(first.join("notes.txt"), "1234567890")?;
+		// a later set linking to the earlier copy of an unchanged file,
+		// before the first is sealed as immutable files can't be linked to
+		let second = Path::new(&dest).join("dhb-set-20000102-000000");
+		fs::create_dir_all(&second)?;
+		fs::hard_link(first.join("notes.txt"), second.join("notes.txt"))?;
+		seal_set(&first)?;
+		seal_set(&second)?;
+
+		remove_set(&second)?;
+
+		assert!(!second.exists());
+		assert!(is_sealed(&first));
+		assert!(fs::metadata(first.join("notes.txt"))?
+			.permissions()
+			.readonly());
+		Ok(())
+	}
+}
@@ -0,0 +1,409 @@
+use crate::backup_sets::seal::while_unsealed;
+use crate::backup_sets::set_namer::current_hostname;
+use crate::dhcopy::archive::ArchiveLayout;
+use crate::dhcopy::codec::Codec;
+use crate::dhcopy::compression::Compression;
+use crate::dhcopy::copy_folder::CopyStats;
+use crate::dhcopy::dictionary::read_dictionary;
+use crate::dhcopy::encryption::{Keyring, PassphraseParams};
+use crate::error::BackupError;
+use crate::storage::backend::StorageBackend;
+use crate::storage::local::LocalStorage;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+// Provenance of a set, kept in its root. Prefixed like the other files this
+// tool keeps in a set so it's unlikely to clash with backed up data.
+pub const METADATA_FILE_NAME: &str = "dhb-set.toml";
+
+/// Layout of sets written by this version. Bump it when the layout changes,
+/// including any new way of storing file contents, and teach `migrate` to
+/// upgrade older sets. Older versions refuse to read sets with a newer
+/// layout rather than misread them, as they'd skip the fields they don't know.
+pub const SET_FORMAT_VERSION: u32 = 2;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SetMetadata {
+	/// Sets with metadata from before the version was recorded are version 1
+	#[serde(default = "first_format_version")]
+	pub format_version: u32,
+	/// Folder backed up, the first of them when there are several
+	pub source: String,
+	/// With several sources, the subfolder of the set each was copied to
+	/// and the folder it came from
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	pub sources: BTreeMap<String, String>,
+	pub hostname: String,
+	pub tool_version: String,
+	pub started_at: DateTime<Utc>,
+	/// Missing until the backup has finished
+	pub finished_at: Option<DateTime<Utc>>,
+	/// Backup options that were in effect, as given
+	#[serde(default)]
+	pub options: BTreeMap<String, String>,
+	pub stats: Option<SetStats>,
+	/// How file contents are stored, see [Compression]
+	#[serde(default, skip_serializing_if = "is_uncompressed")]
+	pub compression: Compression,
+	/// Whether the files are in a single archive rather than copied one by one
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub archive: bool,
+	/// Size of each volume the archive is split into
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub volume_size: Option<u64>,
+	/// Whether file contents are in the destination's chunk store rather
+	/// than in the set, see [crate::chunk_store]
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub chunked: bool,
+	/// Whether file contents are encrypted, to the key in the set's key file
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub encrypted: bool,
+	/// Whether files are stored under names derived from their paths and
+	/// the set's key, the real paths being only in the encrypted manifest
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub encrypted_names: bool,
+	/// Whether files are compressed against a zstd dictionary trained on
+	/// the source, kept in the set, see [crate::dhcopy::dictionary]
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub zstd_dictionary: bool,
+	/// How the set's key is derived from its passphrase, for sets encrypted
+	/// with one
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub passphrase_kdf: Option<PassphraseParams>,
+	#[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+	pub tags: BTreeSet<String>,
+	/// Why the set was kept, added with `annotate`
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub note: Option<String>,
+	/// Older sets that have been merged into this one
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub compacted_from: Vec<String>,
+	/// Whether the backup ran out of time, leaving the files it copied with
+	/// a manifest for the next backup to carry on from
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub resumable: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SetStats {
+	pub files: u64,
+	pub folders: u64,
+	pub bytes: u64,
+	/// Of the bytes, those that took no new space because the same contents
+	/// were already stored, in this set or an earlier one
+	#[serde(default)]
+	pub deduplicated_bytes: u64,
+}
+
+impl From<CopyStats> for SetStats {
+	fn from(stats: CopyStats) -> Self {
+		SetStats {
+			files: stats.files,
+			folders: stats.folders,
+			bytes: stats.bytes,
+			deduplicated_bytes: stats.linked_bytes,
+		}
+	}
+}
+
+impl SetMetadata {
+	/// Metadata for a backup of `source` starting now on this machine
+	pub fn new(source: &str, started_at: DateTime<Utc>, options: BTreeMap<String, String>) -> Self {
+		SetMetadata {
+			format_version: SET_FORMAT_VERSION,
+			source: source.to_string(),
+			sources: BTreeMap::new(),
+			hostname: current_hostname(),
+			tool_version: env!("CARGO_PKG_VERSION").to_string(),
+			started_at,
+			finished_at: None,
+			options,
+			stats: None,
+			compression: Compression::None,
+			archive: false,
+			volume_size: None,
+			chunked: false,
+			encrypted: false,
+			encrypted_names: false,
+			zstd_dictionary: false,
+			passphrase_kdf: None,
+			tags: BTreeSet::new(),
+			note: None,
+			compacted_from: Vec::new(),
+			resumable: false,
+		}
+	}
+}
+
+fn first_format_version() -> u32 {
+	1
+}
+
+fn is_uncompressed(compression: &Compression) -> bool {
+	*compression == Compression::None
+}
+
+pub fn write_metadata(set_folder: &Path, metadata: &SetMetadata) -> io::Result<()> {
+	write_metadata_to(&LocalStorage, set_folder, metadata)
+}
+
+/// Like [write_metadata], into a set in the backend
+pub fn write_metadata_to(
+	backend: &dyn StorageBackend,
+	set_folder: &Path,
+	metadata: &SetMetadata,
+) -> io::Result<()> {
+	let contents = toml::to_string(metadata).map_err(io::Error::other)?;
+	let mut file = backend.create_file(&set_folder.join(METADATA_FILE_NAME))?;
+	file.write_all(contents.as_bytes())?;
+	file.flush()
+}
+
+pub fn read_metadata(set_folder: &Path) -> io::Result<SetMetadata> {
+	let contents = fs::read_to_string(set_folder.join(METADATA_FILE_NAME))?;
+	toml::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// How a set's files are stored, needed to read them back. Everything a
+/// backup does to file contents is recorded in the set's metadata and
+/// gathered here, so restoring and verifying undo it without being told.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SetStorage {
+	pub compression: Compression,
+	pub archive: bool,
+	pub volume_size: Option<u64>,
+	pub chunked: bool,
+	pub encrypted: bool,
+	pub encrypted_names: bool,
+	pub zstd_dictionary: bool,
+	pub passphrase_kdf: Option<PassphraseParams>,
+}
+
+impl SetStorage {
+	/// How to read the set's files back, unlocking its key with the
+	/// keyring if it's encrypted
+	pub fn codec(&self, set_folder: &Path, keys: &Keyring) -> io::Result<Codec> {
+		let key = match self.encrypted {
+			true => Some(keys.unlock(set_folder, self.passphrase_kdf.as_ref())?),
+			false => None,
+		};
+		let dictionary = match self.zstd_dictionary {
+			true => Some(Arc::new(read_dictionary(set_folder, key.as_ref())?)),
+			false => None,
+		};
+		Ok(Codec {
+			compression: self.compression,
+			key,
+			encrypted_names: self.encrypted_names,
+			dictionary,
+		})
+	}
+
+	/// How the archive is laid out, for sets stored as one
+	pub fn archive_layout(&self) -> Option<ArchiveLayout> {
+		self.archive.then_some(ArchiveLayout {
+			compression: self.compression,
+			volume_size: self.volume_size,
+		})
+	}
+}
+
+impl fmt::Display for SetStorage {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let mut parts = Vec::new();
+		if self.archive {
+			parts.push("archive".to_string());
+		}
+		if self.chunked {
+			parts.push("chunked".to_string());
+		}
+		match (self.compression, self.zstd_dictionary) {
+			(Compression::None, _) => {}
+			(compression, true) => {
+				parts.push(format!("{} compressed with a dictionary", compression))
+			}
+			(compression, false) => parts.push(format!("{} compressed", compression)),
+		}
+		match (self.encrypted, self.encrypted_names) {
+			(true, true) => parts.push("encrypted with encrypted names".to_string()),
+			(true, false) => parts.push("encrypted".to_string()),
+			_ => {}
+		}
+		match parts.is_empty() {
+			true => write!(f, "plain files"),
+			false => write!(f, "{}", parts.join(", ")),
+		}
+	}
+}
+
+/// How a set's files are stored, plain files for sets without metadata
+pub fn set_storage(set_folder: &Path) -> io::Result<SetStorage> {
+	match read_metadata(set_folder) {
+		Ok(metadata) if metadata.format_version > SET_FORMAT_VERSION => Err(io::Error::new(
+			io::ErrorKind::InvalidData,
+			format!(
+				"{} has format version {}, newer than this version supports ({}), upgrade to read it",
+				set_folder.display(),
+				metadata.format_version,
+				SET_FORMAT_VERSION
+			),
+		)),
+		Ok(metadata) => Ok(SetStorage {
+			compression: metadata.compression,
+			archive: metadata.archive,
+			volume_size: metadata.volume_size,
+			chunked: metadata.chunked,
+			encrypted: metadata.encrypted,
+			encrypted_names: metadata.encrypted_names,
+			zstd_dictionary: metadata.zstd_dictionary,
+			passphrase_kdf: metadata.passphrase_kdf,
+		}),
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(SetStorage::default()),
+		Err(e) => Err(e),
+	}
+}
+
+/// Records the end of a backup in the set's metadata
+pub fn finish_metadata(
+	set_folder: &Path,
+	finished_at: DateTime<Utc>,
+	stats: SetStats,
+) -> io::Result<SetMetadata> {
+	let mut metadata = read_metadata(set_folder)?;
+	metadata.finished_at = Some(finished_at);
+	metadata.stats = Some(stats);
+	write_metadata(set_folder, &metadata)?;
+	Ok(metadata)
+}
+
+/// Changes the metadata of an existing set, unsealing it for the write
+/// if needed. Fails for legacy sets that have no metadata yet.
+pub fn update_metadata<F>(dest: &str, set_name: &str, change: F) -> Result<(), BackupError>
+where
+	F: FnOnce(&mut SetMetadata),
+{
+	let set_folder = Path::new(dest).join(set_name);
+	if !set_folder.is_dir() {
+		return Err(BackupError::set_missing(dest, set_name));
+	}
+	let mut metadata = read_metadata(&set_folder).map_err(|e| match e.kind() {
+		io::ErrorKind::NotFound => io::Error::new(
+			io::ErrorKind::NotFound,
+			format!(
+				"set {} in {} has no metadata, run migrate first",
+				set_name, dest
+			),
+		),
+		_ => e,
+	})?;
+	change(&mut metadata);
+	Ok(while_unsealed(&set_folder, || {
+		write_metadata(&set_folder, &metadata)
+	})?)
+}
+
+/// Records a note on why the set exists, replacing any earlier one.
+/// An empty note removes it.
+pub fn annotate_set(dest: &str, set_name: &str, note: &str) -> Result<(), BackupError> {
+	let note = note.trim();
+	update_metadata(dest, set_name, |metadata| {
+		metadata.note = (!note.is_empty()).then(|| note.to_string())
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup_sets::backup_set::SetStatus;
+	use crate::backup_sets::seal::seal_set;
+	use crate::test_helpers::test_helpers::{create_set, create_tmp_folder};
+	use chrono::TimeZone;
+
+	#[test]
+	fn test_write_then_finish() -> io::Result<()> {
+		let set_folder = create_tmp_folder("set")?;
+		let set_path = Path::new(&set_folder);
+		let started_at = Utc.with_ymd_and_hms(2001, 2, 3, 14, 5, 6).unwrap();
+		let finished_at = Utc.with_ymd_and_hms(2001, 2, 3, 15, 0, 0).unwrap();
+		let mut options = BTreeMap::new();
+		options.insert("keep_last".to_string(), "3".to_string());
+		let stats = SetStats {
+			files: 2,
+			folders: 1,
+			bytes: 42,
+			deduplicated_bytes: 0,
+		};
+
+		write_metadata(
+			set_path,
+			&SetMetadata::new("/home/susie", started_at, options.clone()),
+		)?;
+		assert_eq!(read_metadata(set_path)?.finished_at, None);
+		finish_metadata(set_path, finished_at, stats)?;
+
+		let metadata = read_metadata(set_path)?;
+		assert_eq!(metadata.source, "/home/susie");
+		assert_eq!(metadata.tool_version, env!("CARGO_PKG_VERSION"));
+		assert_eq!(metadata.started_at, started_at);
+		assert_eq!(metadata.finished_at, Some(finished_at));
+		assert_eq!(metadata.options, options);
+		assert_eq!(metadata.stats, Some(stats));
+		Ok(())
+	}
+
+	#[test]
+	fn test_annotates_sealed_set() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let set_name = "dhb-set-20010203-140506";
+		create_set(&dest, set_name, SetStatus::Complete)?;
+		let set_folder = Path::new(&dest).join(set_name);
+		seal_set(&set_folder)?;
+
+		annotate_set(&dest, set_name, "before OS reinstall")?;
+		assert_eq!(
+			read_metadata(&set_folder)?.note.as_deref(),
+			Some("before OS reinstall")
+		);
+
+		annotate_set(&dest, set_name, "")?;
+		assert_eq!(read_metadata(&set_folder)?.note, None);
+		Ok(())
+	}
+
+	#[test]
+	fn test_storage_from_metadata() -> io::Result<()> {
+		let set_folder = create_tmp_folder("set")?;
+		let set_path = Path::new(&set_folder);
+		assert_eq!(set_storage(set_path)?.to_string(), "plain files");
+		let metadata = SetMetadata {
+			compression: Compression::ZSTD,
+			chunked: true,
+			encrypted: true,
+			..SetMetadata::new("/home/susie", Utc::now(), BTreeMap::new())
+		};
+		write_metadata(set_path, &metadata)?;
+
+		assert_eq!(
+			set_storage(set_path)?.to_string(),
+			"chunked, zstd:3 compressed, encrypted"
+		);
+
+		// a newer version may store contents in ways this one can't undo
+		write_metadata(
+			set_path,
+			&SetMetadata {
+				format_version: SET_FORMAT_VERSION + 1,
+				..metadata
+			},
+		)?;
+		let err = set_storage(set_path).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+		Ok(())
+	}
+}
@@ -1,4 +1,16 @@
-use chrono::{Datelike, Timelike, Utc};
+use chrono::{DateTime, Datelike, FixedOffset, Local, NaiveDateTime, Timelike, Utc};
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+pub const SET_PREFIX: &str = "dhb-set-";
+
+const DATE_FORMAT: &str = "%Y%m%d-%H%M%S";
+const DATE_LENGTH: usize = "YYYYMMDD-HHMMSS".len();
+const OFFSET_FORMAT: &str = "%z";
+const OFFSET_LENGTH: usize = "+HHMM".len();
+const PLACEHOLDERS: [&str; 3] = ["date", "hostname", "source_label"];
 
 pub fn generate_name<F>(get_time: F) -> String
 where
@@ -6,7 +18,8 @@ where
 {
 	let time = get_time();
 	format!(
-		"dhb-set-{:04}{:02}{:02}-{:02}{:02}{:02}",
+		"{}{:04}{:02}{:02}-{:02}{:02}{:02}",
+		SET_PREFIX,
 		time.year(),
 		time.month(),
 		time.day(),
@@ -16,6 +29,245 @@ where
 	)
 }
 
+/// Extracts the time a set was created from the timestamp embedded in its
+/// name, wherever a name template put it. Timestamps without a UTC offset
+/// after them are in UTC.
+pub fn parse_name(name: &str) -> Option<DateTime<Utc>> {
+	let start = name.char_indices().map(|(start, _)| start).find(|start| {
+		name.get(*start..start + DATE_LENGTH)
+			.is_some_and(looks_like_date)
+	})?;
+	let date = &name[start..start + DATE_LENGTH];
+	let offset = name
+		.get(start + DATE_LENGTH..start + DATE_LENGTH + OFFSET_LENGTH)
+		.filter(|offset| looks_like_offset(offset));
+	match offset {
+		Some(offset) => DateTime::parse_from_str(
+			&format!("{}{}", date, offset),
+			&format!("{}{}", DATE_FORMAT, OFFSET_FORMAT),
+		)
+		.ok()
+		.map(|time| time.to_utc()),
+		None => NaiveDateTime::parse_from_str(date, DATE_FORMAT)
+			.ok()
+			.map(|time| time.and_utc()),
+	}
+}
+
+/// A set's folder name along with when the set was made, ordering sets
+/// oldest first. The time comes from the name so survives copying the
+/// destination to another disk; only names without a timestamp fall back
+/// to the folder's modification time.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SetName {
+	time: DateTime<Utc>,
+	name: String,
+}
+
+impl SetName {
+	/// None if the name has no timestamp
+	pub fn parse(name: &str) -> Option<SetName> {
+		parse_name(name).map(|time| SetName {
+			time,
+			name: name.to_string(),
+		})
+	}
+
+	/// Names the set folder `name` in `dest`, using its modification time if
+	/// the name has no timestamp
+	pub fn from_folder(dest: &str, name: &str) -> io::Result<SetName> {
+		match SetName::parse(name) {
+			Some(set_name) => Ok(set_name),
+			None => Ok(SetName {
+				time: Path::new(dest).join(name).metadata()?.modified()?.into(),
+				name: name.to_string(),
+			}),
+		}
+	}
+
+	pub fn time(&self) -> DateTime<Utc> {
+		self.time
+	}
+
+	pub fn as_str(&self) -> &str {
+		&self.name
+	}
+}
+
+impl fmt::Display for SetName {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(&self.name)
+	}
+}
+
+fn looks_like_date(candidate: &str) -> bool {
+	candidate.bytes().enumerate().all(|(i, byte)| match i {
+		8 => byte == b'-',
+		_ => byte.is_ascii_digit(),
+	})
+}
+
+fn looks_like_offset(candidate: &str) -> bool {
+	candidate.bytes().enumerate().all(|(i, byte)| match i {
+		0 => byte == b'+' || byte == b'-',
+		_ => byte.is_ascii_digit(),
+	})
+}
+
+/// Timezone the timestamp in new set names is written in. Names written
+/// in anything but UTC carry their offset, e.g. `20240311-140506+0200`, so
+/// they still sort and age correctly when the zone or daylight saving changes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SetTimezone {
+	#[default]
+	Utc,
+	Local,
+	Fixed(FixedOffset),
+}
+
+impl SetTimezone {
+	fn format(&self, time: DateTime<Utc>) -> String {
+		let with_offset = format!("{}{}", DATE_FORMAT, OFFSET_FORMAT);
+		match self {
+			SetTimezone::Utc => time.format(DATE_FORMAT).to_string(),
+			SetTimezone::Local => time.with_timezone(&Local).format(&with_offset).to_string(),
+			SetTimezone::Fixed(offset) => {
+				time.with_timezone(offset).format(&with_offset).to_string()
+			}
+		}
+	}
+}
+
+impl FromStr for SetTimezone {
+	type Err = String;
+
+	/// Accepts `utc`, `local` or a fixed offset such as `+02:00` or `-0530`
+	fn from_str(timezone: &str) -> Result<Self, Self::Err> {
+		match timezone.to_ascii_lowercase().as_str() {
+			"utc" | "z" => Ok(SetTimezone::Utc),
+			"local" => Ok(SetTimezone::Local),
+			_ => Some(timezone.replace(':', ""))
+				.filter(|offset| offset.len() == OFFSET_LENGTH && looks_like_offset(offset))
+				.and_then(|offset| offset.parse::<FixedOffset>().ok())
+				.map(SetTimezone::Fixed)
+				.ok_or_else(|| {
+					format!(
+						"invalid timezone {}, expected utc, local or an offset like +02:00",
+						timezone
+					)
+				}),
+		}
+	}
+}
+
+impl fmt::Display for SetTimezone {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			SetTimezone::Utc => f.write_str("utc"),
+			SetTimezone::Local => f.write_str("local"),
+			SetTimezone::Fixed(offset) => write!(f, "{}", offset),
+		}
+	}
+}
+
+/// Pattern for naming new sets, e.g. `{hostname}-{source_label}-{date}`.
+/// `{date}` must appear exactly once so the set's age can be read back
+/// from its name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SetNameTemplate(String);
+
+impl Default for SetNameTemplate {
+	fn default() -> Self {
+		SetNameTemplate(format!("{}{{date}}", SET_PREFIX))
+	}
+}
+
+impl SetNameTemplate {
+	pub fn render(&self, time: DateTime<Utc>, timezone: SetTimezone, source: &str) -> String {
+		let mut name = self.0.replace("{date}", &timezone.format(time));
+		if name.contains("{hostname}") {
+			name = name.replace("{hostname}", &sanitize(&current_hostname()));
+		}
+		name.replace("{source_label}", &sanitize(&source_label(source)))
+	}
+}
+
+impl FromStr for SetNameTemplate {
+	type Err = String;
+
+	fn from_str(template: &str) -> Result<Self, Self::Err> {
+		if template.starts_with('.') || template.contains(['/', '\\']) {
+			return Err(format!(
+				"set name template {} can't start with a dot or contain slashes",
+				template
+			));
+		}
+		let mut dates = 0;
+		let mut rest = template;
+		while let Some(open) = rest.find('{') {
+			if rest[..open].contains('}') {
+				return Err(format!("unopened }} in set name template {}", template));
+			}
+			let close = rest[open..]
+				.find('}')
+				.ok_or_else(|| format!("unclosed {{ in set name template {}", template))?;
+			let placeholder = &rest[open + 1..open + close];
+			if !PLACEHOLDERS.contains(&placeholder) {
+				return Err(format!(
+					"unknown placeholder {{{}}} in set name template, expected one of {{{}}}",
+					placeholder,
+					PLACEHOLDERS.join("}, {")
+				));
+			}
+			if placeholder == "date" {
+				dates += 1;
+			}
+			rest = &rest[open + close + 1..];
+		}
+		if rest.contains('}') {
+			return Err(format!("unopened }} in set name template {}", template));
+		}
+		if dates != 1 {
+			return Err(format!(
+				"set name template {} must contain {{date}} exactly once",
+				template
+			));
+		}
+		Ok(SetNameTemplate(template.to_string()))
+	}
+}
+
+impl fmt::Display for SetNameTemplate {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(&self.0)
+	}
+}
+
+pub fn current_hostname() -> String {
+	hostname::get()
+		.map(|name| name.to_string_lossy().into_owned())
+		.unwrap_or_default()
+}
+
+/// Short name for a source folder, its last path component
+pub fn source_label(source: &str) -> String {
+	Path::new(source)
+		.file_name()
+		.map(|name| name.to_string_lossy().into_owned())
+		.unwrap_or_else(|| "root".to_string())
+}
+
+// keeps names safe to use as folder names on any filesystem
+pub(crate) fn sanitize(value: &str) -> String {
+	value
+		.chars()
+		.map(|c| match c {
+			'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+			_ => '-',
+		})
+		.collect()
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -27,4 +279,96 @@ mod tests {
 		let name = generate_name(|| fixed_time);
 		assert_eq!(name, "dhb-set-20010203-140506");
 	}
+
+	#[test]
+	fn test_parses_set_name() {
+		let fixed_time = Utc.with_ymd_and_hms(2001, 2, 3, 14, 5, 6).unwrap();
+		assert_eq!(parse_name(&generate_name(|| fixed_time)), Some(fixed_time));
+		assert_eq!(
+			parse_name("laptop-9-home-20010203-140506"),
+			Some(fixed_time)
+		);
+		assert_eq!(parse_name("dhb-set-nonsense"), None);
+		assert_eq!(parse_name("dhb-set-20011303-140506"), None);
+		assert_eq!(parse_name("holiday-photos"), None);
+	}
+
+	#[test]
+	fn test_orders_set_names_by_time() {
+		let mut names = [
+			SetName::parse("zebra-20010203-140506").unwrap(),
+			SetName::parse("dhb-set-20010203-140506+0200").unwrap(),
+			SetName::parse("aardvark-20020101-000000").unwrap(),
+		];
+		names.sort();
+
+		let names: Vec<&str> = names.iter().map(SetName::as_str).collect();
+		assert_eq!(
+			names,
+			vec![
+				"dhb-set-20010203-140506+0200",
+				"zebra-20010203-140506",
+				"aardvark-20020101-000000"
+			]
+		);
+		assert_eq!(SetName::parse("holiday-photos"), None);
+	}
+
+	#[test]
+	fn test_renders_template() {
+		let fixed_time = Utc.with_ymd_and_hms(2001, 2, 3, 14, 5, 6).unwrap();
+		let template: SetNameTemplate = "{source_label}-{date}".parse().unwrap();
+
+		let name = template.render(fixed_time, SetTimezone::Utc, "/home/susie/my docs");
+
+		assert_eq!(name, "my-docs-20010203-140506");
+		assert_eq!(parse_name(&name), Some(fixed_time));
+		assert_eq!(
+			SetNameTemplate::default().render(fixed_time, SetTimezone::Utc, "/home"),
+			generate_name(|| fixed_time)
+		);
+	}
+
+	#[test]
+	fn test_renders_offset_timezone() {
+		let fixed_time = Utc.with_ymd_and_hms(2001, 2, 3, 23, 5, 6).unwrap();
+		let timezone: SetTimezone = "+02:00".parse().unwrap();
+
+		let name = SetNameTemplate::default().render(fixed_time, timezone, "/home");
+
+		assert_eq!(name, "dhb-set-20010204-010506+0200");
+		assert_eq!(parse_name(&name), Some(fixed_time));
+		assert_eq!(
+			parse_name("dhb-set-20010203-180506-0500-laptop"),
+			Some(fixed_time)
+		);
+	}
+
+	#[test]
+	fn test_parses_timezone() {
+		assert_eq!("UTC".parse(), Ok(SetTimezone::Utc));
+		assert_eq!("local".parse(), Ok(SetTimezone::Local));
+		assert_eq!(
+			"-0530".parse(),
+			Ok(SetTimezone::Fixed(
+				FixedOffset::west_opt(5 * 3600 + 30 * 60).unwrap()
+			))
+		);
+		assert!("Europe/London".parse::<SetTimezone>().is_err());
+		assert!("0200".parse::<SetTimezone>().is_err());
+	}
+
+	#[test]
+	fn test_validates_template() {
+		assert!("{hostname}-{source_label}-{date}"
+			.parse::<SetNameTemplate>()
+			.is_ok());
+		assert!("{hostname}".parse::<SetNameTemplate>().is_err());
+		assert!("{date}-{date}".parse::<SetNameTemplate>().is_err());
+		assert!("{date}-{user}".parse::<SetNameTemplate>().is_err());
+		assert!("{date".parse::<SetNameTemplate>().is_err());
+		assert!("date}-{date}".parse::<SetNameTemplate>().is_err());
+		assert!("sets/{date}".parse::<SetNameTemplate>().is_err());
+		assert!(".{date}".parse::<SetNameTemplate>().is_err());
+	}
 }
@@ -0,0 +1,81 @@
+use crate::backup_sets::set_metadata::{read_metadata, update_metadata};
+use crate::error::BackupError;
+use std::collections::BTreeSet;
+use std::io;
+use std::path::Path;
+
+/// Tags are single words so they can be given on the command line and listed
+/// without quoting
+pub fn validate_tag(tag: &str) -> Result<String, String> {
+	if tag.is_empty() || tag.contains(|c: char| c.is_whitespace() || c == ',') {
+		return Err(format!(
+			"invalid tag \"{}\", tags can't be empty or contain spaces or commas",
+			tag
+		));
+	}
+	Ok(tag.to_string())
+}
+
+/// Tags recorded in a set's metadata, none for sets without metadata
+pub fn set_tags(dest: &str, set_name: &str) -> Result<BTreeSet<String>, BackupError> {
+	match read_metadata(&Path::new(dest).join(set_name)) {
+		Ok(metadata) => Ok(metadata.tags),
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+		Err(e) => Err(e.into()),
+	}
+}
+
+pub fn tag_set(dest: &str, set_name: &str, tags: &[String]) -> Result<(), BackupError> {
+	change_tags(dest, set_name, |set_tags| {
+		set_tags.extend(tags.iter().cloned())
+	})
+}
+
+pub fn untag_set(dest: &str, set_name: &str, tags: &[String]) -> Result<(), BackupError> {
+	change_tags(dest, set_name, |set_tags| {
+		set_tags.retain(|tag| !tags.contains(tag))
+	})
+}
+
+fn change_tags<F>(dest: &str, set_name: &str, change: F) -> Result<(), BackupError>
+where
+	F: FnOnce(&mut BTreeSet<String>),
+{
+	update_metadata(dest, set_name, |metadata| change(&mut metadata.tags))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup_sets::backup_set::SetStatus;
+	use crate::test_helpers::test_helpers::{create_set, create_tmp_folder};
+
+	const SET_NAME: &str = "dhb-set-20010203-140506";
+
+	#[test]
+	fn test_tag_and_untag() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		create_set(&dest, SET_NAME, SetStatus::Complete)?;
+
+		tag_set(
+			&dest,
+			SET_NAME,
+			&["milestone".to_string(), "pre-upgrade".to_string()],
+		)?;
+		untag_set(&dest, SET_NAME, &["pre-upgrade".to_string()])?;
+
+		assert_eq!(
+			set_tags(&dest, SET_NAME)?,
+			BTreeSet::from(["milestone".to_string()])
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_validates_tags() {
+		assert!(validate_tag("pre-upgrade").is_ok());
+		assert!(validate_tag("").is_err());
+		assert!(validate_tag("two words").is_err());
+		assert!(validate_tag("a,b").is_err());
+	}
+}
@@ -0,0 +1,78 @@
+use crate::backup_sets::seal::{is_sealed, remove_set, unseal_set};
+use crate::error::BackupError;
+use crate::space::usage::folder_usage;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Pruned sets wait here for a grace period, in case the retention rules were wrong
+const TRASH_FOLDER_NAME: &str = ".dhb-trash";
+
+pub fn trash_folder(dest: &str) -> PathBuf {
+	Path::new(dest).join(TRASH_FOLDER_NAME)
+}
+
+pub fn move_to_trash(dest: &str, set_name: &str) -> Result<(), BackupError> {
+	let trash = trash_folder(dest);
+	fs::create_dir_all(&trash)?;
+	let trashed = trash.join(set_name);
+	if trashed.exists() {
+		remove_set(&trashed)?;
+	}
+	// moving a folder between parents needs it to be writable
+	let set_folder = Path::new(dest).join(set_name);
+	if is_sealed(&set_folder) {
+		unseal_set(&set_folder)?;
+	}
+	Ok(fs::rename(set_folder, trashed)?)
+}
+
+/// Permanently deletes everything in the trash, returning how many bytes were freed
+pub fn empty_trash(dest: &str) -> Result<u64, BackupError> {
+	let trash = trash_folder(dest);
+	if !trash.exists() {
+		return Ok(0);
+	}
+	let freed = folder_usage(&trash)?.exclusive;
+	for entry in fs::read_dir(&trash)? {
+		let entry = entry?;
+		println!("emptying {:?} from trash", entry.file_name());
+		remove_set(&entry.path())?;
+	}
+	Ok(freed)
+}
+
+pub fn trash_is_empty(dest: &str) -> Result<bool, BackupError> {
+	match fs::read_dir(trash_folder(dest)) {
+		Ok(mut entries) => Ok(entries.next().is_none()),
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+		Err(e) => Err(e.into()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup_sets::backup_set::list_sets;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	const SET_NAME: &str = "dhb-set-20010203-140506";
+
+	#[test]
+	fn test_trash_and_empty() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let set_folder = Path::new(&dest).join(SET_NAME);
+		fs::create_dir_all(&set_folder)?;
+		fs::write(set_folder.join("data"), "1234567890")?;
+
+		move_to_trash(&dest, SET_NAME)?;
+
+		assert!(list_sets(&dest)?.is_empty());
+		assert!(trash_folder(&dest).join(SET_NAME).join("data").exists());
+		assert!(!trash_is_empty(&dest)?);
+
+		assert_eq!(empty_trash(&dest)?, 10);
+		assert!(trash_is_empty(&dest)?);
+		Ok(())
+	}
+}
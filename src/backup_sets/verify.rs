@@ -0,0 +1,384 @@
+use crate::backup_sets::backup_set::{set_status, SetStatus};
+use crate::backup_sets::last_known_good::{clear_last_known_good, record_last_known_good};
+use crate::backup_sets::manifest::{hash_reader, hash_stored_file, read_set_manifest};
+use crate::backup_sets::set_metadata::{read_metadata, set_storage};
+use crate::backup_sets::verify_catalog::{read_catalog, write_catalog, CatalogEntry};
+use crate::chunk_store::chunked_set::{read_chunk_list, ChunkListEntry, ChunkReader};
+use crate::chunk_store::store::ChunkStore;
+use crate::dhcopy::archive::{for_each_archived_file, ArchiveLayout};
+use crate::dhcopy::codec::Codec;
+use crate::dhcopy::encryption::Keyring;
+use crate::error::{BackupError, Operation};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Default)]
+pub struct VerifyResult {
+	pub checked: usize,
+	/// A [`BackupError::ChecksumMismatch`] for each file whose contents have
+	/// changed, or a reading error for one too damaged to read
+	pub corrupt: Vec<BackupError>,
+	pub missing: Vec<String>,
+}
+
+impl VerifyResult {
+	pub fn is_ok(&self) -> bool {
+		self.corrupt.is_empty() && self.missing.is_empty()
+	}
+}
+
+/// Re-hashes every file in a set against its manifest.
+/// A complete set that passes becomes the last known good set, while one
+/// whose backup didn't finish, or ran out of time, only has the files it
+/// got to. Encrypted sets are unlocked with the keyring.
+pub fn verify_set(dest: &str, set_name: &str, keys: &Keyring) -> Result<VerifyResult, BackupError> {
+	let set_folder = Path::new(dest).join(set_name);
+	if !set_folder.is_dir() {
+		return Err(BackupError::set_missing(dest, set_name));
+	}
+	let storage = set_storage(&set_folder)?;
+	println!("verifying set {:?} ({})", set_folder, storage);
+	let codec = storage.codec(&set_folder, keys)?;
+	let result = verify_files(
+		&set_folder,
+		&codec,
+		&read_set_manifest(&set_folder, &codec)?,
+	)?;
+	if result.is_ok() {
+		if has_everything(dest, set_name)? {
+			record_last_known_good(dest, set_name)?;
+		}
+	} else {
+		clear_last_known_good(dest, set_name)?;
+	}
+	Ok(result)
+}
+
+/// Whether the backup that made a set copied all there was to copy
+fn has_everything(dest: &str, set_name: &str) -> io::Result<bool> {
+	if set_status(dest, set_name)? == SetStatus::Incomplete {
+		return Ok(false);
+	}
+	match read_metadata(&Path::new(dest).join(set_name)) {
+		Ok(metadata) => Ok(!metadata.resumable),
+		// sets from before metadata was written
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+		Err(e) => Err(e),
+	}
+}
+
+pub struct SampleResult {
+	pub result: VerifyResult,
+	/// Files checked so far in the current pass through the set
+	pub covered: usize,
+	pub total: usize,
+}
+
+/// Checks `percent` of a set's files per call, working through the whole set
+/// in a shuffled order recorded in the verify catalog so that every file is
+/// covered within `ceil(100 / percent)` calls. A new pass starts with a fresh
+/// order once the set has been covered, or when a different `seed` is given.
+pub fn verify_set_sample(
+	dest: &str,
+	set_name: &str,
+	percent: f64,
+	seed: Option<u64>,
+	keys: &Keyring,
+) -> Result<SampleResult, BackupError> {
+	let set_folder = Path::new(dest).join(set_name);
+	if !set_folder.is_dir() {
+		return Err(BackupError::set_missing(dest, set_name));
+	}
+	println!("verifying {}% sample of set {:?}", percent, set_folder);
+	let codec = set_storage(&set_folder)?.codec(&set_folder, keys)?;
+	let entries = read_set_manifest(&set_folder, &codec)?;
+	let mut catalog = read_catalog(dest)?;
+
+	let pass = match catalog.get(set_name) {
+		Some(pass) if pass.cursor < entries.len() && seed.is_none_or(|seed| seed == pass.seed) => {
+			*pass
+		}
+		_ => CatalogEntry {
+			seed: seed.unwrap_or_else(rand::random),
+			cursor: 0,
+		},
+	};
+
+	let mut order: Vec<&(String, String)> = entries.iter().collect();
+	order.shuffle(&mut StdRng::seed_from_u64(pass.seed));
+	let count = ((entries.len() as f64 * percent / 100.0).ceil() as usize).max(1);
+	let end = (pass.cursor + count).min(entries.len());
+	let sample: Vec<(String, String)> =
+		order[pass.cursor..end].iter().map(|&e| e.clone()).collect();
+
+	let result = verify_files(&set_folder, &codec, &sample)?;
+	if !result.is_ok() {
+		clear_last_known_good(dest, set_name)?;
+	}
+
+	catalog.insert(
+		set_name.to_string(),
+		CatalogEntry {
+			seed: pass.seed,
+			cursor: end,
+		},
+	);
+	write_catalog(dest, &catalog)?;
+
+	Ok(SampleResult {
+		result,
+		covered: end,
+		total: entries.len(),
+	})
+}
+
+fn verify_files(
+	set_folder: &Path,
+	codec: &Codec,
+	entries: &[(String, String)],
+) -> io::Result<VerifyResult> {
+	let storage = set_storage(set_folder)?;
+	if let Some(layout) = storage.archive_layout() {
+		return verify_archive(set_folder, layout, entries);
+	}
+	if storage.chunked {
+		let store = ChunkStore::for_set(set_folder, storage.compression);
+		return verify_chunked(&store, set_folder, entries);
+	}
+	let mut result = VerifyResult::default();
+	for (expected_digest, path) in entries {
+		result.checked += 1;
+		match hash_stored_file(set_folder, path, codec) {
+			Ok((digest, _)) if &digest == expected_digest => {}
+			Ok((digest, _)) => result.corrupt.push(mismatch(path, expected_digest, digest)),
+			// compressed or encrypted data too damaged to read
+			Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+				result.corrupt.push(unreadable(path, e))
+			}
+			Err(e) if e.kind() == io::ErrorKind::NotFound => result.missing.push(path.clone()),
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(result)
+}
+
+/// Checks the files in `entries` by reading them back from their chunks.
+/// A missing chunk makes its file missing.
+fn verify_chunked(
+	store: &ChunkStore,
+	set_folder: &Path,
+	entries: &[(String, String)],
+) -> io::Result<VerifyResult> {
+	let chunks: HashMap<String, Vec<String>> = read_chunk_list(set_folder)?
+		.into_iter()
+		.filter_map(|entry| match entry {
+			ChunkListEntry::File { path, chunks } => Some((path, chunks)),
+			ChunkListEntry::Folder(_) => None,
+		})
+		.collect();
+	let mut result = VerifyResult::default();
+	for (expected_digest, path) in entries {
+		result.checked += 1;
+		let Some(chunks) = chunks.get(path) else {
+			result.missing.push(path.clone());
+			continue;
+		};
+		match hash_reader(&mut ChunkReader::new(store, chunks)) {
+			Ok((digest, _)) if &digest == expected_digest => {}
+			Ok((digest, _)) => result.corrupt.push(mismatch(path, expected_digest, digest)),
+			Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+				result.corrupt.push(unreadable(path, e))
+			}
+			Err(e) if e.kind() == io::ErrorKind::NotFound => result.missing.push(path.clone()),
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(result)
+}
+
+/// Reads through the set's archive once, checking the files in `entries`.
+/// If the archive is damaged, files that couldn't be reached count as corrupt.
+fn verify_archive(
+	set_folder: &Path,
+	layout: ArchiveLayout,
+	entries: &[(String, String)],
+) -> io::Result<VerifyResult> {
+	let mut expected: HashMap<&str, &str> = entries
+		.iter()
+		.map(|(digest, path)| (path.as_str(), digest.as_str()))
+		.collect();
+	let mut result = VerifyResult {
+		checked: entries.len(),
+		..Default::default()
+	};
+	let read = for_each_archived_file(set_folder, layout, |path, contents| {
+		if let Some(expected_digest) = expected.remove(path) {
+			let (digest, _) = hash_reader(contents)?;
+			if digest != expected_digest {
+				result.corrupt.push(mismatch(path, expected_digest, digest));
+			}
+		}
+		Ok(())
+	});
+	let mut unread: Vec<String> = expected.into_keys().map(str::to_string).collect();
+	unread.sort();
+	match read {
+		Ok(()) => result.missing = unread,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => result.missing = unread,
+		Err(e)
+			if matches!(
+				e.kind(),
+				io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof | io::ErrorKind::Other
+			) =>
+		{
+			eprintln!("archive in {:?} is damaged: {}", set_folder, e);
+			result.corrupt.extend(
+				unread
+					.iter()
+					.map(|path| unreadable(path, io::Error::new(e.kind(), e.to_string()))),
+			);
+		}
+		Err(e) => return Err(e),
+	}
+	Ok(result)
+}
+
+fn mismatch(path: &str, expected: &str, actual: String) -> BackupError {
+	BackupError::ChecksumMismatch {
+		path: PathBuf::from(path),
+		expected: expected.to_string(),
+		actual,
+	}
+}
+
+fn unreadable(path: &str, source: io::Error) -> BackupError {
+	BackupError::Io {
+		operation: Operation::Reading,
+		path: PathBuf::from(path),
+		source,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup_sets::last_known_good::last_known_good;
+	use crate::backup_sets::manifest::generate_manifest;
+	use crate::backup_sets::set_metadata::{write_metadata, SetMetadata};
+	use crate::dhcopy::compression::Compression;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+	use chrono::Utc;
+	use std::fs;
+
+	const SET_NAME: &str = "dhb-set-20010203-140506";
+
+	fn create_set() -> io::Result<String> {
+		let dest = create_tmp_folder("backups")?;
+		let set_folder = Path::new(&dest).join(SET_NAME);
+		fs::create_dir_all(&set_folder)?;
+		fs::write(set_folder.join("good.txt"), "backmeup susie")?;
+		fs::write(set_folder.join("rotten.txt"), "backmeup susie")?;
+		fs::write(set_folder.join("lost.txt"), "backmeup susie")?;
+		generate_manifest(&set_folder)?;
+		Ok(dest)
+	}
+
+	#[test]
+	fn test_verifies_good_set() -> io::Result<()> {
+		let dest = create_set()?;
+
+		let result = verify_set(&dest, SET_NAME, &Keyring::default())?;
+
+		assert!(result.is_ok());
+		assert_eq!(result.checked, 3);
+		assert_eq!(last_known_good(&dest)?.as_deref(), Some(SET_NAME));
+		Ok(())
+	}
+
+	#[test]
+	fn test_only_records_complete_sets_as_good() -> io::Result<()> {
+		let dest = create_set()?;
+		let set_folder = Path::new(&dest).join(SET_NAME);
+		let mut metadata = SetMetadata::new("/home", Utc::now(), Default::default());
+		write_metadata(&set_folder, &metadata)?;
+
+		assert!(verify_set(&dest, SET_NAME, &Keyring::default())?.is_ok());
+		assert_eq!(last_known_good(&dest)?, None);
+
+		// ran out of time, so the files it has may be all there are
+		metadata.resumable = true;
+		write_metadata(&set_folder, &metadata)?;
+		assert!(verify_set(&dest, SET_NAME, &Keyring::default())?.is_ok());
+		assert_eq!(last_known_good(&dest)?, None);
+		Ok(())
+	}
+
+	#[test]
+	fn test_detects_damage() -> io::Result<()> {
+		let dest = create_set()?;
+		let set_folder = Path::new(&dest).join(SET_NAME);
+		fs::write(set_folder.join("rotten.txt"), "bit rot")?;
+		fs::remove_file(set_folder.join("lost.txt"))?;
+
+		let result = verify_set(&dest, SET_NAME, &Keyring::default())?;
+
+		assert!(!result.is_ok());
+		assert!(matches!(
+			result.corrupt.as_slice(),
+			[BackupError::ChecksumMismatch { path, .. }] if path == Path::new("rotten.txt")
+		));
+		assert_eq!(result.missing, vec!["lost.txt"]);
+		assert_eq!(last_known_good(&dest)?, None);
+		Ok(())
+	}
+
+	#[test]
+	fn test_verifies_compressed_set() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let set_folder = Path::new(&dest).join(SET_NAME);
+		fs::create_dir_all(&set_folder)?;
+		let mut metadata = SetMetadata::new("/home/susie", Utc::now(), Default::default());
+		metadata.compression = Compression::ZSTD;
+		write_metadata(&set_folder, &metadata)?;
+		for name in ["good.txt", "rotten.txt"] {
+			let stored = Compression::ZSTD.stored_path(&set_folder.join(name));
+			Compression::ZSTD.write(&mut "backmeup susie".as_bytes(), &stored)?;
+		}
+		generate_manifest(&set_folder)?;
+		fs::write(set_folder.join("rotten.txt.zst"), "bit rot")?;
+
+		let result = verify_set(&dest, SET_NAME, &Keyring::default())?;
+
+		assert_eq!(result.checked, 2);
+		let corrupt: Vec<_> = result.corrupt.iter().map(BackupError::path).collect();
+		assert_eq!(corrupt, vec![Some(Path::new("rotten.txt"))]);
+		Ok(())
+	}
+
+	#[test]
+	fn test_sample_covers_whole_set() -> io::Result<()> {
+		let dest = create_set()?;
+
+		let mut checked = 0;
+		for run in 1..=3 {
+			let sample = verify_set_sample(&dest, SET_NAME, 30.0, Some(42), &Keyring::default())?;
+			assert_eq!(sample.result.checked, 1, "30% of 3 files rounds up to 1");
+			assert_eq!(sample.covered, run);
+			checked += sample.result.checked;
+		}
+		assert_eq!(checked, 3);
+
+		let next_pass = verify_set_sample(&dest, SET_NAME, 30.0, Some(42), &Keyring::default())?;
+		assert_eq!(next_pass.covered, 1, "a new pass should start once covered");
+		assert_eq!(
+			last_known_good(&dest)?,
+			None,
+			"sampling should never mark a set as good"
+		);
+		Ok(())
+	}
+}
@@ -0,0 +1,86 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Tracks how far sampled verification has got through each set, so that
+// every file gets checked within a bounded number of runs.
+const VERIFY_CATALOG_FILE: &str = ".dhb-verify-catalog";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CatalogEntry {
+	/// Seed for the order files are checked in during the current pass
+	pub seed: u64,
+	/// How many files of the current pass have been checked
+	pub cursor: usize,
+}
+
+pub fn read_catalog(dest: &str) -> io::Result<BTreeMap<String, CatalogEntry>> {
+	let contents = match fs::read_to_string(Path::new(dest).join(VERIFY_CATALOG_FILE)) {
+		Ok(contents) => contents,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+		Err(e) => return Err(e),
+	};
+	let mut catalog = BTreeMap::new();
+	for line in contents.lines() {
+		// set names from templates can have spaces in them
+		let mut fields = line.rsplitn(3, ' ');
+		let (Some(cursor), Some(seed), Some(set_name)) =
+			(fields.next(), fields.next(), fields.next())
+		else {
+			return Err(malformed(line));
+		};
+		let entry = CatalogEntry {
+			seed: seed.parse().map_err(|_| malformed(line))?,
+			cursor: cursor.parse().map_err(|_| malformed(line))?,
+		};
+		catalog.insert(set_name.to_string(), entry);
+	}
+	Ok(catalog)
+}
+
+/// Saves the catalog, dropping sets that no longer exist
+pub fn write_catalog(dest: &str, catalog: &BTreeMap<String, CatalogEntry>) -> io::Result<()> {
+	let contents: String = catalog
+		.iter()
+		.filter(|(set_name, _)| Path::new(dest).join(set_name).is_dir())
+		.map(|(set_name, entry)| format!("{} {} {}\n", set_name, entry.seed, entry.cursor))
+		.collect();
+	fs::write(Path::new(dest).join(VERIFY_CATALOG_FILE), contents)
+}
+
+fn malformed(line: &str) -> io::Error {
+	io::Error::new(
+		io::ErrorKind::InvalidData,
+		format!("malformed verify catalog line: {}", line),
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	#[test]
+	fn test_round_trip() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		fs::create_dir_all(Path::new(&dest).join("dhb-set-20010203-140506"))?;
+		fs::create_dir_all(Path::new(&dest).join("laptop backup 2001-02-03"))?;
+		let entry = CatalogEntry {
+			seed: 42,
+			cursor: 7,
+		};
+		let mut catalog = BTreeMap::new();
+		catalog.insert("dhb-set-20010203-140506".to_string(), entry);
+		catalog.insert("dhb-set-19990101-000000".to_string(), entry);
+		catalog.insert("laptop backup 2001-02-03".to_string(), entry);
+
+		write_catalog(&dest, &catalog)?;
+		let read_back = read_catalog(&dest)?;
+
+		assert_eq!(read_back.len(), 2, "deleted sets should be dropped");
+		assert_eq!(read_back["dhb-set-20010203-140506"], entry);
+		assert_eq!(read_back["laptop backup 2001-02-03"], entry);
+		Ok(())
+	}
+}
@@ -0,0 +1,115 @@
+use crate::error::BackupError;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// What the process exits with when a backup's cancelled, as shells give
+/// for commands stopped by Ctrl-C
+pub const CANCELLED_EXIT_CODE: i32 = 130;
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether Ctrl-C can be left to the backup, which only looks for it
+/// while writing a set. Otherwise the process exits straight away.
+static STOPS_CLEANLY: AtomicBool = AtomicBool::new(false);
+
+/// Asks backups in this process to stop at the next file
+pub fn cancel() {
+	CANCELLED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_cancelled() -> bool {
+	CANCELLED.load(Ordering::SeqCst)
+}
+
+/// A [BackupError::Cancelled], of kind `Interrupted`, once the backup's
+/// been cancelled. Checked between files, so none is left half copied.
+pub fn check_cancelled() -> io::Result<()> {
+	match is_cancelled() {
+		true => Err(BackupError::Cancelled.into()),
+		false => Ok(()),
+	}
+}
+
+/// Marks when a set's being written, so Ctrl-C stops the backup cleanly
+/// rather than exiting
+pub(crate) fn set_stops_cleanly(stops_cleanly: bool) {
+	STOPS_CLEANLY.store(stops_cleanly, Ordering::SeqCst);
+}
+
+/// Cancels the backup on Ctrl-C or SIGTERM, as systemd stops services
+/// with, leaving it to stop at the next file. Ctrl-C again, or either
+/// before any set's being written, exits straight away. SIGTERM again
+/// doesn't, as systemd sends it to every process in the service.
+#[cfg(unix)]
+pub fn cancel_on_interrupt() -> io::Result<()> {
+	extern "C" fn handle(signal: libc::c_int) {
+		// only atomics, write and _exit, which are safe in a signal handler
+		let again = CANCELLED.swap(true, Ordering::SeqCst);
+		if (again && signal == libc::SIGINT) || !STOPS_CLEANLY.load(Ordering::SeqCst) {
+			unsafe { libc::_exit(CANCELLED_EXIT_CODE) };
+		}
+		if !again {
+			let message = b"\nstopping after the current file, Ctrl-C again to stop now\n";
+			unsafe { libc::write(libc::STDERR_FILENO, message.as_ptr().cast(), message.len()) };
+		}
+	}
+	handle_signals(&[libc::SIGINT, libc::SIGTERM], handle)
+}
+
+/// Cancels on Ctrl-C or SIGTERM without exiting, for the daemon to stop
+/// once it's waited for the backups it started
+#[cfg(unix)]
+pub fn cancel_on_terminate() -> io::Result<()> {
+	extern "C" fn handle(_signal: libc::c_int) {
+		// only an atomic store, which is safe in a signal handler
+		CANCELLED.store(true, Ordering::SeqCst);
+	}
+	handle_signals(&[libc::SIGINT, libc::SIGTERM], handle)
+}
+
+/// Asks a backup running in another process to stop at the next file, as
+/// SIGTERM does
+#[cfg(unix)]
+pub fn cancel_process(pid: u32) -> io::Result<()> {
+	// SAFETY: only sends a signal
+	match unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } {
+		0 => Ok(()),
+		_ => Err(io::Error::last_os_error()),
+	}
+}
+
+#[cfg(unix)]
+fn handle_signals(signals: &[libc::c_int], handle: extern "C" fn(libc::c_int)) -> io::Result<()> {
+	for signal in signals {
+		// SAFETY: the handlers only do what's allowed in one
+		let previous = unsafe { libc::signal(*signal, handle as libc::sighandler_t) };
+		if previous == libc::SIG_ERR {
+			return Err(io::Error::last_os_error());
+		}
+	}
+	Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn cancel_on_interrupt() -> io::Result<()> {
+	Err(io::Error::new(
+		io::ErrorKind::Unsupported,
+		"stopping cleanly on Ctrl-C is not supported on this platform",
+	))
+}
+
+#[cfg(not(unix))]
+pub fn cancel_process(_pid: u32) -> io::Result<()> {
+	Err(io::Error::new(
+		io::ErrorKind::Unsupported,
+		"stopping other processes is not supported on this platform",
+	))
+}
+
+#[cfg(not(unix))]
+pub fn cancel_on_terminate() -> io::Result<()> {
+	Err(io::Error::new(
+		io::ErrorKind::Unsupported,
+		"stopping cleanly on signals is not supported on this platform",
+	))
+}
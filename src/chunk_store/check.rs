@@ -0,0 +1,190 @@
+use crate::backup_sets::manifest::hex_digest;
+use crate::chunk_store::chunked_set::ChunkListEntry;
+use crate::chunk_store::gc::{chunk_users, set_chunks};
+use crate::chunk_store::store::{read_pack_indexes, read_packed, CHUNKS_FOLDER, PACKS_FOLDER};
+use crate::dhcopy::compression::Compression;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// What checking a destination's chunk store found
+#[derive(Debug, Default, PartialEq)]
+pub struct ChunkCheckResult {
+	/// Stored chunks read back
+	pub checked: usize,
+	/// Chunks whose contents don't match the digest in their name, or that
+	/// can't be decompressed
+	pub corrupt: Vec<String>,
+	/// Chunks that sets list but the store doesn't have, each as the name
+	/// of the first set found needing it and the chunk's name
+	pub missing: Vec<(String, String)>,
+}
+
+impl ChunkCheckResult {
+	pub fn is_ok(&self) -> bool {
+		self.corrupt.is_empty() && self.missing.is_empty()
+	}
+}
+
+/// Reads back every chunk in the store, loose or packed, checking its
+/// contents still hash to its name, and checks every chunk the chunked
+/// sets list is there. Unused chunks are checked too, as later backups
+/// reuse any chunk already stored. The destination should be locked, so
+/// `gc` or `repack` don't move chunks from under it.
+pub fn check_chunks(dest: &str) -> io::Result<ChunkCheckResult> {
+	let chunks = Path::new(dest).join(CHUNKS_FOLDER);
+	let mut result = ChunkCheckResult::default();
+	let mut stored = HashSet::new();
+	if chunks.exists() {
+		for folder in fs::read_dir(&chunks)? {
+			let folder = folder?;
+			if folder.file_name() == PACKS_FOLDER || !folder.file_type()?.is_dir() {
+				continue;
+			}
+			for chunk in fs::read_dir(folder.path())? {
+				let chunk = chunk?;
+				let name = chunk.file_name().to_string_lossy().into_owned();
+				// left by an interrupted write, for gc to delete
+				if name.ends_with(".tmp") {
+					continue;
+				}
+				check_chunk(&name, fs::read(chunk.path()), &mut result)?;
+				stored.insert(name);
+			}
+		}
+	}
+	let packed = read_pack_indexes(&chunks.join(PACKS_FOLDER))?;
+	let mut names: Vec<&String> = packed.keys().collect();
+	names.sort();
+	for name in names {
+		check_chunk(name, read_packed(&packed[name]), &mut result)?;
+		stored.insert(name.clone());
+	}
+
+	for set_folder in chunk_users(dest)? {
+		let Some((store, entries)) = set_chunks(&set_folder)? else {
+			continue;
+		};
+		let set = set_folder
+			.file_name()
+			.unwrap()
+			.to_string_lossy()
+			.into_owned();
+		for entry in entries {
+			if let ChunkListEntry::File { chunks, .. } = entry {
+				for digest in chunks {
+					let name = store.chunk_name(&digest);
+					// reported once, for the newest set that needs it
+					if stored.insert(name.clone()) {
+						result.missing.push((set.clone(), name));
+					}
+				}
+			}
+		}
+	}
+	result.corrupt.sort();
+	Ok(result)
+}
+
+/// Counts a chunk as corrupt if reading it back fails with damaged data, or
+/// what's read doesn't hash to its name
+fn check_chunk(
+	name: &str,
+	stored: io::Result<Vec<u8>>,
+	result: &mut ChunkCheckResult,
+) -> io::Result<()> {
+	result.checked += 1;
+	let matches = stored.and_then(|stored| chunk_matches(name, stored));
+	match matches {
+		Ok(true) => {}
+		Ok(false) => result.corrupt.push(name.to_string()),
+		Err(e)
+			if matches!(
+				e.kind(),
+				io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+			) =>
+		{
+			result.corrupt.push(name.to_string())
+		}
+		Err(e) => return Err(e),
+	}
+	Ok(())
+}
+
+/// Whether the stored chunk decompresses to contents with the digest its
+/// name starts with. The rest of the name says how it's compressed.
+fn chunk_matches(name: &str, stored: Vec<u8>) -> io::Result<bool> {
+	let Some((digest, rest)) = name.split_at_checked(64) else {
+		return Ok(false);
+	};
+	let compression = match rest {
+		"" => Compression::None,
+		_ => match rest.strip_prefix('.').and_then(Compression::from_extension) {
+			Some(compression) => compression,
+			None => return Ok(false),
+		},
+	};
+	let mut hasher = Sha256::new();
+	io::copy(
+		&mut compression.decode(io::Cursor::new(stored))?,
+		&mut hasher,
+	)?;
+	Ok(hex_digest(hasher) == digest)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup::backup::BackupOptions;
+	use crate::chunk_store::repack::{repack, DEFAULT_PACK_SIZE};
+	use crate::test_helpers::test_helpers::{backup_folder, create_tmp_folder};
+
+	#[test]
+	fn test_finds_corrupt_and_missing_chunks() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let options = BackupOptions {
+			chunked: true,
+			..Default::default()
+		};
+		let mut sets = Vec::new();
+		for text in ["backmeup susie", "backmeup sammy"] {
+			let source = create_tmp_folder("orig")?;
+			fs::write(Path::new(&source).join("testfile.txt"), text)?;
+			sets.push(backup_folder(&source, &dest, &options)?);
+		}
+		// one chunk in a pack, the other loose
+		repack(&dest, DEFAULT_PACK_SIZE)?;
+		let source = create_tmp_folder("orig")?;
+		fs::write(Path::new(&source).join("testfile.txt"), "backmeup stevie")?;
+		sets.push(backup_folder(&source, &dest, &options)?);
+
+		let result = check_chunks(&dest)?;
+		assert!(result.is_ok(), "{:?}", result);
+		assert_eq!(result.checked, 3);
+
+		let chunks = Path::new(&dest).join(CHUNKS_FOLDER);
+		// repacking leaves the folders of the chunks it packed empty
+		let chunk = fs::read_dir(&chunks)?
+			.map(|entry| entry.unwrap().path())
+			.filter(|path| path.is_dir() && !path.ends_with(PACKS_FOLDER))
+			.find_map(|folder| fs::read_dir(folder).unwrap().next())
+			.unwrap()?;
+		let name = chunk.file_name().to_string_lossy().into_owned();
+		fs::remove_file(chunk.path())?;
+		let pack = fs::read_dir(chunks.join(PACKS_FOLDER))?
+			.map(|entry| entry.unwrap().path())
+			.find(|path| path.extension().is_some_and(|ext| ext == "pack"))
+			.unwrap();
+		let mut contents = fs::read(&pack)?;
+		contents[0] ^= 0xff;
+		fs::write(&pack, contents)?;
+
+		let result = check_chunks(&dest)?;
+		assert_eq!(result.checked, 2);
+		assert_eq!(result.missing, vec![(sets[2].clone(), name)]);
+		assert_eq!(result.corrupt.len(), 1);
+		Ok(())
+	}
+}
@@ -0,0 +1,311 @@
+use crate::backup_sets::manifest::hex_digest;
+use crate::cancel::check_cancelled;
+use crate::chunk_store::chunker::{for_each_chunk, ChunkSizes};
+use crate::chunk_store::store::ChunkStore;
+use crate::dhcopy::copy_folder::CopyStats;
+use crate::pause::wait_while_paused;
+use crate::progress;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+// Which chunks make up each file of a chunked set, one line per folder or
+// file, see [ChunkListEntry].
+pub const CHUNK_LIST_FILE_NAME: &str = "dhb-chunks.tsv";
+
+/// A line of a set's chunk list
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChunkListEntry {
+	/// `d<TAB>path`, listed so empty folders are restored too
+	Folder(String),
+	/// `f<TAB>digest,digest...<TAB>path`, the file's contents being its
+	/// chunks one after the other
+	File { path: String, chunks: Vec<String> },
+}
+
+/// A file written to the chunk store
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChunkedFile {
+	/// Path within the set, with `/` separators
+	pub path: String,
+	pub size: u64,
+	/// SHA-256 of the whole contents, in hex
+	pub digest: String,
+}
+
+/// What a chunked backup added to the store
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChunkStats {
+	pub chunks: u64,
+	/// Chunks that weren't in the store already
+	pub new_chunks: u64,
+	pub new_bytes: u64,
+}
+
+/// Cuts the files of each source into chunks of `sizes` in the chunk store,
+/// and writes the chunk
+/// list to `set_folder`. Each source goes under its label, or at the root
+/// for an empty label.
+pub fn write_chunked_set(
+	store: &ChunkStore,
+	sizes: ChunkSizes,
+	set_folder: &Path,
+	sources: &[(String, &str)],
+) -> io::Result<(CopyStats, ChunkStats, Vec<ChunkedFile>)> {
+	let mut writer = ChunkedSetWriter {
+		store,
+		sizes,
+		list: BufWriter::new(File::create(set_folder.join(CHUNK_LIST_FILE_NAME))?),
+		stats: CopyStats::default(),
+		chunk_stats: ChunkStats::default(),
+		files: Vec::new(),
+	};
+	for (label, source) in sources {
+		if !label.is_empty() {
+			writer.add_folder(label)?;
+		}
+		writer.add_folder_contents(Path::new(source), label)?;
+	}
+	writer.list.flush()?;
+	println!(
+		"stored {} new chunks ({} bytes), {} already in the store",
+		writer.chunk_stats.new_chunks,
+		writer.chunk_stats.new_bytes,
+		writer.chunk_stats.chunks - writer.chunk_stats.new_chunks
+	);
+	Ok((writer.stats, writer.chunk_stats, writer.files))
+}
+
+struct ChunkedSetWriter<'a> {
+	store: &'a ChunkStore,
+	sizes: ChunkSizes,
+	list: BufWriter<File>,
+	stats: CopyStats,
+	chunk_stats: ChunkStats,
+	files: Vec<ChunkedFile>,
+}
+
+impl ChunkedSetWriter<'_> {
+	fn add_folder(&mut self, path: &str) -> io::Result<()> {
+		writeln!(self.list, "d\t{}", path)?;
+		self.stats.folders += 1;
+		Ok(())
+	}
+
+	fn add_folder_contents(&mut self, source: &Path, prefix: &str) -> io::Result<()> {
+		let mut children = fs::read_dir(source)?.collect::<io::Result<Vec<_>>>()?;
+		children.sort_by_key(|entry| entry.file_name());
+		for entry in children {
+			check_cancelled()?;
+			let name = entry.file_name().to_string_lossy().into_owned();
+			let path = match prefix {
+				"" => name,
+				_ => format!("{}/{}", prefix, name),
+			};
+			if fs::metadata(entry.path())?.is_dir() {
+				self.add_folder(&path)?;
+				self.add_folder_contents(&entry.path(), &path)?;
+			} else {
+				self.add_file(&entry.path(), path)?;
+			}
+		}
+		Ok(())
+	}
+
+	fn add_file(&mut self, source: &Path, path: String) -> io::Result<()> {
+		let mut hasher = Sha256::new();
+		let mut chunks = Vec::new();
+		let mut size = 0;
+		for_each_chunk(File::open(source)?, self.sizes, |data| {
+			wait_while_paused();
+			hasher.update(data);
+			let (digest, new) = self.store.put(data)?;
+			self.chunk_stats.chunks += 1;
+			if new {
+				self.chunk_stats.new_chunks += 1;
+				self.chunk_stats.new_bytes += data.len() as u64;
+			}
+			chunks.push(digest);
+			size += data.len() as u64;
+			Ok(())
+		})?;
+		writeln!(self.list, "f\t{}\t{}", chunks.join(","), path)?;
+		self.stats.files += 1;
+		self.stats.bytes += size;
+		progress::file_done(size);
+		self.files.push(ChunkedFile {
+			path,
+			size,
+			digest: hex_digest(hasher),
+		});
+		Ok(())
+	}
+}
+
+pub fn read_chunk_list(set_folder: &Path) -> io::Result<Vec<ChunkListEntry>> {
+	let list = BufReader::new(File::open(set_folder.join(CHUNK_LIST_FILE_NAME))?);
+	list.lines()
+		.map(|line| {
+			let line = line?;
+			let malformed = || {
+				io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("malformed chunk list line: {}", line),
+				)
+			};
+			match line.split_once('\t') {
+				Some(("d", path)) => Ok(ChunkListEntry::Folder(path.to_string())),
+				Some(("f", rest)) => {
+					let (chunks, path) = rest.split_once('\t').ok_or_else(malformed)?;
+					Ok(ChunkListEntry::File {
+						path: path.to_string(),
+						chunks: chunks
+							.split(',')
+							.filter(|chunk| !chunk.is_empty())
+							.map(str::to_string)
+							.collect(),
+					})
+				}
+				_ => Err(malformed()),
+			}
+		})
+		.collect()
+}
+
+/// Reads a file's contents back from its chunks
+pub struct ChunkReader<'a> {
+	store: &'a ChunkStore,
+	chunks: std::slice::Iter<'a, String>,
+	current: io::Cursor<Vec<u8>>,
+}
+
+impl<'a> ChunkReader<'a> {
+	pub fn new(store: &'a ChunkStore, chunks: &'a [String]) -> Self {
+		ChunkReader {
+			store,
+			chunks: chunks.iter(),
+			current: io::Cursor::new(Vec::new()),
+		}
+	}
+}
+
+impl Read for ChunkReader<'_> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		loop {
+			let read = self.current.read(buf)?;
+			if read > 0 || buf.is_empty() {
+				return Ok(read);
+			}
+			match self.chunks.next() {
+				Some(digest) => self.current = io::Cursor::new(self.store.get(digest)?),
+				None => return Ok(0),
+			}
+		}
+	}
+}
+
+/// Writes the files and folders of a chunked set for which `wanted` holds
+/// into `target`
+pub fn unpack_chunked_set<F>(
+	store: &ChunkStore,
+	set_folder: &Path,
+	target: &Path,
+	wanted: F,
+) -> io::Result<CopyStats>
+where
+	F: Fn(&str) -> bool,
+{
+	let mut stats = CopyStats::default();
+	for entry in read_chunk_list(set_folder)? {
+		match entry {
+			ChunkListEntry::Folder(path) if wanted(&path) => {
+				fs::create_dir_all(target.join(checked_path(&path)?))?;
+				stats.folders += 1;
+			}
+			ChunkListEntry::File { path, chunks } if wanted(&path) => {
+				let file = target.join(checked_path(&path)?);
+				if let Some(parent) = file.parent() {
+					fs::create_dir_all(parent)?;
+				}
+				let mut reader = ChunkReader::new(store, &chunks);
+				stats.bytes += io::copy(&mut reader, &mut BufWriter::new(File::create(file)?))?;
+				stats.files += 1;
+			}
+			_ => {}
+		}
+	}
+	Ok(stats)
+}
+
+/// Refuses paths from a damaged or tampered chunk list or manifest that
+/// would end up outside the restore target
+pub fn checked_path(path: &str) -> io::Result<&Path> {
+	let relative = Path::new(path);
+	match relative
+		.components()
+		.all(|component| matches!(component, std::path::Component::Normal(_)))
+	{
+		true => Ok(relative),
+		false => Err(io::Error::new(
+			io::ErrorKind::InvalidData,
+			format!("path {} leads outside the set", path),
+		)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dhcopy::compression::Compression;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	#[test]
+	fn test_stores_each_chunk_once() -> io::Result<()> {
+		let source = create_tmp_folder("orig")?;
+		fs::create_dir_all(Path::new(&source).join("deep/empty"))?;
+		// several chunks' worth, and the same contents twice
+		let contents: Vec<u8> = (0..3 * 1024 * 1024).map(|_| rand::random()).collect();
+		fs::write(Path::new(&source).join("deep/first"), &contents)?;
+		fs::write(Path::new(&source).join("second"), &contents)?;
+		let dest = create_tmp_folder("backups")?;
+		let set_folder = Path::new(&dest).join("set");
+		fs::create_dir(&set_folder)?;
+		let store = ChunkStore::new(Path::new(&dest), Compression::ZSTD);
+
+		let (stats, chunk_stats, files) = write_chunked_set(
+			&store,
+			ChunkSizes::default(),
+			&set_folder,
+			&[(String::new(), source.as_str())],
+		)?;
+
+		assert_eq!((stats.files, stats.folders), (2, 2));
+		assert!(chunk_stats.chunks > 2);
+		assert_eq!(chunk_stats.new_chunks * 2, chunk_stats.chunks);
+		assert_eq!(chunk_stats.new_bytes, contents.len() as u64);
+		assert_eq!(files[0].path, "deep/first");
+		assert_eq!(files[0].digest, files[1].digest);
+		assert_eq!(read_chunk_list(&set_folder)?.len(), 4);
+
+		let target = create_tmp_folder("restored")?;
+		let restored = unpack_chunked_set(&store, &set_folder, Path::new(&target), |_| true)?;
+		assert_eq!(restored, stats);
+		assert_eq!(fs::read(Path::new(&target).join("second"))?, contents);
+		assert!(Path::new(&target).join("deep/empty").is_dir());
+		Ok(())
+	}
+
+	#[test]
+	fn test_reports_missing_chunk() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let store = ChunkStore::new(Path::new(&dest), Compression::None);
+		let (digest, _) = store.put(b"backmeup susie")?;
+		fs::remove_file(store.chunk_path(&digest))?;
+
+		let chunks = vec![digest];
+		let error = io::copy(&mut ChunkReader::new(&store, &chunks), &mut io::sink()).unwrap_err();
+		assert_eq!(error.kind(), io::ErrorKind::NotFound);
+		Ok(())
+	}
+}
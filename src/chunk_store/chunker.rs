@@ -0,0 +1,206 @@
+use crate::chunk_store::store::CHUNKS_FOLDER;
+use crate::parsing::size::parse_size;
+use fastcdc::v2020::{
+	StreamCDC, AVERAGE_MAX, AVERAGE_MIN, MAXIMUM_MAX, MAXIMUM_MIN, MINIMUM_MAX, MINIMUM_MIN,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Chunk sizes of a destination's chunk store, kept in [CHUNKS_FOLDER]
+pub const CHUNK_SIZES_FILE_NAME: &str = "dhb-chunk-sizes.toml";
+
+/// Bounds on the size of chunks. Chunks are cut where the contents say,
+/// not at fixed offsets, so an edit to a file only changes the chunks
+/// around it and the rest are found in the store again. Smaller chunks
+/// find more shared data, e.g. in databases edited in place, while larger
+/// ones mean fewer chunk files and shorter chunk lists, e.g. for photos
+/// that never change. Written as `min,avg,max`, e.g. `64KiB,256KiB,1MiB`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChunkSizes {
+	pub min: u32,
+	pub avg: u32,
+	pub max: u32,
+}
+
+impl Default for ChunkSizes {
+	fn default() -> Self {
+		ChunkSizes {
+			min: 256 * 1024,
+			avg: 1024 * 1024,
+			max: 4 * 1024 * 1024,
+		}
+	}
+}
+
+impl ChunkSizes {
+	/// Checks the sizes are in order and within what the chunker supports
+	pub fn validate(self) -> Result<(), String> {
+		let limits = [
+			("minimum", self.min, MINIMUM_MIN, MINIMUM_MAX),
+			("average", self.avg, AVERAGE_MIN, AVERAGE_MAX),
+			("maximum", self.max, MAXIMUM_MIN, MAXIMUM_MAX),
+		];
+		for (name, size, low, high) in limits {
+			if !(low..=high).contains(&size) {
+				return Err(format!(
+					"{} chunk size must be from {} to {} bytes, not {}",
+					name, low, high, size
+				));
+			}
+		}
+		if self.min > self.avg || self.avg > self.max {
+			return Err(format!(
+				"chunk sizes must be in order minimum, average, maximum, not {}",
+				self
+			));
+		}
+		Ok(())
+	}
+}
+
+impl fmt::Display for ChunkSizes {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{},{},{}", self.min, self.avg, self.max)
+	}
+}
+
+impl FromStr for ChunkSizes {
+	type Err = String;
+
+	fn from_str(value: &str) -> Result<Self, Self::Err> {
+		let sizes = value
+			.split(',')
+			.map(|size| {
+				let size = parse_size(size.trim())?;
+				u32::try_from(size).map_err(|_| format!("chunk size {} is too large", size))
+			})
+			.collect::<Result<Vec<_>, _>>()?;
+		let [min, avg, max] = sizes[..] else {
+			return Err(format!(
+				"expected minimum, average and maximum chunk sizes, e.g. 64KiB,256KiB,1MiB, not \"{}\"",
+				value
+			));
+		};
+		let sizes = ChunkSizes { min, avg, max };
+		sizes.validate()?;
+		Ok(sizes)
+	}
+}
+
+/// The chunk sizes of the destination's chunk store. The first chunked
+/// backup records the sizes it's given, or the defaults, and every later
+/// one uses them, as chunks cut to other sizes would rarely match those
+/// already stored. Asking for different sizes is an error.
+pub fn store_chunk_sizes(dest: &Path, requested: Option<ChunkSizes>) -> io::Result<ChunkSizes> {
+	let path = dest.join(CHUNKS_FOLDER).join(CHUNK_SIZES_FILE_NAME);
+	match fs::read_to_string(&path) {
+		Ok(contents) => {
+			let recorded: ChunkSizes = toml::from_str(&contents)
+				.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+			match requested {
+				Some(requested) if requested != recorded => Err(io::Error::new(
+					io::ErrorKind::InvalidInput,
+					format!(
+						"the chunk store in {} uses chunk sizes {}, not {}",
+						dest.display(),
+						recorded,
+						requested
+					),
+				)),
+				_ => Ok(recorded),
+			}
+		}
+		Err(e) if e.kind() == io::ErrorKind::NotFound => {
+			let sizes = requested.unwrap_or_default();
+			sizes
+				.validate()
+				.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+			fs::create_dir_all(path.parent().unwrap())?;
+			let contents = toml::to_string(&sizes).map_err(io::Error::other)?;
+			fs::write(path, contents)?;
+			Ok(sizes)
+		}
+		Err(e) => Err(e),
+	}
+}
+
+/// Cuts everything read from `source` into content-defined chunks, calling
+/// `f` with each in order
+pub fn for_each_chunk<R, F>(source: R, sizes: ChunkSizes, mut f: F) -> io::Result<()>
+where
+	R: Read,
+	F: FnMut(&[u8]) -> io::Result<()>,
+{
+	for chunk in StreamCDC::new(source, sizes.min, sizes.avg, sizes.max) {
+		f(&chunk?.data)?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	fn chunks_of(data: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+		let mut chunks = Vec::new();
+		for_each_chunk(data, ChunkSizes::default(), |chunk| {
+			chunks.push(chunk.to_vec());
+			Ok(())
+		})?;
+		Ok(chunks)
+	}
+
+	#[test]
+	fn test_insert_only_changes_nearby_chunks() -> io::Result<()> {
+		let original: Vec<u8> = (0..8 * 1024 * 1024).map(|_| rand::random()).collect();
+		let mut edited = b"a few new bytes at the start".to_vec();
+		edited.extend_from_slice(&original);
+
+		let before = chunks_of(&original)?;
+		let after = chunks_of(&edited)?;
+
+		assert_eq!(before.concat(), original);
+		assert!(before.len() > 2);
+		let reused = after.iter().filter(|chunk| before.contains(chunk)).count();
+		assert!(
+			reused >= before.len() - 1,
+			"only the first chunk should change, {} of {} reused",
+			reused,
+			before.len()
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_parses_sizes() {
+		assert_eq!(
+			"64KiB,256KiB,1MiB".parse(),
+			Ok(ChunkSizes {
+				min: 64 * 1024,
+				avg: 256 * 1024,
+				max: 1024 * 1024,
+			})
+		);
+		assert!("1MiB,256KiB,4MiB".parse::<ChunkSizes>().is_err());
+		assert!("64KiB,256KiB".parse::<ChunkSizes>().is_err());
+		assert!("64KiB,256KiB,1GiB".parse::<ChunkSizes>().is_err());
+	}
+
+	#[test]
+	fn test_records_sizes_per_store() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let dest = Path::new(&dest);
+		let small: ChunkSizes = "64KiB,256KiB,1MiB".parse().unwrap();
+
+		assert_eq!(store_chunk_sizes(dest, Some(small))?, small);
+		assert_eq!(store_chunk_sizes(dest, None)?, small);
+		let err = store_chunk_sizes(dest, Some(ChunkSizes::default())).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+		Ok(())
+	}
+}
@@ -0,0 +1,155 @@
+use crate::backup_sets::set_metadata::set_storage;
+use crate::backup_sets::trash::trash_folder;
+use crate::chunk_store::chunked_set::{read_chunk_list, ChunkListEntry, CHUNK_LIST_FILE_NAME};
+use crate::chunk_store::store::{ChunkStore, CHUNKS_FOLDER, PACKS_FOLDER};
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What a garbage collection found in the chunk store
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GcStats {
+	/// Chunks still used by a set, which are kept
+	pub kept: u64,
+	/// Chunks no set uses, and leftovers of interrupted writes
+	pub unreferenced: u64,
+	/// Space the unreferenced chunks take
+	pub bytes: u64,
+}
+
+/// Deletes the chunks no set in the destination uses any more, counting
+/// sets in the trash and unfinished ones as users, and returns what was
+/// found. With `dry_run`, nothing is deleted. The destination must be
+/// locked, or a backup could be adding chunks this doesn't know are used.
+/// Chunks in packs are left for `repack`, which drops unused ones from the
+/// packs it rewrites.
+pub fn collect_garbage(dest: &str, dry_run: bool) -> io::Result<GcStats> {
+	let used: HashSet<String> = used_chunks(dest)?.into_iter().collect();
+	let mut stats = GcStats::default();
+	let chunks = Path::new(dest).join(CHUNKS_FOLDER);
+	if !chunks.exists() {
+		return Ok(stats);
+	}
+	for folder in fs::read_dir(chunks)? {
+		let folder = folder?;
+		if folder.file_name() == PACKS_FOLDER || !folder.file_type()?.is_dir() {
+			continue;
+		}
+		for chunk in fs::read_dir(folder.path())? {
+			let chunk = chunk?;
+			if used.contains(chunk.file_name().to_string_lossy().as_ref()) {
+				stats.kept += 1;
+				continue;
+			}
+			stats.unreferenced += 1;
+			stats.bytes += chunk.metadata()?.len();
+			if !dry_run {
+				fs::remove_file(chunk.path())?;
+			}
+		}
+	}
+	Ok(stats)
+}
+
+/// Names of the chunks that sets in the destination use, see
+/// [ChunkStore::chunk_name], each once. They're in the order the newest
+/// set reads them, then those only older sets need, then those of sets in
+/// the trash.
+pub fn used_chunks(dest: &str) -> io::Result<Vec<String>> {
+	let mut used = Vec::new();
+	let mut seen = HashSet::new();
+	for set_folder in chunk_users(dest)? {
+		let Some((store, entries)) = set_chunks(&set_folder)? else {
+			continue;
+		};
+		for entry in entries {
+			if let ChunkListEntry::File { chunks, .. } = entry {
+				for digest in chunks {
+					let name = store.chunk_name(&digest);
+					if seen.insert(name.clone()) {
+						used.push(name);
+					}
+				}
+			}
+		}
+	}
+	Ok(used)
+}
+
+/// Folders of every set in the destination that could use chunks,
+/// complete or not, newest first, then those in the trash
+pub fn chunk_users(dest: &str) -> io::Result<Vec<PathBuf>> {
+	let mut users = Vec::new();
+	let trash = trash_folder(dest);
+	for folder in [Path::new(dest), trash.as_path()] {
+		let mut sets = match fs::read_dir(folder) {
+			Ok(entries) => entries.collect::<io::Result<Vec<_>>>()?,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+			Err(e) => return Err(e),
+		};
+		// set names start with when they were made
+		sets.sort_by_key(|entry| Reverse(entry.file_name()));
+		for entry in sets {
+			if entry.file_type()?.is_dir() && entry.file_name() != CHUNKS_FOLDER {
+				users.push(entry.path());
+			}
+		}
+	}
+	Ok(users)
+}
+
+/// The store a set's chunks are in and its chunk list, or `None` if it
+/// isn't chunked
+pub fn set_chunks(set_folder: &Path) -> io::Result<Option<(ChunkStore, Vec<ChunkListEntry>)>> {
+	let storage = set_storage(set_folder)?;
+	let has_list = set_folder.join(CHUNK_LIST_FILE_NAME).exists();
+	if storage.chunked && !has_list {
+		// without the list there's no knowing which chunks the set needs
+		return Err(io::Error::new(
+			io::ErrorKind::NotFound,
+			format!("{} is chunked but has no chunk list", set_folder.display()),
+		));
+	}
+	if !has_list {
+		return Ok(None);
+	}
+	let store = ChunkStore::for_set(set_folder, storage.compression);
+	Ok(Some((store, read_chunk_list(set_folder)?)))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup::backup::BackupOptions;
+	use crate::backup_sets::backup_set::BackupSet;
+	use crate::test_helpers::test_helpers::{backup_folder, create_tmp_folder};
+
+	#[test]
+	fn test_deletes_unused_chunks() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let options = BackupOptions {
+			chunked: true,
+			..Default::default()
+		};
+		for text in ["backmeup susie", "backmeup sammy"] {
+			let source = create_tmp_folder("orig")?;
+			fs::write(Path::new(&source).join("testfile.txt"), text)?;
+			backup_folder(&source, &dest, &options)?;
+		}
+		BackupSet::list(&dest)?[0].delete(&dest)?;
+
+		let found = collect_garbage(&dest, true)?;
+		assert_eq!((found.kept, found.unreferenced), (1, 1));
+		assert_eq!(collect_garbage(&dest, false)?, found);
+		assert_eq!(
+			collect_garbage(&dest, false)?,
+			GcStats {
+				kept: 1,
+				..Default::default()
+			}
+		);
+		Ok(())
+	}
+}
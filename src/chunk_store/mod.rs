@@ -0,0 +1,11 @@
+pub mod check;
+pub mod chunked_set;
+pub mod chunker;
+pub mod gc;
+pub mod repack;
+pub mod store;
+
+// Chunked sets don't hold copies of their files. File contents are cut into
+// chunks kept once per destination under `chunks/`, named by their hash, and
+// the set only lists which chunks make up each file. Unchanged files, and
+// files moved or copied between backups, take no new space.
@@ -0,0 +1,208 @@
+use crate::chunk_store::gc::used_chunks;
+use crate::chunk_store::store::{
+	read_pack_indexes, read_packed, CHUNKS_FOLDER, PACKS_FOLDER, PACK_EXTENSION,
+	PACK_INDEX_EXTENSION,
+};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Size packs are filled to unless told otherwise
+pub const DEFAULT_PACK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// What a repack did
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RepackStats {
+	pub packs_written: u64,
+	pub chunks_packed: u64,
+	pub bytes_packed: u64,
+	/// Loose chunk files and old packs deleted once their chunks were packed
+	pub loose_removed: u64,
+	pub packs_removed: u64,
+}
+
+/// Gathers the loose chunks that sets use, and the chunks of packs less
+/// than half full, into new packs of about `pack_size` bytes, then deletes
+/// what they came from. Chunks go in the order the newest set reads them,
+/// so restoring it reads the packs mostly front to back. Unused chunks in
+/// the packs rewritten are dropped; unused loose ones are left for `gc`.
+/// The destination must be locked, as for `gc`.
+pub fn repack(dest: &str, pack_size: u64) -> io::Result<RepackStats> {
+	let chunks = Path::new(dest).join(CHUNKS_FOLDER);
+	let packs = chunks.join(PACKS_FOLDER);
+	let packed = read_pack_indexes(&packs)?;
+	let mut small_packs = HashSet::new();
+	for chunk in packed.values() {
+		if !small_packs.contains(&chunk.pack) && fs::metadata(&chunk.pack)?.len() < pack_size / 2 {
+			small_packs.insert(chunk.pack.clone());
+		}
+	}
+
+	let mut stats = RepackStats::default();
+	let mut writer = PackWriter {
+		folder: packs.clone(),
+		next_number: next_pack_number(&packs)?,
+		pack_size,
+		current: None,
+	};
+	let mut loose = Vec::new();
+	for name in used_chunks(dest)? {
+		let loose_path = chunks.join(name.get(..2).unwrap_or(&name)).join(&name);
+		let is_loose = loose_path.exists();
+		let data = match packed.get(&name) {
+			Some(chunk) if small_packs.contains(&chunk.pack) => read_packed(chunk)?,
+			// already well packed, so a loose copy isn't needed
+			Some(_) => {
+				if is_loose {
+					loose.push(loose_path);
+				}
+				continue;
+			}
+			None if is_loose => fs::read(&loose_path)?,
+			// missing, which verify reports
+			None => continue,
+		};
+		if is_loose {
+			loose.push(loose_path);
+		}
+		writer.add(&name, &data, &mut stats)?;
+	}
+	writer.close(&mut stats)?;
+
+	// everything taken from these is now in complete packs with indexes
+	for path in loose {
+		fs::remove_file(path)?;
+		stats.loose_removed += 1;
+	}
+	for pack in small_packs {
+		// the index first, so a pack is never listed without its data
+		fs::remove_file(pack.with_extension(PACK_INDEX_EXTENSION))?;
+		fs::remove_file(pack)?;
+		stats.packs_removed += 1;
+	}
+	Ok(stats)
+}
+
+/// Packs are numbered, so new ones never clash with those being replaced
+fn next_pack_number(packs: &Path) -> io::Result<u64> {
+	let entries = match fs::read_dir(packs) {
+		Ok(entries) => entries,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(1),
+		Err(e) => return Err(e),
+	};
+	let mut highest = 0;
+	for entry in entries {
+		let name = entry?.file_name().to_string_lossy().into_owned();
+		if let Some(number) = name
+			.strip_prefix("pack-")
+			.and_then(|rest| rest.split('.').next())
+			.and_then(|number| number.parse().ok())
+		{
+			highest = highest.max(number);
+		}
+	}
+	Ok(highest + 1)
+}
+
+struct PackWriter {
+	folder: PathBuf,
+	next_number: u64,
+	pack_size: u64,
+	current: Option<OpenPack>,
+}
+
+struct OpenPack {
+	path: PathBuf,
+	file: File,
+	index: String,
+	size: u64,
+}
+
+impl PackWriter {
+	fn add(&mut self, name: &str, data: &[u8], stats: &mut RepackStats) -> io::Result<()> {
+		if self.current.is_none() {
+			fs::create_dir_all(&self.folder)?;
+			let path = self
+				.folder
+				.join(format!("pack-{:06}.{}", self.next_number, PACK_EXTENSION));
+			self.next_number += 1;
+			self.current = Some(OpenPack {
+				file: File::create(&path)?,
+				path,
+				index: String::new(),
+				size: 0,
+			});
+		}
+		let pack = self.current.as_mut().unwrap();
+		pack.file.write_all(data)?;
+		pack.index
+			.push_str(&format!("{}\t{}\t{}\n", name, pack.size, data.len()));
+		pack.size += data.len() as u64;
+		stats.chunks_packed += 1;
+		stats.bytes_packed += data.len() as u64;
+		if pack.size >= self.pack_size {
+			self.close(stats)?;
+		}
+		Ok(())
+	}
+
+	/// Finishes the open pack, writing its index last so the pack only
+	/// counts once it's complete
+	fn close(&mut self, stats: &mut RepackStats) -> io::Result<()> {
+		let Some(pack) = self.current.take() else {
+			return Ok(());
+		};
+		pack.file.sync_all()?;
+		let index = pack.path.with_extension(PACK_INDEX_EXTENSION);
+		let temp = pack.path.with_extension("idx.tmp");
+		let mut file = File::create(&temp)?;
+		file.write_all(pack.index.as_bytes())?;
+		file.sync_all()?;
+		fs::rename(temp, index)?;
+		stats.packs_written += 1;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup::backup::BackupOptions;
+	use crate::backup_sets::backup_set::BackupSet;
+	use crate::backup_sets::verify::verify_set;
+	use crate::dhcopy::encryption::Keyring;
+	use crate::test_helpers::test_helpers::{backup_folder, create_tmp_folder};
+
+	#[test]
+	fn test_repacks_chunks() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let options = BackupOptions {
+			chunked: true,
+			..Default::default()
+		};
+		for text in ["backmeup susie", "backmeup sammy"] {
+			let source = create_tmp_folder("orig")?;
+			fs::write(Path::new(&source).join("testfile.txt"), text)?;
+			backup_folder(&source, &dest, &options)?;
+		}
+
+		let stats = repack(&dest, DEFAULT_PACK_SIZE)?;
+
+		assert_eq!((stats.packs_written, stats.chunks_packed), (1, 2));
+		assert_eq!(stats.loose_removed, 2);
+		let sets = BackupSet::list(&dest)?;
+		for set in &sets {
+			assert!(verify_set(&dest, &set.name, &Keyring::default())?.is_ok());
+		}
+
+		// the pack is small, so it's rewritten without the deleted set's chunk
+		sets[0].delete(&dest)?;
+		let stats = repack(&dest, DEFAULT_PACK_SIZE)?;
+		assert_eq!((stats.chunks_packed, stats.packs_removed), (1, 1));
+		let packs = Path::new(&dest).join(CHUNKS_FOLDER).join(PACKS_FOLDER);
+		assert_eq!(read_pack_indexes(&packs)?.len(), 1);
+		assert!(verify_set(&dest, &sets[1].name, &Keyring::default())?.is_ok());
+		Ok(())
+	}
+}
@@ -0,0 +1,175 @@
+use crate::backup_sets::manifest::hex_digest;
+use crate::dhcopy::compression::Compression;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+/// Folder in the destination holding the chunks of all chunked sets
+pub const CHUNKS_FOLDER: &str = "chunks";
+
+/// Folder in [CHUNKS_FOLDER] holding pack files, see [PackedChunk]
+pub const PACKS_FOLDER: &str = "packs";
+
+/// Extension of a pack file. Its index has the same name with
+/// [PACK_INDEX_EXTENSION] instead, one `name<TAB>offset<TAB>length` line
+/// per chunk in it.
+pub const PACK_EXTENSION: &str = "pack";
+pub const PACK_INDEX_EXTENSION: &str = "idx";
+
+/// Where a chunk is in a pack file: `repack` gathers chunks into packs, each
+/// chunk stored exactly as it would be on its own
+#[derive(Clone, Debug, PartialEq)]
+pub struct PackedChunk {
+	pub pack: PathBuf,
+	pub offset: u64,
+	pub length: u64,
+}
+
+/// Chunks of a destination, stored by the SHA-256 of their contents under a
+/// subfolder named after the first two hex digits, e.g. `chunks/ab/abcd...`,
+/// so no folder gets too big, or gathered into pack files. Compressed chunks
+/// get the compression's extension, so the same contents compressed in
+/// another format are a separate chunk.
+#[derive(Clone, Debug)]
+pub struct ChunkStore {
+	root: PathBuf,
+	compression: Compression,
+	/// Read from the pack indexes the first time a chunk isn't found loose
+	packed: Arc<OnceLock<HashMap<String, PackedChunk>>>,
+}
+
+impl ChunkStore {
+	pub fn new(dest: &Path, compression: Compression) -> Self {
+		ChunkStore {
+			root: dest.join(CHUNKS_FOLDER),
+			compression,
+			packed: Arc::new(OnceLock::new()),
+		}
+	}
+
+	/// The store for a set in `set_folder`, which lives in its destination
+	pub fn for_set(set_folder: &Path, compression: Compression) -> Self {
+		ChunkStore::new(set_folder.parent().unwrap_or(Path::new(".")), compression)
+	}
+
+	/// Where the chunk is stored when it isn't in a pack
+	pub fn chunk_path(&self, digest: &str) -> PathBuf {
+		let folder = self.root.join(digest.get(..2).unwrap_or(digest));
+		folder.join(self.chunk_name(digest))
+	}
+
+	/// Name of the chunk's file, also its name in pack indexes
+	pub fn chunk_name(&self, digest: &str) -> String {
+		self.compression
+			.stored_path(Path::new(digest))
+			.to_string_lossy()
+			.into_owned()
+	}
+
+	/// Stores the chunk unless it's already there, returning its digest and
+	/// whether it was new
+	pub fn put(&self, data: &[u8]) -> io::Result<(String, bool)> {
+		let mut hasher = Sha256::new();
+		hasher.update(data);
+		let digest = hex_digest(hasher);
+		let path = self.chunk_path(&digest);
+		if path.exists() || self.packed()?.contains_key(&self.chunk_name(&digest)) {
+			return Ok((digest, false));
+		}
+		fs::create_dir_all(path.parent().unwrap())?;
+		// written under a temporary name so an interrupted write never
+		// leaves a truncated chunk where later sets would reuse it
+		let mut temp = path.clone().into_os_string();
+		temp.push(".tmp");
+		self.compression.write(&mut &data[..], Path::new(&temp))?;
+		fs::rename(&temp, &path)?;
+		Ok((digest, true))
+	}
+
+	/// Reads back a chunk's contents
+	pub fn get(&self, digest: &str) -> io::Result<Vec<u8>> {
+		let path = self.chunk_path(digest);
+		let mut reader = match self.compression.reader(&path) {
+			Err(e) if e.kind() == io::ErrorKind::NotFound => {
+				let Some(packed) = self.packed()?.get(&self.chunk_name(digest)) else {
+					return Err(io::Error::new(
+						io::ErrorKind::NotFound,
+						format!("chunk {} is missing", digest),
+					));
+				};
+				self.compression
+					.decode(io::Cursor::new(read_packed(packed)?))?
+			}
+			result => result?,
+		};
+		let mut data = Vec::new();
+		reader.read_to_end(&mut data)?;
+		Ok(data)
+	}
+
+	/// Where each packed chunk is, by [ChunkStore::chunk_name]
+	pub fn packed(&self) -> io::Result<&HashMap<String, PackedChunk>> {
+		if let Some(packed) = self.packed.get() {
+			return Ok(packed);
+		}
+		let packed = read_pack_indexes(&self.root.join(PACKS_FOLDER))?;
+		Ok(self.packed.get_or_init(|| packed))
+	}
+}
+
+/// The stored bytes of a packed chunk, still compressed
+pub fn read_packed(packed: &PackedChunk) -> io::Result<Vec<u8>> {
+	let mut pack = File::open(&packed.pack)?;
+	pack.seek(SeekFrom::Start(packed.offset))?;
+	let mut data = vec![0; packed.length as usize];
+	pack.read_exact(&mut data)?;
+	Ok(data)
+}
+
+/// Reads every pack index in `packs`. A pack without its index was being
+/// written when repacking stopped, and its chunks are still elsewhere.
+pub fn read_pack_indexes(packs: &Path) -> io::Result<HashMap<String, PackedChunk>> {
+	let mut packed = HashMap::new();
+	let entries = match fs::read_dir(packs) {
+		Ok(entries) => entries,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(packed),
+		Err(e) => return Err(e),
+	};
+	for entry in entries {
+		let index = entry?.path();
+		if index
+			.extension()
+			.is_none_or(|ext| ext != PACK_INDEX_EXTENSION)
+		{
+			continue;
+		}
+		let pack = index.with_extension(PACK_EXTENSION);
+		for line in BufReader::new(File::open(&index)?).lines() {
+			let line = line?;
+			let malformed = || {
+				io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("malformed line in {}: {}", index.display(), line),
+				)
+			};
+			let mut fields = line.split('\t');
+			let (Some(name), Some(offset), Some(length), None) =
+				(fields.next(), fields.next(), fields.next(), fields.next())
+			else {
+				return Err(malformed());
+			};
+			packed.insert(
+				name.to_string(),
+				PackedChunk {
+					pack: pack.clone(),
+					offset: offset.parse().map_err(|_| malformed())?,
+					length: length.parse().map_err(|_| malformed())?,
+				},
+			);
+		}
+	}
+	Ok(packed)
+}
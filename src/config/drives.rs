@@ -0,0 +1,176 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Destinations given as `uuid:1234-ABCD/backups` are on the drive with
+/// that filesystem UUID, at `backups` within it
+pub const UUID_PREFIX: &str = "uuid:";
+/// Destinations given as `label:USBDISK/backups` are on the drive with
+/// that volume label
+pub const LABEL_PREFIX: &str = "label:";
+
+/// A filesystem found by what's recorded on it rather than where it's
+/// mounted, which for removable drives changes between machines and
+/// plugging it in
+#[derive(Clone, Debug, PartialEq)]
+pub enum DriveId {
+	Uuid(String),
+	Label(String),
+}
+
+impl fmt::Display for DriveId {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			DriveId::Uuid(uuid) => write!(f, "UUID {}", uuid),
+			DriveId::Label(label) => write!(f, "label {}", label),
+		}
+	}
+}
+
+impl DriveId {
+	/// Splits `uuid:…` or `label:…` destinations into the drive and the
+	/// path within it, `None` for any other destination
+	pub fn parse_destination(destination: &str) -> Option<(DriveId, &str)> {
+		let (id, rest): (fn(String) -> DriveId, &str) =
+			if let Some(rest) = destination.strip_prefix(UUID_PREFIX) {
+				(DriveId::Uuid, rest)
+			} else {
+				(DriveId::Label, destination.strip_prefix(LABEL_PREFIX)?)
+			};
+		let (name, path) = rest.split_once('/').unwrap_or((rest, ""));
+		Some((id(name.to_string()), path))
+	}
+
+	/// Where the drive is mounted, failing if it isn't, so backups meant
+	/// for a removable drive never land on the disk its mount point is on
+	#[cfg(target_os = "linux")]
+	pub fn mount_point(&self) -> io::Result<PathBuf> {
+		use std::fs;
+		use std::os::unix::fs::MetadataExt;
+		use std::path::Path;
+
+		let device = match self {
+			DriveId::Uuid(uuid) => Path::new("/dev/disk/by-uuid").join(uuid),
+			DriveId::Label(label) => Path::new("/dev/disk/by-label").join(encode_label(label)),
+		};
+		let rdev = match fs::metadata(&device) {
+			Ok(metadata) => metadata.rdev(),
+			Err(e) if e.kind() == io::ErrorKind::NotFound => {
+				return Err(io::Error::new(
+					io::ErrorKind::NotFound,
+					format!("no drive with {} is connected", self),
+				))
+			}
+			Err(e) => return Err(e),
+		};
+		let device_number = format!("{}:{}", libc::major(rdev), libc::minor(rdev));
+		let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+		find_mount(&mountinfo, &device_number).ok_or_else(|| {
+			io::Error::new(
+				io::ErrorKind::NotFound,
+				format!("the drive with {} is connected but not mounted", self),
+			)
+		})
+	}
+
+	#[cfg(not(target_os = "linux"))]
+	pub fn mount_point(&self) -> io::Result<PathBuf> {
+		Err(io::Error::new(
+			io::ErrorKind::Unsupported,
+			format!(
+				"finding drives by {} is not supported on this platform",
+				self
+			),
+		))
+	}
+}
+
+/// Labels are escaped in /dev/disk/by-label as udev does it, e.g. a space
+/// as `\x20`
+fn encode_label(label: &str) -> String {
+	label
+		.chars()
+		.map(
+			|c| match c.is_ascii_alphanumeric() || "#+-.:=@_".contains(c) || !c.is_ascii() {
+				true => c.to_string(),
+				false => format!("\\x{:02x}", c as u32),
+			},
+		)
+		.collect()
+}
+
+/// The mount point of the whole filesystem on the device, given as
+/// `major:minor`, in the contents of /proc/self/mountinfo. Bind mounts of
+/// folders within it are skipped.
+fn find_mount(mountinfo: &str, device_number: &str) -> Option<PathBuf> {
+	mountinfo.lines().find_map(|line| {
+		let fields: Vec<&str> = line.split(' ').collect();
+		match fields[..] {
+			[_, _, number, "/", mount_point, ..] if number == device_number => {
+				Some(PathBuf::from(unescape_mount_point(mount_point)))
+			}
+			_ => None,
+		}
+	})
+}
+
+/// Mount points have spaces and other awkward characters as octal escapes,
+/// e.g. `\040`
+pub(crate) fn unescape_mount_point(escaped: &str) -> String {
+	let mut bytes = Vec::new();
+	let mut rest = escaped.as_bytes();
+	while let Some((&byte, tail)) = rest.split_first() {
+		let octal = tail
+			.get(..3)
+			.and_then(|digits| std::str::from_utf8(digits).ok())
+			.and_then(|digits| u8::from_str_radix(digits, 8).ok());
+		match (byte, octal) {
+			(b'\\', Some(decoded)) => {
+				bytes.push(decoded);
+				rest = &tail[3..];
+			}
+			_ => {
+				bytes.push(byte);
+				rest = tail;
+			}
+		}
+	}
+	String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const MOUNTINFO: &str = "\
+22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw
+97 22 8:17 /backups /srv/backups rw,relatime shared:50 - exfat /dev/sdb1 rw
+98 22 8:17 / /media/susie/USB\\040DISK rw,nosuid shared:51 - exfat /dev/sdb1 rw
+";
+
+	#[test]
+	fn test_finds_mount_points() {
+		assert_eq!(
+			find_mount(MOUNTINFO, "8:17"),
+			Some(PathBuf::from("/media/susie/USB DISK"))
+		);
+		assert_eq!(find_mount(MOUNTINFO, "259:2"), Some(PathBuf::from("/")));
+		assert_eq!(find_mount(MOUNTINFO, "8:33"), None);
+		assert_eq!(encode_label("USB DISK/2"), "USB\\x20DISK\\x2f2");
+	}
+
+	#[test]
+	fn test_parses_drive_destinations() {
+		assert_eq!(
+			DriveId::parse_destination("uuid:1234-ABCD/dhb/backups"),
+			Some((DriveId::Uuid("1234-ABCD".to_string()), "dhb/backups"))
+		);
+		assert_eq!(
+			DriveId::parse_destination("label:USBDISK"),
+			Some((DriveId::Label("USBDISK".to_string()), ""))
+		);
+		assert_eq!(DriveId::parse_destination("/media/usb"), None);
+		let missing = DriveId::Uuid("00000000-dhb-missing".to_string());
+		assert!(missing.mount_point().is_err());
+	}
+}
@@ -0,0 +1,4 @@
+pub mod drives;
+pub mod profiles;
+pub mod remotes;
+pub mod sources;
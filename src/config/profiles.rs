@@ -0,0 +1,202 @@
+use crate::parsing::duration::parse_duration;
+use crate::power::OnBattery;
+use crate::schedule::cron::Schedule;
+use chrono::TimeDelta;
+use serde::{Deserialize, Deserializer};
+
+/// A backup defined once in the config, run by the daemon on its schedule
+/// or by hand with `run --profile photos`, e.g.
+///
+/// ```toml
+/// [profiles.photos]
+/// sources = ["/home/susie/Pictures"]
+/// destination = "remote:nas"
+/// schedule = "30 2 * * *"
+/// jitter = "30min"
+/// min_interval = "12h"
+/// options = ["--chunked", "--keep-daily", "7"]
+/// pre_backup = "mount /mnt/nas"
+/// post_backup = "umount /mnt/nas"
+/// retries = 5
+/// retry_delay = "10min"
+/// on_battery = "pause"
+/// idle = "15min"
+/// idle_deadline = "3h"
+/// ```
+///
+/// The commands before and after the backup are given its details in
+/// `DHB_*` variables as with `--pre-backup` and `--post-backup`, and
+/// `DHB_PROFILE`, the profile's name, when the daemon runs it.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+	pub sources: Vec<String>,
+	/// As given on the command line, e.g. a folder or `remote:name`
+	pub destination: String,
+	/// When the daemon runs it, see [Schedule]. Profiles without one
+	/// aren't run by the daemon.
+	pub schedule: Option<Schedule>,
+	/// Up to how long after the scheduled time the daemon waits, picked at
+	/// random for each run, so machines sharing a schedule and a
+	/// destination don't all start at once
+	#[serde(default, deserialize_with = "deserialize_optional_duration")]
+	pub jitter: Option<TimeDelta>,
+	/// Whether the daemon runs it as soon as it starts if it missed a
+	/// scheduled time while it wasn't running, e.g. with the machine off
+	#[serde(default = "default_catch_up")]
+	pub catch_up: bool,
+	/// The daemon doesn't start it again sooner than this after it last
+	/// did, even if it's due or catching up, retries aside
+	#[serde(default, deserialize_with = "deserialize_optional_duration")]
+	pub min_interval: Option<TimeDelta>,
+	/// Anything else for the backup, as it would be given on the command
+	/// line
+	#[serde(default)]
+	pub options: Vec<String>,
+	/// Run through the shell before the backup, which fails if it does
+	pub pre_backup: Option<String>,
+	/// Run through the shell once the backup's done, however it went
+	pub post_backup: Option<String>,
+	/// How many more times the daemon tries a run that fails, e.g. as
+	/// the destination wasn't there
+	#[serde(default = "default_retries")]
+	pub retries: u32,
+	/// How long the daemon waits before retrying, doubling each time
+	#[serde(
+		default = "default_retry_delay",
+		deserialize_with = "deserialize_duration"
+	)]
+	pub retry_delay: TimeDelta,
+	/// Whether the daemon holds off while the machine's on battery, see
+	/// [OnBattery]
+	#[serde(default)]
+	pub on_battery: OnBattery,
+	/// How long the system has to have been idle before the daemon starts
+	/// a run, so big backups stay out of the way while it's in use
+	#[serde(default, deserialize_with = "deserialize_optional_duration")]
+	pub idle: Option<TimeDelta>,
+	/// How long after it's due a run waiting for the system to be idle
+	/// starts anyway
+	#[serde(
+		default = "default_idle_deadline",
+		deserialize_with = "deserialize_duration"
+	)]
+	pub idle_deadline: TimeDelta,
+}
+
+fn default_catch_up() -> bool {
+	true
+}
+
+fn default_retries() -> u32 {
+	3
+}
+
+fn default_retry_delay() -> TimeDelta {
+	TimeDelta::minutes(5)
+}
+
+fn default_idle_deadline() -> TimeDelta {
+	TimeDelta::hours(4)
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<TimeDelta, D::Error> {
+	let text = String::deserialize(deserializer)?;
+	parse_duration(&text).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_optional_duration<'de, D: Deserializer<'de>>(
+	deserializer: D,
+) -> Result<Option<TimeDelta>, D::Error> {
+	deserialize_duration(deserializer).map(Some)
+}
+
+impl Profile {
+	/// The command line arguments that run the profile's backup
+	pub fn backup_args(&self) -> Vec<String> {
+		let mut args = Vec::new();
+		for source in &self.sources {
+			args.push("--source".to_string());
+			args.push(source.clone());
+		}
+		args.push("--destination".to_string());
+		args.push(self.destination.clone());
+		if let Some(command) = &self.pre_backup {
+			args.push("--pre-backup".to_string());
+			args.push(command.clone());
+		}
+		if let Some(command) = &self.post_backup {
+			args.push("--post-backup".to_string());
+			args.push(command.clone());
+		}
+		args.extend(self.options.iter().cloned());
+		args
+	}
+
+	/// How long to wait before trying again after the given attempt
+	/// failed, the first being the scheduled run, or None once there have
+	/// been as many retries as allowed
+	pub fn retry_delay_after(&self, attempt: u32) -> Option<TimeDelta> {
+		if attempt == 0 || attempt > self.retries {
+			return None;
+		}
+		self.retry_delay.checked_mul(1 << (attempt - 1).min(16))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::config::remotes::Config;
+	use crate::power::OnBattery;
+	use chrono::TimeDelta;
+
+	#[test]
+	fn test_reads_profiles() {
+		let config: Config = toml::from_str(
+			r#"
+[profiles.photos]
+sources = ["/home/susie/Pictures", "/home/susie/Videos"]
+destination = "remote:nas"
+schedule = "30 2 * * *"
+jitter = "1h"
+catch_up = false
+options = ["--chunked"]
+post_backup = "systemctl start photo-index"
+retry_delay = "2min"
+on_battery = "wait"
+idle = "10min"
+"#,
+		)
+		.unwrap();
+
+		let photos = &config.profiles["photos"];
+		assert_eq!(photos.schedule.as_ref().unwrap().to_string(), "30 2 * * *");
+		assert_eq!(
+			photos.backup_args(),
+			vec![
+				"--source",
+				"/home/susie/Pictures",
+				"--source",
+				"/home/susie/Videos",
+				"--destination",
+				"remote:nas",
+				"--post-backup",
+				"systemctl start photo-index",
+				"--chunked"
+			]
+		);
+		assert_eq!(photos.retry_delay_after(1), Some(TimeDelta::minutes(2)));
+		assert_eq!(photos.retry_delay_after(3), Some(TimeDelta::minutes(8)));
+		assert_eq!(photos.retry_delay_after(4), None);
+		assert_eq!(photos.jitter, Some(TimeDelta::hours(1)));
+		assert!(!photos.catch_up);
+		assert_eq!(photos.min_interval, None);
+		assert_eq!(photos.on_battery, OnBattery::Wait);
+		assert_eq!(photos.idle, Some(TimeDelta::minutes(10)));
+		assert_eq!(photos.idle_deadline, TimeDelta::hours(4));
+		let bad = toml::from_str::<Config>(
+			"[profiles.photos]\nsources = []\ndestination = \"/mnt\"\nschedule = \"daily\"\n",
+		);
+		assert!(bad.is_err());
+	}
+}
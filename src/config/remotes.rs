@@ -0,0 +1,242 @@
+use crate::config::drives::DriveId;
+use crate::config::profiles::Profile;
+use crate::config::sources::SourceConfig;
+use crate::notify::NotifyConfig;
+use crate::storage::sftp::SftpLocation;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Destinations given as `remote:name` are looked up in the config file
+pub const REMOTE_PREFIX: &str = "remote:";
+
+const DEFAULT_SFTP_PORT: u16 = 22;
+
+/// The config file, by default `disk-hog-backup/config.toml` in the user's
+/// config folder
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+	/// Destinations defined once and used by name, like rclone's remotes,
+	/// so commands stay short and login details stay out of shell history
+	#[serde(default)]
+	pub remotes: BTreeMap<String, Remote>,
+	#[serde(default)]
+	pub notify: NotifyConfig,
+	/// Settings for sources, by path
+	#[serde(default)]
+	pub sources: BTreeMap<String, SourceConfig>,
+	/// Backups the daemon runs on schedules, by name
+	#[serde(default)]
+	pub profiles: BTreeMap<String, Profile>,
+}
+
+/// A named destination, e.g.
+///
+/// ```toml
+/// [remotes.nas]
+/// type = "sftp"
+/// host = "nas.local"
+/// user = "susie"
+/// path = "/srv/backups"
+/// identity_file = "~/.ssh/nas_backups"
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Remote {
+	/// A folder on a local or mounted filesystem
+	Local { path: String },
+	/// A folder on a removable drive, found by its filesystem UUID or its
+	/// volume label wherever it's mounted. Backups refuse to run while it
+	/// isn't.
+	Drive {
+		uuid: Option<String>,
+		label: Option<String>,
+		/// Within the drive, defaulting to its top folder
+		#[serde(default)]
+		path: String,
+	},
+	/// A folder on an SSH server, see [SftpLocation]
+	Sftp {
+		host: String,
+		/// Defaults to the local user
+		user: Option<String>,
+		port: Option<u16>,
+		path: String,
+		/// Private key to log in with, `~/` meaning the home folder
+		identity_file: Option<String>,
+	},
+}
+
+/// Where a destination is, once any `remote:name` has been looked up
+#[derive(Clone, Debug, PartialEq)]
+pub enum Destination {
+	Local(String),
+	Sftp(SftpLocation),
+}
+
+impl Config {
+	/// Reads the config file. A missing file is an empty config unless it
+	/// was asked for by name.
+	pub fn load(path: Option<&Path>) -> io::Result<Config> {
+		let (path, required) = match path {
+			Some(path) => (path.to_path_buf(), true),
+			None => match default_config_path() {
+				Some(path) => (path, false),
+				None => return Ok(Config::default()),
+			},
+		};
+		match fs::read_to_string(&path) {
+			Ok(contents) => toml::from_str(&contents).map_err(|e| {
+				io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("{}: {}", path.display(), e),
+				)
+			}),
+			Err(e) if e.kind() == io::ErrorKind::NotFound && !required => Ok(Config::default()),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Works out where a destination given on the command line is: a
+	/// `remote:name` from the config, an `sftp://` URL, a folder on a drive
+	/// given as `uuid:…` or `label:…`, or a local folder
+	pub fn resolve(&self, destination: &str) -> io::Result<Destination> {
+		if let Some((drive, path)) = DriveId::parse_destination(destination) {
+			return on_drive(&drive, path);
+		}
+		if SftpLocation::is_sftp_url(destination) {
+			return destination
+				.parse()
+				.map(Destination::Sftp)
+				.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e));
+		}
+		let Some(name) = destination.strip_prefix(REMOTE_PREFIX) else {
+			return Ok(Destination::Local(destination.to_string()));
+		};
+		match self.remotes.get(name) {
+			Some(Remote::Local { path }) => Ok(Destination::Local(path.clone())),
+			Some(Remote::Drive { uuid, label, path }) => match (uuid, label) {
+				(Some(uuid), None) => on_drive(&DriveId::Uuid(uuid.clone()), path),
+				(None, Some(label)) => on_drive(&DriveId::Label(label.clone()), path),
+				_ => Err(io::Error::new(
+					io::ErrorKind::InvalidInput,
+					format!("remote {} needs either a uuid or a label", name),
+				)),
+			},
+			Some(Remote::Sftp {
+				host,
+				user,
+				port,
+				path,
+				identity_file,
+			}) => Ok(Destination::Sftp(SftpLocation {
+				user: match user {
+					Some(user) => user.clone(),
+					None => env::var("USER").map_err(|_| {
+						io::Error::new(
+							io::ErrorKind::InvalidInput,
+							format!("remote {} has no user, and $USER isn't set", name),
+						)
+					})?,
+				},
+				host: host.clone(),
+				port: port.unwrap_or(DEFAULT_SFTP_PORT),
+				path: path.clone(),
+				identity_file: identity_file.as_deref().map(expand_home),
+			})),
+			None => Err(io::Error::new(
+				io::ErrorKind::NotFound,
+				format!("no remote named {} in the config file", name),
+			)),
+		}
+	}
+}
+
+fn on_drive(drive: &DriveId, path: &str) -> io::Result<Destination> {
+	let folder = drive.mount_point()?.join(path);
+	Ok(Destination::Local(folder.to_string_lossy().into_owned()))
+}
+
+/// `$XDG_CONFIG_HOME/disk-hog-backup/config.toml`, or under `~/.config`
+pub fn default_config_path() -> Option<PathBuf> {
+	let config_home = match env::var_os("XDG_CONFIG_HOME") {
+		Some(config_home) => PathBuf::from(config_home),
+		None => Path::new(&env::var_os("HOME")?).join(".config"),
+	};
+	Some(config_home.join("disk-hog-backup").join("config.toml"))
+}
+
+fn expand_home(path: &str) -> PathBuf {
+	match (path.strip_prefix("~/"), env::var_os("HOME")) {
+		(Some(rest), Some(home)) => Path::new(&home).join(rest),
+		_ => PathBuf::from(path),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	const CONFIG: &str = r#"
+[remotes.usb]
+type = "local"
+path = "/media/usb/backups"
+
+[remotes.nas]
+type = "sftp"
+host = "nas.local"
+user = "susie"
+port = 2222
+path = "/srv/backups"
+identity_file = "/keys/nas"
+"#;
+
+	#[test]
+	fn test_resolves_remotes() -> io::Result<()> {
+		let folder = create_tmp_folder("config")?;
+		let path = Path::new(&folder).join("config.toml");
+		fs::write(&path, CONFIG)?;
+		let config = Config::load(Some(&path))?;
+
+		assert_eq!(
+			config.resolve("remote:usb")?,
+			Destination::Local("/media/usb/backups".to_string())
+		);
+		assert_eq!(
+			config.resolve("remote:nas")?,
+			Destination::Sftp(SftpLocation {
+				user: "susie".to_string(),
+				host: "nas.local".to_string(),
+				port: 2222,
+				path: "/srv/backups".to_string(),
+				identity_file: Some(PathBuf::from("/keys/nas")),
+			})
+		);
+		assert_eq!(
+			config.resolve("/mnt/backups")?,
+			Destination::Local("/mnt/backups".to_string())
+		);
+		assert_eq!(
+			config.resolve("remote:missing").unwrap_err().kind(),
+			io::ErrorKind::NotFound
+		);
+		assert!(config.resolve("uuid:00000000-dhb-missing/backups").is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_rejects_unknown_remote_types() -> io::Result<()> {
+		let folder = create_tmp_folder("config")?;
+		let path = Path::new(&folder).join("config.toml");
+		fs::write(&path, "[remotes.cloud]\ntype = \"s3\"\npath = \"bucket\"\n")?;
+		let err = Config::load(Some(&path)).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+		assert!(Config::load(Some(&Path::new(&folder).join("missing.toml"))).is_err());
+		Ok(())
+	}
+}
@@ -0,0 +1,69 @@
+use crate::config::remotes::Config;
+use crate::hooks::HookFailure;
+use serde::Deserialize;
+use std::fs;
+
+/// Settings for one source, by its path, e.g. to dump a database into a
+/// folder that's then backed up
+///
+/// ```toml
+/// [sources."/srv/dumps"]
+/// before_backup = "pg_dump --format=custom shop > /srv/dumps/shop.dump"
+/// on_failure = "warn"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SourceConfig {
+	/// A shell command run before the source is backed up, with the
+	/// source's path in `DHB_SOURCE`
+	pub before_backup: Option<String>,
+	/// Whether the command failing fails the run, as by default, or only
+	/// warns
+	#[serde(default)]
+	pub on_failure: HookFailure,
+}
+
+impl Config {
+	/// The settings for a source, found by the path it's given as or, if
+	/// they both exist, the folder it's in the config as
+	pub fn source(&self, source: &str) -> Option<&SourceConfig> {
+		if let Some(config) = self.sources.get(source) {
+			return Some(config);
+		}
+		let canonical = fs::canonicalize(source).ok()?;
+		self.sources.iter().find_map(|(path, config)| {
+			(fs::canonicalize(path).ok()? == canonical).then_some(config)
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+	use std::io;
+	use std::path::Path;
+
+	#[test]
+	fn test_finds_source_settings() -> io::Result<()> {
+		let folder = create_tmp_folder("dumps")?;
+		let config: Config = toml::from_str(&format!(
+			"[sources.{:?}]\nbefore_backup = \"pg_dump shop\"\non_failure = \"warn\"\n",
+			folder
+		))
+		.map_err(io::Error::other)?;
+
+		let expected = SourceConfig {
+			before_backup: Some("pg_dump shop".to_string()),
+			on_failure: HookFailure::Warn,
+		};
+		assert_eq!(config.source(&folder), Some(&expected));
+		let same_folder = Path::new(&folder).join(".");
+		assert_eq!(
+			config.source(&same_folder.to_string_lossy()),
+			Some(&expected)
+		);
+		assert_eq!(config.source("/srv/elsewhere"), None);
+		Ok(())
+	}
+}
@@ -0,0 +1,559 @@
+use crate::backup_sets::manifest::hex_digest;
+use crate::cancel::check_cancelled;
+use crate::dhcopy::compression::{CompressWriter, Compression};
+use crate::dhcopy::copy_folder::CopyStats;
+use crate::pause::wait_while_paused;
+use crate::progress;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tar::{Archive, Builder, EntryType, Header};
+
+/// Name of a set's archive before the compression's extension is added
+pub const ARCHIVE_BASE_NAME: &str = "dhb-archive.tar";
+
+// Where to find each file in the archive, one
+// `volume<TAB>start<TAB>offset<TAB>size<TAB>path` line per file, see
+// [ArchivedFile].
+pub const ARCHIVE_INDEX_FILE_NAME: &str = "dhb-archive-index.tsv";
+
+/// How a set's archive is laid out on disk
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ArchiveLayout {
+	pub compression: Compression,
+	/// Split the archive into numbered volumes of this many bytes, as with
+	/// `split`, so `cat` of the volumes gives back the whole archive
+	pub volume_size: Option<u64>,
+}
+
+/// A file written to an archive
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArchivedFile {
+	/// Path within the archive, with `/` separators
+	pub path: String,
+	/// First volume needed to read the file, counting from 1
+	pub volume: u64,
+	/// Position in the stored archive, across volumes, that reading can
+	/// start from to reach the file. The compression is restarted at the
+	/// first file in each volume so reading needn't start at the beginning.
+	pub start: u64,
+	/// Offset of the file's first header in the uncompressed tar stream
+	pub offset: u64,
+	pub size: u64,
+	/// SHA-256 of the contents, in hex
+	pub digest: String,
+}
+
+/// Streams the folders into a single tar archive in `set_folder`, compressed
+/// and split as the layout says, along with its index. Each source is
+/// archived under its label, or at the root for an empty label.
+pub fn write_archive(
+	set_folder: &Path,
+	sources: &[(String, &str)],
+	layout: ArchiveLayout,
+) -> io::Result<(CopyStats, Vec<ArchivedFile>)> {
+	let volumes = VolumeWriter {
+		base: archive_path(set_folder, layout),
+		volume_size: layout.volume_size,
+		file: None,
+		position: 0,
+	};
+	println!("archiving into {:?}", volumes.base);
+	let mut builder = Builder::new(ArchiveWriter {
+		compression: layout.compression,
+		encoder: Some(layout.compression.wrap(volumes)?),
+		restart: (1, 0),
+		count: 0,
+	});
+	let mut stats = CopyStats::default();
+	let mut files = Vec::new();
+	for (label, source) in sources {
+		if !label.is_empty() {
+			builder.append_dir(label, source)?;
+			stats.folders += 1;
+		}
+		archive_folder(
+			&mut builder,
+			Path::new(source),
+			label,
+			&mut stats,
+			&mut files,
+		)?;
+	}
+	builder.into_inner()?.finish()?;
+	write_index(set_folder, &files)?;
+	Ok((stats, files))
+}
+
+fn archive_folder(
+	builder: &mut Builder<ArchiveWriter>,
+	source: &Path,
+	prefix: &str,
+	stats: &mut CopyStats,
+	files: &mut Vec<ArchivedFile>,
+) -> io::Result<()> {
+	let mut children = fs::read_dir(source)?.collect::<io::Result<Vec<_>>>()?;
+	children.sort_by_key(|entry| entry.file_name());
+	for entry in children {
+		check_cancelled()?;
+		let name = entry.file_name().to_string_lossy().into_owned();
+		let path = match prefix {
+			"" => name,
+			_ => format!("{}/{}", prefix, name),
+		};
+		let metadata = fs::metadata(entry.path())?;
+		if metadata.is_dir() {
+			builder.append_dir(&path, entry.path())?;
+			stats.folders += 1;
+			archive_folder(builder, &entry.path(), &path, stats, files)?;
+		} else {
+			let mut header = Header::new_gnu();
+			header.set_metadata(&metadata);
+			header.set_entry_type(EntryType::Regular);
+			let (volume, start) = builder.get_mut().restart_if_new_volume()?;
+			let offset = builder.get_ref().count;
+			let mut reader = HashingReader {
+				inner: File::open(entry.path())?,
+				hasher: Sha256::new(),
+			};
+			builder.append_data(&mut header, &path, &mut reader)?;
+			stats.files += 1;
+			stats.bytes += metadata.len();
+			progress::file_done(metadata.len());
+			files.push(ArchivedFile {
+				path,
+				volume,
+				start,
+				offset,
+				size: metadata.len(),
+				digest: hex_digest(reader.hasher),
+			});
+		}
+	}
+	Ok(())
+}
+
+fn write_index(set_folder: &Path, files: &[ArchivedFile]) -> io::Result<()> {
+	let mut out = BufWriter::new(File::create(set_folder.join(ARCHIVE_INDEX_FILE_NAME))?);
+	for file in files {
+		writeln!(
+			out,
+			"{}\t{}\t{}\t{}\t{}",
+			file.volume, file.start, file.offset, file.size, file.path
+		)?;
+	}
+	out.flush()
+}
+
+/// Reads back a set's archive index. Digests aren't kept in the index, see
+/// the manifest for those.
+pub fn read_index(set_folder: &Path) -> io::Result<Vec<ArchivedFile>> {
+	let index = BufReader::new(File::open(set_folder.join(ARCHIVE_INDEX_FILE_NAME))?);
+	index
+		.lines()
+		.map(|line| {
+			let line = line?;
+			let malformed = || {
+				io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("malformed archive index line: {}", line),
+				)
+			};
+			let mut fields = line.splitn(5, '\t');
+			let mut number = || -> io::Result<u64> {
+				fields
+					.next()
+					.and_then(|field| field.parse().ok())
+					.ok_or_else(malformed)
+			};
+			let (volume, start, offset, size) = (number()?, number()?, number()?, number()?);
+			Ok(ArchivedFile {
+				volume,
+				start,
+				offset,
+				size,
+				path: fields.next().ok_or_else(malformed)?.to_string(),
+				digest: String::new(),
+			})
+		})
+		.collect()
+}
+
+/// Calls `f` with the path and contents of each file in the set's archive, in order
+pub fn for_each_archived_file<F>(
+	set_folder: &Path,
+	layout: ArchiveLayout,
+	mut f: F,
+) -> io::Result<()>
+where
+	F: FnMut(&str, &mut dyn Read) -> io::Result<()>,
+{
+	for_each_archived_entry(set_folder, layout, |path, contents| match contents {
+		Some(contents) => f(path, contents),
+		None => Ok(()),
+	})
+}
+
+/// Calls `f` with the path of each folder and file in the set's archive, in
+/// order, and the contents of the files
+pub fn for_each_archived_entry<F>(
+	set_folder: &Path,
+	layout: ArchiveLayout,
+	mut f: F,
+) -> io::Result<()>
+where
+	F: FnMut(&str, Option<&mut dyn Read>) -> io::Result<()>,
+{
+	let mut archive = open_archive(set_folder, layout, 0)?;
+	for entry in archive.entries()? {
+		let mut entry = entry?;
+		let entry_type = entry.header().entry_type();
+		if entry_type.is_file() || entry_type.is_dir() {
+			let path = entry.path()?.to_string_lossy().into_owned();
+			match entry_type.is_dir() {
+				true => f(path.trim_end_matches('/'), None)?,
+				false => f(&path, Some(&mut entry))?,
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Extracts the files and folders of the set's archive for which `wanted`
+/// holds into `target`. Reading starts from `start`, see [ArchivedFile], and
+/// stops once `remaining` files have been extracted, if given.
+pub fn unpack_archive<F>(
+	set_folder: &Path,
+	layout: ArchiveLayout,
+	target: &Path,
+	start: u64,
+	mut remaining: Option<u64>,
+	wanted: F,
+) -> io::Result<CopyStats>
+where
+	F: Fn(&str) -> bool,
+{
+	let mut archive = open_archive(set_folder, layout, start)?;
+	let mut stats = CopyStats::default();
+	for entry in archive.entries()? {
+		if remaining == Some(0) {
+			break;
+		}
+		let mut entry = entry?;
+		if !wanted(&entry.path()?.to_string_lossy()) {
+			continue;
+		}
+		let entry_type = entry.header().entry_type();
+		let size = entry.size();
+		// unpack_in creates any missing parent folders, and refuses paths
+		// that would end up outside the target
+		if entry.unpack_in(target)? {
+			if entry_type.is_dir() {
+				stats.folders += 1;
+			} else if entry_type.is_file() {
+				stats.files += 1;
+				stats.bytes += size;
+				remaining = remaining.map(|remaining| remaining - 1);
+			}
+		}
+	}
+	Ok(stats)
+}
+
+fn open_archive(
+	set_folder: &Path,
+	layout: ArchiveLayout,
+	start: u64,
+) -> io::Result<Archive<Box<dyn Read>>> {
+	let mut volumes = VolumeReader {
+		base: archive_path(set_folder, layout),
+		volume_size: layout.volume_size,
+		file: None,
+		position: start,
+	};
+	// fail early if the archive isn't there at all
+	volumes.open_current()?;
+	Ok(Archive::new(layout.compression.decode(volumes)?))
+}
+
+fn archive_path(set_folder: &Path, layout: ArchiveLayout) -> PathBuf {
+	layout
+		.compression
+		.stored_path(&set_folder.join(ARCHIVE_BASE_NAME))
+}
+
+fn volume_path(base: &Path, volume_size: Option<u64>, volume: u64) -> PathBuf {
+	match volume_size {
+		None => base.to_path_buf(),
+		Some(_) => {
+			let mut name = base.as_os_str().to_owned();
+			name.push(format!(".{:03}", volume));
+			PathBuf::from(name)
+		}
+	}
+}
+
+/// Compresses the tar stream, restarting the compression when it moves into
+/// a new volume so reading can start from there
+struct ArchiveWriter {
+	compression: Compression,
+	encoder: Option<CompressWriter<VolumeWriter>>,
+	/// Volume and position of the latest restart
+	restart: (u64, u64),
+	/// Bytes of tar stream written
+	count: u64,
+}
+
+impl ArchiveWriter {
+	fn encoder(&mut self) -> &mut CompressWriter<VolumeWriter> {
+		self.encoder
+			.as_mut()
+			.expect("encoder is only taken while restarting")
+	}
+
+	/// Where reading can start to reach what's written next
+	fn restart_if_new_volume(&mut self) -> io::Result<(u64, u64)> {
+		let volumes = self.encoder().get_ref();
+		if volumes.volume() > self.restart.0 && self.compression != Compression::None {
+			let volumes = self.encoder.take().unwrap().finish()?;
+			self.restart = (volumes.volume(), volumes.position);
+			self.encoder = Some(self.compression.wrap(volumes)?);
+		} else if self.compression == Compression::None {
+			// with no compression any position will do
+			let volumes = self.encoder().get_ref();
+			self.restart = (volumes.volume(), volumes.position);
+		}
+		Ok(self.restart)
+	}
+
+	fn finish(mut self) -> io::Result<()> {
+		let mut volumes = self.encoder.take().unwrap().finish()?;
+		volumes.flush()
+	}
+}
+
+impl Write for ArchiveWriter {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let written = self.encoder().write(buf)?;
+		self.count += written as u64;
+		Ok(written)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.encoder().flush()
+	}
+}
+
+/// Writes a stream across volumes of a fixed size
+struct VolumeWriter {
+	base: PathBuf,
+	volume_size: Option<u64>,
+	file: Option<BufWriter<File>>,
+	/// Bytes written across all volumes
+	position: u64,
+}
+
+impl VolumeWriter {
+	/// The volume the next byte goes in
+	fn volume(&self) -> u64 {
+		self.volume_size.map_or(1, |size| self.position / size + 1)
+	}
+}
+
+impl Write for VolumeWriter {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let room = match self.volume_size {
+			Some(size) => {
+				let room = size - self.position % size;
+				if room == size {
+					// starting a new volume
+					if let Some(mut file) = self.file.take() {
+						file.flush()?;
+					}
+				}
+				room as usize
+			}
+			None => buf.len(),
+		};
+		if self.file.is_none() {
+			let path = volume_path(&self.base, self.volume_size, self.volume());
+			self.file = Some(BufWriter::new(File::create(path)?));
+		}
+		let written = self
+			.file
+			.as_mut()
+			.unwrap()
+			.write(&buf[..buf.len().min(room)])?;
+		self.position += written as u64;
+		Ok(written)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match &mut self.file {
+			Some(file) => file.flush(),
+			None => Ok(()),
+		}
+	}
+}
+
+/// Reads a stream back from its volumes, starting part way through
+struct VolumeReader {
+	base: PathBuf,
+	volume_size: Option<u64>,
+	file: Option<BufReader<File>>,
+	position: u64,
+}
+
+impl VolumeReader {
+	fn open_current(&mut self) -> io::Result<()> {
+		let volume = self.volume_size.map_or(1, |size| self.position / size + 1);
+		let path = volume_path(&self.base, self.volume_size, volume);
+		let mut file = File::open(&path).map_err(|e| match e.kind() {
+			io::ErrorKind::NotFound => io::Error::new(
+				io::ErrorKind::NotFound,
+				format!("archive volume {} is missing", path.display()),
+			),
+			_ => e,
+		})?;
+		file.seek(SeekFrom::Start(
+			self.volume_size
+				.map_or(self.position, |size| self.position % size),
+		))?;
+		self.file = Some(BufReader::new(file));
+		Ok(())
+	}
+}
+
+impl Read for VolumeReader {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if self.file.is_none() {
+			self.open_current()?;
+		}
+		let read = self.file.as_mut().unwrap().read(buf)?;
+		self.position += read as u64;
+		match (read, self.volume_size) {
+			(0, Some(size)) if !buf.is_empty() && self.position % size == 0 => {
+				// end of a full volume, carry on with the next if there is one
+				let next = volume_path(&self.base, self.volume_size, self.position / size + 1);
+				if !next.exists() {
+					return Ok(0);
+				}
+				self.file = None;
+				self.read(buf)
+			}
+			_ => Ok(read),
+		}
+	}
+}
+
+/// Hashes the contents as they're archived, so files are only read once
+struct HashingReader<R> {
+	inner: R,
+	hasher: Sha256,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		wait_while_paused();
+		let read = self.inner.read(buf)?;
+		self.hasher.update(&buf[..read]);
+		Ok(read)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	const THE_TEXT: &str = "backmeup susie";
+
+	#[test]
+	fn test_archives_and_unpacks() -> io::Result<()> {
+		let source = create_tmp_folder("orig")?;
+		fs::create_dir_all(Path::new(&source).join("deep"))?;
+		fs::write(Path::new(&source).join("deep/testfile.txt"), THE_TEXT)?;
+		let set_folder = create_tmp_folder("set")?;
+		let set_path = Path::new(&set_folder);
+		let layout = ArchiveLayout {
+			compression: Compression::ZSTD,
+			volume_size: None,
+		};
+
+		let (stats, files) = write_archive(set_path, &[(String::new(), source.as_str())], layout)?;
+
+		assert_eq!(stats.files, 1);
+		assert_eq!(stats.folders, 1);
+		assert_eq!(files[0].path, "deep/testfile.txt");
+		assert_eq!(
+			files[0].digest,
+			"d4c6dd316c319e25f5599ba149dfaafd1127bdd1a152b6e148217e5860b57ba7"
+		);
+		assert!(set_path.join("dhb-archive.tar.zst").exists());
+		let index = read_index(set_path)?;
+		assert_eq!(index[0].path, files[0].path);
+		assert_eq!((index[0].volume, index[0].start), (1, 0));
+
+		let target = create_tmp_folder("restored")?;
+		unpack_archive(set_path, layout, Path::new(&target), 0, None, |_| true)?;
+		assert_eq!(
+			fs::read_to_string(Path::new(&target).join("deep/testfile.txt"))?,
+			THE_TEXT
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_splits_into_volumes() -> io::Result<()> {
+		let source = create_tmp_folder("orig")?;
+		for n in 0..20 {
+			// random so compression can't squeeze it into one volume
+			let contents: Vec<u8> = (0..100_000).map(|_| rand::random()).collect();
+			fs::write(Path::new(&source).join(format!("file{:02}", n)), contents)?;
+		}
+		let set_folder = create_tmp_folder("set")?;
+		let set_path = Path::new(&set_folder);
+		let layout = ArchiveLayout {
+			compression: Compression::ZSTD,
+			volume_size: Some(500_000),
+		};
+
+		let (_, files) = write_archive(set_path, &[(String::new(), source.as_str())], layout)?;
+
+		let volumes: Vec<u64> = (1..)
+			.map(|n| set_path.join(format!("dhb-archive.tar.zst.{:03}", n)))
+			.take_while(|path| path.exists())
+			.map(|path| fs::metadata(path).map(|m| m.len()))
+			.collect::<io::Result<_>>()?;
+		assert!(volumes.len() > 2);
+		assert!(volumes.iter().all(|&size| size <= 500_000));
+		let last = files.last().unwrap();
+		assert!(last.volume > 1, "later files should start in later volumes");
+
+		// partial restore reads only from the file's restart point on
+		let target = create_tmp_folder("restored")?;
+		let stats = unpack_archive(
+			set_path,
+			layout,
+			Path::new(&target),
+			last.start,
+			Some(1),
+			|path| path == last.path,
+		)?;
+		assert_eq!(stats.files, 1);
+		assert_eq!(
+			fs::read(Path::new(&target).join(&last.path))?,
+			fs::read(Path::new(&source).join(&last.path))?
+		);
+
+		// and all of it reads back in one go
+		let mut count = 0;
+		for_each_archived_file(set_path, layout, |_, _| {
+			count += 1;
+			Ok(())
+		})?;
+		assert_eq!(count, 20);
+		Ok(())
+	}
+}
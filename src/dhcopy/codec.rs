@@ -0,0 +1,175 @@
+use crate::dhcopy::compression::{CompressWriter, Compression};
+use crate::dhcopy::encryption::{SetKey, ENCRYPTED_EXTENSION};
+use crate::storage::backend::StorageBackend;
+use crate::storage::local::LocalStorage;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Folder in the root of a set with encrypted names that holds all its files
+pub const ENCRYPTED_NAMES_FOLDER: &str = "dhb-files";
+
+/// How file contents are stored in a set: compressed, then encrypted.
+/// Reading a file back undoes both.
+#[derive(Clone, Debug, Default)]
+pub struct Codec {
+	pub compression: Compression,
+	/// Key to encrypt to, for encrypted sets
+	pub key: Option<SetKey>,
+	/// Whether files are stored under obscured names, see [Codec::stored_file]
+	pub encrypted_names: bool,
+	/// zstd dictionary files are compressed against, see
+	/// [crate::dhcopy::dictionary]
+	pub dictionary: Option<Arc<Vec<u8>>>,
+}
+
+impl From<Compression> for Codec {
+	fn from(compression: Compression) -> Self {
+		Codec {
+			compression,
+			key: None,
+			encrypted_names: false,
+			dictionary: None,
+		}
+	}
+}
+
+impl Codec {
+	/// Whether files are stored as they are
+	pub fn is_plain(&self) -> bool {
+		self.compression == Compression::None && self.key.is_none()
+	}
+
+	/// Where a file is stored in the set
+	pub fn stored_path(&self, path: &Path) -> PathBuf {
+		let path = self.compression.stored_path(path);
+		match self.key {
+			None => path,
+			Some(_) => {
+				let mut name = path.into_os_string();
+				name.push(".");
+				name.push(ENCRYPTED_EXTENSION);
+				PathBuf::from(name)
+			}
+		}
+	}
+
+	/// Where the file at `path` in the manifest is stored in the set. With
+	/// encrypted names that's under [ENCRYPTED_NAMES_FOLDER], named by
+	/// [SetKey::obscure_name] and fanned out by its first two characters.
+	pub fn stored_file(&self, set_folder: &Path, path: &str) -> PathBuf {
+		match &self.key {
+			Some(key) if self.encrypted_names => {
+				let name = key.obscure_name(path);
+				self.stored_path(
+					&set_folder
+						.join(ENCRYPTED_NAMES_FOLDER)
+						.join(&name[..2])
+						.join(&name[2..]),
+				)
+			}
+			_ => self.stored_path(&set_folder.join(path)),
+		}
+	}
+
+	/// The original name of a stored file, if it has the extensions this
+	/// codec adds
+	pub fn original_name(&self, stored: &OsStr) -> Option<OsString> {
+		match self.key {
+			None => self.compression.original_name(stored),
+			Some(_) => {
+				let compressed = stored
+					.to_str()?
+					.strip_suffix(ENCRYPTED_EXTENSION)?
+					.strip_suffix('.')?;
+				self.compression.original_name(OsStr::new(compressed))
+			}
+		}
+	}
+
+	/// Writes everything from `source` to `dest`, returning the bytes read
+	pub fn write(&self, source: &mut impl Read, dest: &Path) -> io::Result<u64> {
+		self.write_to(&LocalStorage, source, dest)
+	}
+
+	/// Like [Codec::write], into `dest` in the backend
+	pub fn write_to(
+		&self,
+		backend: &dyn StorageBackend,
+		source: &mut impl Read,
+		dest: &Path,
+	) -> io::Result<u64> {
+		let file = BufWriter::new(backend.create_file(dest)?);
+		let Some(key) = &self.key else {
+			let mut writer = self.compress(file)?;
+			let bytes = io::copy(source, &mut writer)?;
+			writer.finish()?;
+			return Ok(bytes);
+		};
+		let mut writer = self.compress(key.encrypt(file)?)?;
+		let bytes = io::copy(source, &mut writer)?;
+		writer.finish()?.finish()?.flush()?;
+		Ok(bytes)
+	}
+
+	/// Reads back the original contents of a stored file
+	pub fn reader(&self, stored: &Path) -> io::Result<Box<dyn Read>> {
+		let file = BufReader::new(File::open(stored)?);
+		match &self.key {
+			None => self.decompress(file),
+			Some(key) => self.decompress(key.decrypt(file)?),
+		}
+	}
+
+	fn compress<W: Write>(&self, inner: W) -> io::Result<CompressWriter<W>> {
+		match &self.dictionary {
+			Some(dictionary) => self.compression.wrap_with_dictionary(inner, dictionary),
+			None => self.compression.wrap(inner),
+		}
+	}
+
+	fn decompress<R: Read + 'static>(&self, inner: R) -> io::Result<Box<dyn Read>> {
+		match &self.dictionary {
+			Some(dictionary) => self.compression.decode_with_dictionary(inner, dictionary),
+			None => self.compression.decode(inner),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	#[test]
+	fn test_round_trips_compressed_and_encrypted() -> io::Result<()> {
+		let folder = create_tmp_folder("codec")?;
+		let codec = Codec {
+			compression: Compression::ZSTD,
+			key: Some(SetKey::generate()),
+			encrypted_names: false,
+			dictionary: None,
+		};
+		let stored = codec.stored_path(&Path::new(&folder).join("testfile.txt"));
+		assert!(stored.ends_with("testfile.txt.zst.age"));
+		assert_eq!(
+			codec.original_name(stored.file_name().unwrap()),
+			Some(OsString::from("testfile.txt"))
+		);
+
+		codec.write(&mut "backmeup susie".as_bytes(), &stored)?;
+
+		let mut contents = String::new();
+		codec.reader(&stored)?.read_to_string(&mut contents)?;
+		assert_eq!(contents, "backmeup susie");
+		let other_key = Codec {
+			key: Some(SetKey::generate()),
+			..codec
+		};
+		let err = other_key.reader(&stored).err().unwrap();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+		Ok(())
+	}
+}
@@ -0,0 +1,362 @@
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Levels used when none is given, the codecs' own defaults
+const ZSTD_LEVELS: (i32, i32, i32) = (1, 3, 22);
+const GZIP_LEVELS: (i32, i32, i32) = (1, 6, 9);
+
+/// How file contents are stored in a set, written as `zstd` or `zstd:7` with
+/// a level. Compressed files get an extension added to their name, and the
+/// manifest lists them by their original name and contents, so restoring and
+/// verifying need the set's compression, though not its level.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Compression {
+	#[default]
+	None,
+	/// Level 1 (fastest) to 22 (smallest)
+	Zstd(i32),
+	/// Slower and larger than zstd, but files can be unpacked with `gunzip`
+	/// anywhere without this tool. Level 1 (fastest) to 9 (smallest).
+	Gzip(i32),
+}
+
+impl Compression {
+	pub const ZSTD: Compression = Compression::Zstd(ZSTD_LEVELS.1);
+	pub const GZIP: Compression = Compression::Gzip(GZIP_LEVELS.1);
+
+	/// Whether files written with the two can be read back the same way,
+	/// whatever their levels
+	pub fn same_format(self, other: Compression) -> bool {
+		std::mem::discriminant(&self) == std::mem::discriminant(&other)
+	}
+
+	fn name(self) -> &'static str {
+		match self {
+			Compression::None => "none",
+			Compression::Zstd(_) => "zstd",
+			Compression::Gzip(_) => "gzip",
+		}
+	}
+
+	/// Extension added to the names of stored files
+	pub fn extension(self) -> Option<&'static str> {
+		match self {
+			Compression::None => None,
+			Compression::Zstd(_) => Some("zst"),
+			Compression::Gzip(_) => Some("gz"),
+		}
+	}
+
+	/// The compression that adds `extension` to names, at its default level
+	pub fn from_extension(extension: &str) -> Option<Compression> {
+		[Compression::ZSTD, Compression::GZIP]
+			.into_iter()
+			.find(|compression| compression.extension() == Some(extension))
+	}
+
+	/// Where a file is stored in the set
+	pub fn stored_path(self, path: &Path) -> PathBuf {
+		match self.extension() {
+			None => path.to_path_buf(),
+			Some(extension) => {
+				let mut name = path.as_os_str().to_owned();
+				name.push(".");
+				name.push(extension);
+				PathBuf::from(name)
+			}
+		}
+	}
+
+	/// The original name of a stored file, if it has this compression's extension
+	pub fn original_name(self, stored: &OsStr) -> Option<OsString> {
+		let Some(extension) = self.extension() else {
+			return Some(stored.to_owned());
+		};
+		let stored = stored.to_str()?;
+		let original = stored.strip_suffix(extension)?.strip_suffix('.')?;
+		(!original.is_empty()).then(|| OsString::from(original))
+	}
+
+	/// Writes everything from `source` to `dest`, returning the bytes read
+	pub fn write(self, source: &mut impl Read, dest: &Path) -> io::Result<u64> {
+		let mut writer = self.writer(dest)?;
+		let bytes = io::copy(source, &mut writer)?;
+		writer.finish()?;
+		Ok(bytes)
+	}
+
+	/// Creates `dest` for writing a stream of data with this compression
+	pub fn writer(self, dest: &Path) -> io::Result<CompressWriter> {
+		self.wrap(BufWriter::new(File::create(dest)?))
+	}
+
+	/// Compresses whatever is written on into `inner`
+	pub fn wrap<W: Write>(self, inner: W) -> io::Result<CompressWriter<W>> {
+		Ok(CompressWriter(match self {
+			Compression::None => Encoder::None(inner),
+			Compression::Zstd(level) => Encoder::Zstd(zstd::Encoder::new(inner, level)?),
+			Compression::Gzip(level) => Encoder::Gzip(GzEncoder::new(
+				inner,
+				flate2::Compression::new(level as u32),
+			)),
+		}))
+	}
+
+	/// Compresses whatever is written on into `inner` against a dictionary,
+	/// which only zstd supports
+	pub fn wrap_with_dictionary<W: Write>(
+		self,
+		inner: W,
+		dictionary: &[u8],
+	) -> io::Result<CompressWriter<W>> {
+		match self {
+			Compression::Zstd(level) => Ok(CompressWriter(Encoder::Zstd(
+				zstd::Encoder::with_dictionary(inner, level, dictionary)?,
+			))),
+			_ => Err(no_dictionary(self)),
+		}
+	}
+
+	/// Reads back the original contents of a stored file
+	pub fn reader(self, stored: &Path) -> io::Result<Box<dyn Read>> {
+		self.decode(BufReader::new(File::open(stored)?))
+	}
+
+	/// Decompresses what's read from `inner`. Several compressed streams one
+	/// after the other read back as one.
+	pub fn decode<R: Read + 'static>(self, inner: R) -> io::Result<Box<dyn Read>> {
+		Ok(match self {
+			Compression::None => Box::new(inner),
+			Compression::Zstd(_) => Box::new(Decoded(zstd::Decoder::new(inner)?)),
+			Compression::Gzip(_) => Box::new(Decoded(MultiGzDecoder::new(BufReader::new(inner)))),
+		})
+	}
+
+	/// Decompresses what's read from `inner` with the dictionary it was
+	/// compressed against
+	pub fn decode_with_dictionary<R: Read + 'static>(
+		self,
+		inner: R,
+		dictionary: &[u8],
+	) -> io::Result<Box<dyn Read>> {
+		match self {
+			Compression::Zstd(_) => Ok(Box::new(Decoded(zstd::Decoder::with_dictionary(
+				BufReader::new(inner),
+				dictionary,
+			)?))),
+			_ => Err(no_dictionary(self)),
+		}
+	}
+}
+
+fn no_dictionary(compression: Compression) -> io::Error {
+	io::Error::new(
+		io::ErrorKind::InvalidInput,
+		format!("{} compression can't use a dictionary", compression.name()),
+	)
+}
+
+/// A file being written with some compression. It must be finished, or the
+/// end of the compressed data will be missing.
+pub struct CompressWriter<W: Write = BufWriter<File>>(Encoder<W>);
+
+enum Encoder<W: Write> {
+	None(W),
+	Zstd(zstd::Encoder<'static, W>),
+	Gzip(GzEncoder<W>),
+}
+
+impl<W: Write> CompressWriter<W> {
+	/// Ends the compressed stream, returning what it was written to
+	pub fn finish(self) -> io::Result<W> {
+		let mut inner = match self.0 {
+			Encoder::None(inner) => inner,
+			Encoder::Zstd(encoder) => encoder.finish()?,
+			Encoder::Gzip(encoder) => encoder.finish()?,
+		};
+		inner.flush()?;
+		Ok(inner)
+	}
+
+	pub fn get_ref(&self) -> &W {
+		match &self.0 {
+			Encoder::None(inner) => inner,
+			Encoder::Zstd(encoder) => encoder.get_ref(),
+			Encoder::Gzip(encoder) => encoder.get_ref(),
+		}
+	}
+}
+
+impl<W: Write> Write for CompressWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match &mut self.0 {
+			Encoder::None(file) => file.write(buf),
+			Encoder::Zstd(encoder) => encoder.write(buf),
+			Encoder::Gzip(encoder) => encoder.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match &mut self.0 {
+			Encoder::None(file) => file.flush(),
+			Encoder::Zstd(encoder) => encoder.flush(),
+			Encoder::Gzip(encoder) => encoder.flush(),
+		}
+	}
+}
+
+/// Reports damaged compressed data as `InvalidData`, like a failed checksum,
+/// rather than the codec's catch-all error
+struct Decoded<R>(R);
+
+impl<R: Read> Read for Decoded<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		self.0.read(buf).map_err(|e| match e.kind() {
+			io::ErrorKind::Other | io::ErrorKind::InvalidInput | io::ErrorKind::UnexpectedEof => {
+				io::Error::new(io::ErrorKind::InvalidData, e)
+			}
+			_ => e,
+		})
+	}
+}
+
+impl fmt::Display for Compression {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Compression::None => write!(f, "none"),
+			Compression::Zstd(level) | Compression::Gzip(level) => {
+				write!(f, "{}:{}", self.name(), level)
+			}
+		}
+	}
+}
+
+impl FromStr for Compression {
+	type Err = String;
+
+	fn from_str(value: &str) -> Result<Self, Self::Err> {
+		let lower = value.to_ascii_lowercase();
+		let (name, level) = match lower.split_once(':') {
+			Some((name, level)) => (name, Some(level)),
+			None => (lower.as_str(), None),
+		};
+		let (make, (min, default, max)): (fn(i32) -> Compression, _) = match name {
+			"none" if level.is_none() => return Ok(Compression::None),
+			"none" => return Err("no compression has no level to set".to_string()),
+			"zstd" => (Compression::Zstd, ZSTD_LEVELS),
+			"gzip" | "gz" => (Compression::Gzip, GZIP_LEVELS),
+			_ => {
+				return Err(format!(
+					"unknown compression \"{}\", expected none, zstd or gzip",
+					name
+				))
+			}
+		};
+		let level = match level {
+			None => default,
+			Some(level) => level
+				.parse()
+				.ok()
+				.filter(|level| (min..=max).contains(level))
+				.ok_or_else(|| {
+					format!(
+						"{} level must be a whole number from {} to {}, not \"{}\"",
+						name, min, max, level
+					)
+				})?,
+		};
+		Ok(make(level))
+	}
+}
+
+impl TryFrom<String> for Compression {
+	type Error = String;
+
+	fn try_from(value: String) -> Result<Self, Self::Error> {
+		value.parse()
+	}
+}
+
+impl From<Compression> for String {
+	fn from(compression: Compression) -> Self {
+		compression.to_string()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+	use std::fs;
+
+	const THE_TEXT: &str = "backmeup susie backmeup susie backmeup susie";
+
+	#[test]
+	fn test_round_trips_zstd() -> io::Result<()> {
+		let folder = create_tmp_folder("compressed")?;
+		let stored = Compression::ZSTD.stored_path(&Path::new(&folder).join("notes.txt"));
+
+		let bytes = Compression::ZSTD.write(&mut THE_TEXT.as_bytes(), &stored)?;
+
+		assert_eq!(bytes, THE_TEXT.len() as u64);
+		assert_ne!(fs::read(&stored)?, THE_TEXT.as_bytes());
+		let mut contents = String::new();
+		Compression::ZSTD
+			.reader(&stored)?
+			.read_to_string(&mut contents)?;
+		assert_eq!(contents, THE_TEXT);
+		Ok(())
+	}
+
+	#[test]
+	fn test_gzip_is_standard_gzip() -> io::Result<()> {
+		let folder = create_tmp_folder("compressed")?;
+		let stored = Compression::GZIP.stored_path(&Path::new(&folder).join("notes.txt"));
+
+		Compression::GZIP.write(&mut THE_TEXT.as_bytes(), &stored)?;
+
+		assert_eq!(stored.extension(), Some(OsStr::new("gz")));
+		assert_eq!(fs::read(&stored)?[..2], [0x1f, 0x8b], "gzip magic number");
+		let mut contents = String::new();
+		MultiGzDecoder::new(File::open(&stored)?).read_to_string(&mut contents)?;
+		assert_eq!(contents, THE_TEXT);
+		Ok(())
+	}
+
+	#[test]
+	fn test_parses_levels() {
+		assert_eq!("zstd".parse(), Ok(Compression::Zstd(3)));
+		assert_eq!("zstd:7".parse(), Ok(Compression::Zstd(7)));
+		assert_eq!("GZIP:9".parse(), Ok(Compression::Gzip(9)));
+		assert_eq!("none".parse(), Ok(Compression::None));
+		assert!("zstd:23".parse::<Compression>().is_err());
+		assert!("gzip:0".parse::<Compression>().is_err());
+		assert!("gzip:fast".parse::<Compression>().is_err());
+		assert!("none:3".parse::<Compression>().is_err());
+		assert!("lz4".parse::<Compression>().is_err());
+		assert_eq!(Compression::Zstd(7).to_string(), "zstd:7");
+	}
+
+	#[test]
+	fn test_names() {
+		let zstd = Compression::ZSTD;
+		assert_eq!(
+			zstd.stored_path(Path::new("a/notes.txt")),
+			Path::new("a/notes.txt.zst")
+		);
+		assert_eq!(
+			zstd.original_name(OsStr::new("notes.txt.zst")),
+			Some(OsString::from("notes.txt"))
+		);
+		assert_eq!(zstd.original_name(OsStr::new("notes.txt")), None);
+		assert_eq!(zstd.original_name(OsStr::new(".zst")), None);
+	}
+}
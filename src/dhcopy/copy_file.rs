@@ -1,9 +1,23 @@
+#[cfg(test)]
+use crate::backup_sets::manifest::hash_file;
+use crate::dhcopy::codec::Codec;
+use crate::dhcopy::hashing_reader::HashingReader;
+use crate::error::{BackupError, Context, Operation};
+use crate::storage::backend::StorageBackend;
+#[cfg(test)]
+use crate::storage::local::{LocalSource, LocalStorage};
+use crate::storage::source::SourceBackend;
+#[cfg(test)]
 use crate::test_helpers::test_helpers::{create_tmp_folder, file_contents_matches};
+#[cfg(test)]
 use std::fs;
+#[cfg(test)]
 use std::io::{self, Write};
 use std::path::Path;
 
+#[cfg(test)]
 const THE_FILE: &str = "testfile.txt";
+#[cfg(test)]
 const THE_TEXT: &str = "backmeup susie";
 
 #[test]
@@ -17,7 +31,13 @@ fn test_copy() -> io::Result<()> {
 
 	let destination_file_path = Path::new(&dest).join(THE_FILE);
 
-	copy_file(&source_file_path, &destination_file_path)?;
+	let (digest, size) = copy_file(
+		&LocalSource,
+		&LocalStorage,
+		&source_file_path,
+		&destination_file_path,
+		&Codec::default(),
+	)?;
 
 	let contents_matches = file_contents_matches(
 		&source_file_path.to_string_lossy(),
@@ -27,10 +47,47 @@ fn test_copy() -> io::Result<()> {
 		contents_matches,
 		"file contents should be copied to backup folder"
 	);
+	assert_eq!(
+		(digest, size),
+		hash_file(&destination_file_path)?,
+		"the digest should be of the copied contents"
+	);
 
 	Ok(())
 }
 
-fn copy_file(source: &Path, dest: &Path) -> io::Result<u64> {
-	fs::copy(source, dest)
+/// Copies a file into a set, stored as the codec says, returning the digest
+/// and size of its original contents. The file is read once, each block
+/// being hashed, compressed and encrypted on its way to `dest`, so only a
+/// block of it is in memory at a time whatever the codec does. Failures
+/// say which of the two files it was.
+pub fn copy_file(
+	source_backend: &dyn SourceBackend,
+	backend: &dyn StorageBackend,
+	source: &Path,
+	dest: &Path,
+	codec: &Codec,
+) -> Result<(String, u64), BackupError> {
+	let mut reader = HashingReader::new(
+		source_backend
+			.open(source)
+			.context(Operation::Reading, source)?,
+	);
+	let stored = codec.stored_path(dest);
+	// reading the source fails here too, but rarely once it's open
+	codec
+		.write_to(backend, &mut reader, &stored)
+		.context(Operation::Writing, &stored)?;
+	if codec.is_plain() {
+		// as fs::copy would
+		let permissions = source_backend
+			.permissions(source)
+			.context(Operation::Reading, source)?;
+		if let Some(permissions) = permissions {
+			backend
+				.set_permissions(dest, permissions)
+				.context(Operation::Writing, dest)?;
+		}
+	}
+	Ok(reader.finish())
 }
@@ -1,13 +1,33 @@
+use crate::backup_sets::hash_catalog::{EarlierCopy, HashCatalog};
+use crate::cancel::check_cancelled;
+use crate::dhcopy::codec::Codec;
+use crate::dhcopy::copy_file::copy_file;
+use crate::error::{BackupError, Context, Operation};
+use crate::progress;
+use crate::storage::backend::StorageBackend;
+#[cfg(test)]
+use crate::storage::local::{LocalSource, LocalStorage};
+use crate::storage::source::SourceBackend;
+#[cfg(test)]
 use crate::test_helpers::test_helpers::create_tmp_folder;
+use std::fmt;
+#[cfg(test)]
 use std::fs;
+#[cfg(test)]
 use std::fs::File;
 use std::io;
+#[cfg(test)]
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
+#[cfg(test)]
 const EMPTY_FOLDER: &str = "NothingInHere";
+#[cfg(test)]
 const BACKUP_FOLDER_NAME: &str = "backups";
+#[cfg(test)]
 const THE_FILE: &str = "testfile.txt";
+#[cfg(test)]
 const THE_TEXT: &str = "backmeup susie";
 
 #[test]
@@ -16,8 +36,28 @@ fn test_copies_file() -> io::Result<()> {
 	make_test_file(&source, THE_FILE, THE_TEXT)?;
 	let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
 
-	copy_folder(&source, &dest)?;
+	let (stats, files, _) = copy_folder(
+		&LocalSource,
+		&LocalStorage,
+		&source,
+		&dest,
+		&Codec::default(),
+		None,
+		&CopyOrder::default(),
+	)?;
 
+	assert_eq!(files.len(), 1);
+	assert_eq!(files[0].path, THE_FILE);
+	assert_eq!(
+		stats,
+		CopyStats {
+			files: 1,
+			folders: 0,
+			bytes: THE_TEXT.len() as u64,
+			linked_bytes: 0,
+			out_of_time: false,
+		}
+	);
 	let test_file_path = Path::new(&dest).join(THE_FILE);
 	assert!(
 		test_file_path.exists(),
@@ -39,13 +79,22 @@ fn test_copy_empty_folder() -> io::Result<()> {
 
 	let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
 
-	copy_folder(&source, &dest)?;
+	copy_folder(
+		&LocalSource,
+		&LocalStorage,
+		&source,
+		&dest,
+		&Codec::default(),
+		None,
+		&CopyOrder::default(),
+	)?;
 
 	check_empty_folder_copied(&dest)?;
 
 	Ok(())
 }
 
+#[cfg(test)]
 fn check_empty_folder_copied(dest: &str) -> io::Result<()> {
 	let dir_path = Path::new(dest).join(EMPTY_FOLDER);
 	let dir = fs::read_dir(&dir_path)?;
@@ -57,11 +106,13 @@ fn check_empty_folder_copied(dest: &str) -> io::Result<()> {
 	Ok(())
 }
 
+#[cfg(test)]
 fn create_source() -> io::Result<String> {
 	let source = create_tmp_folder("orig")?;
 	Ok(source)
 }
 
+#[cfg(test)]
 fn make_test_file(folder_path: &str, filename: &str, contents: &str) -> io::Result<()> {
 	let deep_test_file_name = Path::new(folder_path).join(filename);
 	let mut file = File::create(deep_test_file_name)?;
@@ -69,21 +120,487 @@ fn make_test_file(folder_path: &str, filename: &str, contents: &str) -> io::Resu
 	Ok(())
 }
 
-pub fn copy_folder(source: &str, dest: &str) -> io::Result<()> {
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CopyStats {
+	pub files: u64,
+	pub folders: u64,
+	pub bytes: u64,
+	/// Of the bytes, those hard-linked to a copy in an earlier set rather
+	/// than written again
+	pub linked_bytes: u64,
+	/// Whether copying stopped at the deadline with files left to copy
+	pub out_of_time: bool,
+}
+
+impl CopyStats {
+	pub fn add(&mut self, other: CopyStats) {
+		self.files += other.files;
+		self.folders += other.folders;
+		self.bytes += other.bytes;
+		self.linked_bytes += other.linked_bytes;
+		self.out_of_time |= other.out_of_time;
+	}
+}
+
+/// Which files of a folder are copied first, and when copying stops, for
+/// backups with a time limit
+#[derive(Clone, Debug, Default)]
+pub struct CopyOrder {
+	/// Paths within the folder copied before anything else, in this order
+	pub priority: Vec<PathBuf>,
+	/// No file is started once it's passed, the rest being left for the
+	/// next backup
+	pub deadline: Option<Instant>,
+}
+
+impl CopyOrder {
+	/// Where a path within the folder comes among the priority paths, the
+	/// folders leading to them counting too
+	fn rank(&self, relative: &Path) -> usize {
+		self.priority
+			.iter()
+			.position(|path| path.starts_with(relative) || relative.starts_with(path))
+			.unwrap_or(self.priority.len())
+	}
+
+	fn is_out_of_time(&self) -> bool {
+		self.deadline
+			.is_some_and(|deadline| Instant::now() >= deadline)
+	}
+}
+
+/// A file copied into a set
+#[derive(Clone, Debug, PartialEq)]
+pub struct CopiedFile {
+	/// Path within the folder copied
+	pub path: String,
+	pub size: u64,
+	/// SHA-256 of the original contents, in hex
+	pub digest: String,
+	/// Whether it's a hard link to an earlier set's copy
+	pub linked: bool,
+}
+
+/// Something that went wrong with one file without stopping the backup
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileIssue {
+	/// Path within the folder copied
+	pub path: String,
+	pub problem: FileProblem,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileProblem {
+	/// Deleted after its folder was listed, so it isn't in the set
+	Vanished,
+	/// Its size changed while it was being copied, so the copy may be a
+	/// mix of the old and new contents
+	Changed,
+}
+
+impl fmt::Display for FileIssue {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.problem {
+			FileProblem::Vanished => write!(f, "{} was deleted before it was copied", self.path),
+			FileProblem::Changed => write!(f, "{} changed while it was copied", self.path),
+		}
+	}
+}
+
+/// Earlier sets' copies that the files of a folder can be linked to
+#[derive(Clone, Copy, Debug)]
+pub struct EarlierCopies<'a> {
+	pub catalog: &'a HashCatalog,
+	/// Where in the set the folder goes, as the catalog has paths within
+	/// sets
+	pub within: &'a Path,
+}
+
+/// Copies the folder into a set, hashing each file as it goes so the
+/// manifest needn't read the set back. Given earlier sets' copies, files
+/// that haven't changed since one was made are hard-linked to it without
+/// being read or written, and new copies of contents already stored are
+/// swapped for a link once it's known what the file holds. Files are
+/// copied in `order`, stopping at its deadline. Files deleted or changed
+/// while copying are returned as issues rather than failing the copy.
+pub fn copy_folder(
+	source_backend: &dyn SourceBackend,
+	backend: &dyn StorageBackend,
+	source: &str,
+	dest: &str,
+	codec: &Codec,
+	earlier: Option<EarlierCopies>,
+	order: &CopyOrder,
+) -> Result<(CopyStats, Vec<CopiedFile>, Vec<FileIssue>), BackupError> {
 	println!("backing up folder {} into {}", source, dest);
-	let contents = fs::read_dir(source)?;
+	let mut stats = CopyStats::default();
+	let mut files = Vec::new();
+	let mut issues = Vec::new();
+	let context = CopyContext {
+		source_backend,
+		backend,
+		codec,
+		earlier,
+		order,
+	};
+	copy_folder_contents(
+		&context,
+		Path::new(source),
+		Path::new(dest),
+		Path::new(""),
+		&mut stats,
+		&mut files,
+		&mut issues,
+	)?;
+	Ok((stats, files, issues))
+}
+
+/// Where from, where to and how the files of a folder are copied, the same
+/// for all of it
+struct CopyContext<'a> {
+	source_backend: &'a dyn SourceBackend,
+	backend: &'a dyn StorageBackend,
+	codec: &'a Codec,
+	earlier: Option<EarlierCopies<'a>>,
+	order: &'a CopyOrder,
+}
+
+fn copy_folder_contents(
+	context: &CopyContext,
+	source: &Path,
+	dest: &Path,
+	relative: &Path,
+	stats: &mut CopyStats,
+	files: &mut Vec<CopiedFile>,
+	issues: &mut Vec<FileIssue>,
+) -> Result<(), BackupError> {
+	let CopyContext {
+		source_backend,
+		backend,
+		codec,
+		earlier,
+		order,
+	} = *context;
+	let mut children = source_backend
+		.list(source)
+		.context(Operation::Reading, source)?;
+	children.sort_by(|a, b| a.name.cmp(&b.name));
+	children.sort_by_key(|entry| order.rank(&relative.join(&entry.name)));
 
-	for entry in contents {
-		let entry = entry?;
-		let path = entry.path();
-		let dest_path = Path::new(dest).join(entry.file_name());
+	for entry in children {
+		check_cancelled()?;
+		if stats.out_of_time || order.is_out_of_time() {
+			stats.out_of_time = true;
+			return Ok(());
+		}
+		let path = source.join(&entry.name);
+		let dest_path = dest.join(&entry.name);
+		let relative_path = relative.join(&entry.name);
 
-		if path.is_dir() {
-			fs::create_dir_all(&dest_path)?;
-			copy_folder(path.to_str().unwrap(), dest_path.to_str().unwrap())?;
+		if entry.is_dir {
+			backend
+				.create_dir_all(&dest_path)
+				.context(Operation::Creating, &dest_path)?;
+			stats.folders += 1;
+			copy_folder_contents(
+				context,
+				&path,
+				&dest_path,
+				&relative_path,
+				stats,
+				files,
+				issues,
+			)?;
 		} else {
-			fs::copy(&path, &dest_path)?;
+			let unchanged = earlier.and_then(|earlier| {
+				let within_set = earlier.within.join(&relative_path);
+				earlier
+					.catalog
+					.unchanged(&within_set.to_string_lossy(), entry.size, entry.modified)
+			});
+			let linked = match unchanged {
+				Some(unchanged) => link_unchanged(context, unchanged, &dest_path),
+				None => None,
+			};
+			let (digest, size, linked) = match linked {
+				Some(digest) => {
+					stats.linked_bytes += entry.size;
+					(digest, entry.size, true)
+				}
+				None => {
+					// hashed as it's copied, so it's only read once whether
+					// or not there's an earlier copy
+					let copied = copy_file(source_backend, backend, &path, &dest_path, codec);
+					let (digest, size) = match copied {
+						Err(BackupError::Io {
+							operation: Operation::Reading,
+							path: failed,
+							source,
+						}) if failed == path && source.kind() == io::ErrorKind::NotFound => {
+							// e.g. a temporary file, deleted once it's
+							// served its purpose
+							let _ = backend.remove_file(&codec.stored_path(&dest_path));
+							let issue = FileIssue {
+								path: relative_path.to_string_lossy().into_owned(),
+								problem: FileProblem::Vanished,
+							};
+							eprintln!("warning: {}", issue);
+							issues.push(issue);
+							continue;
+						}
+						result => result?,
+					};
+					if size != entry.size {
+						let issue = FileIssue {
+							path: relative_path.to_string_lossy().into_owned(),
+							problem: FileProblem::Changed,
+						};
+						eprintln!("warning: {}", issue);
+						issues.push(issue);
+					}
+					let mut linked = false;
+					if let Some(earlier) = earlier {
+						linked =
+							link_earlier_copy(context, earlier.catalog, &digest, size, &dest_path)
+								.context(Operation::Writing, &dest_path)?;
+						if linked {
+							stats.linked_bytes += size;
+						}
+					}
+					(digest, size, linked)
+				}
+			};
+			stats.bytes += size;
+			stats.files += 1;
+			progress::file_done(size);
+			files.push(CopiedFile {
+				path: relative_path.to_string_lossy().into_owned(),
+				size,
+				digest,
+				linked,
+			});
 		}
 	}
 	Ok(())
 }
+
+/// Hard-links an earlier set's copy of an unchanged file in at `dest`,
+/// returning the digest of its contents, or None if it couldn't be linked
+/// and needs copying after all
+fn link_unchanged(context: &CopyContext, earlier: &EarlierCopy, dest: &Path) -> Option<String> {
+	// e.g. the earlier set is on another filesystem mounted in the
+	// destination, its copy was deleted since, or the backend can't link
+	context
+		.backend
+		.hard_link(&earlier.stored, &context.codec.stored_path(dest))
+		.ok()?;
+	Some(earlier.digest.clone())
+}
+
+/// Replaces the new copy at `dest` with a hard link to an earlier set's copy
+/// of the same contents, returning whether there was one to link to
+fn link_earlier_copy(
+	context: &CopyContext,
+	catalog: &HashCatalog,
+	digest: &str,
+	size: u64,
+	dest: &Path,
+) -> io::Result<bool> {
+	// empty files cost nothing to copy
+	let Some(earlier) = catalog.find(digest).filter(|_| size > 0) else {
+		return Ok(false);
+	};
+	let stored = context.codec.stored_path(dest);
+	let mut link = stored.clone().into_os_string();
+	link.push(".dhb-link");
+	let link = PathBuf::from(link);
+	// e.g. the earlier set is on another filesystem mounted in the
+	// destination, its copy was deleted since, or the backend can't link
+	if context.backend.hard_link(earlier, &link).is_err() {
+		return Ok(false);
+	}
+	// renamed over the copy, so the file's never missing from the set
+	context.backend.rename(&link, &stored)?;
+	Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup_sets::manifest::generate_manifest;
+	use crate::backup_sets::SetStatus;
+	use crate::dhcopy::compression::Compression;
+	use crate::storage::local::{LocalSource, LocalStorage};
+	use crate::storage::source::SourceEntry;
+	use crate::test_helpers::test_helpers::{create_set, create_tmp_folder};
+	use std::fs::{self, File};
+	use std::time::{Duration, SystemTime};
+
+	#[test]
+	fn test_compresses_files() -> io::Result<()> {
+		let source = create_source()?;
+		make_test_file(&source, THE_FILE, THE_TEXT)?;
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+
+		let (stats, _, _) = copy_folder(
+			&LocalSource,
+			&LocalStorage,
+			&source,
+			&dest,
+			&Compression::ZSTD.into(),
+			None,
+			&CopyOrder::default(),
+		)?;
+
+		assert_eq!(stats.bytes, THE_TEXT.len() as u64);
+		assert!(Path::new(&dest).join("testfile.txt.zst").exists());
+		assert!(!Path::new(&dest).join(THE_FILE).exists());
+		Ok(())
+	}
+
+	#[test]
+	fn test_copies_priority_paths_first() -> io::Result<()> {
+		let source = create_source()?;
+		for folder in ["music", "photos/2023", "photos/2024"] {
+			fs::create_dir_all(Path::new(&source).join(folder))?;
+			make_test_file(&source, &format!("{}/{}", folder, THE_FILE), THE_TEXT)?;
+		}
+		let mut order = CopyOrder {
+			priority: vec![PathBuf::from("photos/2024")],
+			deadline: None,
+		};
+		assert_eq!(order.rank(Path::new("photos")), 0);
+		assert_eq!(order.rank(Path::new("photos/2024/testfile.txt")), 0);
+		assert_eq!(order.rank(Path::new("photos/2023")), 1);
+
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+		let copy = |order: &CopyOrder| {
+			copy_folder(
+				&LocalSource,
+				&LocalStorage,
+				&source,
+				&dest,
+				&Codec::default(),
+				None,
+				order,
+			)
+		};
+		let (stats, files, _) = copy(&order)?;
+		// the rest of the folders leading to it come next
+		assert_eq!(files[0].path, "photos/2024/testfile.txt");
+		assert_eq!(files[1].path, "photos/2023/testfile.txt");
+		assert_eq!(files[2].path, "music/testfile.txt");
+		assert!(!stats.out_of_time);
+
+		order.deadline = Some(Instant::now());
+		let (stats, files, _) = copy(&order)?;
+		assert!(files.is_empty());
+		assert!(stats.out_of_time);
+		Ok(())
+	}
+
+	#[test]
+	fn test_links_unchanged_files_without_copying() -> io::Result<()> {
+		let source = create_source()?;
+		make_test_file(&source, THE_FILE, THE_TEXT)?;
+		let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+		File::options()
+			.write(true)
+			.open(Path::new(&source).join(THE_FILE))?
+			.set_modified(an_hour_ago)?;
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+		create_set(&dest, "dhb-set-20010101-000000", SetStatus::Complete)?;
+		let earlier_set = Path::new(&dest).join("dhb-set-20010101-000000");
+		// the same size but not the same contents, so it shows whether the
+		// source was read at all
+		make_test_file(earlier_set.to_str().unwrap(), THE_FILE, "BACKMEUP SUSIE")?;
+		let earlier_digest = generate_manifest(&earlier_set)?.remove(0).digest;
+		let catalog = HashCatalog::load(&dest, Compression::None)?;
+		let new_set = Path::new(&dest).join("new");
+		fs::create_dir(&new_set)?;
+
+		let (stats, files, _) = copy_folder(
+			&LocalSource,
+			&LocalStorage,
+			&source,
+			new_set.to_str().unwrap(),
+			&Codec::default(),
+			Some(EarlierCopies {
+				catalog: &catalog,
+				within: Path::new(""),
+			}),
+			&CopyOrder::default(),
+		)?;
+
+		assert_eq!(stats.linked_bytes, THE_TEXT.len() as u64);
+		assert_eq!(files[0].digest, earlier_digest);
+		assert_eq!(
+			fs::read_to_string(new_set.join(THE_FILE))?,
+			"BACKMEUP SUSIE"
+		);
+		Ok(())
+	}
+
+	/// Local files, where one is deleted once listed and another grows
+	struct ChangingSource;
+
+	impl SourceBackend for ChangingSource {
+		fn list(&self, path: &Path) -> io::Result<Vec<SourceEntry>> {
+			let mut entries = LocalSource.list(path)?;
+			for entry in &mut entries {
+				if entry.name == "growing.txt" {
+					entry.size -= 1;
+				}
+			}
+			Ok(entries)
+		}
+
+		fn open(&self, path: &Path) -> io::Result<Box<dyn io::Read>> {
+			if path.ends_with("deleted.txt") {
+				return Err(io::ErrorKind::NotFound.into());
+			}
+			LocalSource.open(path)
+		}
+
+		fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+			LocalSource.canonicalize(path)
+		}
+	}
+
+	#[test]
+	fn test_reports_files_changed_while_copying() -> io::Result<()> {
+		let source = create_source()?;
+		for name in ["deleted.txt", "growing.txt", THE_FILE] {
+			make_test_file(&source, name, THE_TEXT)?;
+		}
+		let dest = create_tmp_folder(BACKUP_FOLDER_NAME)?;
+
+		let (stats, files, issues) = copy_folder(
+			&ChangingSource,
+			&LocalStorage,
+			&source,
+			&dest,
+			&Codec::default(),
+			None,
+			&CopyOrder::default(),
+		)?;
+
+		assert_eq!(stats.files, 2);
+		assert_eq!(files.len(), 2);
+		assert!(!Path::new(&dest).join("deleted.txt").exists());
+		assert_eq!(
+			issues,
+			vec![
+				FileIssue {
+					path: "deleted.txt".to_string(),
+					problem: FileProblem::Vanished,
+				},
+				FileIssue {
+					path: "growing.txt".to_string(),
+					problem: FileProblem::Changed,
+				},
+			]
+		);
+		Ok(())
+	}
+}
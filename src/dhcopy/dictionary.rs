@@ -0,0 +1,142 @@
+use crate::dhcopy::codec::Codec;
+use crate::dhcopy::encryption::SetKey;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+// The dictionary a set's files are compressed against, in the set's root.
+// Encrypted like the files in encrypted sets, as it's made of pieces of them.
+pub const DICTIONARY_FILE_NAME: &str = "dhb-zstd-dictionary";
+
+/// Files up to this size are sampled, larger ones compress well on their own
+const SAMPLE_FILE_SIZE: u64 = 16 * 1024;
+
+/// Plenty of samples for a dictionary, without reading the whole source
+const SAMPLE_BYTES: u64 = 16 * 1024 * 1024;
+
+/// zstd's own default, about 100 times smaller than the samples
+const DICTIONARY_SIZE: usize = 112 * 1024;
+
+/// Trains a zstd dictionary on small files from the sources, for files
+/// that are too small to compress well alone, like source code or JSON,
+/// but share a lot with each other. Returns `None` if there aren't enough
+/// small files to train on.
+pub fn train_dictionary(sources: &[&str]) -> io::Result<Option<Vec<u8>>> {
+	let mut samples = Vec::new();
+	let mut sampled = 0;
+	for source in sources {
+		sample_folder(Path::new(source), &mut samples, &mut sampled)?;
+	}
+	match zstd::dict::from_samples(&samples, DICTIONARY_SIZE) {
+		Ok(dictionary) => Ok(Some(dictionary)),
+		// zstd doesn't say why beyond its message, but it's always that the
+		// samples were too few or too small
+		Err(e) => {
+			println!(
+				"not using a zstd dictionary, couldn't train one on {} small files: {}",
+				samples.len(),
+				e
+			);
+			Ok(None)
+		}
+	}
+}
+
+fn sample_folder(folder: &Path, samples: &mut Vec<Vec<u8>>, sampled: &mut u64) -> io::Result<()> {
+	let mut children = fs::read_dir(folder)?.collect::<io::Result<Vec<_>>>()?;
+	children.sort_by_key(|entry| entry.file_name());
+	for entry in children {
+		if *sampled >= SAMPLE_BYTES {
+			break;
+		}
+		let file_type = entry.file_type()?;
+		if file_type.is_dir() {
+			sample_folder(&entry.path(), samples, sampled)?;
+		} else if file_type.is_file() && entry.metadata()?.len() <= SAMPLE_FILE_SIZE {
+			let contents = fs::read(entry.path())?;
+			*sampled += contents.len() as u64;
+			samples.push(contents);
+		}
+	}
+	Ok(())
+}
+
+/// Writes the dictionary into the set, encrypted to the set's key if any
+pub fn write_dictionary(
+	set_folder: &Path,
+	dictionary: &[u8],
+	key: Option<&SetKey>,
+) -> io::Result<()> {
+	let codec = Codec {
+		key: key.cloned(),
+		..Default::default()
+	};
+	codec.write(&mut &dictionary[..], &set_folder.join(DICTIONARY_FILE_NAME))?;
+	Ok(())
+}
+
+pub fn read_dictionary(set_folder: &Path, key: Option<&SetKey>) -> io::Result<Vec<u8>> {
+	let codec = Codec {
+		key: key.cloned(),
+		..Default::default()
+	};
+	let mut dictionary = Vec::new();
+	codec
+		.reader(&set_folder.join(DICTIONARY_FILE_NAME))?
+		.read_to_end(&mut dictionary)?;
+	Ok(dictionary)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dhcopy::compression::Compression;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+	use std::sync::Arc;
+
+	#[test]
+	fn test_dictionary_shrinks_small_files() -> io::Result<()> {
+		let source = create_tmp_folder("orig")?;
+		for i in 0..500 {
+			fs::write(
+				Path::new(&source).join(format!("{}.json", i)),
+				format!(
+					r#"{{"id": {}, "name": "susie {}", "status": "backed up", "tags": ["disk", "hog"]}}"#,
+					i,
+					i * 7
+				),
+			)?;
+		}
+		let dictionary = train_dictionary(&[&source])?.expect("enough samples");
+		let set_folder = create_tmp_folder("set")?;
+		let key = SetKey::generate();
+		write_dictionary(Path::new(&set_folder), &dictionary, Some(&key))?;
+		let dictionary = read_dictionary(Path::new(&set_folder), Some(&key))?;
+
+		let plain = Codec::from(Compression::ZSTD);
+		let with_dictionary = Codec {
+			dictionary: Some(Arc::new(dictionary)),
+			..plain.clone()
+		};
+		let input = Path::new(&source).join("42.json");
+		let mut sizes = Vec::new();
+		for (codec, name) in [(&plain, "plain.zst"), (&with_dictionary, "dict.zst")] {
+			let stored = Path::new(&set_folder).join(name);
+			codec.write(&mut fs::File::open(&input)?, &stored)?;
+			sizes.push(fs::metadata(&stored)?.len());
+			let mut contents = Vec::new();
+			codec.reader(&stored)?.read_to_end(&mut contents)?;
+			assert_eq!(contents, fs::read(&input)?);
+		}
+		assert!(sizes[1] < sizes[0], "{:?}", sizes);
+		Ok(())
+	}
+
+	#[test]
+	fn test_too_few_samples() -> io::Result<()> {
+		let source = create_tmp_folder("orig")?;
+		fs::write(Path::new(&source).join("testfile.txt"), "backmeup susie")?;
+		assert_eq!(train_dictionary(&[&source])?, None);
+		Ok(())
+	}
+}
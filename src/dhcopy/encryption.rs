@@ -0,0 +1,385 @@
+use age::secrecy::{ExposeSecret, SecretString};
+use age::stream::{StreamReader, StreamWriter};
+use age::x25519;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::iter;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Extension added to the names of encrypted files
+pub const ENCRYPTED_EXTENSION: &str = "age";
+
+// The set's key, encrypted to the recipients the set was backed up for.
+// Nothing in an encrypted set can be read without an identity for one of them.
+pub const SET_KEY_FILE_NAME: &str = "dhb-set-key.age";
+
+// The set's key encrypted with ChaCha20-Poly1305 under a key derived from a
+// passphrase, as the nonce followed by the ciphertext. How the key was
+// derived is in the set's metadata, see [PassphraseParams].
+pub const PASSPHRASE_KEY_FILE_NAME: &str = "dhb-set-key.pass";
+
+const NONCE_LENGTH: usize = 12;
+
+/// How the key protecting a set's key is derived from a passphrase with
+/// Argon2id. Kept in the set's metadata, so new sets can use higher costs
+/// without older sets becoming unreadable.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PassphraseParams {
+	pub algorithm: String,
+	/// Random for each set, in hex
+	pub salt: String,
+	pub memory_kib: u32,
+	pub iterations: u32,
+	pub parallelism: u32,
+}
+
+impl PassphraseParams {
+	/// Argon2id with 64MiB of memory and 3 passes, and a fresh salt
+	pub fn generate() -> Self {
+		PassphraseParams {
+			algorithm: "argon2id".to_string(),
+			salt: to_hex(&rand::random::<[u8; 16]>()),
+			memory_kib: 64 * 1024,
+			iterations: 3,
+			parallelism: 1,
+		}
+	}
+
+	fn derive_key(&self, passphrase: &SecretString) -> io::Result<Key> {
+		let invalid = |e: String| {
+			io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!("bad passphrase parameters: {}", e),
+			)
+		};
+		if self.algorithm != "argon2id" {
+			return Err(invalid(format!("unknown algorithm {}", self.algorithm)));
+		}
+		let salt = from_hex(&self.salt).ok_or_else(|| invalid("salt isn't hex".to_string()))?;
+		let params = Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+			.map_err(|e| invalid(e.to_string()))?;
+		let mut key = Key::default();
+		Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+			.hash_password_into(passphrase.expose_secret().as_bytes(), &salt, &mut key)
+			.map_err(|e| invalid(e.to_string()))?;
+		Ok(key)
+	}
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+	(0..hex.len())
+		.step_by(2)
+		.map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+		.collect()
+}
+
+/// Key the files of an encrypted set are encrypted to. Each set gets its
+/// own, kept in the set encrypted to its owner, so the owner's key can be
+/// changed by re-encrypting just that.
+#[derive(Clone)]
+pub struct SetKey(x25519::Identity);
+
+impl SetKey {
+	pub fn generate() -> Self {
+		SetKey(x25519::Identity::generate())
+	}
+
+	/// Encrypts whatever is written on into `inner`. The writer must be
+	/// finished, or the end of the file won't decrypt.
+	pub fn encrypt<W: Write>(&self, inner: W) -> io::Result<StreamWriter<W>> {
+		let recipient = self.0.to_public();
+		age::Encryptor::with_recipients(iter::once(&recipient as &dyn age::Recipient))
+			.map_err(io::Error::other)?
+			.wrap_output(inner)
+	}
+
+	/// Decrypts what's read from `inner`. Damaged data fails with InvalidData.
+	pub fn decrypt<R: Read>(&self, inner: R) -> io::Result<StreamReader<R>> {
+		age::Decryptor::new(inner)
+			.and_then(|decryptor| decryptor.decrypt(iter::once(&self.0 as &dyn age::Identity)))
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+	}
+
+	/// Stores the key in the set, encrypted to the recipients
+	pub fn write_locked(
+		&self,
+		set_folder: &Path,
+		recipients: &[x25519::Recipient],
+	) -> io::Result<()> {
+		let encryptor = age::Encryptor::with_recipients(
+			recipients
+				.iter()
+				.map(|recipient| recipient as &dyn age::Recipient),
+		)
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+		write_key_file(&set_folder.join(SET_KEY_FILE_NAME), |file| {
+			let mut writer = encryptor.wrap_output(file)?;
+			writer.write_all(self.0.to_string().expose_secret().as_bytes())?;
+			writer.finish()?;
+			Ok(())
+		})
+	}
+
+	/// Stores the key in the set, encrypted under the passphrase
+	pub fn write_with_passphrase(
+		&self,
+		set_folder: &Path,
+		passphrase: &SecretString,
+		params: &PassphraseParams,
+	) -> io::Result<()> {
+		let cipher = ChaCha20Poly1305::new(&params.derive_key(passphrase)?);
+		let nonce: [u8; NONCE_LENGTH] = rand::random();
+		let secret = self.0.to_string();
+		let ciphertext = cipher
+			.encrypt(Nonce::from_slice(&nonce), secret.expose_secret().as_bytes())
+			.map_err(|_| io::Error::other("couldn't encrypt the set key"))?;
+		write_key_file(&set_folder.join(PASSPHRASE_KEY_FILE_NAME), |file| {
+			file.write_all(&nonce)?;
+			file.write_all(&ciphertext)
+		})
+	}
+
+	/// Name a file is stored under in a set with encrypted names: a digest
+	/// of its path keyed with the set's key, which gives nothing away
+	/// without the key but is found again from the manifest
+	pub fn obscure_name(&self, path: &str) -> String {
+		let mut hasher = Sha256::new();
+		hasher.update(b"dhb-name\0");
+		hasher.update(self.0.to_string().expose_secret().as_bytes());
+		hasher.update(b"\0");
+		hasher.update(path.as_bytes());
+		to_hex(&hasher.finalize())
+	}
+}
+
+/// Writes a locked key beside its final name first, so replacing the key
+/// of an existing set never leaves it with half a key file
+fn write_key_file<F>(path: &Path, write: F) -> io::Result<()>
+where
+	F: FnOnce(&mut File) -> io::Result<()>,
+{
+	let mut temp_name = path.as_os_str().to_owned();
+	temp_name.push(".tmp");
+	let mut file = File::create(&temp_name)?;
+	write(&mut file)?;
+	file.sync_all()?;
+	fs::rename(&temp_name, path)
+}
+
+impl fmt::Debug for SetKey {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		// never the secret itself
+		write!(f, "SetKey({})", self.0.to_public())
+	}
+}
+
+/// Identities and passphrase that can unlock encrypted sets
+#[derive(Default)]
+pub struct Keyring {
+	identities: Vec<Box<dyn age::Identity>>,
+	passphrase: Option<SecretString>,
+}
+
+impl Keyring {
+	pub fn new(identities: Vec<Box<dyn age::Identity>>) -> Self {
+		Keyring {
+			identities,
+			passphrase: None,
+		}
+	}
+
+	pub fn with_passphrase(mut self, passphrase: Option<SecretString>) -> Self {
+		self.passphrase = passphrase;
+		self
+	}
+
+	/// Reads the identities from files such as `age-keygen` writes
+	pub fn from_identity_files(paths: &[String]) -> io::Result<Self> {
+		let mut identities = Vec::new();
+		for path in paths {
+			let file = age::IdentityFile::from_file(path.clone())?;
+			identities.extend(
+				file.into_identities()
+					.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+			);
+		}
+		Ok(Keyring::new(identities))
+	}
+
+	/// Decrypts the key of an encrypted set, with an identity if the set
+	/// has recipients or with the passphrase if it has passphrase parameters
+	pub fn unlock(
+		&self,
+		set_folder: &Path,
+		passphrase_params: Option<&PassphraseParams>,
+	) -> io::Result<SetKey> {
+		let has_recipients = set_folder.join(SET_KEY_FILE_NAME).exists();
+		if has_recipients && !self.identities.is_empty() {
+			return self.unlock_with_identities(set_folder);
+		}
+		if let (Some(passphrase), Some(params)) = (&self.passphrase, passphrase_params) {
+			return self.unlock_with_passphrase(set_folder, passphrase, params);
+		}
+		let needed = match (has_recipients, passphrase_params.is_some()) {
+			(true, true) => "an identity or the passphrase",
+			(false, true) => "the passphrase",
+			_ => "an identity",
+		};
+		Err(io::Error::new(
+			io::ErrorKind::PermissionDenied,
+			format!(
+				"{} is encrypted, give {} to unlock it",
+				set_folder.display(),
+				needed
+			),
+		))
+	}
+
+	fn unlock_with_identities(&self, set_folder: &Path) -> io::Result<SetKey> {
+		let file = BufReader::new(File::open(set_folder.join(SET_KEY_FILE_NAME))?);
+		let mut reader = age::Decryptor::new_buffered(file)
+			.and_then(|decryptor| {
+				decryptor.decrypt(self.identities.iter().map(|identity| identity.as_ref()))
+			})
+			.map_err(|e| match e {
+				age::DecryptError::NoMatchingKeys => io::Error::new(
+					io::ErrorKind::PermissionDenied,
+					format!(
+						"none of the identities given can unlock {}",
+						set_folder.display()
+					),
+				),
+				e => io::Error::new(io::ErrorKind::InvalidData, e),
+			})?;
+		let mut secret = String::new();
+		reader.read_to_string(&mut secret)?;
+		parse_set_key(&secret)
+	}
+
+	fn unlock_with_passphrase(
+		&self,
+		set_folder: &Path,
+		passphrase: &SecretString,
+		params: &PassphraseParams,
+	) -> io::Result<SetKey> {
+		let locked = fs::read(set_folder.join(PASSPHRASE_KEY_FILE_NAME))?;
+		if locked.len() < NONCE_LENGTH {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!("{} is cut short", PASSPHRASE_KEY_FILE_NAME),
+			));
+		}
+		let (nonce, ciphertext) = locked.split_at(NONCE_LENGTH);
+		let cipher = ChaCha20Poly1305::new(&params.derive_key(passphrase)?);
+		let secret = cipher
+			.decrypt(Nonce::from_slice(nonce), ciphertext)
+			.map_err(|_| {
+				io::Error::new(
+					io::ErrorKind::PermissionDenied,
+					format!("wrong passphrase for {}", set_folder.display()),
+				)
+			})?;
+		parse_set_key(&String::from_utf8_lossy(&secret))
+	}
+}
+
+fn parse_set_key(secret: &str) -> io::Result<SetKey> {
+	x25519::Identity::from_str(secret.trim())
+		.map(SetKey)
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Recipients to encrypt sets to, each given as an `age1...` public key or
+/// as a file of them, one per line, as `age -R` takes
+pub fn parse_recipients(specs: &[String]) -> io::Result<Vec<x25519::Recipient>> {
+	let mut recipients = Vec::new();
+	for spec in specs {
+		if spec.starts_with("age1") {
+			recipients.push(parse_recipient(spec)?);
+			continue;
+		}
+		for line in BufReader::new(fs::File::open(spec)?).lines() {
+			let line = line?;
+			let line = line.trim();
+			if !line.is_empty() && !line.starts_with('#') {
+				recipients.push(parse_recipient(line)?);
+			}
+		}
+	}
+	Ok(recipients)
+}
+
+fn parse_recipient(key: &str) -> io::Result<x25519::Recipient> {
+	x25519::Recipient::from_str(key).map_err(|e| {
+		io::Error::new(
+			io::ErrorKind::InvalidInput,
+			format!("{} isn't an age recipient: {}", key, e),
+		)
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	#[test]
+	fn test_unlocks_set_key_with_identity() -> io::Result<()> {
+		let set_folder = create_tmp_folder("set")?;
+		let owner = x25519::Identity::generate();
+		let key = SetKey::generate();
+		key.write_locked(Path::new(&set_folder), &[owner.to_public()])?;
+
+		let unlocked = Keyring::new(vec![Box::new(owner)]).unlock(Path::new(&set_folder), None)?;
+		let mut encrypted = key.encrypt(Vec::new())?;
+		encrypted.write_all(b"backmeup susie")?;
+		let encrypted = encrypted.finish()?;
+		let mut decrypted = String::new();
+		unlocked
+			.decrypt(&encrypted[..])?
+			.read_to_string(&mut decrypted)?;
+		assert_eq!(decrypted, "backmeup susie");
+
+		let stranger = Keyring::new(vec![Box::new(x25519::Identity::generate())]);
+		let err = stranger.unlock(Path::new(&set_folder), None).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+		Ok(())
+	}
+
+	#[test]
+	fn test_unlocks_set_key_with_passphrase() -> io::Result<()> {
+		let set_folder = create_tmp_folder("set")?;
+		let params = PassphraseParams {
+			// cheap, to keep the test quick
+			memory_kib: 64,
+			iterations: 1,
+			..PassphraseParams::generate()
+		};
+		let key = SetKey::generate();
+		let passphrase = SecretString::from("correct horse".to_string());
+		key.write_with_passphrase(Path::new(&set_folder), &passphrase, &params)?;
+
+		let unlocked = Keyring::default()
+			.with_passphrase(Some(passphrase))
+			.unlock(Path::new(&set_folder), Some(&params))?;
+		assert_eq!(format!("{:?}", unlocked), format!("{:?}", key));
+
+		let wrong = Keyring::default().with_passphrase(Some("battery staple".to_string().into()));
+		let err = wrong
+			.unlock(Path::new(&set_folder), Some(&params))
+			.unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+		Ok(())
+	}
+}
@@ -0,0 +1,37 @@
+use crate::backup_sets::manifest::hex_digest;
+use crate::pause::wait_while_paused;
+use sha2::{Digest, Sha256};
+use std::io::{self, Read};
+
+/// Hashes everything read through it, so a file can be hashed on its way
+/// into a set rather than read a second time for the manifest
+pub struct HashingReader<R> {
+	inner: R,
+	hasher: Sha256,
+	bytes: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+	pub fn new(inner: R) -> Self {
+		HashingReader {
+			inner,
+			hasher: Sha256::new(),
+			bytes: 0,
+		}
+	}
+
+	/// The digest, in the manifest's hex, and size of what was read
+	pub fn finish(self) -> (String, u64) {
+		(hex_digest(self.hasher), self.bytes)
+	}
+}
+
+impl<R: Read> Read for HashingReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		wait_while_paused();
+		let read = self.inner.read(buf)?;
+		self.hasher.update(&buf[..read]);
+		self.bytes += read as u64;
+		Ok(read)
+	}
+}
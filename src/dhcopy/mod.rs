@@ -1,4 +1,10 @@
+pub mod archive;
+pub mod codec;
+pub mod compression;
 pub mod copy_file;
 pub mod copy_folder;
+pub mod dictionary;
+pub mod encryption;
+pub mod hashing_reader;
 
 // dhcopy = disk-hog-copy, just to make it a bit less ambiguous than just "copy"
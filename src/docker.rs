@@ -0,0 +1,316 @@
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+const DEFAULT_SOCKET: &str = "/var/run/docker.sock";
+
+/// What's done to the containers using the volumes being backed up, so
+/// they don't write to them meanwhile
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Quiesce {
+	/// Freeze their processes, which is quick but leaves files open
+	Pause,
+	/// Shut them down cleanly, for databases that should flush everything
+	Stop,
+}
+
+impl fmt::Display for Quiesce {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let name = match self {
+			Quiesce::Pause => "pause",
+			Quiesce::Stop => "stop",
+		};
+		f.write_str(name)
+	}
+}
+
+/// A running container, as the Engine API lists them
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Container {
+	#[serde(rename = "Id")]
+	pub id: String,
+	#[serde(rename = "Names", default)]
+	pub names: Vec<String>,
+}
+
+impl Container {
+	fn name(&self) -> &str {
+		self.names
+			.first()
+			.map(|name| name.trim_start_matches('/'))
+			.unwrap_or(&self.id)
+	}
+}
+
+#[derive(Deserialize)]
+struct VolumeList {
+	#[serde(rename = "Volumes", default)]
+	volumes: Option<Vec<Volume>>,
+}
+
+#[derive(Deserialize)]
+struct Volume {
+	#[serde(rename = "Name")]
+	name: String,
+	#[serde(rename = "Mountpoint")]
+	mountpoint: PathBuf,
+}
+
+/// The Docker daemon, reached through the Engine API on its Unix socket
+pub struct Docker {
+	socket: PathBuf,
+}
+
+impl Docker {
+	/// The daemon `DOCKER_HOST` names, if it's a `unix://` socket, or the
+	/// usual one otherwise
+	pub fn from_env() -> Docker {
+		let host = env::var("DOCKER_HOST").unwrap_or_default();
+		let socket = host.strip_prefix("unix://").unwrap_or(DEFAULT_SOCKET);
+		Docker::at(socket)
+	}
+
+	pub fn at(socket: impl Into<PathBuf>) -> Docker {
+		Docker {
+			socket: socket.into(),
+		}
+	}
+
+	/// The names of all the volumes
+	pub fn volumes(&self) -> io::Result<Vec<String>> {
+		let list: VolumeList = parse_json(&self.request("GET", "/volumes")?)?;
+		let mut names: Vec<String> = list
+			.volumes
+			.unwrap_or_default()
+			.into_iter()
+			.map(|volume| volume.name)
+			.collect();
+		names.sort();
+		Ok(names)
+	}
+
+	/// Where a volume's data is on the host. Reading it needs root.
+	pub fn volume_path(&self, name: &str) -> io::Result<PathBuf> {
+		let path = format!("/volumes/{}", percent_encode(name));
+		let volume: Volume = parse_json(&self.request("GET", &path)?)?;
+		Ok(volume.mountpoint)
+	}
+
+	/// The running containers that have any of the volumes mounted
+	pub fn containers_using(&self, volumes: &[String]) -> io::Result<Vec<Container>> {
+		let filters = serde_json::json!({ "volume": volumes }).to_string();
+		let path = format!("/containers/json?filters={}", percent_encode(&filters));
+		parse_json(&self.request("GET", &path)?)
+	}
+
+	/// Pauses or stops the running containers using the volumes, giving
+	/// them back to be resumed once they've been backed up. Any already
+	/// paused or stopped are resumed again if one fails.
+	pub fn quiesce(&self, mode: Quiesce, volumes: &[String]) -> io::Result<Quiesced<'_>> {
+		let mut quiesced = Quiesced {
+			docker: self,
+			mode,
+			containers: Vec::new(),
+		};
+		// an empty filter would match every container
+		if volumes.is_empty() {
+			return Ok(quiesced);
+		}
+		for container in self.containers_using(volumes)? {
+			let (action, done) = match mode {
+				Quiesce::Pause => ("pause", "paused"),
+				Quiesce::Stop => ("stop", "stopped"),
+			};
+			if let Err(e) = self.container_action(&container, action) {
+				for e in quiesced.resume() {
+					eprintln!("{}", e);
+				}
+				return Err(e);
+			}
+			println!("{} container {}", done, container.name());
+			quiesced.containers.push(container);
+		}
+		Ok(quiesced)
+	}
+
+	fn container_action(&self, container: &Container, action: &str) -> io::Result<()> {
+		let path = format!("/containers/{}/{}", container.id, action);
+		self.request("POST", &path).map_err(|e| {
+			io::Error::new(
+				e.kind(),
+				format!("can't {} container {}: {}", action, container.name(), e),
+			)
+		})?;
+		Ok(())
+	}
+
+	/// Makes a request with no body, giving the response's body if it
+	/// succeeded. HTTP/1.0 keeps the daemon from chunking the response.
+	#[cfg(unix)]
+	fn request(&self, method: &str, path: &str) -> io::Result<String> {
+		use std::io::{Read, Write};
+		use std::os::unix::net::UnixStream;
+
+		let mut stream = UnixStream::connect(&self.socket).map_err(|e| {
+			io::Error::new(
+				e.kind(),
+				format!("can't reach Docker at {}: {}", self.socket.display(), e),
+			)
+		})?;
+		write!(
+			stream,
+			"{} {} HTTP/1.0\r\nHost: docker\r\nContent-Length: 0\r\n\r\n",
+			method, path
+		)?;
+		let mut response = Vec::new();
+		stream.read_to_end(&mut response)?;
+		parse_response(&String::from_utf8_lossy(&response))
+	}
+
+	#[cfg(not(unix))]
+	fn request(&self, _method: &str, _path: &str) -> io::Result<String> {
+		Err(io::Error::new(
+			io::ErrorKind::Unsupported,
+			"reaching Docker is not supported on this platform",
+		))
+	}
+}
+
+/// Containers paused or stopped for a backup
+pub struct Quiesced<'a> {
+	docker: &'a Docker,
+	mode: Quiesce,
+	containers: Vec<Container>,
+}
+
+impl Quiesced<'_> {
+	/// Unpauses or starts the containers again, trying each even if others
+	/// fail, and giving the failures
+	pub fn resume(&mut self) -> Vec<io::Error> {
+		let (action, done) = match self.mode {
+			Quiesce::Pause => ("unpause", "unpaused"),
+			Quiesce::Stop => ("start", "started"),
+		};
+		let mut failures = Vec::new();
+		for container in self.containers.drain(..) {
+			match self.docker.container_action(&container, action) {
+				Ok(()) => println!("{} container {}", done, container.name()),
+				Err(e) => failures.push(e),
+			}
+		}
+		failures
+	}
+}
+
+/// The body of a successful response, or the daemon's message as an error
+fn parse_response(response: &str) -> io::Result<String> {
+	let (head, body) = response.split_once("\r\n\r\n").unwrap_or((response, ""));
+	let status: u16 = head
+		.split(' ')
+		.nth(1)
+		.and_then(|status| status.parse().ok())
+		.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an HTTP response"))?;
+	if (200..300).contains(&status) {
+		return Ok(body.to_string());
+	}
+	let message = serde_json::from_str::<serde_json::Value>(body)
+		.ok()
+		.and_then(|error| error["message"].as_str().map(str::to_string))
+		.unwrap_or_else(|| body.trim().to_string());
+	let kind = match status {
+		404 => io::ErrorKind::NotFound,
+		_ => io::ErrorKind::Other,
+	};
+	Err(io::Error::new(kind, message))
+}
+
+fn parse_json<'a, T: Deserialize<'a>>(body: &'a str) -> io::Result<T> {
+	serde_json::from_str(body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn percent_encode(text: &str) -> String {
+	text.bytes()
+		.map(
+			|byte| match byte.is_ascii_alphanumeric() || b"-_.~".contains(&byte) {
+				true => (byte as char).to_string(),
+				false => format!("%{:02X}", byte),
+			},
+		)
+		.collect()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+	use std::io::{BufRead, BufReader, Write};
+	use std::os::unix::net::UnixListener;
+	use std::path::Path;
+	use std::thread;
+
+	/// A daemon answering requests from `responses`, giving back the
+	/// request lines
+	fn fake_daemon(
+		listener: UnixListener,
+		responses: Vec<&'static str>,
+	) -> thread::JoinHandle<Vec<String>> {
+		thread::spawn(move || {
+			let mut requests = Vec::new();
+			for response in responses {
+				let (stream, _) = listener.accept().unwrap();
+				let mut reader = BufReader::new(stream);
+				let mut request_line = String::new();
+				reader.read_line(&mut request_line).unwrap();
+				requests.push(request_line.trim().to_string());
+				loop {
+					let mut line = String::new();
+					reader.read_line(&mut line).unwrap();
+					if line.trim().is_empty() {
+						break;
+					}
+				}
+				write!(reader.get_mut(), "HTTP/1.0 {}", response).unwrap();
+			}
+			requests
+		})
+	}
+
+	#[test]
+	fn test_pauses_containers_using_volumes() -> io::Result<()> {
+		let folder = create_tmp_folder("docker")?;
+		let socket = Path::new(&folder).join("docker.sock");
+		let listener = UnixListener::bind(&socket)?;
+		let requests = fake_daemon(
+			listener,
+			vec![
+				"200 OK\r\n\r\n{\"Name\":\"pgdata\",\"Mountpoint\":\"/var/lib/docker/volumes/pgdata/_data\"}",
+				"200 OK\r\n\r\n[{\"Id\":\"abc123\",\"Names\":[\"/postgres\"]}]",
+				"204 No Content\r\n\r\n",
+				"404 Not Found\r\n\r\n{\"message\":\"No such container: abc123\"}",
+			],
+		);
+		let docker = Docker::at(&socket);
+		let volumes = vec!["pgdata".to_string()];
+
+		assert_eq!(
+			docker.volume_path("pgdata")?,
+			PathBuf::from("/var/lib/docker/volumes/pgdata/_data")
+		);
+		let mut quiesced = docker.quiesce(Quiesce::Pause, &volumes)?;
+		let failures = quiesced.resume();
+
+		assert_eq!(failures.len(), 1);
+		assert!(failures[0].to_string().contains("No such container"));
+		let requests = requests.join().unwrap();
+		assert_eq!(
+			requests[1],
+			"GET /containers/json?filters=%7B%22volume%22%3A%5B%22pgdata%22%5D%7D HTTP/1.0"
+		);
+		assert_eq!(requests[2], "POST /containers/abc123/pause HTTP/1.0");
+		assert_eq!(requests[3], "POST /containers/abc123/unpause HTTP/1.0");
+		Ok(())
+	}
+}
@@ -0,0 +1,176 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What was being done with a path when something failed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operation {
+	Reading,
+	Writing,
+	Creating,
+}
+
+impl fmt::Display for Operation {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match self {
+			Operation::Reading => "reading",
+			Operation::Writing => "writing",
+			Operation::Creating => "creating",
+		})
+	}
+}
+
+/// Why backing up, copying, restoring or managing sets failed, for the
+/// cases worth telling apart. Anything else is kept as the `io::Error` it
+/// was.
+///
+/// Much of the crate still works in `io::Result`, so this converts both
+/// ways: one wrapped in an `io::Error` on its way out of a function that
+/// returns `io::Result` is unwrapped again when converted back.
+#[derive(Debug, thiserror::Error)]
+pub enum BackupError {
+	#[error("source {} doesn't exist", path.display())]
+	SourceMissing { path: PathBuf },
+	#[error("not enough space in {}: {source}", path.display())]
+	DestinationFull { path: PathBuf, source: io::Error },
+	#[error("{} doesn't match its checksum, expected {expected} but got {actual}", path.display())]
+	ChecksumMismatch {
+		path: PathBuf,
+		expected: String,
+		actual: String,
+	},
+	#[error("another backup is already using {}", path.display())]
+	DestinationLocked { path: PathBuf },
+	/// A set named by the caller isn't in the destination
+	#[error("no set named {set_name} in {}", dest.display())]
+	SetMissing { dest: PathBuf, set_name: String },
+	#[error("the backup was cancelled")]
+	Cancelled,
+	#[error("{operation} {}: {source}", path.display())]
+	Io {
+		operation: Operation,
+		path: PathBuf,
+		source: io::Error,
+	},
+	#[error(transparent)]
+	Other(io::Error),
+}
+
+impl BackupError {
+	/// The nearest `io::ErrorKind`, as the error had before it was typed
+	pub fn kind(&self) -> io::ErrorKind {
+		match self {
+			BackupError::SourceMissing { .. } => io::ErrorKind::NotFound,
+			BackupError::DestinationFull { .. } => io::ErrorKind::StorageFull,
+			BackupError::ChecksumMismatch { .. } => io::ErrorKind::InvalidData,
+			BackupError::DestinationLocked { .. } => io::ErrorKind::WouldBlock,
+			BackupError::SetMissing { .. } => io::ErrorKind::NotFound,
+			BackupError::Cancelled => io::ErrorKind::Interrupted,
+			BackupError::Io { source, .. } => source.kind(),
+			BackupError::Other(e) => e.kind(),
+		}
+	}
+
+	/// The file or folder the error is about, if it's about one
+	pub fn path(&self) -> Option<&Path> {
+		match self {
+			BackupError::SourceMissing { path }
+			| BackupError::DestinationFull { path, .. }
+			| BackupError::ChecksumMismatch { path, .. }
+			| BackupError::DestinationLocked { path }
+			| BackupError::SetMissing { dest: path, .. }
+			| BackupError::Io { path, .. } => Some(path),
+			BackupError::Cancelled | BackupError::Other(_) => None,
+		}
+	}
+
+	pub(crate) fn set_missing(dest: &str, set_name: &str) -> BackupError {
+		BackupError::SetMissing {
+			dest: PathBuf::from(dest),
+			set_name: set_name.to_string(),
+		}
+	}
+}
+
+impl From<BackupError> for io::Error {
+	fn from(e: BackupError) -> io::Error {
+		match e {
+			BackupError::Other(e) => e,
+			e => io::Error::new(e.kind(), e),
+		}
+	}
+}
+
+impl From<io::Error> for BackupError {
+	fn from(e: io::Error) -> BackupError {
+		if !e.get_ref().is_some_and(|inner| inner.is::<BackupError>()) {
+			return BackupError::Other(e);
+		}
+		let kind = e.kind();
+		match e.into_inner().map(|inner| inner.downcast::<BackupError>()) {
+			Some(Ok(inner)) => *inner,
+			Some(Err(inner)) => BackupError::Other(io::Error::new(kind, inner)),
+			None => BackupError::Other(kind.into()),
+		}
+	}
+}
+
+pub(crate) trait Context<T> {
+	/// Records what was being done with `path` when this failed. Running
+	/// out of space is a full destination whatever was being done.
+	fn context(self, operation: Operation, path: &Path) -> Result<T, BackupError>;
+}
+
+impl<T> Context<T> for io::Result<T> {
+	fn context(self, operation: Operation, path: &Path) -> Result<T, BackupError> {
+		self.map_err(|e| match BackupError::from(e) {
+			BackupError::Other(source) if source.kind() == io::ErrorKind::StorageFull => {
+				BackupError::DestinationFull {
+					path: path.to_path_buf(),
+					source,
+				}
+			}
+			BackupError::Other(source) => BackupError::Io {
+				operation,
+				path: path.to_path_buf(),
+				source,
+			},
+			typed => typed,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_survives_io_results() {
+		let missing = BackupError::SourceMissing {
+			path: PathBuf::from("/home/alice"),
+		};
+		let wrapped = io::Error::from(missing);
+		assert_eq!(wrapped.kind(), io::ErrorKind::NotFound);
+		assert_eq!(wrapped.to_string(), "source /home/alice doesn't exist");
+		assert!(matches!(
+			BackupError::from(wrapped),
+			BackupError::SourceMissing { path } if path == Path::new("/home/alice")
+		));
+
+		let full: io::Result<()> = Err(io::Error::from(io::ErrorKind::StorageFull));
+		assert!(matches!(
+			full.context(Operation::Writing, Path::new("/backups/file")),
+			Err(BackupError::DestinationFull { .. })
+		));
+		let denied: io::Result<()> = Err(io::Error::from(io::ErrorKind::PermissionDenied));
+		let err = denied
+			.context(Operation::Reading, Path::new("/etc/shadow"))
+			.unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+		assert!(
+			err.to_string().starts_with("reading /etc/shadow: "),
+			"{}",
+			err
+		);
+	}
+}
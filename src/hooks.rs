@@ -0,0 +1,53 @@
+use serde::Deserialize;
+use std::io;
+use std::process::Command;
+
+/// What a failing hook does to the run
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HookFailure {
+	/// Fail the run without backing anything up
+	#[default]
+	Abort,
+	/// Back up anyway, with the failure as a warning
+	Warn,
+}
+
+/// Runs a command through the shell with `env` added to its environment,
+/// failing if it can't be started or doesn't exit successfully. Its output
+/// goes wherever the backup's does.
+pub fn run_hook(command: &str, env: &[(&str, &str)]) -> io::Result<()> {
+	let mut shell = match cfg!(windows) {
+		true => {
+			let mut shell = Command::new("cmd");
+			shell.arg("/C");
+			shell
+		}
+		false => {
+			let mut shell = Command::new("sh");
+			shell.arg("-c");
+			shell
+		}
+	};
+	let status = shell.arg(command).envs(env.iter().copied()).status()?;
+	match status.success() {
+		true => Ok(()),
+		false => Err(io::Error::other(format!(
+			"`{}` exited with {}",
+			command, status
+		))),
+	}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_runs_hooks() {
+		let env = [("DHB_SOURCE", "/srv/dumps")];
+		assert!(run_hook("test \"$DHB_SOURCE\" = /srv/dumps", &env).is_ok());
+		let err = run_hook("exit 3", &env).unwrap_err();
+		assert!(err.to_string().contains("exit status: 3"));
+	}
+}
@@ -0,0 +1,128 @@
+use std::io;
+use std::time::{Duration, SystemTime};
+
+/// How long since anyone last used the machine, through the keyboard or
+/// mouse or however the platform tracks it. Logind only knows about
+/// desktops that tell it, and a machine no one's logged in to is idle.
+#[cfg(target_os = "linux")]
+pub fn idle_time() -> io::Result<Duration> {
+	let output = crate::snapshot::run_command(std::process::Command::new("loginctl").args([
+		"show",
+		"-p",
+		"IdleHint",
+		"-p",
+		"IdleSinceHint",
+	]))?;
+	logind_idle_time(&output, SystemTime::now())
+}
+
+#[cfg(target_os = "macos")]
+pub fn idle_time() -> io::Result<Duration> {
+	let output = crate::snapshot::run_command(std::process::Command::new("ioreg").args([
+		"-c",
+		"IOHIDSystem",
+		"-d",
+		"4",
+	]))?;
+	ioreg_idle_time(&output)
+}
+
+#[cfg(windows)]
+pub fn idle_time() -> io::Result<Duration> {
+	// milliseconds since the last input, from GetLastInputInfo
+	let output = crate::snapshot::run_command(std::process::Command::new("powershell").args([
+		"-NoProfile",
+		"-NonInteractive",
+		"-Command",
+		"Add-Type 'using System.Runtime.InteropServices; \
+		 public struct LastInput { public uint Size; public uint Time; } \
+		 public static class Idle { [DllImport(\"user32.dll\")] \
+		 public static extern bool GetLastInputInfo(ref LastInput info); }'; \
+		 $info = New-Object LastInput; $info.Size = 8; \
+		 [void][Idle]::GetLastInputInfo([ref]$info); \
+		 [uint32]([Environment]::TickCount - $info.Time)",
+	]))?;
+	let millis: u64 = output.trim().parse().map_err(|_| unreadable(&output))?;
+	Ok(Duration::from_millis(millis))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub fn idle_time() -> io::Result<Duration> {
+	Err(io::Error::new(
+		io::ErrorKind::Unsupported,
+		"checking how long the system has been idle is not supported on this platform",
+	))
+}
+
+/// Reads `loginctl show`'s idle hint, and when it was set in microseconds
+/// since the epoch
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn logind_idle_time(output: &str, now: SystemTime) -> io::Result<Duration> {
+	let property = |name: &str| {
+		output
+			.lines()
+			.find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+			.ok_or_else(|| unreadable(output))
+	};
+	if property("IdleHint")? != "yes" {
+		return Ok(Duration::ZERO);
+	}
+	let since: u64 = property("IdleSinceHint")?
+		.parse()
+		.map_err(|_| unreadable(output))?;
+	// idle without ever having been used, as with no one logged in
+	if since == 0 {
+		return Ok(Duration::MAX);
+	}
+	let since = SystemTime::UNIX_EPOCH + Duration::from_micros(since);
+	Ok(now.duration_since(since).unwrap_or_default())
+}
+
+/// Reads the nanoseconds since the last input from `ioreg`'s listing of
+/// the HID system
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn ioreg_idle_time(output: &str) -> io::Result<Duration> {
+	let nanos = output
+		.lines()
+		.find_map(|line| line.split_once("\"HIDIdleTime\" = "))
+		.and_then(|(_, nanos)| nanos.trim().parse().ok())
+		.ok_or_else(|| unreadable(output))?;
+	Ok(Duration::from_nanos(nanos))
+}
+
+fn unreadable(output: &str) -> io::Error {
+	io::Error::new(
+		io::ErrorKind::InvalidData,
+		format!(
+			"can't tell how long the system has been idle from {:?}",
+			output.trim()
+		),
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_reads_idle_time() -> io::Result<()> {
+		let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_600);
+		assert_eq!(
+			logind_idle_time("IdleHint=yes\nIdleSinceHint=1700000000000000\n", now)?,
+			Duration::from_secs(600)
+		);
+		assert_eq!(
+			logind_idle_time("IdleHint=no\nIdleSinceHint=1700000000000000\n", now)?,
+			Duration::ZERO
+		);
+		assert_eq!(
+			logind_idle_time("IdleHint=yes\nIdleSinceHint=0\n", now)?,
+			Duration::MAX
+		);
+		assert!(logind_idle_time("", now).is_err());
+
+		let ioreg = "    | |   \"HIDIdleTime\" = 93000000000\n    | |   \"HIDKeyboardModifierMappingPairs\" = ()";
+		assert_eq!(ioreg_idle_time(ioreg)?, Duration::from_secs(93));
+		Ok(())
+	}
+}
@@ -0,0 +1,24 @@
+pub mod backup;
+pub mod backup_sets;
+pub mod cancel;
+pub mod chunk_store;
+pub mod config;
+pub mod dhcopy;
+pub mod docker;
+pub mod error;
+pub mod hooks;
+pub mod idle;
+pub mod logging;
+pub mod notify;
+pub mod parsing;
+pub mod pause;
+pub mod power;
+pub mod progress;
+pub mod schedule;
+pub mod snapshot;
+pub mod space;
+pub mod status;
+pub mod storage;
+#[cfg(test)]
+mod test_helpers;
+pub mod watch;
@@ -0,0 +1,274 @@
+use crate::notify::{RunEvent, RunReport};
+use clap::ValueEnum;
+use std::fmt;
+use std::io;
+use std::thread::JoinHandle;
+
+const IDENTIFIER: &str = "disk-hog-backup";
+
+// syslog(3) priorities, which journald uses too
+pub const PRIORITY_ERR: u8 = 3;
+pub const PRIORITY_WARNING: u8 = 4;
+pub const PRIORITY_INFO: u8 = 6;
+
+/// Where a backup's output goes
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum LogTarget {
+	/// Standard output and error, as for any command
+	#[default]
+	Stdout,
+	/// The system log through syslog(3)
+	Syslog,
+	/// systemd's journal, with fields for each run's outcome
+	Journald,
+}
+
+impl fmt::Display for LogTarget {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let name = match self {
+			LogTarget::Stdout => "stdout",
+			LogTarget::Syslog => "syslog",
+			LogTarget::Journald => "journald",
+		};
+		f.write_str(name)
+	}
+}
+
+/// Sends everything the process prints to syslog or the journal instead,
+/// a record for each line. Lines on standard error are errors, or
+/// warnings if they say so, and those on standard output information.
+pub struct LogForwarder {
+	target: LogTarget,
+	readers: Vec<JoinHandle<()>>,
+}
+
+impl LogForwarder {
+	/// Starts forwarding output, `None` for [LogTarget::Stdout]. Must be
+	/// called before anything's printed.
+	#[cfg(unix)]
+	pub fn start(target: LogTarget) -> io::Result<Option<LogForwarder>> {
+		use std::io::{BufRead, BufReader};
+		use std::os::fd::FromRawFd;
+
+		if target == LogTarget::Stdout {
+			return Ok(None);
+		}
+		if target == LogTarget::Syslog {
+			open_syslog();
+		}
+		let mut readers = Vec::new();
+		for (fd, priority) in [
+			(libc::STDOUT_FILENO, PRIORITY_INFO),
+			(libc::STDERR_FILENO, PRIORITY_ERR),
+		] {
+			let mut ends = [0; 2];
+			// SAFETY: pipe fills in the two descriptors it's given room for
+			if unsafe { libc::pipe(ends.as_mut_ptr()) } != 0 {
+				return Err(io::Error::last_os_error());
+			}
+			// SAFETY: the write end replaces the output, then isn't needed twice
+			if unsafe { libc::dup2(ends[1], fd) } < 0 || unsafe { libc::close(ends[1]) } != 0 {
+				return Err(io::Error::last_os_error());
+			}
+			// SAFETY: the read end was just opened and nothing else owns it
+			let read_end = unsafe { std::fs::File::from_raw_fd(ends[0]) };
+			readers.push(std::thread::spawn(move || {
+				for line in BufReader::new(read_end).lines() {
+					let Ok(line) = line else { break };
+					let priority = match priority {
+						PRIORITY_ERR if line.to_ascii_lowercase().starts_with("warning") => {
+							PRIORITY_WARNING
+						}
+						priority => priority,
+					};
+					// nowhere left to report a failure to log
+					let _ = send_record(target, priority, &line, &[]);
+				}
+			}));
+		}
+		Ok(Some(LogForwarder { target, readers }))
+	}
+
+	#[cfg(not(unix))]
+	pub fn start(target: LogTarget) -> io::Result<Option<LogForwarder>> {
+		match target {
+			LogTarget::Stdout => Ok(None),
+			_ => Err(io::Error::new(
+				io::ErrorKind::Unsupported,
+				format!("logging to {} is not supported on this platform", target),
+			)),
+		}
+	}
+
+	pub fn target(&self) -> LogTarget {
+		self.target
+	}
+
+	/// Forwards whatever's still to be and stops, sending any further
+	/// output nowhere. Call before exiting so the last lines aren't lost.
+	#[cfg(unix)]
+	pub fn finish(self) {
+		use std::io::Write;
+		use std::os::fd::AsRawFd;
+
+		let _ = io::stdout().flush();
+		if let Ok(null) = std::fs::OpenOptions::new().write(true).open("/dev/null") {
+			// SAFETY: swapping the outputs for /dev/null closes the pipes'
+			// last write ends, so the readers see the end of them
+			unsafe {
+				libc::dup2(null.as_raw_fd(), libc::STDOUT_FILENO);
+				libc::dup2(null.as_raw_fd(), libc::STDERR_FILENO);
+			}
+		}
+		for reader in self.readers {
+			let _ = reader.join();
+		}
+	}
+
+	#[cfg(not(unix))]
+	pub fn finish(self) {}
+}
+
+/// Sends one record. For the journal, `fields` are added to it as they
+/// are, e.g. `DHB_SET`, while syslog gets them appended to the message as
+/// `key=value`.
+pub fn send_record(
+	target: LogTarget,
+	priority: u8,
+	message: &str,
+	fields: &[(&str, String)],
+) -> io::Result<()> {
+	match target {
+		LogTarget::Stdout => {
+			println!("{}", message);
+			Ok(())
+		}
+		LogTarget::Syslog => {
+			let mut line = message.to_string();
+			for (name, value) in fields {
+				line.push_str(&format!(" {}={:?}", name.to_ascii_lowercase(), value));
+			}
+			write_syslog(priority, &line)
+		}
+		LogTarget::Journald => {
+			let mut record = vec![
+				("MESSAGE", message.to_string()),
+				("PRIORITY", priority.to_string()),
+				("SYSLOG_IDENTIFIER", IDENTIFIER.to_string()),
+			];
+			record.extend(fields.iter().map(|(name, value)| (*name, value.clone())));
+			send_to_journal(&journal_datagram(&record))
+		}
+	}
+}
+
+/// Records how a run went with its details as fields, e.g. `DHB_SET`, for
+/// filtering in the journal
+pub fn log_report(target: LogTarget, report: &RunReport) -> io::Result<()> {
+	let priority = match report.event() {
+		RunEvent::Success => PRIORITY_INFO,
+		RunEvent::Warning => PRIORITY_WARNING,
+		RunEvent::Failure => PRIORITY_ERR,
+	};
+	let event = match report.event() {
+		RunEvent::Success => "success",
+		RunEvent::Warning => "warning",
+		RunEvent::Failure => "failure",
+	};
+	let mut fields = vec![
+		("DHB_EVENT", event.to_string()),
+		("DHB_DESTINATION", report.destination.clone()),
+		(
+			"DHB_DURATION_SECONDS",
+			(report.finished_at - report.started_at)
+				.num_seconds()
+				.to_string(),
+		),
+	];
+	fields.extend(report.set_name.clone().map(|set| ("DHB_SET", set)));
+	if let Some(stats) = report.stats {
+		fields.push(("DHB_FILES", stats.files.to_string()));
+		fields.push(("DHB_BYTES", stats.bytes.to_string()));
+	}
+	fields.extend(report.error.clone().map(|error| ("DHB_ERROR", error)));
+	send_record(target, priority, &report.headline(), &fields)
+}
+
+#[cfg(unix)]
+fn open_syslog() {
+	use std::ffi::CString;
+	use std::sync::OnceLock;
+
+	// openlog keeps the pointer, so the identifier has to live on
+	static IDENT: OnceLock<CString> = OnceLock::new();
+	let ident = IDENT.get_or_init(|| CString::new(IDENTIFIER).unwrap());
+	// SAFETY: the identifier is never freed
+	unsafe { libc::openlog(ident.as_ptr(), libc::LOG_PID, libc::LOG_USER) };
+}
+
+#[cfg(unix)]
+fn write_syslog(priority: u8, line: &str) -> io::Result<()> {
+	let line = std::ffi::CString::new(line.replace('\0', ""))?;
+	// SAFETY: the format takes the one string given
+	unsafe { libc::syslog(priority.into(), c"%s".as_ptr(), line.as_ptr()) };
+	Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_syslog(_priority: u8, _line: &str) -> io::Result<()> {
+	Err(io::Error::new(
+		io::ErrorKind::Unsupported,
+		"syslog is not supported on this platform",
+	))
+}
+
+/// A record in the journal's native protocol: `NAME=value` lines, with
+/// values that span lines given as their length and bytes instead
+fn journal_datagram(fields: &[(&str, String)]) -> Vec<u8> {
+	let mut datagram = Vec::new();
+	for (name, value) in fields {
+		datagram.extend_from_slice(name.as_bytes());
+		if value.contains('\n') {
+			datagram.push(b'\n');
+			datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+		} else {
+			datagram.push(b'=');
+		}
+		datagram.extend_from_slice(value.as_bytes());
+		datagram.push(b'\n');
+	}
+	datagram
+}
+
+#[cfg(unix)]
+fn send_to_journal(datagram: &[u8]) -> io::Result<()> {
+	let socket = std::os::unix::net::UnixDatagram::unbound()?;
+	socket.send_to(datagram, "/run/systemd/journal/socket")?;
+	Ok(())
+}
+
+#[cfg(not(unix))]
+fn send_to_journal(_datagram: &[u8]) -> io::Result<()> {
+	Err(io::Error::new(
+		io::ErrorKind::Unsupported,
+		"journald is not supported on this platform",
+	))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_encodes_journal_records() {
+		let datagram = journal_datagram(&[
+			("MESSAGE", "Backup successful".to_string()),
+			("DHB_ERROR", "two\nlines".to_string()),
+		]);
+
+		let mut expected = b"MESSAGE=Backup successful\nDHB_ERROR\n".to_vec();
+		expected.extend_from_slice(&9_u64.to_le_bytes());
+		expected.extend_from_slice(b"two\nlines\n");
+		assert_eq!(datagram, expected);
+	}
+}
@@ -1,33 +1,1527 @@
-mod backup;
-mod backup_sets;
-mod dhcopy;
-mod test_helpers;
-
-use crate::backup::backup::backup;
-use clap::Parser;
+use age::secrecy::SecretString;
+use chrono::{DateTime, Local, TimeDelta, Utc};
+use clap::{Parser, Subcommand};
+use disk_hog_backup::backup::backup::BackupOptions;
+use disk_hog_backup::backup::export_squashfs::export_squashfs;
+use disk_hog_backup::backup::export_zip::export_zip;
+use disk_hog_backup::backup::push::push_set;
+use disk_hog_backup::backup::restore::{default_restore_set, restore_set};
+use disk_hog_backup::backup::run::{report_unstarted, run_backup, FollowUps, Preparation};
+use disk_hog_backup::backup_sets::backup_set::{
+	clean_up_temp_sets, list_sets, list_sets_by_status,
+};
+use disk_hog_backup::backup_sets::compact::compact_sets;
+use disk_hog_backup::backup_sets::lock::{lock_destination, DestinationLock};
+use disk_hog_backup::backup_sets::migrate::migrate_sets;
+use disk_hog_backup::backup_sets::pin::{pin_set, unpin_set};
+use disk_hog_backup::backup_sets::rekey::{rekey_set, rekey_sets, NewLocks};
+use disk_hog_backup::backup_sets::retention::{prune_sets, simulate, RetentionPolicy};
+use disk_hog_backup::backup_sets::set_metadata::{annotate_set, read_metadata};
+use disk_hog_backup::backup_sets::set_namer::{SetNameTemplate, SetTimezone};
+use disk_hog_backup::backup_sets::tags::{tag_set, untag_set, validate_tag};
+use disk_hog_backup::backup_sets::trash::empty_trash;
+use disk_hog_backup::backup_sets::verify::{verify_set, verify_set_sample};
+#[cfg(unix)]
+use disk_hog_backup::cancel::{cancel_on_interrupt, cancel_on_terminate};
+use disk_hog_backup::cancel::{is_cancelled, CANCELLED_EXIT_CODE};
+use disk_hog_backup::chunk_store::check::check_chunks;
+use disk_hog_backup::chunk_store::chunker::ChunkSizes;
+use disk_hog_backup::chunk_store::gc::collect_garbage;
+use disk_hog_backup::chunk_store::repack::{repack, DEFAULT_PACK_SIZE};
+use disk_hog_backup::config::profiles::Profile;
+use disk_hog_backup::config::remotes::{Config, Destination};
+use disk_hog_backup::dhcopy::compression::Compression;
+use disk_hog_backup::dhcopy::encryption::{parse_recipients, Keyring};
+use disk_hog_backup::docker::{Docker, Quiesce};
+use disk_hog_backup::error::BackupError;
+use disk_hog_backup::idle::idle_time;
+use disk_hog_backup::logging::{LogForwarder, LogTarget};
+use disk_hog_backup::notify::{RunEvent, RunReport};
+use disk_hog_backup::parsing::duration::parse_duration;
+use disk_hog_backup::parsing::percentage::parse_percentage;
+use disk_hog_backup::parsing::size::parse_size;
+#[cfg(unix)]
+use disk_hog_backup::pause::pause_on_signals;
+use disk_hog_backup::power::on_battery;
+use disk_hog_backup::schedule::daemon::run_daemon;
+use disk_hog_backup::schedule::last_runs::{LastRuns, LAST_RUNS_FILE};
+use disk_hog_backup::snapshot::SnapshotKind;
+use disk_hog_backup::space::max_space::SpaceLimit;
+use disk_hog_backup::space::usage::{sets_usage, total_stats};
+use disk_hog_backup::status::history::{default_state_folder, RunHistory};
+use disk_hog_backup::status::server::serve_status;
+use disk_hog_backup::storage::availability::{wait_until_available, RETRY_INTERVAL};
+use disk_hog_backup::storage::sftp::{SftpLocation, SftpStorage};
+use disk_hog_backup::watch::SourceWatcher;
+use std::env;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Set while output is going to the system log instead of stdout
+static LOG_FORWARDER: Mutex<Option<LogForwarder>> = Mutex::new(None);
 
 #[derive(Parser)]
 #[command(name = "diskhog")]
 #[command(about = "A tool for backing up directories", long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-	/// Source folder to back up
-	#[arg(short, long)]
-	source: String,
+	#[command(subcommand)]
+	command: Option<Command>,
+
+	/// Source folder to back up. Repeat to back up several folders into one
+	/// set, each in its own subfolder. A folder on an SSH server can be
+	/// backed up on its own, given as [user@]host:/path or an sftp:// URL.
+	#[arg(
+		short,
+		long,
+		required_unless_present_any = ["docker_volume", "all_docker_volumes"]
+	)]
+	source: Vec<String>,
+
+	/// Back up a Docker volume's data too, as another source. Repeat for
+	/// several. Reading volumes needs root.
+	#[arg(long)]
+	docker_volume: Vec<String>,
+
+	/// Back up every Docker volume, as with --docker-volume
+	#[arg(long, conflicts_with = "docker_volume")]
+	all_docker_volumes: bool,
+
+	/// Pause or stop the running containers using the Docker volumes while
+	/// they're backed up, so nothing writes to them meanwhile. They're
+	/// unpaused or started again as soon as the set's written.
+	#[arg(long, value_enum)]
+	docker_containers: Option<Quiesce>,
+
+	/// Destination folder for backups, sftp://user@host/path for a folder
+	/// on an SSH server, or remote:name for a remote defined in the config
+	/// file. Remote sets are plain or compressed copies, without retention,
+	/// sealing or encryption. A folder on a removable drive can be given as
+	/// uuid:1234-ABCD/path or label:NAME/path, and the backup refuses to
+	/// run while the drive isn't mounted.
+	#[arg(short, long, required = true)]
+	destination: Option<String>,
+
+	/// Config file defining remotes, instead of
+	/// ~/.config/disk-hog-backup/config.toml
+	#[arg(long)]
+	config: Option<PathBuf>,
+
+	/// Where the backup's output goes: syslog or journald send each line to
+	/// the system log, and the outcome as a record of its own, for backups
+	/// run as a system service
+	#[arg(long, value_enum, default_value_t)]
+	log_to: LogTarget,
+
+	#[command(flatten)]
+	retention: RetentionArgs,
+
+	/// How to name new sets, using {date} (required), {hostname} and {source_label}
+	#[arg(long, default_value_t)]
+	set_name_template: SetNameTemplate,
+
+	/// Timezone for the timestamp in set names: utc, local or an offset like +02:00
+	#[arg(long, default_value_t)]
+	timezone: SetTimezone,
+
+	/// Wait for another backup to the same destination to finish instead of failing
+	#[arg(long)]
+	wait_lock: bool,
+
+	/// If the destination isn't there, e.g. a drive not mounted or a server
+	/// not reachable, look for it again every minute for up to this long,
+	/// e.g. 12h, before failing. A local destination folder has to exist.
+	#[arg(long, value_parser = parse_duration)]
+	wait_for_destination: Option<TimeDelta>,
+
+	/// Back up from a snapshot of each source's filesystem, taken as the
+	/// backup starts and removed once it's done, so files in use are
+	/// copied as they all were at one moment: vss for a Volume Shadow Copy
+	/// on Windows, which needs an administrator, or btrfs, zfs or lvm on
+	/// Linux, which need root
+	#[arg(long, value_enum, conflicts_with_all = ["archive", "chunked", "zstd_dictionary"])]
+	snapshot: Option<SnapshotKind>,
+
+	/// Make each set read-only once complete. Pruning and other commands lift this as needed.
+	/// Run as root on Linux, sets are made immutable too (chattr +i), which only root can
+	/// undo, and later sets copy unchanged files rather than linking to them. Otherwise they're
+	/// only read-only, with a warning, which stops accidents but not anything running as the
+	/// backup user that makes them writable again.
+	#[arg(long)]
+	seal: bool,
+
+	/// Compress each file in the set: none, zstd, or gzip so files can be
+	/// unpacked with gunzip. Add a level as in zstd:7 (1-22, default 3) or
+	/// gzip:9 (1-9, default 6). Restore and verify decompress automatically.
+	#[arg(long)]
+	compress: Option<Compression>,
+
+	/// Write each set as one archive plus an index instead of a copy of every
+	/// file, for destinations that are slow with many small files. The
+	/// archive is zstd compressed unless --compress says otherwise.
+	#[arg(long)]
+	archive: bool,
+
+	/// With --archive, split the archive into volumes of this size, e.g. 4GiB
+	/// for FAT32 drives. Restoring single paths only reads the volumes needed.
+	#[arg(long, requires = "archive", value_parser = parse_size)]
+	volume_size: Option<u64>,
+
+	/// Cut files into chunks kept once in a chunks folder in the
+	/// destination, with each set listing the chunks it needs, so unchanged
+	/// data takes no new space. Chunks are zstd compressed unless --compress
+	/// says otherwise.
+	#[arg(long, conflicts_with = "archive")]
+	chunked: bool,
+
+	/// With --chunked, the minimum, average and maximum size to cut chunks
+	/// to, e.g. 64KiB,256KiB,1MiB to find more shared data in databases or
+	/// 1MiB,4MiB,16MiB for fewer chunks of photos and videos. Recorded by
+	/// the first chunked backup to the destination, which later ones then
+	/// use. Defaults to 256KiB,1MiB,4MiB.
+	#[arg(long, requires = "chunked")]
+	chunk_sizes: Option<ChunkSizes>,
+
+	/// Keep separate copies of files with the same contents instead of
+	/// hard-linking them together, within the set or to earlier sets
+	#[arg(long)]
+	no_link_duplicates: bool,
+
+	/// Encrypt each file to this age recipient, given as an age1... public
+	/// key or a file of them. Repeat for several. Restore and verify then
+	/// need --identity.
+	#[arg(long = "encrypt-to", conflicts_with_all = ["archive", "chunked"])]
+	encrypt_to: Vec<String>,
+
+	#[command(flatten)]
+	passphrase: PassphraseArgs,
+
+	/// With --encrypt-to or a passphrase, encrypt the manifest too so its
+	/// digests don't reveal which files are in the set
+	#[arg(long)]
+	encrypt_manifest: bool,
+
+	/// With --encrypt-to or a passphrase, store files under meaningless
+	/// names too, with the real ones only in the encrypted manifest. Empty
+	/// folders aren't kept.
+	#[arg(long)]
+	encrypt_names: bool,
+
+	/// Compress files against a zstd dictionary trained on the small files
+	/// being backed up, for sources of many small similar files like source
+	/// code or JSON. Implies --compress zstd unless it says another level.
+	#[arg(long, conflicts_with_all = ["archive", "chunked"])]
+	zstd_dictionary: bool,
+
+	/// Tag the new set, e.g. pre-upgrade. Repeat to add several tags.
+	#[arg(long = "tag", value_parser = validate_tag)]
+	tags: Vec<String>,
+
+	/// Once the backup is complete, copy the new set to this rsync target,
+	/// e.g. host:/backups or rsync://host/module, sending only the files
+	/// changed since the set last pushed there. Needs rsync.
+	#[arg(long)]
+	push_to: Option<String>,
+
+	/// Once the backup is complete, copy the new set to another destination
+	/// too, a folder, sftp://user@host/path or remote:name from the config
+	/// file. Repeat to replicate to several; each is reported on its own
+	/// and one failing doesn't stop the rest.
+	#[arg(long)]
+	replicate_to: Vec<String>,
+
+	/// Upload to SFTP destinations, replicas and push targets no faster
+	/// than this many bytes a second, e.g. 2MB, to leave room on the
+	/// connection for everything else. Local disks aren't limited.
+	#[arg(long, value_parser = parse_size)]
+	upload_limit: Option<u64>,
+
+	/// Move pruned sets to a trash folder in the destination instead of deleting them.
+	/// The trash is emptied by the next backup or by empty-trash.
+	#[arg(long)]
+	trash: bool,
+
+	/// Run this command through the shell before the backup, e.g. to mount
+	/// a drive or stop a service. The backup fails without running if it
+	/// does. It's given DHB_SOURCES, one per line, and DHB_DESTINATION.
+	#[arg(long)]
+	pre_backup: Option<String>,
+
+	/// Run this command through the shell once the backup's done, whether
+	/// it succeeded or not, e.g. to start a service again or a follow-up
+	/// job. It's given what --pre-backup is, with DHB_STATUS of success,
+	/// warning or failure and DHB_SET naming the new set if there is one.
+	/// Its failing is a warning.
+	#[arg(long)]
+	post_backup: Option<String>,
+
+	/// If the backup's cancelled with Ctrl-C, keep what was copied as an
+	/// incomplete set rather than removing it. It's pruned once a later
+	/// backup completes.
+	#[arg(long)]
+	keep_cancelled: bool,
+
+	/// Stop starting new files once the backup has been going this long,
+	/// e.g. 2h or 90min, to fit a maintenance window. What was copied is
+	/// kept as a resumable set, and the next backup carries on from it
+	/// rather than copying it again. Only for plain sets of copied files.
+	#[arg(long, value_parser = parse_duration)]
+	max_duration: Option<TimeDelta>,
+
+	/// With --max-duration, copy this file or folder in the sources before
+	/// the rest, so it's backed up even if time runs out. Can be given more
+	/// than once, most important first.
+	#[arg(long, requires = "max_duration")]
+	priority: Vec<String>,
+
+	/// Keep running after the backup, backing up again whenever files in
+	/// the sources change. Only for local sources.
+	#[arg(long)]
+	watch: bool,
+
+	/// With --watch, wait until nothing has changed for this long before
+	/// backing up, e.g. 30s or 2min, so a burst of changes is backed up
+	/// together
+	#[arg(long, requires = "watch", value_parser = parse_duration, default_value = "10s")]
+	watch_quiet: TimeDelta,
+
+	/// With --watch, back up at most this long after a change even if
+	/// changes haven't stopped
+	#[arg(long, requires = "watch", value_parser = parse_duration, default_value = "10min")]
+	watch_max_delay: TimeDelta,
+}
+
+#[derive(clap::Args)]
+struct PassphraseArgs {
+	/// Encrypt sets under a passphrase, or unlock them with it, typed at a prompt
+	#[arg(long, conflicts_with_all = ["passphrase_env", "passphrase_file"])]
+	passphrase: bool,
+
+	/// Take the passphrase from this environment variable
+	#[arg(long, value_name = "VAR", conflicts_with = "passphrase_file")]
+	passphrase_env: Option<String>,
+
+	/// Take the passphrase from the first line of this file, e.g. a secret
+	/// provided by a service manager
+	#[arg(long, value_name = "FILE")]
+	passphrase_file: Option<String>,
+}
+
+impl PassphraseArgs {
+	/// The passphrase, if one was given. A new passphrase typed at the
+	/// prompt has to be typed twice.
+	fn read(&self, confirm: bool) -> std::io::Result<Option<SecretString>> {
+		read_passphrase(
+			self.passphrase.then_some("Passphrase"),
+			&self.passphrase_env,
+			&self.passphrase_file,
+			confirm,
+		)
+	}
+}
+
+#[derive(clap::Args)]
+struct NewPassphraseArgs {
+	/// Lock the sets under a new passphrase, typed at a prompt
+	#[arg(long, conflicts_with_all = ["new_passphrase_env", "new_passphrase_file"])]
+	new_passphrase: bool,
+
+	/// Take the new passphrase from this environment variable
+	#[arg(long, value_name = "VAR", conflicts_with = "new_passphrase_file")]
+	new_passphrase_env: Option<String>,
+
+	/// Take the new passphrase from the first line of this file
+	#[arg(long, value_name = "FILE")]
+	new_passphrase_file: Option<String>,
+}
+
+impl NewPassphraseArgs {
+	fn read(&self) -> std::io::Result<Option<SecretString>> {
+		read_passphrase(
+			self.new_passphrase.then_some("New passphrase"),
+			&self.new_passphrase_env,
+			&self.new_passphrase_file,
+			true,
+		)
+	}
+}
+
+/// Reads a passphrase at a prompt with this label, from an environment
+/// variable or from the first line of a file, whichever was given
+fn read_passphrase(
+	prompt: Option<&str>,
+	env: &Option<String>,
+	file: &Option<String>,
+	confirm: bool,
+) -> std::io::Result<Option<SecretString>> {
+	let passphrase = if let Some(prompt) = prompt {
+		let passphrase = rpassword::prompt_password(format!("{}: ", prompt))?;
+		if confirm && rpassword::prompt_password(format!("{} again: ", prompt))? != passphrase {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidInput,
+				"the passphrases don't match",
+			));
+		}
+		passphrase
+	} else if let Some(var) = env {
+		std::env::var(var).map_err(|_| {
+			std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} isn't set", var))
+		})?
+	} else if let Some(file) = file {
+		let contents = std::fs::read_to_string(file)?;
+		contents.lines().next().unwrap_or_default().to_string()
+	} else {
+		return Ok(None);
+	};
+	if passphrase.is_empty() {
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidInput,
+			"the passphrase is empty",
+		));
+	}
+	Ok(Some(passphrase.into()))
+}
+
+#[derive(clap::Args)]
+struct RetentionArgs {
+	/// Always keep the newest N sets
+	#[arg(long)]
+	keep_last: Option<usize>,
+
+	/// Keep all sets younger than this, e.g. 90d (units h, d, w, m, y)
+	#[arg(long, value_parser = parse_duration)]
+	keep_within: Option<TimeDelta>,
+
+	/// Keep the newest set from each of the last N days
+	#[arg(long)]
+	keep_daily: Option<usize>,
+
+	/// Keep the newest set from each of the last N weeks
+	#[arg(long)]
+	keep_weekly: Option<usize>,
+
+	/// Keep the newest set from each of the last N months
+	#[arg(long)]
+	keep_monthly: Option<usize>,
+
+	/// Maximum space the backups may use, including a new set, as a size
+	/// (500MB, 1.5TB, 200GiB, a bare number is GB) or a percentage of the
+	/// destination disk, e.g. 80%.
+	/// The oldest sets are deleted to make room, even if the keep rules other than
+	/// --keep-last would keep them.
+	#[arg(long)]
+	max_space: Option<SpaceLimit>,
+
+	/// Free space to always leave on the destination disk, as a size or a
+	/// percentage of the disk. The oldest sets are deleted to make room as for --max-space.
+	#[arg(long)]
+	min_free: Option<SpaceLimit>,
+
+	/// Never delete sets with this tag, whatever the other rules say. Repeat for several tags.
+	#[arg(long, value_parser = validate_tag)]
+	keep_tagged: Vec<String>,
+}
+
+impl RetentionArgs {
+	fn policy(&self) -> RetentionPolicy {
+		RetentionPolicy {
+			last: self.keep_last.unwrap_or(0),
+			within: self.keep_within,
+			daily: self.keep_daily.unwrap_or(0),
+			weekly: self.keep_weekly.unwrap_or(0),
+			monthly: self.keep_monthly.unwrap_or(0),
+			max_space: self.max_space,
+			min_free: self.min_free,
+			keep_tagged: self.keep_tagged.clone(),
+		}
+	}
+}
+
+#[derive(Subcommand)]
+enum RetentionCommand {
+	/// Show which sets the rules would keep or delete over the next backups
+	Simulate {
+		/// Destination folder containing the backups
+		#[arg(short, long)]
+		destination: String,
+
+		#[command(flatten)]
+		retention: RetentionArgs,
+
+		/// Number of backups to simulate
+		#[arg(long, default_value_t = 14)]
+		runs: usize,
+
+		/// Time between backups, e.g. 1d or 12h
+		#[arg(long, value_parser = parse_duration, default_value = "1d")]
+		interval: TimeDelta,
+	},
+}
+
+#[derive(Subcommand)]
+enum Command {
+	/// Copy a set's files back out, decompressing them if needed
+	#[command(
+		override_usage = "diskhog restore [OPTIONS] --destination <DESTINATION> [SET] <TARGET>"
+	)]
+	Restore {
+		/// Destination folder containing the backups
+		#[arg(short, long)]
+		destination: String,
+
+		/// Set to restore, defaulting to the last known good one, then the
+		/// empty or new folder to restore into
+		#[arg(num_args = 1..=2, required = true, value_names = ["SET", "TARGET"])]
+		set_and_target: Vec<String>,
+
+		/// Only restore this file or folder, as a path within the set. Repeat for several.
+		#[arg(long = "path")]
+		paths: Vec<String>,
+
+		/// age identity file to unlock an encrypted set. Repeat for several.
+		#[arg(long = "identity")]
+		identities: Vec<String>,
+
+		#[command(flatten)]
+		passphrase: PassphraseArgs,
+	},
+	/// Write a set's files into a zip file, e.g. to hand them to someone on Windows
+	ExportZip {
+		/// Destination folder containing the backups
+		#[arg(short, long)]
+		destination: String,
+
+		/// Set to export
+		set: String,
+
+		/// Zip file to write, which mustn't exist yet
+		output: String,
+
+		/// age identity file to unlock an encrypted set. Repeat for several.
+		#[arg(long = "identity")]
+		identities: Vec<String>,
+
+		#[command(flatten)]
+		passphrase: PassphraseArgs,
+	},
+	/// Write a set's files into a SquashFS image that can be mounted
+	/// read-only. Needs mksquashfs from squashfs-tools.
+	ExportSquashfs {
+		/// Destination folder containing the backups
+		#[arg(short, long)]
+		destination: String,
+
+		/// Set to export
+		set: String,
+
+		/// Image to write, which mustn't exist yet
+		output: String,
+
+		/// age identity file to unlock an encrypted set. Repeat for several.
+		#[arg(long = "identity")]
+		identities: Vec<String>,
+
+		#[command(flatten)]
+		passphrase: PassphraseArgs,
+	},
+	/// Copy a complete set to an rsync target, sending only the files
+	/// changed since the set last pushed there. Needs rsync.
+	Push {
+		/// Destination folder containing the backups
+		#[arg(short, long)]
+		destination: String,
+
+		/// Where rsync copies the set into, e.g. host:/backups or
+		/// rsync://host/module
+		target: String,
+
+		/// Set to push, defaults to the newest
+		set: Option<String>,
+
+		/// Upload no faster than this many bytes a second, e.g. 2MB
+		#[arg(long, value_parser = parse_size)]
+		upload_limit: Option<u64>,
+	},
+	/// Check a set's files against its manifest
+	Verify {
+		/// Destination folder containing the backups
+		#[arg(short, long)]
+		destination: String,
+
+		/// Set to verify, defaults to the newest
+		set: Option<String>,
+
+		/// Only check this percentage of files per run, e.g. 5%, covering the whole set over successive runs
+		#[arg(long, value_parser = parse_percentage)]
+		sample: Option<f64>,
+
+		/// Seed for the order sampled files are checked in
+		#[arg(long, requires = "sample")]
+		seed: Option<u64>,
+
+		/// age identity file to unlock an encrypted set. Repeat for several.
+		#[arg(long = "identity")]
+		identities: Vec<String>,
 
-	/// Destination folder for backups
-	#[arg(short, long)]
-	destination: String,
+		#[command(flatten)]
+		passphrase: PassphraseArgs,
+	},
+	/// List the sets in a destination with where they came from
+	List {
+		/// Destination folder containing the backups
+		#[arg(short, long)]
+		destination: String,
+
+		/// Only list sets with this tag
+		#[arg(long, value_parser = validate_tag)]
+		tag: Option<String>,
+	},
+	/// Show how much space each set takes, and how much it shares with other sets
+	Usage {
+		/// Destination folder containing the backups
+		#[arg(short, long)]
+		destination: String,
+	},
+	/// Protect a set from ever being pruned
+	Pin {
+		/// Destination folder containing the backups
+		#[arg(short, long)]
+		destination: String,
+
+		/// Set to protect
+		set: String,
+	},
+	/// Allow a pinned set to be pruned again
+	Unpin {
+		/// Destination folder containing the backups
+		#[arg(short, long)]
+		destination: String,
+
+		/// Set to unprotect
+		set: String,
+	},
+	/// Add tags to an existing set
+	Tag {
+		/// Destination folder containing the backups
+		#[arg(short, long)]
+		destination: String,
+
+		/// Set to tag
+		set: String,
+
+		/// Tags to add
+		#[arg(required = true, value_parser = validate_tag)]
+		tags: Vec<String>,
+	},
+	/// Remove tags from a set
+	Untag {
+		/// Destination folder containing the backups
+		#[arg(short, long)]
+		destination: String,
+
+		/// Set to untag
+		set: String,
+
+		/// Tags to remove
+		#[arg(required = true, value_parser = validate_tag)]
+		tags: Vec<String>,
+	},
+	/// Record a note on why a set exists, shown by list. An empty note removes it.
+	Annotate {
+		/// Destination folder containing the backups
+		#[arg(short, long)]
+		destination: String,
+
+		/// Set to annotate
+		set: String,
+
+		/// Note to keep with the set
+		note: String,
+	},
+	/// Merge a range of old sets into the newest of them, keeping the newest version of each file
+	Compact {
+		/// Destination folder containing the backups
+		#[arg(short, long)]
+		destination: String,
+
+		/// Oldest set to merge
+		first: String,
+
+		/// Set to merge into, which is kept
+		last: String,
+	},
+	/// Upgrade sets written by older versions to the current layout
+	Migrate {
+		/// Destination folder containing the backups
+		#[arg(short, long)]
+		destination: String,
+	},
+	/// Try out retention rules
+	Retention {
+		#[command(subcommand)]
+		command: RetentionCommand,
+	},
+	/// Delete sets that fall outside the retention rules
+	Prune {
+		/// Destination folder containing the backups
+		#[arg(short, long)]
+		destination: String,
+
+		#[command(flatten)]
+		retention: RetentionArgs,
+
+		/// Move pruned sets to the trash instead of deleting them
+		#[arg(long)]
+		trash: bool,
+	},
+	/// Lock encrypted sets to new recipients or a new passphrase, without
+	/// rewriting their files. The old recipients and passphrase stop working.
+	Rekey {
+		/// Destination folder containing the backups
+		#[arg(short, long)]
+		destination: String,
+
+		/// Set to rekey, every encrypted set if not given
+		set: Option<String>,
+
+		/// age identity file to unlock the sets with now. Repeat for several.
+		#[arg(long = "identity")]
+		identities: Vec<String>,
+
+		#[command(flatten)]
+		passphrase: PassphraseArgs,
+
+		/// age recipient to lock the sets to from now on, as for backups.
+		/// Repeat for several.
+		#[arg(long = "encrypt-to")]
+		encrypt_to: Vec<String>,
+
+		#[command(flatten)]
+		new_passphrase: NewPassphraseArgs,
+	},
+	/// Delete chunks that no chunked set uses any more, e.g. after pruning
+	Gc {
+		/// Destination folder containing the backups
+		#[arg(short, long)]
+		destination: String,
+
+		/// Only report what would be deleted
+		#[arg(long)]
+		dry_run: bool,
+	},
+	/// Check every chunk in the chunk store still matches its digest, and
+	/// that every chunk a chunked set lists is there
+	CheckChunks {
+		/// Destination folder containing the backups
+		#[arg(short, long)]
+		destination: String,
+	},
+	/// Gather loose chunks and small packs into larger pack files, so the
+	/// chunk store uses fewer files and restores read less scattered data
+	Repack {
+		/// Destination folder containing the backups
+		#[arg(short, long)]
+		destination: String,
+
+		/// Size to fill each pack to, e.g. 256MiB. Defaults to 64MiB.
+		#[arg(long, value_parser = parse_size)]
+		pack_size: Option<u64>,
+	},
+	/// Permanently delete sets that were moved to the trash
+	EmptyTrash {
+		/// Destination folder containing the backups
+		#[arg(short, long)]
+		destination: String,
+	},
+	/// Serve a page showing running backups and how recent ones went, the
+	/// same as JSON at /status.json, and Prometheus metrics at /metrics, to
+	/// check on a headless machine
+	ServeStatus {
+		/// Address to listen on. Anyone who can reach it sees destinations
+		/// and errors, so keep it local or behind a proxy.
+		#[arg(long, default_value = "127.0.0.1:8470")]
+		listen: String,
+
+		/// How many recent runs to show
+		#[arg(long, default_value_t = 20)]
+		runs: usize,
+	},
+	/// Back up a profile from the config file now, as the daemon would,
+	/// with its sources, destination and options
+	Run {
+		/// Name of the profile, as in [profiles.NAME]
+		#[arg(long)]
+		profile: String,
+
+		/// Config file with the profiles, instead of
+		/// ~/.config/disk-hog-backup/config.toml
+		#[arg(long)]
+		config: Option<PathBuf>,
+	},
+
+	/// Keep running, backing up each profile in the config file whenever
+	/// its schedule comes round, for machines without cron or systemd
+	/// timers. Each backup runs as its own process, as if run by hand.
+	Daemon {
+		/// Config file with the profiles, instead of
+		/// ~/.config/disk-hog-backup/config.toml
+		#[arg(long)]
+		config: Option<PathBuf>,
+
+		/// Also serve the status page, as serve-status does, on this
+		/// address, e.g. 127.0.0.1:8470. It shows when the next backup is
+		/// due too.
+		#[arg(long)]
+		serve_status: Option<String>,
+	},
 }
 
 fn main() {
 	let args = Args::parse();
 
-	match backup(&args.source, &args.destination) {
-		Ok(_) => println!("Backup successful"),
+	match args.command {
+		Some(Command::Restore {
+			destination,
+			set_and_target,
+			paths,
+			identities,
+			passphrase,
+		}) => {
+			let keys = exit_on_error("Restore", keyring(&identities, &passphrase));
+			let (set, target) = match set_and_target.as_slice() {
+				[set, target] => (set.clone(), target),
+				[target] => (
+					exit_on_error("Restore", default_restore_set(&destination)),
+					target,
+				),
+				_ => unreachable!("clap takes one or two values"),
+			};
+			let stats = exit_on_error(
+				"Restore",
+				restore_set(&destination, &set, target, &paths, &keys),
+			);
+			println!("Restored {} files, {} bytes", stats.files, stats.bytes);
+		}
+		Some(Command::ExportZip {
+			destination,
+			set,
+			output,
+			identities,
+			passphrase,
+		}) => {
+			let keys = exit_on_error("Export", keyring(&identities, &passphrase));
+			let stats = exit_on_error("Export", export_zip(&destination, &set, &output, &keys));
+			println!(
+				"Exported {} files, {} bytes into {}",
+				stats.files, stats.bytes, output
+			);
+		}
+		Some(Command::ExportSquashfs {
+			destination,
+			set,
+			output,
+			identities,
+			passphrase,
+		}) => {
+			let keys = exit_on_error("Export", keyring(&identities, &passphrase));
+			let stats = exit_on_error(
+				"Export",
+				export_squashfs(&destination, &set, &output, &keys),
+			);
+			println!(
+				"Exported {} files, {} bytes into {}",
+				stats.files, stats.bytes, output
+			);
+		}
+		Some(Command::Push {
+			destination,
+			target,
+			set,
+			upload_limit,
+		}) => {
+			// gc could remove chunks the set needs while they're sent
+			let _lock = lock(&destination);
+			let set = match set {
+				Some(set) => set,
+				None => exit_on_error("Push", newest_set(&destination)).unwrap_or_else(|| {
+					eprintln!("No sets found in {}", destination);
+					exit(1);
+				}),
+			};
+			exit_on_error("Push", push(&destination, &set, &target, upload_limit));
+		}
+		Some(Command::Verify {
+			destination,
+			set,
+			sample,
+			seed,
+			identities,
+			passphrase,
+		}) => {
+			let keys = exit_on_error("Verify", keyring(&identities, &passphrase));
+			verify(&destination, set, sample, seed, &keys)
+		}
+		Some(Command::List { destination, tag }) => list(&destination, tag.as_deref()),
+		Some(Command::Usage { destination }) => usage(&destination),
+		Some(Command::Pin { destination, set }) => {
+			exit_on_error("Pin", pin_set(&destination, &set));
+			println!("Pinned {}", set);
+		}
+		Some(Command::Unpin { destination, set }) => {
+			exit_on_error("Unpin", unpin_set(&destination, &set));
+			println!("Unpinned {}", set);
+		}
+		Some(Command::Tag {
+			destination,
+			set,
+			tags,
+		}) => {
+			exit_on_error("Tag", tag_set(&destination, &set, &tags));
+			println!("Tagged {} with {}", set, tags.join(", "));
+		}
+		Some(Command::Untag {
+			destination,
+			set,
+			tags,
+		}) => {
+			exit_on_error("Untag", untag_set(&destination, &set, &tags));
+			println!("Removed {} from {}", tags.join(", "), set);
+		}
+		Some(Command::Annotate {
+			destination,
+			set,
+			note,
+		}) => {
+			exit_on_error("Annotate", annotate_set(&destination, &set, &note));
+			println!("Annotated {}", set);
+		}
+		Some(Command::Compact {
+			destination,
+			first,
+			last,
+		}) => {
+			let _lock = lock(&destination);
+			let merged = exit_on_error("Compact", compact_sets(&destination, &first, &last));
+			println!("Merged {} sets into {}", merged.len(), last);
+		}
+		Some(Command::Migrate { destination }) => {
+			let _lock = lock(&destination);
+			let migrated = exit_on_error("Migrate", migrate_sets(&destination));
+			println!("Migrated {} sets", migrated.len());
+		}
+		Some(Command::Retention {
+			command:
+				RetentionCommand::Simulate {
+					destination,
+					retention,
+					runs,
+					interval,
+				},
+		}) => simulate_retention(&destination, &retention.policy(), runs, interval),
+		Some(Command::Prune {
+			destination,
+			retention,
+			trash,
+		}) => prune(&destination, &retention.policy(), trash),
+		Some(Command::Rekey {
+			destination,
+			set,
+			identities,
+			passphrase,
+			encrypt_to,
+			new_passphrase,
+		}) => {
+			let keys = exit_on_error("Rekey", keyring(&identities, &passphrase));
+			let recipients = exit_on_error("Rekey", parse_recipients(&encrypt_to));
+			let new_passphrase = exit_on_error("Rekey", new_passphrase.read());
+			let locks = NewLocks {
+				recipients: &recipients,
+				passphrase: new_passphrase.as_ref(),
+			};
+			let _lock = lock(&destination);
+			match set {
+				Some(set) => {
+					exit_on_error("Rekey", rekey_set(&destination, &set, &keys, &locks));
+					println!("Rekeyed {}", set);
+				}
+				None => {
+					let rekeyed = exit_on_error("Rekey", rekey_sets(&destination, &keys, &locks));
+					println!("Rekeyed {} sets", rekeyed.len());
+				}
+			}
+		}
+		Some(Command::Gc {
+			destination,
+			dry_run,
+		}) => {
+			// a backup running alongside could be storing chunks it needs
+			let _lock = lock(&destination);
+			let stats = exit_on_error("Gc", collect_garbage(&destination, dry_run));
+			let verb = match dry_run {
+				true => "would delete",
+				false => "deleted",
+			};
+			println!(
+				"Gc {} {} unused chunks ({} bytes), kept {}",
+				verb, stats.unreferenced, stats.bytes, stats.kept
+			);
+		}
+		Some(Command::CheckChunks { destination }) => {
+			// gc and repack move chunks around
+			let _lock = lock(&destination);
+			let result = exit_on_error("Check chunks", check_chunks(&destination));
+			for chunk in &result.corrupt {
+				println!("corrupt: {}", chunk);
+			}
+			for (set, chunk) in &result.missing {
+				println!("missing: {} (needed by {})", chunk, set);
+			}
+			if result.is_ok() {
+				println!("Check chunks successful, {} chunks checked", result.checked);
+			} else {
+				eprintln!(
+					"Check chunks failed, {} corrupt and {} missing chunks",
+					result.corrupt.len(),
+					result.missing.len()
+				);
+				exit(1);
+			}
+		}
+		Some(Command::Repack {
+			destination,
+			pack_size,
+		}) => {
+			let _lock = lock(&destination);
+			let pack_size = pack_size.unwrap_or(DEFAULT_PACK_SIZE);
+			let stats = exit_on_error("Repack", repack(&destination, pack_size));
+			println!(
+				"Repacked {} chunks ({} bytes) into {} packs, removed {} loose chunks and {} old packs",
+				stats.chunks_packed,
+				stats.bytes_packed,
+				stats.packs_written,
+				stats.loose_removed,
+				stats.packs_removed
+			);
+		}
+		Some(Command::EmptyTrash { destination }) => {
+			let _lock = lock(&destination);
+			let freed = exit_on_error("Empty trash", empty_trash(&destination));
+			println!("Trash emptied, {} bytes freed", freed);
+		}
+		Some(Command::ServeStatus { listen, runs }) => {
+			let history = exit_on_error("Serving status", RunHistory::open_default());
+			println!("Serving backup status on http://{}/", listen);
+			exit_on_error(
+				"Serving status",
+				serve_status(&listen, &history, runs, &|| None),
+			);
+		}
+		Some(Command::Run { profile, config }) => {
+			let loaded = exit_on_error("Run", Config::load(config.as_deref()));
+			let Some(found) = loaded.profiles.get(&profile) else {
+				let names: Vec<&str> = loaded.profiles.keys().map(String::as_str).collect();
+				match names.is_empty() {
+					true => eprintln!("Run failed: the config has no profiles"),
+					false => eprintln!(
+						"Run failed: no profile named {}, the config has {}",
+						profile,
+						names.join(", ")
+					),
+				}
+				exit(1);
+			};
+			let program = exit_on_error("Run", env::current_exe());
+			let mut command = profile_command(&program, config.as_deref(), &profile, found);
+			// becoming the backup leaves Ctrl-C and the exit code to it
+			#[cfg(unix)]
+			{
+				use std::os::unix::process::CommandExt;
+				let e = command.exec();
+				eprintln!("Run failed: {}", e);
+				exit(1);
+			}
+			#[cfg(not(unix))]
+			{
+				let status = exit_on_error("Run", command.status());
+				exit(status.code().unwrap_or(1));
+			}
+		}
+		Some(Command::Daemon {
+			config,
+			serve_status: status_listen,
+		}) => {
+			let loaded = exit_on_error("Daemon", Config::load(config.as_deref()));
+			// so pausing every backup with pkill doesn't kill the daemon
+			#[cfg(unix)]
+			exit_on_error("Daemon", pause_on_signals());
+			#[cfg(unix)]
+			exit_on_error("Daemon", cancel_on_terminate());
+			let next_run = Arc::new(Mutex::new(None));
+			if let Some(listen) = status_listen {
+				let history = exit_on_error("Serving status", RunHistory::open_default());
+				let next_run = Arc::clone(&next_run);
+				println!("Serving backup status on http://{}/", listen);
+				thread::spawn(move || {
+					let next_run = || *next_run.lock().unwrap();
+					if let Err(e) = serve_status(&listen, &history, 20, &next_run) {
+						eprintln!("Serving status failed: {}", e);
+					}
+				});
+			}
+			let last_runs = exit_on_error(
+				"Daemon",
+				default_state_folder()
+					.and_then(|folder| LastRuns::load(&folder.join(LAST_RUNS_FILE))),
+			);
+			let program = exit_on_error("Daemon", env::current_exe());
+			let launch = |name: &str, profile: &Profile, attempt: u32| {
+				profile_command(&program, config.as_deref(), name, profile)
+					.env("DHB_ATTEMPT", attempt.to_string())
+					.spawn()
+			};
+			let scheduled = |next: Option<DateTime<Local>>| {
+				*next_run.lock().unwrap() = next.map(|next| next.with_timezone(&Utc));
+			};
+			exit_on_error(
+				"Daemon",
+				run_daemon(
+					&loaded.profiles,
+					&launch,
+					&scheduled,
+					&on_battery,
+					&idle_time,
+					last_runs,
+				),
+			);
+		}
+		None => {
+			match LogForwarder::start(args.log_to) {
+				Ok(forwarder) => *LOG_FORWARDER.lock().unwrap() = forwarder,
+				Err(e) => {
+					eprintln!("Logging to {} failed: {}", args.log_to, e);
+					exit(1);
+				}
+			}
+			#[cfg(unix)]
+			{
+				exit_on_error("Backup", pause_on_signals());
+				exit_on_error("Backup", cancel_on_interrupt());
+			}
+			let docker = Docker::from_env();
+			let docker_volumes = match args.all_docker_volumes {
+				true => exit_on_error("Backup", docker.volumes()),
+				false => args.docker_volume,
+			};
+			let mut sources: Vec<String> = args.source;
+			for volume in &docker_volumes {
+				let path = exit_on_error("Backup", docker.volume_path(volume));
+				sources.push(path.to_string_lossy().into_owned());
+			}
+			let sources: Vec<&str> = sources.iter().map(String::as_str).collect();
+			let config = exit_on_error("Backup", Config::load(args.config.as_deref()));
+			let destination_name = args.destination.expect("destination is required");
+			let started_at = Utc::now();
+			let resolved = match args.wait_for_destination {
+				Some(limit) => wait_for_destination(&config, &destination_name, limit),
+				None => config.resolve(&destination_name),
+			};
+			let attempt = daemon_attempt();
+			let destination = resolved.unwrap_or_else(|e| {
+				report_unstarted(&destination_name, started_at, &e, &config.notify, attempt);
+				exit(finish_run(&Err(e.into())))
+			});
+			let replicas: Vec<(&str, Destination)> = args
+				.replicate_to
+				.iter()
+				.map(|replica| {
+					let resolved = exit_on_error("Backup", config.resolve(replica));
+					(replica.as_str(), resolved)
+				})
+				.collect();
+			let options = BackupOptions {
+				retention: args.retention.policy(),
+				trash: args.trash,
+				set_name_template: args.set_name_template,
+				timezone: args.timezone,
+				wait_lock: args.wait_lock,
+				seal: args.seal,
+				tags: args.tags,
+				compression: args.compress.unwrap_or(
+					match args.archive || args.chunked || args.zstd_dictionary {
+						true => Compression::ZSTD,
+						false => Compression::None,
+					},
+				),
+				archive: args.archive,
+				volume_size: args.volume_size,
+				chunked: args.chunked,
+				chunk_sizes: args.chunk_sizes,
+				copy_duplicates: args.no_link_duplicates,
+				encrypt_to: args.encrypt_to,
+				passphrase: exit_on_error("Backup", args.passphrase.read(true)),
+				encrypt_manifest: args.encrypt_manifest,
+				encrypt_names: args.encrypt_names,
+				zstd_dictionary: args.zstd_dictionary,
+				keep_cancelled: args.keep_cancelled,
+				max_duration: args.max_duration,
+				priority: args.priority,
+			};
+			let preparation = Preparation {
+				pre_backup: args.pre_backup.as_deref(),
+				before_backup: sources
+					.iter()
+					.filter_map(|source| Some((*source, config.source(source)?)))
+					.collect(),
+				snapshot: args.snapshot,
+				containers: args
+					.docker_containers
+					.map(|mode| (mode, docker_volumes.as_slice())),
+			};
+			let follow_ups = FollowUps {
+				push_to: args.push_to.as_deref(),
+				replicas: &replicas,
+				upload_limit: args.upload_limit,
+				notify: &config.notify,
+				post_backup: args.post_backup.as_deref(),
+				attempt,
+				log_target: LOG_FORWARDER
+					.lock()
+					.unwrap()
+					.as_ref()
+					.map(LogForwarder::target),
+			};
+			let watcher = match args.watch {
+				true => Some(exit_on_error(
+					"Watching",
+					watch_sources(&sources, &destination),
+				)),
+				false => None,
+			};
+			let back_up = || {
+				finish_run(&run_backup(
+					&sources,
+					&destination_name,
+					&destination,
+					&preparation,
+					&options,
+					&follow_ups,
+				))
+			};
+			let code = back_up();
+			let Some(watcher) = watcher.filter(|_| !is_cancelled()) else {
+				exit(code);
+			};
+			loop {
+				let changed = exit_on_error(
+					"Watching",
+					watcher.wait_for_changes(
+						args.watch_quiet.to_std().unwrap_or_default(),
+						args.watch_max_delay.to_std().unwrap_or_default(),
+					),
+				);
+				println!("{} paths changed, backing up again", changed.len());
+				let code = back_up();
+				if is_cancelled() {
+					exit(code);
+				}
+			}
+		}
+	}
+	exit(0)
+}
+
+/// Starts watching the sources for --watch, leaving out the destination
+/// in case it's inside one
+fn watch_sources(sources: &[&str], destination: &Destination) -> std::io::Result<SourceWatcher> {
+	if sources
+		.iter()
+		.any(|source| SftpLocation::is_sftp_url(source) || SftpLocation::is_scp_style(source))
+	{
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::Unsupported,
+			"only local sources can be watched for changes",
+		));
+	}
+	let ignored = match destination {
+		Destination::Local(folder) => vec![folder.as_str()],
+		Destination::Sftp(_) => Vec::new(),
+	};
+	SourceWatcher::new(sources, &ignored)
+}
+
+/// Says why a backup run failed, if it did, and gives its exit code: 1 if
+/// the set wasn't written or something after it failed
+fn finish_run(run: &Result<RunReport, BackupError>) -> i32 {
+	if let Err(e) = run {
+		eprintln!("Backup failed: {}", e);
+	}
+	match run {
+		_ if is_cancelled() => CANCELLED_EXIT_CODE,
+		Ok(report) if report.event() == RunEvent::Success => 0,
+		_ => 1,
+	}
+}
+
+/// Looks for the destination until it's there or `limit` has passed,
+/// failing if it never turns up
+fn wait_for_destination(
+	config: &Config,
+	destination: &str,
+	limit: TimeDelta,
+) -> std::io::Result<Destination> {
+	let probe = || {
+		let resolved = config.resolve(destination)?;
+		match &resolved {
+			Destination::Local(path) if !Path::new(path).is_dir() => {
+				return Err(std::io::Error::new(
+					std::io::ErrorKind::NotFound,
+					format!("{} doesn't exist", path),
+				));
+			}
+			Destination::Sftp(location) => drop(SftpStorage::connect(location)?),
+			Destination::Local(_) => {}
+		}
+		Ok(resolved)
+	};
+	let limit = limit.to_std().unwrap_or_default();
+	wait_until_available(probe, limit, RETRY_INTERVAL, |e| {
+		println!(
+			"{} isn't available ({}), looking again in {}s",
+			destination,
+			e,
+			RETRY_INTERVAL.as_secs()
+		)
+	})
+}
+
+/// Runs this program again to back up a profile, naming it in
+/// `DHB_PROFILE` for the commands before and after
+fn profile_command(
+	program: &Path,
+	config: Option<&Path>,
+	name: &str,
+	profile: &Profile,
+) -> process::Command {
+	let mut command = process::Command::new(program);
+	if let Some(path) = config {
+		command.arg("--config").arg(path);
+	}
+	command.args(profile.backup_args()).env("DHB_PROFILE", name);
+	command
+}
+
+/// Which attempt this is, for runs the daemon starts
+fn daemon_attempt() -> Option<u32> {
+	env::var("DHB_ATTEMPT")
+		.ok()
+		.and_then(|attempt| attempt.parse().ok())
+}
+
+fn push(
+	destination: &str,
+	set: &str,
+	target: &str,
+	upload_limit: Option<u64>,
+) -> std::io::Result<()> {
+	let plan = push_set(destination, set, target, upload_limit)?;
+	println!(
+		"Pushed {} to {}, {} unchanged files linked and {} files and folders sent",
+		set,
+		target,
+		plan.unchanged.len(),
+		plan.changed.len()
+	);
+	Ok(())
+}
+
+fn list(destination: &str, tag: Option<&str>) {
+	for set in exit_on_error("List", list_sets(destination)) {
+		match read_metadata(&Path::new(destination).join(&set)) {
+			Ok(metadata) if tag.is_some_and(|tag| !metadata.tags.contains(tag)) => {}
+			Err(_) if tag.is_some() => {}
+			Ok(metadata) => {
+				let stats = metadata
+					.stats
+					.map(|stats| match stats.deduplicated_bytes {
+						0 => format!("{} files, {} bytes", stats.files, stats.bytes),
+						deduplicated => format!(
+							"{} files, {} bytes ({} deduplicated)",
+							stats.files, stats.bytes, deduplicated
+						),
+					})
+					.unwrap_or_else(|| "incomplete".to_string());
+				let source = match metadata.sources.len() {
+					0 | 1 => metadata.source,
+					n => format!("{} (+{} more)", metadata.source, n - 1),
+				};
+				let tags = match metadata.tags.is_empty() {
+					true => String::new(),
+					false => format!("  [{}]", Vec::from_iter(metadata.tags).join(", ")),
+				};
+				println!(
+					"{}  {}:{}  {}{}",
+					set, metadata.hostname, source, stats, tags
+				);
+				if let Some(note) = metadata.note {
+					println!("    {}", note);
+				}
+			}
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => println!("{}", set),
+			Err(e) => println!("{}  unreadable metadata: {}", set, e),
+		}
+	}
+}
+
+fn usage(destination: &str) {
+	let sets = exit_on_error("Usage", sets_usage(destination));
+	let width = sets.iter().map(|set| set.set.len()).max().unwrap_or(0);
+	println!(
+		"{:width$}  {:>15}  {:>15}  {:>15}",
+		"set", "apparent bytes", "unique bytes", "cumulative bytes"
+	);
+	for set in sets {
+		println!(
+			"{:width$}  {:>15}  {:>15}  {:>15}",
+			set.set, set.usage.apparent, set.usage.exclusive, set.cumulative
+		);
+	}
+	let total = exit_on_error("Usage", total_stats(destination));
+	println!(
+		"{} of {} bytes backed up took no new space thanks to deduplication",
+		total.deduplicated_bytes, total.bytes
+	);
+}
+
+fn prune(destination: &str, policy: &RetentionPolicy, trash: bool) {
+	if policy.is_unlimited() {
+		eprintln!("No retention rules given, nothing to prune");
+		exit(1);
+	}
+	let _lock = lock(destination);
+	match prune_sets(destination, policy, trash) {
+		Ok(pruned) => println!("Prune successful, {} sets deleted", pruned.len()),
 		Err(e) => {
-			eprintln!("Backup failed: {}", e);
-			process::exit(1);
+			eprintln!("Prune failed: {}", e);
+			exit(1);
 		}
 	}
 }
+
+fn verify(
+	destination: &str,
+	set: Option<String>,
+	sample: Option<f64>,
+	seed: Option<u64>,
+	keys: &Keyring,
+) {
+	let set = match set.map_or_else(|| newest_set(destination), |set| Ok(Some(set))) {
+		Ok(Some(set)) => set,
+		Ok(None) => {
+			eprintln!("No sets found in {}", destination);
+			exit(1);
+		}
+		Err(e) => {
+			eprintln!("Verify failed: {}", e);
+			exit(1);
+		}
+	};
+	let verified = match sample {
+		Some(percent) => verify_set_sample(destination, &set, percent, seed, keys).map(|sample| {
+			println!(
+				"{} of {} files covered in this sampling pass",
+				sample.covered, sample.total
+			);
+			sample.result
+		}),
+		None => verify_set(destination, &set, keys),
+	};
+	match verified {
+		Ok(result) => {
+			for error in &result.corrupt {
+				println!("corrupt: {}", error);
+			}
+			for path in &result.missing {
+				println!("missing: {}", path);
+			}
+			if result.is_ok() {
+				println!("Verify successful, {} files checked", result.checked);
+			} else {
+				eprintln!(
+					"Verify failed, {} of {} files damaged",
+					result.corrupt.len() + result.missing.len(),
+					result.checked
+				);
+				exit(1);
+			}
+		}
+		Err(e) => {
+			eprintln!("Verify failed: {}", e);
+			exit(1);
+		}
+	}
+}
+
+fn simulate_retention(
+	destination: &str,
+	policy: &RetentionPolicy,
+	runs: usize,
+	interval: TimeDelta,
+) {
+	let sets = exit_on_error("Simulate", list_sets_by_status(destination)).complete;
+	let timeline = simulate(policy, &sets, Utc::now(), interval, runs, |set| {
+		policy.protects(destination, set)
+	});
+	let mut kept = sets;
+	for (run, simulated) in timeline.iter().enumerate() {
+		let pruned = match simulated.pruned.is_empty() {
+			true => "nothing".to_string(),
+			false => simulated.pruned.join(", "),
+		};
+		println!(
+			"run {} at {}: adds {}, deletes {}",
+			run + 1,
+			simulated.time.format("%Y-%m-%d %H:%M"),
+			simulated.new_set,
+			pruned
+		);
+		kept.push(simulated.new_set.clone());
+		kept.retain(|set| !simulated.pruned.contains(set));
+	}
+	println!("after {} runs {} sets would be kept:", runs, kept.len());
+	for set in kept {
+		println!("  {}", set);
+	}
+}
+
+/// Keeps backups from changing the destination while a command does, and
+/// tidies up after any backup that crashed
+fn lock(destination: &str) -> DestinationLock {
+	let lock = exit_on_error("Locking destination", lock_destination(destination, false));
+	exit_on_error("Cleaning up", clean_up_temp_sets(destination));
+	lock
+}
+
+fn keyring(identities: &[String], passphrase: &PassphraseArgs) -> std::io::Result<Keyring> {
+	Ok(Keyring::from_identity_files(identities)?.with_passphrase(passphrase.read(false)?))
+}
+
+/// Exits, first sending any output still on its way to the log
+fn exit(code: i32) -> ! {
+	if let Some(forwarder) = LOG_FORWARDER.lock().unwrap().take() {
+		forwarder.finish();
+	}
+	process::exit(code)
+}
+
+fn exit_on_error<T, E: std::fmt::Display>(operation: &str, result: Result<T, E>) -> T {
+	result.unwrap_or_else(|e| {
+		eprintln!("{} failed: {}", operation, e);
+		exit(1);
+	})
+}
+
+fn newest_set(destination: &str) -> std::io::Result<Option<String>> {
+	Ok(list_sets_by_status(destination)?.complete.pop())
+}
@@ -0,0 +1,71 @@
+use crate::notify::{every_event, RunEvent, RunReport};
+use notify_rust::Notification;
+use serde::Deserialize;
+use std::io;
+
+/// Shows a desktop notification when a run ends, for backups started from
+/// a desktop session, e.g.
+///
+/// ```toml
+/// [notify.desktop]
+/// on = ["warning", "failure"]
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Desktop {
+	/// Which runs to show, all of them by default
+	#[serde(default = "every_event")]
+	pub on: Vec<RunEvent>,
+}
+
+impl Desktop {
+	pub fn send(&self, report: &RunReport) -> io::Result<()> {
+		let event = report.event();
+		if !self.on.contains(&event) {
+			return Ok(());
+		}
+		// the headline is the summary's first line
+		let summary = report.summary();
+		let body = summary.split_once('\n').map_or("", |(_, body)| body);
+		let mut notification = Notification::new();
+		notification
+			.appname(env!("CARGO_PKG_NAME"))
+			.summary(&report.headline())
+			.body(body.trim_end());
+		#[cfg(all(unix, not(target_os = "macos")))]
+		notification.urgency(match event {
+			RunEvent::Failure => notify_rust::Urgency::Critical,
+			_ => notify_rust::Urgency::Normal,
+		});
+		notification.show().map_err(io::Error::other)?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::Utc;
+
+	#[test]
+	fn test_skips_other_events() -> io::Result<()> {
+		let desktop = Desktop {
+			on: vec![RunEvent::Failure],
+		};
+		let report = RunReport {
+			destination: "/media/usb".to_string(),
+			set_name: Some("dhb-set-20250101-000000".to_string()),
+			started_at: Utc::now(),
+			finished_at: Utc::now(),
+			stats: None,
+			error: None,
+			warnings: Vec::new(),
+			attempt: None,
+			set_count: None,
+			free_bytes: None,
+		};
+
+		// without a desktop session to show it in, showing it would fail
+		desktop.send(&report)
+	}
+}
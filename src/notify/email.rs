@@ -0,0 +1,181 @@
+use crate::notify::{every_event, RunEvent, RunReport};
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use serde::Deserialize;
+use std::io;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Mails a summary of each run through an SMTP server, e.g.
+///
+/// ```toml
+/// [notify.email]
+/// from = "backups@example.com"
+/// to = ["susie@example.com"]
+/// server = "smtp.example.com"
+/// username = "backups@example.com"
+/// password = "…"
+/// ```
+///
+/// Keep the config file readable only by its owner if it has a password.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Email {
+	pub from: String,
+	pub to: Vec<String>,
+	pub server: String,
+	/// Defaults to the usual port for the security
+	pub port: Option<u16>,
+	#[serde(default)]
+	pub security: SmtpSecurity,
+	pub username: Option<String>,
+	pub password: Option<String>,
+	/// Which runs to mail about, all of them by default
+	#[serde(default = "every_event")]
+	pub on: Vec<RunEvent>,
+}
+
+/// How the connection to the SMTP server is secured
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+	/// Upgrading a plain connection, usually on port 587
+	#[default]
+	StartTls,
+	/// TLS from the start, usually on port 465
+	Tls,
+	/// Unencrypted, only for a relay on the same machine or network
+	None,
+}
+
+impl Email {
+	/// Mails the run's summary, if it's one of the events asked for
+	pub fn send(&self, report: &RunReport) -> io::Result<()> {
+		if !self.on.contains(&report.event()) {
+			return Ok(());
+		}
+		let message = self.message(report)?;
+		let builder = match self.security {
+			SmtpSecurity::StartTls => SmtpTransport::starttls_relay(&self.server),
+			SmtpSecurity::Tls => SmtpTransport::relay(&self.server),
+			SmtpSecurity::None => Ok(SmtpTransport::builder_dangerous(&self.server)),
+		}
+		.map_err(io::Error::other)?;
+		let mut builder = builder.timeout(Some(TIMEOUT));
+		if let Some(port) = self.port {
+			builder = builder.port(port);
+		}
+		if let (Some(username), Some(password)) = (&self.username, &self.password) {
+			builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+		}
+		builder.build().send(&message).map_err(io::Error::other)?;
+		Ok(())
+	}
+
+	fn message(&self, report: &RunReport) -> io::Result<Message> {
+		let mailbox = |address: &str| {
+			address.parse::<Mailbox>().map_err(|e| {
+				io::Error::new(
+					io::ErrorKind::InvalidInput,
+					format!("{} is not an email address: {}", address, e),
+				)
+			})
+		};
+		let mut builder = Message::builder()
+			.from(mailbox(&self.from)?)
+			.subject(report.headline())
+			.header(ContentType::TEXT_PLAIN);
+		for to in &self.to {
+			builder = builder.to(mailbox(to)?);
+		}
+		builder
+			.body(report.summary())
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::Utc;
+	use std::io::{BufRead, BufReader, Write};
+	use std::net::TcpListener;
+	use std::thread;
+
+	/// Plays an SMTP server for one message, giving back what was sent
+	fn receive_one(listener: TcpListener) -> thread::JoinHandle<String> {
+		thread::spawn(move || {
+			let (stream, _) = listener.accept().unwrap();
+			let mut writer = stream.try_clone().unwrap();
+			let mut reader = BufReader::new(stream);
+			let mut data = String::new();
+			writer.write_all(b"220 localhost ready\r\n").unwrap();
+			loop {
+				let mut line = String::new();
+				if reader.read_line(&mut line).unwrap() == 0 {
+					break;
+				}
+				let reply: &[u8] = match line.to_ascii_uppercase() {
+					command if command.starts_with("DATA") => {
+						writer.write_all(b"354 go ahead\r\n").unwrap();
+						loop {
+							let mut line = String::new();
+							reader.read_line(&mut line).unwrap();
+							if line == ".\r\n" {
+								break;
+							}
+							data.push_str(&line);
+						}
+						b"250 queued\r\n"
+					}
+					command if command.starts_with("QUIT") => {
+						writer.write_all(b"221 bye\r\n").unwrap();
+						break;
+					}
+					_ => b"250 ok\r\n",
+				};
+				writer.write_all(reply).unwrap();
+			}
+			data
+		})
+	}
+
+	#[test]
+	fn test_mails_summary() -> io::Result<()> {
+		let listener = TcpListener::bind("127.0.0.1:0")?;
+		let port = listener.local_addr()?.port();
+		let received = receive_one(listener);
+		let email = Email {
+			from: "backups@example.com".to_string(),
+			to: vec!["susie@example.com".to_string()],
+			server: "127.0.0.1".to_string(),
+			port: Some(port),
+			security: SmtpSecurity::None,
+			username: None,
+			password: None,
+			on: every_event(),
+		};
+		let report = RunReport {
+			destination: "/media/usb".to_string(),
+			set_name: None,
+			started_at: Utc::now(),
+			finished_at: Utc::now(),
+			stats: None,
+			error: Some("disk full".to_string()),
+			warnings: Vec::new(),
+			attempt: None,
+			set_count: None,
+			free_bytes: None,
+		};
+
+		email.send(&report)?;
+
+		let data = received.join().unwrap();
+		assert!(data.contains("Subject: Backup to /media/usb failed"));
+		assert!(data.contains("Error: disk full"));
+		Ok(())
+	}
+}
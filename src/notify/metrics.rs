@@ -0,0 +1,269 @@
+use crate::notify::RunReport;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const PREFIX: &str = "disk_hog_backup";
+
+/// A metric's name without the prefix, type, help and value, if it has one
+type Metric = (&'static str, &'static str, &'static str, Option<f64>);
+
+/// Writes the run's metrics in Prometheus' text format for node_exporter's
+/// textfile collector to pick up. Counters and the time of the last
+/// successful run carry on from the file the previous run left. The file
+/// is replaced in one go so a scrape never sees half of it.
+pub fn write_metrics_file(path: &Path, report: &RunReport) -> io::Result<()> {
+	let previous = match fs::read_to_string(path) {
+		Ok(previous) => previous,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+		Err(e) => return Err(e),
+	};
+	let contents = render_metrics(report, &previous);
+	let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+	temp_name.push(".tmp");
+	let temp_path = path.with_file_name(temp_name);
+	fs::write(&temp_path, contents)?;
+	fs::rename(temp_path, path)
+}
+
+/// The metrics for a run, given the metrics the previous run wrote
+pub fn render_metrics(report: &RunReport, previous: &str) -> String {
+	format_metrics(&[(labels(report), run_metrics(report, previous))])
+}
+
+/// The metrics of each destination's last run in the history, which is
+/// oldest first, as [write_metrics_file] would have left them had every
+/// run written to the same file. Counters only go back as far as the
+/// history does.
+pub fn render_history_metrics(runs: &[RunReport]) -> String {
+	let mut last_runs: BTreeMap<&str, (&RunReport, String)> = BTreeMap::new();
+	for run in runs {
+		let previous = match last_runs.get(run.destination.as_str()) {
+			Some((report, before)) => render_metrics(report, before),
+			None => String::new(),
+		};
+		last_runs.insert(&run.destination, (run, previous));
+	}
+	let destinations: Vec<_> = last_runs
+		.values()
+		.map(|(report, previous)| (labels(report), run_metrics(report, previous)))
+		.collect();
+	format_metrics(&destinations)
+}
+
+fn labels(report: &RunReport) -> String {
+	format!("{{destination=\"{}\"}}", escape_label(&report.destination))
+}
+
+fn run_metrics(report: &RunReport, previous: &str) -> Vec<Metric> {
+	let carried = |name: &str| previous_value(previous, &format!("{}_{}", PREFIX, name));
+	let finished = report.finished_at.timestamp() as f64;
+	let duration = (report.finished_at - report.started_at).num_milliseconds() as f64 / 1000.0;
+	let succeeded = report.succeeded();
+
+	let mut metrics: Vec<Metric> = vec![
+		(
+			"last_run_timestamp_seconds",
+			"gauge",
+			"When the last backup finished",
+			Some(finished),
+		),
+		(
+			"last_run_success",
+			"gauge",
+			"Whether the last backup succeeded",
+			Some(if succeeded { 1.0 } else { 0.0 }),
+		),
+		(
+			"last_run_duration_seconds",
+			"gauge",
+			"How long the last backup took",
+			Some(duration),
+		),
+		(
+			"last_success_timestamp_seconds",
+			"gauge",
+			"When the last successful backup finished",
+			match succeeded {
+				true => Some(finished),
+				false => carried("last_success_timestamp_seconds"),
+			},
+		),
+		(
+			"runs_total",
+			"counter",
+			"Backups run",
+			Some(carried("runs_total").unwrap_or(0.0) + 1.0),
+		),
+		(
+			"failed_runs_total",
+			"counter",
+			"Backups that failed",
+			Some(carried("failed_runs_total").unwrap_or(0.0) + if succeeded { 0.0 } else { 1.0 }),
+		),
+	];
+	if let Some(stats) = report.stats {
+		metrics.extend([
+			(
+				"last_run_files",
+				"gauge",
+				"Files in the last set",
+				Some(stats.files as f64),
+			),
+			(
+				"last_run_bytes",
+				"gauge",
+				"Bytes backed up by the last backup",
+				Some(stats.bytes as f64),
+			),
+			(
+				"last_run_deduplicated_bytes",
+				"gauge",
+				"Of the bytes backed up by the last backup, those already stored",
+				Some(stats.deduplicated_bytes as f64),
+			),
+		]);
+	}
+	metrics.extend([
+		(
+			"sets",
+			"gauge",
+			"Sets in the destination",
+			report.set_count.map(|count| count as f64),
+		),
+		(
+			"destination_free_bytes",
+			"gauge",
+			"Space left in the destination",
+			report.free_bytes.map(|free| free as f64),
+		),
+	]);
+	metrics
+}
+
+/// Prometheus' text format, each metric's help and type given once ahead
+/// of its value for every set of labels that has one
+fn format_metrics(labelled: &[(String, Vec<Metric>)]) -> String {
+	let mut out = String::new();
+	let mut written = Vec::new();
+	for (_, metrics) in labelled {
+		for (name, kind, help, _) in metrics {
+			if written.contains(name) {
+				continue;
+			}
+			written.push(*name);
+			let values: Vec<_> = labelled
+				.iter()
+				.filter_map(|(labels, metrics)| {
+					let value = metrics.iter().find(|metric| metric.0 == *name)?.3?;
+					Some((labels, value))
+				})
+				.collect();
+			if values.is_empty() {
+				continue;
+			}
+			let name = format!("{}_{}", PREFIX, name);
+			let _ = writeln!(out, "# HELP {} {}", name, help);
+			let _ = writeln!(out, "# TYPE {} {}", name, kind);
+			for (labels, value) in values {
+				let _ = writeln!(out, "{}{} {}", name, labels, value);
+			}
+		}
+	}
+	out
+}
+
+fn previous_value(previous: &str, name: &str) -> Option<f64> {
+	previous
+		.lines()
+		.filter(|line| !line.starts_with('#'))
+		.find(|line| {
+			line.starts_with(&format!("{}{{", name)) || line.starts_with(&format!("{} ", name))
+		})
+		.and_then(|line| line.rsplit(' ').next())
+		.and_then(|value| value.parse().ok())
+}
+
+fn escape_label(value: &str) -> String {
+	value
+		.replace('\\', "\\\\")
+		.replace('"', "\\\"")
+		.replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup_sets::set_metadata::SetStats;
+	use chrono::{TimeDelta, Utc};
+
+	fn report(error: Option<&str>) -> RunReport {
+		let finished_at = Utc::now();
+		RunReport {
+			destination: "/media/usb".to_string(),
+			set_name: None,
+			started_at: finished_at - TimeDelta::seconds(90),
+			finished_at,
+			stats: Some(SetStats {
+				files: 3,
+				folders: 1,
+				bytes: 1000,
+				deduplicated_bytes: 400,
+			}),
+			error: error.map(str::to_string),
+			warnings: Vec::new(),
+			attempt: None,
+			set_count: Some(5),
+			free_bytes: None,
+		}
+	}
+
+	#[test]
+	fn test_renders_metrics() {
+		let succeeded = render_metrics(&report(None), "");
+		assert!(
+			succeeded.contains("disk_hog_backup_last_run_success{destination=\"/media/usb\"} 1\n")
+		);
+		assert!(succeeded.contains(
+			"disk_hog_backup_last_run_duration_seconds{destination=\"/media/usb\"} 90\n"
+		));
+		assert!(succeeded.contains("disk_hog_backup_sets{destination=\"/media/usb\"} 5\n"));
+		assert!(!succeeded.contains("destination_free_bytes"));
+
+		// counters and the last success carry on through a failure
+		let failed = render_metrics(&report(Some("disk full")), &succeeded);
+		assert!(failed.contains("disk_hog_backup_last_run_success{destination=\"/media/usb\"} 0\n"));
+		assert!(failed.contains("disk_hog_backup_runs_total{destination=\"/media/usb\"} 2\n"));
+		assert!(
+			failed.contains("disk_hog_backup_failed_runs_total{destination=\"/media/usb\"} 1\n")
+		);
+		assert_eq!(
+			previous_value(&failed, "disk_hog_backup_last_success_timestamp_seconds"),
+			previous_value(&succeeded, "disk_hog_backup_last_success_timestamp_seconds")
+		);
+	}
+
+	#[test]
+	fn test_renders_history_metrics() {
+		let mut nas = report(None);
+		nas.destination = "/mnt/nas".to_string();
+		let runs = [report(None), nas, report(Some("disk full"))];
+
+		let metrics = render_history_metrics(&runs);
+
+		assert_eq!(
+			metrics
+				.matches("# HELP disk_hog_backup_runs_total ")
+				.count(),
+			1
+		);
+		assert!(metrics.contains("disk_hog_backup_runs_total{destination=\"/media/usb\"} 2\n"));
+		assert!(metrics.contains("disk_hog_backup_runs_total{destination=\"/mnt/nas\"} 1\n"));
+		assert!(
+			metrics.contains("disk_hog_backup_last_run_success{destination=\"/media/usb\"} 0\n")
+		);
+		assert!(metrics.contains("disk_hog_backup_last_run_success{destination=\"/mnt/nas\"} 1\n"));
+	}
+}
@@ -0,0 +1,268 @@
+pub mod desktop;
+pub mod email;
+pub mod metrics;
+pub mod mqtt;
+pub mod ping;
+pub mod webhook;
+
+use crate::backup_sets::backup_set::list_sets;
+use crate::backup_sets::set_metadata::{read_metadata, SetStats};
+use crate::space::filesystem::filesystem_space;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Write as _};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The config file's `[notify]` section, saying who hears how backups went
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyConfig {
+	/// Prometheus metrics for node_exporter's textfile collector, e.g.
+	/// `/var/lib/node_exporter/textfile/disk_hog_backup.prom`
+	pub metrics_file: Option<PathBuf>,
+	/// URLs the run report is posted to as JSON, e.g. for Slack, Discord
+	/// or ntfy
+	#[serde(default)]
+	pub webhooks: Vec<webhook::Webhook>,
+	/// A summary of each run mailed out
+	pub email: Option<email::Email>,
+	/// Desktop notifications, for backups run from a desktop session
+	pub desktop: Option<desktop::Desktop>,
+	/// A dead man's switch told when runs start and end
+	pub ping: Option<ping::Ping>,
+	/// An MQTT broker told when runs start and end, e.g. for Home
+	/// Assistant
+	pub mqtt: Option<mqtt::Mqtt>,
+}
+
+/// How a run ended, for choosing who hears about it
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunEvent {
+	Success,
+	/// The set was written but something after it wasn't, like a replica
+	Warning,
+	Failure,
+}
+
+impl fmt::Display for RunEvent {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let name = match self {
+			RunEvent::Success => "success",
+			RunEvent::Warning => "warning",
+			RunEvent::Failure => "failure",
+		};
+		f.write_str(name)
+	}
+}
+
+pub(crate) fn every_event() -> Vec<RunEvent> {
+	vec![RunEvent::Success, RunEvent::Warning, RunEvent::Failure]
+}
+
+/// How a backup run went
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RunReport {
+	/// Where the set went, as given on the command line
+	pub destination: String,
+	pub set_name: Option<String>,
+	pub started_at: DateTime<Utc>,
+	pub finished_at: DateTime<Utc>,
+	pub stats: Option<SetStats>,
+	/// Why the run failed, if it did
+	pub error: Option<String>,
+	/// What went wrong without failing the run
+	#[serde(default)]
+	pub warnings: Vec<String>,
+	/// Which try this was, when the daemon retries failed runs
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub attempt: Option<u32>,
+	/// Sets in the destination after the run, for local destinations
+	pub set_count: Option<usize>,
+	/// Space left in the destination after the run, for local destinations
+	pub free_bytes: Option<u64>,
+}
+
+impl RunReport {
+	/// Reports the outcome of a backup, adding what the set and a local
+	/// destination say about it
+	pub fn new(
+		destination: &str,
+		local_folder: Option<&str>,
+		started_at: DateTime<Utc>,
+		result: &io::Result<String>,
+	) -> RunReport {
+		let set_name = result.as_ref().ok().cloned();
+		let stats = match (local_folder, &set_name) {
+			(Some(folder), Some(set_name)) => read_metadata(&Path::new(folder).join(set_name))
+				.ok()
+				.and_then(|metadata| metadata.stats),
+			_ => None,
+		};
+		RunReport {
+			destination: destination.to_string(),
+			set_name,
+			started_at,
+			finished_at: Utc::now(),
+			stats,
+			error: result.as_ref().err().map(|e| e.to_string()),
+			warnings: Vec::new(),
+			attempt: None,
+			set_count: local_folder
+				.and_then(|folder| list_sets(folder).ok().map(|sets| sets.len())),
+			free_bytes: local_folder
+				.and_then(|folder| filesystem_space(Path::new(folder)).ok())
+				.map(|space| space.free),
+		}
+	}
+
+	pub fn succeeded(&self) -> bool {
+		self.error.is_none()
+	}
+
+	/// One line saying how the run went, e.g. for a subject line
+	pub fn headline(&self) -> String {
+		match self.event() {
+			RunEvent::Success => format!("Backup to {} succeeded", self.destination),
+			RunEvent::Warning => format!("Backup to {} finished with warnings", self.destination),
+			RunEvent::Failure => format!("Backup to {} failed", self.destination),
+		}
+	}
+
+	/// What's known about the run, a line for each thing
+	pub fn summary(&self) -> String {
+		let mut out = String::new();
+		let _ = writeln!(out, "{}", self.headline());
+		if let Some(set_name) = &self.set_name {
+			let _ = writeln!(out, "Set: {}", set_name);
+		}
+		if let Some(attempt) = self.attempt.filter(|attempt| *attempt > 1) {
+			let _ = writeln!(out, "Attempt: {}", attempt);
+		}
+		let _ = writeln!(out, "Started: {}", self.started_at.to_rfc3339());
+		let _ = writeln!(
+			out,
+			"Took: {}s",
+			(self.finished_at - self.started_at).num_seconds()
+		);
+		if let Some(stats) = self.stats {
+			let _ = writeln!(
+				out,
+				"Backed up: {} files, {} bytes of which {} were already stored",
+				stats.files, stats.bytes, stats.deduplicated_bytes
+			);
+		}
+		if let Some(set_count) = self.set_count {
+			let _ = writeln!(out, "Sets in the destination: {}", set_count);
+		}
+		if let Some(free_bytes) = self.free_bytes {
+			let _ = writeln!(out, "Free space: {} bytes", free_bytes);
+		}
+		if let Some(error) = &self.error {
+			let _ = writeln!(out, "Error: {}", error);
+		}
+		for warning in &self.warnings {
+			let _ = writeln!(out, "Warning: {}", warning);
+		}
+		out
+	}
+
+	pub fn event(&self) -> RunEvent {
+		match (&self.error, self.warnings.is_empty()) {
+			(Some(_), _) => RunEvent::Failure,
+			(None, false) => RunEvent::Warning,
+			(None, true) => RunEvent::Success,
+		}
+	}
+}
+
+/// Tells those the config asks for that a run is starting. Failures are
+/// returned rather than stopping the run.
+pub fn notify_started(config: &NotifyConfig, destination: &str) -> Vec<io::Error> {
+	let mut failures = Vec::new();
+	if let Some(mqtt) = &config.mqtt {
+		if let Err(e) = mqtt.started(destination) {
+			failures.push(io::Error::new(
+				e.kind(),
+				format!("publishing to {}: {}", mqtt.host, e),
+			));
+		}
+	}
+	if let Some(ping) = &config.ping {
+		if let Err(e) = ping.started() {
+			failures.push(io::Error::new(
+				e.kind(),
+				format!("pinging {}: {}", ping.url, e),
+			));
+		}
+	}
+	failures
+}
+
+/// Keeps those the config asks for up to date with how far the run in
+/// this process has got, until it's dropped
+pub fn publish_progress(
+	config: &NotifyConfig,
+	destination: &str,
+) -> Option<mqtt::ProgressPublisher> {
+	config
+		.mqtt
+		.as_ref()
+		.map(|mqtt| mqtt.publish_progress(destination))
+}
+
+/// Tells everyone the config asks for how the run went. Failing to reach
+/// one doesn't stop the others, and each failure is returned.
+pub fn notify(config: &NotifyConfig, report: &RunReport) -> Vec<io::Error> {
+	let mut failures = Vec::new();
+	if let Some(path) = &config.metrics_file {
+		if let Err(e) = metrics::write_metrics_file(path, report) {
+			failures.push(io::Error::new(
+				e.kind(),
+				format!("writing metrics to {}: {}", path.display(), e),
+			));
+		}
+	}
+	if let Some(email) = &config.email {
+		if let Err(e) = email.send(report) {
+			failures.push(io::Error::new(
+				e.kind(),
+				format!("emailing {}: {}", email.to.join(", "), e),
+			));
+		}
+	}
+	if let Some(desktop) = &config.desktop {
+		if let Err(e) = desktop.send(report) {
+			failures.push(io::Error::new(
+				e.kind(),
+				format!("showing a desktop notification: {}", e),
+			));
+		}
+	}
+	if let Some(ping) = &config.ping {
+		if let Err(e) = ping.finished(report) {
+			failures.push(io::Error::new(
+				e.kind(),
+				format!("pinging {}: {}", ping.url, e),
+			));
+		}
+	}
+	if let Some(mqtt) = &config.mqtt {
+		if let Err(e) = mqtt.finished(report) {
+			failures.push(io::Error::new(
+				e.kind(),
+				format!("publishing to {}: {}", mqtt.host, e),
+			));
+		}
+	}
+	for webhook in &config.webhooks {
+		if let Err(e) = webhook.send(report) {
+			failures.push(io::Error::new(
+				e.kind(),
+				format!("posting to {}: {}", webhook.url, e),
+			));
+		}
+	}
+	failures
+}
@@ -0,0 +1,322 @@
+use crate::notify::webhook::Payload;
+use crate::notify::RunReport;
+use crate::progress::{self, Progress, Stage};
+use chrono::{DateTime, Utc};
+use rumqttc::{Client, Event, MqttOptions, Outgoing, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// As long as posting to a webhook is given
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// How often the run's progress is looked at
+const PROGRESS_CHECK: Duration = Duration::from_secs(2);
+/// The least time between progress messages, as each is a connection
+const PROGRESS_GAP: Duration = Duration::from_secs(15);
+
+/// An MQTT broker that's told when runs start and end, e.g. for a Home
+/// Assistant dashboard
+///
+/// ```toml
+/// [notify.mqtt]
+/// host = "homeassistant.local"
+/// topic = "disk-hog-backup/laptop"
+/// username = "backups"
+/// password = "…"
+/// ```
+///
+/// Each event is published to the topic as JSON, with an `event` field of
+/// `started`, `success`, `warning` or `failure`, and the run report once
+/// it's finished. They're retained, so anything subscribing later sees
+/// how the last run went. While it runs, `progress` events give its
+/// stage, files and bytes done and `percent` of the estimate, at most
+/// every 15 seconds and only when the stage or whole percent changes.
+/// They aren't retained.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Mqtt {
+	pub host: String,
+	#[serde(default = "default_port")]
+	pub port: u16,
+	#[serde(default = "default_topic")]
+	pub topic: String,
+	pub username: Option<String>,
+	pub password: Option<String>,
+}
+
+fn default_port() -> u16 {
+	1883
+}
+
+fn default_topic() -> String {
+	"disk-hog-backup".to_string()
+}
+
+#[derive(Serialize)]
+struct Started<'a> {
+	event: &'static str,
+	destination: &'a str,
+	started_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct ProgressEvent<'a> {
+	event: &'static str,
+	destination: &'a str,
+	#[serde(flatten)]
+	progress: &'a Progress,
+	percent: Option<u64>,
+}
+
+/// Publishes the run's progress until dropped
+pub struct ProgressPublisher {
+	stop: Option<Sender<()>>,
+	publisher: Option<JoinHandle<()>>,
+}
+
+impl Drop for ProgressPublisher {
+	fn drop(&mut self) {
+		drop(self.stop.take());
+		if let Some(publisher) = self.publisher.take() {
+			let _ = publisher.join();
+		}
+	}
+}
+
+/// Whether progress has moved on enough from what was last published to
+/// be worth another message
+fn worth_publishing(last: Option<&Progress>, progress: &Progress) -> bool {
+	let whole_percent = |progress: &Progress| progress.percent().map(|percent| percent.floor());
+	match last {
+		None => progress.stage != Stage::Preparing,
+		Some(last) => {
+			last.stage != progress.stage || whole_percent(last) != whole_percent(progress)
+		}
+	}
+}
+
+impl Mqtt {
+	/// Publishes that a run to the destination is starting
+	pub fn started(&self, destination: &str) -> io::Result<()> {
+		let payload = serde_json::to_string(&Started {
+			event: "started",
+			destination,
+			started_at: Utc::now(),
+		})
+		.map_err(io::Error::other)?;
+		self.publish(payload, true)
+	}
+
+	/// Publishes how far the run to the destination has got
+	pub fn progress(&self, destination: &str, progress: &Progress) -> io::Result<()> {
+		let payload = serde_json::to_string(&ProgressEvent {
+			event: "progress",
+			destination,
+			progress,
+			percent: progress.percent().map(|percent| percent.floor() as u64),
+		})
+		.map_err(io::Error::other)?;
+		self.publish(payload, false)
+	}
+
+	/// Starts publishing the progress of the run in this process, which
+	/// should be dropped before publishing how it finished. A broker that
+	/// can't be reached isn't tried again until the next run.
+	pub fn publish_progress(&self, destination: &str) -> ProgressPublisher {
+		let mqtt = self.clone();
+		let destination = destination.to_string();
+		let (stop, stopped) = mpsc::channel();
+		let publisher = thread::spawn(move || {
+			let mut last: Option<(Progress, Instant)> = None;
+			while stopped.recv_timeout(PROGRESS_CHECK) == Err(RecvTimeoutError::Timeout) {
+				let progress = progress::current();
+				if last.is_some_and(|(_, at)| at.elapsed() < PROGRESS_GAP)
+					|| !worth_publishing(last.as_ref().map(|(last, _)| last), &progress)
+				{
+					continue;
+				}
+				if let Err(e) = mqtt.progress(&destination, &progress) {
+					eprintln!("Publishing progress to {} failed: {}", mqtt.host, e);
+					break;
+				}
+				last = Some((progress, Instant::now()));
+			}
+		});
+		ProgressPublisher {
+			stop: Some(stop),
+			publisher: Some(publisher),
+		}
+	}
+
+	/// Publishes how the run ended, with its report
+	pub fn finished(&self, report: &RunReport) -> io::Result<()> {
+		let payload = serde_json::to_string(&Payload {
+			event: report.event(),
+			report,
+		})
+		.map_err(io::Error::other)?;
+		self.publish(payload, true)
+	}
+
+	/// Connects, publishes the one message and waits for the broker to
+	/// acknowledge it before disconnecting
+	fn publish(&self, payload: String, retain: bool) -> io::Result<()> {
+		let client_id = format!("disk-hog-backup-{}", std::process::id());
+		let mut options = MqttOptions::new(client_id, &self.host, self.port);
+		options.set_keep_alive(TIMEOUT);
+		if let Some(username) = &self.username {
+			options.set_credentials(username, self.password.clone().unwrap_or_default());
+		}
+		let (client, mut connection) = Client::new(options, 10);
+		client
+			.publish(&self.topic, QoS::AtLeastOnce, retain, payload)
+			.map_err(io::Error::other)?;
+		let deadline = Instant::now() + TIMEOUT;
+		loop {
+			match connection.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+				Ok(Ok(Event::Incoming(Packet::PubAck(_)))) => break,
+				Ok(Ok(_)) => {}
+				Ok(Err(e)) => return Err(io::Error::other(e)),
+				Err(_) => {
+					return Err(io::Error::new(
+						io::ErrorKind::TimedOut,
+						format!("{} didn't acknowledge the event", self.host),
+					))
+				}
+			}
+		}
+		// the event's delivered, so a broker that's slow to hear the
+		// goodbye doesn't matter
+		if client.disconnect().is_ok() {
+			while let Ok(Ok(event)) = connection.recv_timeout(Duration::from_secs(1)) {
+				if event == Event::Outgoing(Outgoing::Disconnect) {
+					break;
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::{Read, Write};
+	use std::net::{TcpListener, TcpStream};
+	use std::thread;
+
+	/// Reads an MQTT packet, giving its type and what follows its header
+	fn read_packet(stream: &mut TcpStream) -> io::Result<(u8, Vec<u8>)> {
+		let mut byte = [0; 1];
+		stream.read_exact(&mut byte)?;
+		let packet_type = byte[0] >> 4;
+		let (mut length, mut shift) = (0, 0);
+		loop {
+			stream.read_exact(&mut byte)?;
+			length |= ((byte[0] & 0x7f) as usize) << shift;
+			shift += 7;
+			if byte[0] & 0x80 == 0 {
+				break;
+			}
+		}
+		let mut rest = vec![0; length];
+		stream.read_exact(&mut rest)?;
+		Ok((packet_type, rest))
+	}
+
+	/// A broker that accepts one connection and publish, giving back the
+	/// topic and message once the client disconnects
+	fn receive_one(listener: TcpListener) -> thread::JoinHandle<(String, String)> {
+		thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			let (packet_type, _) = read_packet(&mut stream).unwrap();
+			assert_eq!(packet_type, 1, "CONNECT");
+			stream.write_all(&[0x20, 2, 0, 0]).unwrap();
+			let (packet_type, publish) = read_packet(&mut stream).unwrap();
+			assert_eq!(packet_type, 3, "PUBLISH");
+			let topic_length = u16::from_be_bytes([publish[0], publish[1]]) as usize;
+			let topic = String::from_utf8(publish[2..2 + topic_length].to_vec()).unwrap();
+			let packet_id = &publish[2 + topic_length..4 + topic_length];
+			stream
+				.write_all(&[0x40, 2, packet_id[0], packet_id[1]])
+				.unwrap();
+			let message = String::from_utf8(publish[4 + topic_length..].to_vec()).unwrap();
+			while !matches!(read_packet(&mut stream), Ok((14, _)) | Err(_)) {}
+			(topic, message)
+		})
+	}
+
+	#[test]
+	fn test_publishes_events() -> io::Result<()> {
+		let listener = TcpListener::bind("127.0.0.1:0")?;
+		let mqtt = Mqtt {
+			host: "127.0.0.1".to_string(),
+			port: listener.local_addr()?.port(),
+			topic: "home/backups".to_string(),
+			username: None,
+			password: None,
+		};
+		let received = receive_one(listener);
+
+		mqtt.started("/media/usb")?;
+
+		let (topic, message) = received.join().unwrap();
+		assert_eq!(topic, "home/backups");
+		let message: serde_json::Value = serde_json::from_str(&message)?;
+		assert_eq!(message["event"], "started");
+		assert_eq!(message["destination"], "/media/usb");
+		Ok(())
+	}
+
+	#[test]
+	fn test_publishes_progress() -> io::Result<()> {
+		let listener = TcpListener::bind("127.0.0.1:0")?;
+		let mqtt = Mqtt {
+			host: "127.0.0.1".to_string(),
+			port: listener.local_addr()?.port(),
+			topic: "home/backups".to_string(),
+			username: None,
+			password: None,
+		};
+		let received = receive_one(listener);
+
+		mqtt.progress(
+			"/media/usb",
+			&Progress {
+				stage: Stage::Copying,
+				files_done: 3,
+				bytes_done: 2999,
+				bytes_estimated: Some(10000),
+			},
+		)?;
+
+		let (_, message) = received.join().unwrap();
+		let message: serde_json::Value = serde_json::from_str(&message)?;
+		assert_eq!(message["event"], "progress");
+		assert_eq!(message["stage"], "copying");
+		assert_eq!(message["files_done"], 3);
+		assert_eq!(message["percent"], 29);
+		Ok(())
+	}
+
+	#[test]
+	fn test_only_publishes_progress_that_has_moved_on() {
+		let mut progress = Progress {
+			bytes_estimated: Some(1000),
+			..Default::default()
+		};
+		assert!(!worth_publishing(None, &progress));
+		progress.stage = Stage::Copying;
+		assert!(worth_publishing(None, &progress));
+		let last = progress;
+		progress.bytes_done = 9;
+		assert!(!worth_publishing(Some(&last), &progress));
+		progress.bytes_done = 10;
+		assert!(worth_publishing(Some(&last), &progress));
+		let last = progress;
+		progress.stage = Stage::Finishing;
+		assert!(worth_publishing(Some(&last), &progress));
+	}
+}
@@ -0,0 +1,114 @@
+use crate::notify::webhook::post;
+use crate::notify::{RunEvent, RunReport};
+use serde::Deserialize;
+use std::io;
+
+/// A dead man's switch URL, like healthchecks.io's, pinged when a run
+/// starts and ends, e.g.
+///
+/// ```toml
+/// [notify.ping]
+/// url = "https://hc-ping.com/your-check-uuid"
+/// ```
+///
+/// The service alerts when pings stop coming, which catches scheduled
+/// backups that no longer run at all.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Ping {
+	pub url: String,
+}
+
+impl Ping {
+	/// Pings `<url>/start`, so the service can also tell how long runs take
+	/// and alert on runs that never finish
+	pub fn started(&self) -> io::Result<()> {
+		post(&format!("{}/start", self.url()), "text/plain", "")
+	}
+
+	/// Pings the URL itself when the run succeeded, or `<url>/fail` when it
+	/// didn't or had warnings, sending the run's summary along
+	pub fn finished(&self, report: &RunReport) -> io::Result<()> {
+		let url = match report.event() {
+			RunEvent::Success => self.url().to_string(),
+			RunEvent::Warning | RunEvent::Failure => format!("{}/fail", self.url()),
+		};
+		post(&url, "text/plain", &report.summary())
+	}
+
+	fn url(&self) -> &str {
+		self.url.trim_end_matches('/')
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::Utc;
+	use std::io::{BufRead, BufReader, Read, Write};
+	use std::net::TcpListener;
+	use std::thread;
+
+	/// Answers requests with 200 OK, giving back the path each was for
+	fn receive(listener: TcpListener, count: usize) -> thread::JoinHandle<Vec<String>> {
+		thread::spawn(move || {
+			let mut paths = Vec::new();
+			for _ in 0..count {
+				let (stream, _) = listener.accept().unwrap();
+				let mut reader = BufReader::new(stream);
+				let mut request_line = String::new();
+				reader.read_line(&mut request_line).unwrap();
+				paths.push(request_line.split(' ').nth(1).unwrap().to_string());
+				let mut length = 0;
+				loop {
+					let mut line = String::new();
+					reader.read_line(&mut line).unwrap();
+					if line.trim().is_empty() {
+						break;
+					}
+					if let Some((name, value)) = line.split_once(':') {
+						if name.eq_ignore_ascii_case("content-length") {
+							length = value.trim().parse().unwrap();
+						}
+					}
+				}
+				reader.read_exact(&mut vec![0; length]).unwrap();
+				reader
+					.get_mut()
+					.write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 0\r\n\r\n")
+					.unwrap();
+			}
+			paths
+		})
+	}
+
+	#[test]
+	fn test_pings_start_and_end() -> io::Result<()> {
+		let listener = TcpListener::bind("127.0.0.1:0")?;
+		let ping = Ping {
+			url: format!("http://{}/check/", listener.local_addr()?),
+		};
+		let received = receive(listener, 2);
+		let report = RunReport {
+			destination: "/media/usb".to_string(),
+			set_name: None,
+			started_at: Utc::now(),
+			finished_at: Utc::now(),
+			stats: None,
+			error: Some("disk full".to_string()),
+			warnings: Vec::new(),
+			attempt: None,
+			set_count: None,
+			free_bytes: None,
+		};
+
+		ping.started()?;
+		ping.finished(&report)?;
+
+		assert_eq!(
+			received.join().unwrap(),
+			vec!["/check/start", "/check/fail"]
+		);
+		Ok(())
+	}
+}
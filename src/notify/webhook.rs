@@ -0,0 +1,132 @@
+use crate::notify::{every_event, RunEvent, RunReport};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::time::Duration;
+
+// Long enough for a slow service, short enough not to hold up the next
+// scheduled backup
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A URL the run report is posted to, e.g.
+///
+/// ```toml
+/// [[notify.webhooks]]
+/// url = "https://ntfy.example/backups"
+/// on = ["failure", "warning"]
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+	pub url: String,
+	/// Which runs to post about, all of them by default
+	#[serde(default = "every_event")]
+	pub on: Vec<RunEvent>,
+}
+
+/// A run report with an `event` field saying how the run ended
+#[derive(Serialize)]
+pub(crate) struct Payload<'a> {
+	pub event: RunEvent,
+	#[serde(flatten)]
+	pub report: &'a RunReport,
+}
+
+impl Webhook {
+	/// Posts the report as JSON, with an `event` field saying how the run
+	/// ended, if it's one of the events the webhook is for
+	pub fn send(&self, report: &RunReport) -> io::Result<()> {
+		let event = report.event();
+		if !self.on.contains(&event) {
+			return Ok(());
+		}
+		let body = serde_json::to_string(&Payload { event, report }).map_err(io::Error::other)?;
+		post(&self.url, "application/json", &body)
+	}
+}
+
+/// Posts to a URL, failing on anything but a success status
+pub(crate) fn post(url: &str, content_type: &str, body: &str) -> io::Result<()> {
+	let agent: ureq::Agent = ureq::Agent::config_builder()
+		.timeout_global(Some(TIMEOUT))
+		.build()
+		.into();
+	agent
+		.post(url)
+		.header("Content-Type", content_type)
+		.send(body)
+		.map_err(io::Error::other)?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::Utc;
+	use std::io::{BufRead, BufReader, Read, Write};
+	use std::net::TcpListener;
+	use std::thread;
+
+	/// Answers one request with 200 OK, giving back its body
+	fn receive_one(listener: TcpListener) -> thread::JoinHandle<String> {
+		thread::spawn(move || {
+			let (stream, _) = listener.accept().unwrap();
+			let mut reader = BufReader::new(stream);
+			let mut length = 0;
+			loop {
+				let mut line = String::new();
+				reader.read_line(&mut line).unwrap();
+				if line.trim().is_empty() {
+					break;
+				}
+				if let Some((name, value)) = line.split_once(':') {
+					if name.eq_ignore_ascii_case("content-length") {
+						length = value.trim().parse().unwrap();
+					}
+				}
+			}
+			let mut body = vec![0; length];
+			reader.read_exact(&mut body).unwrap();
+			reader
+				.get_mut()
+				.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+				.unwrap();
+			String::from_utf8(body).unwrap()
+		})
+	}
+
+	fn report(error: Option<&str>) -> RunReport {
+		RunReport {
+			destination: "/media/usb".to_string(),
+			set_name: None,
+			started_at: Utc::now(),
+			finished_at: Utc::now(),
+			stats: None,
+			error: error.map(str::to_string),
+			warnings: Vec::new(),
+			attempt: None,
+			set_count: None,
+			free_bytes: None,
+		}
+	}
+
+	#[test]
+	fn test_posts_report() -> io::Result<()> {
+		let listener = TcpListener::bind("127.0.0.1:0")?;
+		let url = format!("http://{}/hook", listener.local_addr()?);
+		let received = receive_one(listener);
+		let webhook = Webhook {
+			url,
+			on: vec![RunEvent::Failure],
+		};
+
+		// not a failure, so not posted, or it would be what was received
+		webhook.send(&report(None))?;
+		webhook.send(&report(Some("disk full")))?;
+
+		let body: serde_json::Value = serde_json::from_str(&received.join().unwrap())?;
+		assert_eq!(body["event"], "failure");
+		assert_eq!(body["error"], "disk full");
+		assert_eq!(body["destination"], "/media/usb");
+		Ok(())
+	}
+}
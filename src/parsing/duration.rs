@@ -0,0 +1,53 @@
+use chrono::TimeDelta;
+
+/// Parses durations like `30s`, `5min`, `12h`, `90d`, `2w`, `6m` or `1y`.
+/// Months are 30 days and years 365 days.
+pub fn parse_duration(value: &str) -> Result<TimeDelta, String> {
+	let invalid = || {
+		format!(
+			"{} is not a duration like 30s, 5min, 12h, 90d, 2w, 6m or 1y",
+			value
+		)
+	};
+	let value = value.trim();
+	let unit_start = value
+		.find(|c: char| !c.is_ascii_digit())
+		.ok_or_else(invalid)?;
+	let (number, unit) = value.split_at(unit_start);
+	let number: i64 = number.parse().map_err(|_| invalid())?;
+	const HOUR: i64 = 60 * 60;
+	let seconds = match unit {
+		"s" => 1,
+		"min" => 60,
+		"h" => HOUR,
+		"d" => 24 * HOUR,
+		"w" => 24 * 7 * HOUR,
+		"m" => 24 * 30 * HOUR,
+		"y" => 24 * 365 * HOUR,
+		_ => return Err(invalid()),
+	};
+	number
+		.checked_mul(seconds)
+		.and_then(TimeDelta::try_seconds)
+		.ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parses_durations() {
+		assert_eq!(parse_duration("12h"), Ok(TimeDelta::hours(12)));
+		assert_eq!(parse_duration("90d"), Ok(TimeDelta::days(90)));
+		assert_eq!(parse_duration("2w"), Ok(TimeDelta::days(14)));
+		assert_eq!(parse_duration("6m"), Ok(TimeDelta::days(180)));
+		assert_eq!(parse_duration("30s"), Ok(TimeDelta::seconds(30)));
+		assert_eq!(parse_duration("5min"), Ok(TimeDelta::minutes(5)));
+		assert_eq!(parse_duration("1y"), Ok(TimeDelta::days(365)));
+		assert!(parse_duration("90").is_err());
+		assert!(parse_duration("d").is_err());
+		assert!(parse_duration("1.5d").is_err());
+		assert!(parse_duration("3 fortnights").is_err());
+	}
+}
@@ -0,0 +1,3 @@
+pub mod duration;
+pub mod percentage;
+pub mod size;
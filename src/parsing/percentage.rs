@@ -0,0 +1,29 @@
+/// Parses a percentage like `5%` or `5`, which must be above 0 and at most 100
+pub fn parse_percentage(value: &str) -> Result<f64, String> {
+	let percent: f64 = value
+		.trim_end_matches('%')
+		.parse()
+		.map_err(|_| format!("{} is not a percentage", value))?;
+	// written so NaN fails too
+	if !(percent > 0.0 && percent <= 100.0) {
+		return Err(format!("{} is not between 0% and 100%", value));
+	}
+	Ok(percent)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parses_percentages() {
+		assert_eq!(parse_percentage("5%"), Ok(5.0));
+		assert_eq!(parse_percentage("12.5"), Ok(12.5));
+		assert_eq!(parse_percentage("100%"), Ok(100.0));
+		assert!(parse_percentage("0%").is_err());
+		assert!(parse_percentage("101%").is_err());
+		assert!(parse_percentage("lots").is_err());
+		assert!(parse_percentage("NaN").is_err());
+		assert!(parse_percentage("inf%").is_err());
+	}
+}
@@ -0,0 +1,48 @@
+/// Parses sizes like `500MB`, `1.5TB`, `200GiB` or `50G` into bytes.
+/// Units without an `i` are powers of 1000, with an `i` powers of 1024,
+/// and a bare number is a count of bytes.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+	let invalid = || format!("{} is not a size like 500MB, 1.5TB or 200GiB", value);
+	let value = value.trim();
+	let unit_start = value
+		.find(|c: char| !(c.is_ascii_digit() || c == '.'))
+		.unwrap_or(value.len());
+	let (number, unit) = value.split_at(unit_start);
+	let number: f64 = number.parse().map_err(|_| invalid())?;
+	let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+		"" | "b" => 1,
+		"k" | "kb" => 1000,
+		"m" | "mb" => 1000_u64.pow(2),
+		"g" | "gb" => 1000_u64.pow(3),
+		"t" | "tb" => 1000_u64.pow(4),
+		"kib" => 1 << 10,
+		"mib" => 1 << 20,
+		"gib" => 1 << 30,
+		"tib" => 1 << 40,
+		_ => return Err(invalid()),
+	};
+	let bytes = number * multiplier as f64;
+	if !bytes.is_finite() || bytes >= u64::MAX as f64 {
+		return Err(invalid());
+	}
+	Ok(bytes.round() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parses_sizes() {
+		assert_eq!(parse_size("500MB"), Ok(500_000_000));
+		assert_eq!(parse_size("1.5TB"), Ok(1_500_000_000_000));
+		assert_eq!(parse_size("200GiB"), Ok(200 * 1024 * 1024 * 1024));
+		assert_eq!(parse_size("50G"), Ok(50_000_000_000));
+		assert_eq!(parse_size("10 kib"), Ok(10240));
+		assert_eq!(parse_size("1234"), Ok(1234));
+		assert!(parse_size("lots").is_err());
+		assert!(parse_size("5 parsecs").is_err());
+		assert!(parse_size("1.2.3GB").is_err());
+		assert!(parse_size("99999999999TB").is_err());
+	}
+}
@@ -0,0 +1,110 @@
+use crate::cancel::is_cancelled;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// How often a paused backup looks to see if it's been resumed
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Pauses backups in this process until `resume`, once they next read a
+/// block of a file
+pub fn pause() {
+	PAUSED.store(true, Ordering::SeqCst);
+}
+
+pub fn resume() {
+	PAUSED.store(false, Ordering::SeqCst);
+}
+
+/// Waits for as long as backups are paused. Called between the blocks of
+/// files being read, so a pause leaves everything as it was mid-copy
+/// rather than abandoning anything.
+pub fn wait_while_paused() {
+	if !PAUSED.load(Ordering::SeqCst) {
+		return;
+	}
+	println!("backup paused");
+	// cancelling stops a paused backup too
+	while PAUSED.load(Ordering::SeqCst) && !is_cancelled() {
+		thread::sleep(POLL_INTERVAL);
+	}
+	println!("backup resumed");
+}
+
+/// Pauses backups on SIGUSR1 and resumes them on SIGUSR2, e.g. from
+/// `pkill -USR1 disk-hog-backup` when the disk's needed for something
+/// urgent
+#[cfg(unix)]
+pub fn pause_on_signals() -> io::Result<()> {
+	extern "C" fn handle(signal: libc::c_int) {
+		// only an atomic store, which is safe in a signal handler
+		PAUSED.store(signal == libc::SIGUSR1, Ordering::SeqCst);
+	}
+	for signal in [libc::SIGUSR1, libc::SIGUSR2] {
+		// SAFETY: the handler only touches an atomic
+		let previous = unsafe {
+			libc::signal(
+				signal,
+				handle as extern "C" fn(libc::c_int) as libc::sighandler_t,
+			)
+		};
+		if previous == libc::SIG_ERR {
+			return Err(io::Error::last_os_error());
+		}
+	}
+	Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn pause_on_signals() -> io::Result<()> {
+	Err(io::Error::new(
+		io::ErrorKind::Unsupported,
+		"pausing on signals is not supported on this platform",
+	))
+}
+
+/// Pauses or resumes the backup running in another process, as SIGUSR1
+/// and SIGUSR2 do
+#[cfg(unix)]
+pub fn pause_process(pid: u32, pause: bool) -> io::Result<()> {
+	let signal = match pause {
+		true => libc::SIGUSR1,
+		false => libc::SIGUSR2,
+	};
+	// SAFETY: only sends a signal
+	match unsafe { libc::kill(pid as libc::pid_t, signal) } {
+		0 => Ok(()),
+		_ => Err(io::Error::last_os_error()),
+	}
+}
+
+#[cfg(not(unix))]
+pub fn pause_process(_pid: u32, _pause: bool) -> io::Result<()> {
+	Err(io::Error::new(
+		io::ErrorKind::Unsupported,
+		"pausing other processes is not supported on this platform",
+	))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::mpsc;
+
+	#[test]
+	fn test_waits_until_resumed() {
+		pause();
+		let (sender, receiver) = mpsc::channel();
+		let waiter = thread::spawn(move || {
+			wait_while_paused();
+			sender.send(()).unwrap();
+		});
+		assert!(receiver.recv_timeout(Duration::from_millis(300)).is_err());
+		resume();
+		assert!(receiver.recv_timeout(Duration::from_secs(5)).is_ok());
+		waiter.join().unwrap();
+	}
+}
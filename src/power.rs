@@ -0,0 +1,107 @@
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// What a scheduled backup does while the machine's on battery
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnBattery {
+	/// Run as usual
+	#[default]
+	Run,
+	/// Don't start while on battery, running once it's on AC power again
+	Wait,
+	/// As with wait, and pause runs already going until it's on AC power
+	Pause,
+}
+
+/// Whether the machine's running on battery, as opposed to AC power or
+/// having no battery at all
+#[cfg(target_os = "linux")]
+pub fn on_battery() -> io::Result<bool> {
+	supplies_on_battery(Path::new("/sys/class/power_supply"))
+}
+
+#[cfg(target_os = "macos")]
+pub fn on_battery() -> io::Result<bool> {
+	let output =
+		crate::snapshot::run_command(std::process::Command::new("pmset").args(["-g", "batt"]))?;
+	Ok(pmset_on_battery(&output))
+}
+
+#[cfg(windows)]
+pub fn on_battery() -> io::Result<bool> {
+	// 1 is discharging; there's no output without a battery
+	let output = crate::snapshot::run_command(std::process::Command::new("powershell").args([
+		"-NoProfile",
+		"-NonInteractive",
+		"-Command",
+		"(Get-CimInstance -ClassName Win32_Battery).BatteryStatus",
+	]))?;
+	Ok(output.lines().any(|line| line.trim() == "1"))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub fn on_battery() -> io::Result<bool> {
+	Err(io::Error::new(
+		io::ErrorKind::Unsupported,
+		"checking the power source is not supported on this platform",
+	))
+}
+
+/// Whether any battery among the power supplies the kernel lists in
+/// `folder` is discharging
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn supplies_on_battery(folder: &Path) -> io::Result<bool> {
+	let entries = match fs::read_dir(folder) {
+		Ok(entries) => entries,
+		// no power supplies at all, as in containers and some desktops
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+		Err(e) => return Err(e),
+	};
+	for entry in entries {
+		let path = entry?.path();
+		let read = |name: &str| fs::read_to_string(path.join(name)).unwrap_or_default();
+		if read("type").trim() == "Battery" && read("status").trim() == "Discharging" {
+			return Ok(true);
+		}
+	}
+	Ok(false)
+}
+
+/// Whether `pmset -g batt` says power's coming from the battery
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn pmset_on_battery(output: &str) -> bool {
+	output.contains("'Battery Power'")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	#[test]
+	fn test_reads_power_supplies() -> io::Result<()> {
+		let folder = create_tmp_folder("power")?;
+		let supply = |name: &str, kind: &str, status: &str| -> io::Result<()> {
+			let path = Path::new(&folder).join(name);
+			fs::create_dir(&path)?;
+			fs::write(path.join("type"), format!("{}\n", kind))?;
+			fs::write(path.join("status"), format!("{}\n", status))
+		};
+		supply("AC", "Mains", "")?;
+		supply("BAT0", "Battery", "Charging")?;
+		assert!(!supplies_on_battery(Path::new(&folder))?);
+
+		fs::write(Path::new(&folder).join("BAT0/status"), "Discharging\n")?;
+		assert!(supplies_on_battery(Path::new(&folder))?);
+		assert!(!supplies_on_battery(&Path::new(&folder).join("missing"))?);
+
+		assert!(pmset_on_battery(
+			"Now drawing from 'Battery Power'\n -InternalBattery-0 (id=4653155)\t87%; discharging"
+		));
+		assert!(!pmset_on_battery("Now drawing from 'AC Power'\n"));
+		Ok(())
+	}
+}
@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Mutex;
+
+/// What a backup's doing
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+	/// Running the commands before, taking snapshots and checking space
+	#[default]
+	Preparing,
+	Copying,
+	/// Writing the manifest, linking duplicates and pruning
+	Finishing,
+	Pushing,
+	Replicating,
+}
+
+impl fmt::Display for Stage {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match self {
+			Stage::Preparing => "preparing",
+			Stage::Copying => "copying",
+			Stage::Finishing => "finishing",
+			Stage::Pushing => "pushing",
+			Stage::Replicating => "replicating",
+		})
+	}
+}
+
+/// How far the backup in this process has got
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Progress {
+	pub stage: Stage,
+	/// Files copied into the set, or linked to an earlier copy, so far
+	pub files_done: u64,
+	pub bytes_done: u64,
+	/// Size of the sources, once it's been worked out
+	pub bytes_estimated: Option<u64>,
+}
+
+impl Progress {
+	/// How much of the estimate is done, if there is one. Sources that
+	/// grow while being copied can take it over 100.
+	pub fn percent(&self) -> Option<f64> {
+		match self.bytes_estimated {
+			Some(0) => Some(100.0),
+			Some(estimated) => Some(self.bytes_done as f64 * 100.0 / estimated as f64),
+			None => None,
+		}
+	}
+}
+
+// only one backup runs in a process at a time, the daemon starting a
+// process for each
+static PROGRESS: Mutex<Progress> = Mutex::new(Progress {
+	stage: Stage::Preparing,
+	files_done: 0,
+	bytes_done: 0,
+	bytes_estimated: None,
+});
+
+/// The progress of the backup running in this process
+pub fn current() -> Progress {
+	*PROGRESS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn update(change: impl FnOnce(&mut Progress)) {
+	change(&mut PROGRESS.lock().unwrap_or_else(|e| e.into_inner()));
+}
+
+/// Starts a backup's progress over, as it starts
+pub(crate) fn start() {
+	update(|progress| *progress = Progress::default());
+}
+
+pub(crate) fn set_stage(stage: Stage) {
+	update(|progress| progress.stage = stage);
+}
+
+/// Moves on to copying about `bytes_estimated`
+pub(crate) fn start_copying(bytes_estimated: u64) {
+	update(|progress| {
+		progress.stage = Stage::Copying;
+		progress.bytes_estimated = Some(bytes_estimated);
+	});
+}
+
+/// Counts a file that's been copied or linked into the set
+pub(crate) fn file_done(size: u64) {
+	update(|progress| {
+		progress.files_done += 1;
+		progress.bytes_done += size;
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_percent() {
+		let mut progress = Progress {
+			bytes_done: 250,
+			..Default::default()
+		};
+		assert_eq!(progress.percent(), None);
+		progress.bytes_estimated = Some(1000);
+		assert_eq!(progress.percent(), Some(25.0));
+		progress.bytes_estimated = Some(0);
+		assert_eq!(progress.percent(), Some(100.0));
+	}
+}
@@ -0,0 +1,245 @@
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, TimeDelta, TimeZone, Timelike};
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+const MONTHS: [&str; 12] = [
+	"jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+/// When something runs, as a cron expression in local time:
+/// `minute hour day-of-month month day-of-week`, e.g. `30 2 * * mon-fri`.
+/// Fields take `*`, numbers, ranges like `1-5`, steps like `*/15` and
+/// lists of those, with names for months and weekdays. `@hourly`,
+/// `@daily`, `@weekly` and `@monthly` are short for the usual schedules.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Schedule {
+	text: String,
+	minutes: u64,
+	hours: u64,
+	days: u64,
+	months: u64,
+	weekdays: u64,
+	/// Whether the day of the month or week was given rather than `*`, as
+	/// when both are, either matching is enough
+	days_given: bool,
+	weekdays_given: bool,
+}
+
+impl fmt::Display for Schedule {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(&self.text)
+	}
+}
+
+impl FromStr for Schedule {
+	type Err = String;
+
+	fn from_str(value: &str) -> Result<Self, Self::Err> {
+		let expanded = match value.trim() {
+			"@hourly" => "0 * * * *",
+			"@daily" | "@midnight" => "0 0 * * *",
+			"@weekly" => "0 0 * * sun",
+			"@monthly" => "0 0 1 * *",
+			other => other,
+		};
+		let fields: Vec<&str> = expanded.split_whitespace().collect();
+		let [minutes, hours, days, months, weekdays] = fields[..] else {
+			return Err(format!(
+				"\"{}\" isn't a schedule, expected five fields like \"30 2 * * *\"",
+				value
+			));
+		};
+		let mut weekdays_parsed = parse_field(weekdays, 0, 7, &WEEKDAYS, 0)?;
+		// 7 is Sunday too
+		if weekdays_parsed & (1 << 7) != 0 {
+			weekdays_parsed |= 1;
+		}
+		Ok(Schedule {
+			text: value.trim().to_string(),
+			minutes: parse_field(minutes, 0, 59, &[], 0)?,
+			hours: parse_field(hours, 0, 23, &[], 0)?,
+			days: parse_field(days, 1, 31, &[], 0)?,
+			months: parse_field(months, 1, 12, &MONTHS, 1)?,
+			weekdays: weekdays_parsed,
+			days_given: days != "*",
+			weekdays_given: weekdays != "*",
+		})
+	}
+}
+
+impl TryFrom<String> for Schedule {
+	type Error = String;
+
+	fn try_from(value: String) -> Result<Self, Self::Error> {
+		value.parse()
+	}
+}
+
+impl Schedule {
+	/// The first time the schedule comes round after `after`, looking up to
+	/// a few years ahead for schedules like the 29th of February
+	pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+		let start = after.naive_local().with_second(0)?.with_nanosecond(0)? + TimeDelta::minutes(1);
+		let mut date = start.date();
+		let mut from_minute = start.hour() * 60 + start.minute();
+		for _ in 0..5 * 366 {
+			if self.matches_day(date) {
+				for minute_of_day in from_minute..24 * 60 {
+					let (hour, minute) = (minute_of_day / 60, minute_of_day % 60);
+					if !has(self.hours, hour) || !has(self.minutes, minute) {
+						continue;
+					}
+					let naive =
+						NaiveDateTime::new(date, chrono::NaiveTime::from_hms_opt(hour, minute, 0)?);
+					// times skipped by a clock change don't happen that day
+					if let Some(time) = Local.from_local_datetime(&naive).earliest() {
+						return Some(time);
+					}
+				}
+			}
+			date = date.succ_opt()?;
+			from_minute = 0;
+		}
+		None
+	}
+
+	fn matches_day(&self, date: NaiveDate) -> bool {
+		if !has(self.months, date.month()) {
+			return false;
+		}
+		let day = has(self.days, date.day());
+		let weekday = has(self.weekdays, date.weekday().num_days_from_sunday());
+		match (self.days_given, self.weekdays_given) {
+			(true, true) => day || weekday,
+			_ => day && weekday,
+		}
+	}
+}
+
+fn has(set: u64, value: u32) -> bool {
+	set & (1 << value) != 0
+}
+
+/// A field's values as bits, from lists of `*`, `n`, `a-b` and steps of
+/// those, with `names` standing for the numbers from `first_name` on
+fn parse_field(
+	field: &str,
+	min: u32,
+	max: u32,
+	names: &[&str],
+	first_name: u32,
+) -> Result<u64, String> {
+	let value = |text: &str| -> Result<u32, String> {
+		let lower = text.to_ascii_lowercase();
+		let number = match names.iter().position(|name| *name == lower) {
+			Some(position) => position as u32 + first_name,
+			None => text
+				.parse()
+				.map_err(|_| format!("\"{}\" isn't a number or name in \"{}\"", text, field))?,
+		};
+		match (min..=max).contains(&number) {
+			true => Ok(number),
+			false => Err(format!(
+				"{} is out of range {}-{} in \"{}\"",
+				number, min, max, field
+			)),
+		}
+	};
+	let mut set = 0;
+	for part in field.split(',') {
+		let (range, step) = match part.split_once('/') {
+			Some((range, step)) => (
+				range,
+				step.parse()
+					.ok()
+					.filter(|step| *step > 0)
+					.ok_or_else(|| format!("\"{}\" isn't a step in \"{}\"", step, field))?,
+			),
+			None => (part, 1),
+		};
+		let (start, end) = match range.split_once('-') {
+			_ if range == "*" => (min, max),
+			Some((start, end)) => (value(start)?, value(end)?),
+			// a single value with a step runs to the end, as in 5/15
+			None if step > 1 => (value(range)?, max),
+			None => {
+				let single = value(range)?;
+				(single, single)
+			}
+		};
+		if start > end {
+			return Err(format!("{}-{} runs backwards in \"{}\"", start, end, field));
+		}
+		for number in (start..=end).step_by(step) {
+			set |= 1 << number;
+		}
+	}
+	Ok(set)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn local(text: &str) -> DateTime<Local> {
+		let naive = NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").unwrap();
+		Local.from_local_datetime(&naive).earliest().unwrap()
+	}
+
+	#[test]
+	fn test_finds_next_runs() -> Result<(), String> {
+		let nightly: Schedule = "30 2 * * *".parse()?;
+		assert_eq!(
+			nightly.next_after(local("2025-03-10 01:00")),
+			Some(local("2025-03-10 02:30"))
+		);
+		assert_eq!(
+			nightly.next_after(local("2025-03-10 02:30")),
+			Some(local("2025-03-11 02:30"))
+		);
+
+		let weekdays: Schedule = "0 */6 * * mon-fri".parse()?;
+		// a Saturday
+		assert_eq!(
+			weekdays.next_after(local("2025-03-15 10:00")),
+			Some(local("2025-03-17 00:00"))
+		);
+		assert_eq!(
+			weekdays.next_after(local("2025-03-17 00:00")),
+			Some(local("2025-03-17 06:00"))
+		);
+
+		// the 1st or any Sunday
+		let either: Schedule = "0 12 1 * 7".parse()?;
+		assert_eq!(
+			either.next_after(local("2025-03-10 00:00")),
+			Some(local("2025-03-16 12:00"))
+		);
+		let leap: Schedule = "0 0 29 feb *".parse()?;
+		assert_eq!(
+			leap.next_after(local("2025-03-10 00:00")),
+			Some(local("2028-02-29 00:00"))
+		);
+		assert_eq!(
+			"@daily".parse::<Schedule>()?.days,
+			"0 0 * * *".parse::<Schedule>()?.days
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_rejects_bad_schedules() {
+		for bad in [
+			"* * * *",
+			"60 * * * *",
+			"5-1 * * * *",
+			"*/0 * * * *",
+			"0 0 * * funday",
+		] {
+			assert!(bad.parse::<Schedule>().is_err(), "{}", bad);
+		}
+	}
+}
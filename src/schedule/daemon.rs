@@ -0,0 +1,490 @@
+use crate::cancel::{cancel_process, is_cancelled};
+use crate::config::profiles::Profile;
+use crate::pause::pause_process;
+use crate::power::OnBattery;
+use crate::schedule::cron::Schedule;
+use crate::schedule::last_runs::LastRuns;
+use crate::schedule::systemd;
+use chrono::{DateTime, Local, TimeDelta};
+use std::collections::BTreeMap;
+use std::io;
+use std::process::Child;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The longest the daemon sleeps between checks, so finished runs are
+/// noticed and clock changes caught up with
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+/// How often runs still going are checked on, so a failed one is retried
+/// on time
+const RUNNING_CHECK: Duration = Duration::from_secs(5);
+
+/// How often a sleeping daemon looks to see if it's been asked to stop
+const STOP_CHECK: Duration = Duration::from_millis(500);
+
+/// A scheduled profile and when it runs next
+struct Entry<'a> {
+	name: &'a str,
+	schedule: &'a Schedule,
+	jitter: TimeDelta,
+	next: Option<DateTime<Local>>,
+}
+
+impl Entry<'_> {
+	/// The next scheduled time after `now`, put off by up to the jitter
+	fn next_after(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+		let next = self.schedule.next_after(now)?;
+		let seconds = self.jitter.num_seconds();
+		match seconds > 0 {
+			true => next.checked_add_signed(TimeDelta::seconds(rand::random_range(0..seconds))),
+			false => Some(next),
+		}
+	}
+}
+
+/// When each scheduled profile runs next
+pub struct Scheduler<'a> {
+	entries: Vec<Entry<'a>>,
+}
+
+impl<'a> Scheduler<'a> {
+	/// Schedules the profiles that have a schedule, from `now`. Those that
+	/// catch up and missed a scheduled time since they last ran are due
+	/// straight away.
+	pub fn new(
+		profiles: &'a BTreeMap<String, Profile>,
+		now: DateTime<Local>,
+		last_runs: &LastRuns,
+	) -> Scheduler<'a> {
+		let entries = profiles
+			.iter()
+			.filter_map(|(name, profile)| {
+				let mut entry = Entry {
+					name,
+					schedule: profile.schedule.as_ref()?,
+					jitter: profile.jitter.unwrap_or_default(),
+					next: None,
+				};
+				let missed = last_runs
+					.get(name)
+					.and_then(|last| entry.schedule.next_after(last))
+					.is_some_and(|missed| missed < now);
+				entry.next = match missed && profile.catch_up {
+					true => Some(now),
+					false => entry.next_after(now),
+				};
+				Some(entry)
+			})
+			.collect();
+		Scheduler { entries }
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.entries.is_empty()
+	}
+
+	/// The profiles due by `now`, each then scheduled for its next time
+	/// after it. Several times missed, say while the machine was
+	/// suspended, make one run.
+	pub fn due(&mut self, now: DateTime<Local>) -> Vec<&'a str> {
+		let mut due = Vec::new();
+		for entry in &mut self.entries {
+			if entry.next.is_some_and(|next| next <= now) {
+				due.push(entry.name);
+				entry.next = entry.next_after(now);
+			}
+		}
+		due
+	}
+
+	/// When the next profile's due
+	pub fn next_run(&self) -> Option<DateTime<Local>> {
+		self.entries.iter().filter_map(|entry| entry.next).min()
+	}
+
+	/// Each profile with when it's next due
+	pub fn upcoming(&self) -> impl Iterator<Item = (&'a str, Option<DateTime<Local>>)> + '_ {
+		self.entries.iter().map(|entry| (entry.name, entry.next))
+	}
+}
+
+/// A failed run waiting to be tried again
+struct Retry {
+	attempt: u32,
+	at: DateTime<Local>,
+}
+
+/// A run that's come due but is being held back
+struct Held {
+	attempt: u32,
+	due: DateTime<Local>,
+	/// What it's waiting for, to say so once
+	until: &'static str,
+}
+
+/// Runs each profile whenever its schedule comes round, forever. `launch`
+/// starts a profile's backup as another process, given which attempt it
+/// is, so a run that fails or exits can't take the daemon with it. Failed
+/// runs are tried again after the profile's retry delay, doubling each
+/// time, until its retries are used up or it's next due anyway. A profile
+/// still running when it's due again isn't started twice. `scheduled` is
+/// told each time when the next run is.
+///
+/// Profiles that wait while on battery, as `on_battery` says, are held
+/// until it's on AC power again, or paused if they're running and should
+/// be. Those that wait for the system to be idle, going by `idle_time`,
+/// are held until it has been for long enough or their deadline passes.
+///
+/// Runs started on schedule are recorded in `last_runs`, so those missed
+/// while the daemon wasn't running are caught up when it starts, and
+/// profiles aren't run more often than their `min_interval`.
+///
+/// Under systemd it says when it's ready and pings the watchdog, if the
+/// unit has one. Once cancelled, e.g. by SIGTERM, it waits for the runs
+/// going to stop, which they do cleanly as they get the signal too, and
+/// returns.
+pub fn run_daemon(
+	profiles: &BTreeMap<String, Profile>,
+	launch: &dyn Fn(&str, &Profile, u32) -> io::Result<Child>,
+	scheduled: &dyn Fn(Option<DateTime<Local>>),
+	on_battery: &dyn Fn() -> io::Result<bool>,
+	idle_time: &dyn Fn() -> io::Result<Duration>,
+	mut last_runs: LastRuns,
+) -> io::Result<()> {
+	let started_at = Local::now();
+	let mut scheduler = Scheduler::new(profiles, started_at, &last_runs);
+	if scheduler.is_empty() {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			"no profile in the config has a schedule",
+		));
+	}
+	for (name, next) in scheduler.upcoming() {
+		match next {
+			Some(next) if next <= started_at => {
+				println!(
+					"{} missed a run while the daemon was stopped, catching up",
+					name
+				)
+			}
+			Some(next) => println!("{} runs next at {}", name, next.to_rfc3339()),
+			None => eprintln!("Warning: {}'s schedule never comes round", name),
+		}
+	}
+	if let Err(e) = systemd::notify("READY=1") {
+		eprintln!("Warning: telling systemd the daemon's ready failed: {}", e);
+	}
+	let watchdog = systemd::watchdog_interval();
+	let mut running: BTreeMap<&str, (Child, u32)> = BTreeMap::new();
+	let mut retries: BTreeMap<&str, Retry> = BTreeMap::new();
+	let mut held: BTreeMap<&str, Held> = BTreeMap::new();
+	let checks_power = profiles
+		.values()
+		.any(|profile| profile.on_battery != OnBattery::Run);
+	let (mut was_on_battery, mut power_warned, mut idle_warned) = (false, false, false);
+	loop {
+		if is_cancelled() {
+			stop(running);
+			return Ok(());
+		}
+		let now = Local::now();
+		running.retain(|name, (child, attempt)| match child.try_wait() {
+			Ok(Some(status)) => {
+				println!("{} finished with {}", name, status);
+				if status.success() {
+					return false;
+				}
+				match profiles[*name].retry_delay_after(*attempt) {
+					Some(delay) => {
+						let retry = Retry {
+							attempt: *attempt + 1,
+							at: now + delay,
+						};
+						println!("retrying {} at {}", name, retry.at.to_rfc3339());
+						retries.insert(name, retry);
+					}
+					None if *attempt > 1 => {
+						eprintln!(
+							"{} failed {} times, giving up until it's next due",
+							name, attempt
+						)
+					}
+					None => {}
+				}
+				false
+			}
+			Ok(None) => true,
+			Err(e) => {
+				eprintln!("Checking on {} failed: {}", name, e);
+				false
+			}
+		});
+		let battery = checks_power
+			&& on_battery().unwrap_or_else(|e| {
+				if !power_warned {
+					eprintln!("Checking the power source failed, taking it as AC: {}", e);
+					power_warned = true;
+				}
+				false
+			});
+		if battery != was_on_battery {
+			match battery {
+				true => println!("on battery power"),
+				false => println!("on AC power again"),
+			}
+			for (name, (child, _)) in &running {
+				if profiles[*name].on_battery != OnBattery::Pause {
+					continue;
+				}
+				if let Err(e) = pause_process(child.id(), battery) {
+					eprintln!("Pausing or resuming {} failed: {}", name, e);
+				}
+			}
+			was_on_battery = battery;
+		}
+		let mut starting: BTreeMap<&str, Held> = std::mem::take(&mut held);
+		for name in scheduler.due(now) {
+			// a scheduled run takes the place of a retry
+			retries.remove(name);
+			let due = starting.get(name).map_or(now, |held| held.due);
+			starting.insert(
+				name,
+				Held {
+					attempt: 1,
+					due,
+					until: "",
+				},
+			);
+		}
+		retries.retain(|name, retry| match retry.at <= now {
+			true => {
+				let held = Held {
+					attempt: retry.attempt,
+					due: retry.at,
+					until: "",
+				};
+				starting.insert(name, held);
+				false
+			}
+			false => true,
+		});
+		// only asked once a run's waiting on it
+		let mut idle: Option<Duration> = None;
+		for (name, mut run) in starting {
+			let profile = &profiles[name];
+			let waiting_for_idle = match profile.idle.and_then(|idle| idle.to_std().ok()) {
+				Some(wanted) if now - run.due < profile.idle_deadline => {
+					*idle.get_or_insert_with(|| system_idle(idle_time, &mut idle_warned)) < wanted
+				}
+				_ => false,
+			};
+			let until = if battery && profile.on_battery != OnBattery::Run {
+				"on AC power"
+			} else if waiting_for_idle {
+				"the system is idle"
+			} else {
+				""
+			};
+			if !until.is_empty() {
+				if run.until != until {
+					println!("holding {} until {}", name, until);
+					run.until = until;
+				}
+				held.insert(name, run);
+				continue;
+			}
+			let attempt = run.attempt;
+			if running.contains_key(name) {
+				eprintln!("Skipping {}: its last run hasn't finished", name);
+				continue;
+			}
+			let last = last_runs.get(name);
+			if let (1, Some(min_interval), Some(last)) = (attempt, profile.min_interval, last) {
+				if now - last < min_interval {
+					println!(
+						"skipping {}: it last ran at {}, sooner than its min_interval allows",
+						name,
+						last.to_rfc3339()
+					);
+					continue;
+				}
+			}
+			match attempt {
+				1 => println!("starting {}", name),
+				_ => println!("starting {}, attempt {}", name, attempt),
+			}
+			match launch(name, &profiles[name], attempt) {
+				Ok(child) => {
+					running.insert(name, (child, attempt));
+					if attempt == 1 {
+						if let Err(e) = last_runs.started(name, now) {
+							eprintln!("Warning: recording when {} ran failed: {}", name, e);
+						}
+					}
+				}
+				Err(e) => eprintln!("Starting {} failed: {}", name, e),
+			}
+		}
+		let next_run = scheduler
+			.next_run()
+			.into_iter()
+			.chain(retries.values().map(|retry| retry.at))
+			.min();
+		scheduled(next_run);
+		if let Some(next) = next_run {
+			// failing this doesn't matter once it's said it's ready
+			let _ = systemd::notify(&format!("STATUS=next run at {}", next.to_rfc3339()));
+		}
+		let until_next = next_run
+			.and_then(|next| (next - Local::now()).to_std().ok())
+			.unwrap_or(MAX_SLEEP);
+		let mut longest = match running.is_empty() {
+			true => MAX_SLEEP,
+			false => RUNNING_CHECK,
+		};
+		if let Some(watchdog) = watchdog {
+			let _ = systemd::notify("WATCHDOG=1");
+			longest = longest.min(watchdog / 2);
+		}
+		sleep_unless_cancelled(until_next.min(longest));
+	}
+}
+
+/// Waits for the runs going to stop, which they've been asked to as well
+fn stop(running: BTreeMap<&str, (Child, u32)>) {
+	let _ = systemd::notify("STOPPING=1");
+	println!("stopping");
+	for (name, (mut child, _)) in running {
+		// outside systemd nothing else asks them to
+		let _ = cancel_process(child.id());
+		println!("waiting for {} to stop", name);
+		if let Err(e) = child.wait() {
+			eprintln!("Waiting for {} failed: {}", name, e);
+		}
+	}
+}
+
+fn sleep_unless_cancelled(duration: Duration) {
+	let until = Instant::now() + duration;
+	while !is_cancelled() {
+		let left = until.saturating_duration_since(Instant::now());
+		if left.is_zero() {
+			return;
+		}
+		thread::sleep(left.min(STOP_CHECK));
+	}
+}
+
+/// How long the system's been idle, taken as forever if it can't be told
+/// so runs aren't held back for nothing
+fn system_idle(idle_time: &dyn Fn() -> io::Result<Duration>, warned: &mut bool) -> Duration {
+	idle_time().unwrap_or_else(|e| {
+		if !*warned {
+			eprintln!(
+				"Checking whether the system is idle failed, taking it as idle: {}",
+				e
+			);
+			*warned = true;
+		}
+		Duration::MAX
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+	use chrono::{NaiveDateTime, TimeZone};
+	use std::path::Path;
+
+	fn local(text: &str) -> DateTime<Local> {
+		let naive = NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").unwrap();
+		Local.from_local_datetime(&naive).earliest().unwrap()
+	}
+
+	fn profile(schedule: Option<&str>) -> Profile {
+		Profile {
+			sources: vec!["/home".to_string()],
+			destination: "/mnt/backups".to_string(),
+			schedule: schedule.map(|schedule| schedule.parse().unwrap()),
+			jitter: None,
+			catch_up: true,
+			min_interval: None,
+			options: Vec::new(),
+			pre_backup: None,
+			post_backup: None,
+			retries: 0,
+			retry_delay: TimeDelta::zero(),
+			on_battery: Default::default(),
+			idle: None,
+			idle_deadline: TimeDelta::zero(),
+		}
+	}
+
+	#[test]
+	fn test_schedules_profiles() -> io::Result<()> {
+		let profiles = BTreeMap::from([
+			("hourly".to_string(), profile(Some("0 * * * *"))),
+			("nightly".to_string(), profile(Some("30 2 * * *"))),
+			("by_hand".to_string(), profile(None)),
+		]);
+		let last_runs = LastRuns::load(&Path::new(&create_tmp_folder("daemon")?).join("runs"))?;
+		let mut scheduler = Scheduler::new(&profiles, local("2025-03-10 01:15"), &last_runs);
+		assert_eq!(scheduler.next_run(), Some(local("2025-03-10 02:00")));
+		assert!(scheduler.due(local("2025-03-10 01:59")).is_empty());
+
+		// asleep until after both were due, and the hourly twice
+		assert_eq!(
+			scheduler.due(local("2025-03-10 03:10")),
+			vec!["hourly", "nightly"]
+		);
+		assert_eq!(scheduler.next_run(), Some(local("2025-03-10 04:00")));
+		Ok(())
+	}
+
+	#[test]
+	fn test_catches_up_missed_runs() -> io::Result<()> {
+		let path = Path::new(&create_tmp_folder("daemon")?).join("runs");
+		let mut last_runs = LastRuns::load(&path)?;
+		last_runs.started("nightly", local("2025-03-08 02:30"))?;
+		last_runs.started("hourly", local("2025-03-10 01:00"))?;
+		let profiles = BTreeMap::from([
+			("hourly".to_string(), profile(Some("0 * * * *"))),
+			("nightly".to_string(), profile(Some("30 2 * * *"))),
+		]);
+
+		// off through the night of the 9th
+		let now = local("2025-03-10 01:15");
+		let mut scheduler = Scheduler::new(&profiles, now, &last_runs);
+		assert_eq!(scheduler.due(now), vec!["nightly"]);
+
+		let profiles = BTreeMap::from([(
+			"nightly".to_string(),
+			Profile {
+				catch_up: false,
+				..profile(Some("30 2 * * *"))
+			},
+		)]);
+		let scheduler = Scheduler::new(&profiles, now, &last_runs);
+		assert_eq!(scheduler.next_run(), Some(local("2025-03-10 02:30")));
+		Ok(())
+	}
+
+	#[test]
+	fn test_jitters_scheduled_times() -> io::Result<()> {
+		let jittered = Profile {
+			jitter: Some(TimeDelta::minutes(30)),
+			..profile(Some("0 3 * * *"))
+		};
+		let profiles = BTreeMap::from([("nightly".to_string(), jittered)]);
+		let last_runs = LastRuns::load(&Path::new(&create_tmp_folder("daemon")?).join("runs"))?;
+		for _ in 0..20 {
+			let scheduler = Scheduler::new(&profiles, local("2025-03-10 01:15"), &last_runs);
+			let next = scheduler.next_run().unwrap();
+			assert!(next >= local("2025-03-10 03:00"), "{}", next);
+			assert!(next < local("2025-03-10 03:30"), "{}", next);
+		}
+		Ok(())
+	}
+}
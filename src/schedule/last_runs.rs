@@ -0,0 +1,70 @@
+use chrono::{DateTime, Local, Utc};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Kept in the state folder alongside the run history
+pub const LAST_RUNS_FILE: &str = "daemon-runs.json";
+
+/// When the daemon last started each profile's scheduled run, kept across
+/// restarts so runs missed while it wasn't running can be caught up
+pub struct LastRuns {
+	path: PathBuf,
+	runs: BTreeMap<String, DateTime<Utc>>,
+}
+
+impl LastRuns {
+	/// Reads the runs recorded at `path`, none if it isn't there yet
+	pub fn load(path: &Path) -> io::Result<LastRuns> {
+		let runs = match fs::read(path) {
+			Ok(contents) => serde_json::from_slice(&contents).map_err(|e| {
+				io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("{}: {}", path.display(), e),
+				)
+			})?,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+			Err(e) => return Err(e),
+		};
+		Ok(LastRuns {
+			path: path.to_path_buf(),
+			runs,
+		})
+	}
+
+	pub fn get(&self, name: &str) -> Option<DateTime<Local>> {
+		self.runs.get(name).map(|at| at.with_timezone(&Local))
+	}
+
+	/// Records a run starting and saves it
+	pub fn started(&mut self, name: &str, at: DateTime<Local>) -> io::Result<()> {
+		self.runs.insert(name.to_string(), at.with_timezone(&Utc));
+		if let Some(folder) = self.path.parent() {
+			fs::create_dir_all(folder)?;
+		}
+		// renamed into place so a crash never leaves it part written
+		let temp_path = self.path.with_extension("json.tmp");
+		fs::write(&temp_path, serde_json::to_vec(&self.runs)?)?;
+		fs::rename(temp_path, &self.path)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	#[test]
+	fn test_remembers_last_runs() -> io::Result<()> {
+		let folder = create_tmp_folder("last_runs")?;
+		let path = Path::new(&folder).join("state").join(LAST_RUNS_FILE);
+		let mut last_runs = LastRuns::load(&path)?;
+		assert_eq!(last_runs.get("photos"), None);
+
+		let at = Local::now();
+		last_runs.started("photos", at)?;
+		assert_eq!(LastRuns::load(&path)?.get("photos"), Some(at));
+		Ok(())
+	}
+}
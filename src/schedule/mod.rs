@@ -0,0 +1,4 @@
+pub mod cron;
+pub mod daemon;
+pub mod last_runs;
+pub mod systemd;
@@ -0,0 +1,103 @@
+use std::env;
+use std::ffi::OsStr;
+use std::io;
+use std::time::Duration;
+
+/// Tells systemd how the daemon's doing through `$NOTIFY_SOCKET`, as a
+/// unit with `Type=notify` waits for, e.g. `READY=1` or `WATCHDOG=1`. Does
+/// nothing when it wasn't started by systemd.
+pub fn notify(state: &str) -> io::Result<()> {
+	match env::var_os("NOTIFY_SOCKET") {
+		Some(socket) => notify_socket(&socket, state),
+		None => Ok(()),
+	}
+}
+
+#[cfg(unix)]
+fn notify_socket(socket: &OsStr, state: &str) -> io::Result<()> {
+	use std::os::unix::ffi::OsStrExt;
+	use std::os::unix::net::UnixDatagram;
+
+	let sender = UnixDatagram::unbound()?;
+	// systemd's own socket is usually in the abstract namespace
+	match socket.as_bytes().strip_prefix(b"@") {
+		#[cfg(target_os = "linux")]
+		Some(name) => {
+			use std::os::linux::net::SocketAddrExt;
+			let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+			sender.send_to_addr(state.as_bytes(), &address)?;
+		}
+		#[cfg(not(target_os = "linux"))]
+		Some(_) => {
+			return Err(io::Error::new(
+				io::ErrorKind::Unsupported,
+				"abstract sockets are not supported on this platform",
+			))
+		}
+		None => {
+			sender.send_to(state.as_bytes(), socket)?;
+		}
+	}
+	Ok(())
+}
+
+#[cfg(not(unix))]
+fn notify_socket(_socket: &OsStr, _state: &str) -> io::Result<()> {
+	Ok(())
+}
+
+/// How often systemd expects to hear the daemon's still going, when the
+/// unit sets `WatchdogSec=`
+pub fn watchdog_interval() -> Option<Duration> {
+	let usec = env::var("WATCHDOG_USEC").ok()?;
+	let pid = env::var("WATCHDOG_PID").ok();
+	watchdog_for(&usec, pid.as_deref(), std::process::id())
+}
+
+/// The watchdog interval given in `usec`, if it's meant for process `pid`
+fn watchdog_for(usec: &str, watched: Option<&str>, pid: u32) -> Option<Duration> {
+	// a process the daemon started might be given the variables too
+	if watched.is_some_and(|watched| watched.parse() != Ok(pid)) {
+		return None;
+	}
+	match usec.parse() {
+		Ok(0) | Err(_) => None,
+		Ok(usec) => Some(Duration::from_micros(usec)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_reads_watchdog_interval() {
+		assert_eq!(
+			watchdog_for("30000000", Some("42"), 42),
+			Some(Duration::from_secs(30))
+		);
+		assert_eq!(
+			watchdog_for("30000000", None, 42),
+			Some(Duration::from_secs(30))
+		);
+		assert_eq!(watchdog_for("30000000", Some("7"), 42), None);
+		assert_eq!(watchdog_for("0", None, 42), None);
+		assert_eq!(watchdog_for("soon", None, 42), None);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_notifies_socket() -> io::Result<()> {
+		use crate::test_helpers::test_helpers::create_tmp_folder;
+		use std::os::unix::net::UnixDatagram;
+		use std::path::Path;
+
+		let path = Path::new(&create_tmp_folder("systemd")?).join("notify");
+		let receiver = UnixDatagram::bind(&path)?;
+		notify_socket(path.as_os_str(), "READY=1")?;
+		let mut buffer = [0; 64];
+		let received = receiver.recv(&mut buffer)?;
+		assert_eq!(&buffer[..received], b"READY=1");
+		Ok(())
+	}
+}
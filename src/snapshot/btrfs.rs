@@ -0,0 +1,63 @@
+use crate::snapshot::{run_command, snapshot_name, Mount};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The root folder of every subvolume has this inode number
+#[cfg(unix)]
+const SUBVOLUME_ROOT_INODE: u64 = 256;
+
+/// The root of the subvolume `path` is in, on the btrfs filesystem mounted
+/// as `mount`
+#[cfg(unix)]
+pub fn subvolume_root(path: &Path, mount: &Mount) -> io::Result<PathBuf> {
+	use std::os::unix::fs::MetadataExt;
+
+	if mount.fs_type != "btrfs" {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			format!("{} isn't on a btrfs filesystem", path.display()),
+		));
+	}
+	// the mount point's the root of a subvolume, so this stops there at
+	// the latest
+	let mut folder = path;
+	while std::fs::metadata(folder)?.ino() != SUBVOLUME_ROOT_INODE {
+		folder = folder.parent().unwrap_or(&mount.mount_point);
+	}
+	Ok(folder.to_path_buf())
+}
+
+#[cfg(not(unix))]
+pub fn subvolume_root(path: &Path, _mount: &Mount) -> io::Result<PathBuf> {
+	Err(io::Error::new(
+		io::ErrorKind::Unsupported,
+		format!(
+			"btrfs snapshots of {} are not supported on this platform",
+			path.display()
+		),
+	))
+}
+
+/// Takes a read-only snapshot of the subvolume at `root`, kept in a hidden
+/// folder within it, giving where it is as both its path and ID
+pub fn create(root: &Path) -> io::Result<(PathBuf, String)> {
+	let path = root.join(format!(".{}", snapshot_name()));
+	run_command(
+		Command::new("btrfs")
+			.args(["subvolume", "snapshot", "-r"])
+			.arg(root)
+			.arg(&path),
+	)?;
+	let id = path.to_string_lossy().into_owned();
+	Ok((path, id))
+}
+
+pub fn remove(path: &Path) -> io::Result<()> {
+	run_command(
+		Command::new("btrfs")
+			.args(["subvolume", "delete"])
+			.arg(path),
+	)?;
+	Ok(())
+}
@@ -0,0 +1,95 @@
+use crate::snapshot::{run_command, snapshot_name, Mount};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Snapshots the logical volume mounted as `mount` and mounts the snapshot
+/// read-only in a temporary folder, giving the folder and the snapshot's
+/// `vg/lv` name
+pub fn create(mount: &Mount) -> io::Result<(PathBuf, String)> {
+	let output = run_command(
+		Command::new("lvs")
+			.args(["--noheadings", "--separator", "/", "-o", "vg_name,lv_name"])
+			.arg(&mount.source),
+	)
+	.map_err(|e| {
+		io::Error::new(
+			e.kind(),
+			format!(
+				"{} isn't on an LVM logical volume: {}",
+				mount.mount_point.display(),
+				e
+			),
+		)
+	})?;
+	let (group, volume) = parse_volume(&output)?;
+	let name = format!("{}-{}", volume, snapshot_name());
+	run_command(
+		Command::new("lvcreate")
+			.args(["--snapshot", "--extents", "10%ORIGIN", "--name", &name])
+			.arg(format!("{}/{}", group, volume)),
+	)?;
+	let id = format!("{}/{}", group, name);
+	let folder = env::temp_dir().join(&name);
+	// XFS refuses to mount a second filesystem with the same UUID
+	let options = match mount.fs_type.as_str() {
+		"xfs" => "ro,nouuid",
+		_ => "ro",
+	};
+	let mounted = fs::create_dir_all(&folder).and_then(|_| {
+		run_command(
+			Command::new("mount")
+				.args(["-o", options])
+				.arg(format!("/dev/{}", id))
+				.arg(&folder),
+		)
+	});
+	if let Err(e) = mounted {
+		let _ = fs::remove_dir(&folder);
+		let _ = remove_volume(&id);
+		return Err(e);
+	}
+	Ok((folder, id))
+}
+
+/// Unmounts the snapshot from `folder` and removes it
+pub fn remove(id: &str, folder: &Path) -> io::Result<()> {
+	run_command(Command::new("umount").arg(folder))?;
+	let _ = fs::remove_dir(folder);
+	remove_volume(id)
+}
+
+fn remove_volume(id: &str) -> io::Result<()> {
+	run_command(Command::new("lvremove").arg("--yes").arg(id))?;
+	Ok(())
+}
+
+/// Reads the volume group and logical volume names lvs gives, as `vg/lv`
+fn parse_volume(output: &str) -> io::Result<(String, String)> {
+	match output.trim().split_once('/') {
+		Some((group, volume)) if !group.is_empty() && !volume.is_empty() => {
+			Ok((group.to_string(), volume.to_string()))
+		}
+		_ => Err(io::Error::new(
+			io::ErrorKind::InvalidData,
+			format!("unexpected output from lvs: {}", output.trim()),
+		)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_reads_volume_names() -> io::Result<()> {
+		assert_eq!(
+			parse_volume("  vg0/home\n")?,
+			("vg0".to_string(), "home".to_string())
+		);
+		assert!(parse_volume("").is_err());
+		Ok(())
+	}
+}
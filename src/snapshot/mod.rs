@@ -0,0 +1,326 @@
+pub mod btrfs;
+pub mod lvm;
+pub mod vss;
+pub mod zfs;
+
+use crate::config::drives::unescape_mount_point;
+use crate::storage::local::LocalSource;
+use crate::storage::source::{SourceBackend, SourceEntry};
+use clap::ValueEnum;
+use std::fmt;
+use std::fs::{self, Permissions};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+/// How a source is snapshotted before it's backed up, so files changing
+/// or held open while the backup runs are copied as they all were at one
+/// moment
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum SnapshotKind {
+	/// A Volume Shadow Copy of the source's volume, on Windows
+	Vss,
+	/// A read-only snapshot of the btrfs subvolume the source is in
+	Btrfs,
+	/// A snapshot of the ZFS dataset the source is in
+	Zfs,
+	/// A snapshot of the LVM logical volume the source is on, mounted
+	/// read-only while the backup runs. It's given a tenth of the volume's
+	/// size for changes made meanwhile.
+	Lvm,
+}
+
+impl fmt::Display for SnapshotKind {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let name = match self {
+			SnapshotKind::Vss => "vss",
+			SnapshotKind::Btrfs => "btrfs",
+			SnapshotKind::Zfs => "zfs",
+			SnapshotKind::Lvm => "lvm",
+		};
+		f.write_str(name)
+	}
+}
+
+impl SnapshotKind {
+	/// The root of what gets snapshotted to capture `path`
+	fn root_of(&self, path: &Path) -> io::Result<PathBuf> {
+		match self {
+			SnapshotKind::Vss => Ok(volume_root(path)),
+			SnapshotKind::Btrfs => btrfs::subvolume_root(path, &mount_of(path)?),
+			SnapshotKind::Zfs | SnapshotKind::Lvm => Ok(mount_of(path)?.mount_point),
+		}
+	}
+}
+
+/// A snapshot of a filesystem, removed when dropped
+pub struct Snapshot {
+	kind: SnapshotKind,
+	/// What was snapshotted, as it's normally reached
+	original: PathBuf,
+	/// Where the snapshot's copy of `original` can be read
+	path: PathBuf,
+	/// What the snapshot's removed by
+	id: String,
+}
+
+impl Snapshot {
+	pub fn take(kind: SnapshotKind, root: &Path) -> io::Result<Snapshot> {
+		let (path, id) = match kind {
+			SnapshotKind::Vss => vss::create(root)?,
+			SnapshotKind::Btrfs => btrfs::create(root)?,
+			SnapshotKind::Zfs => zfs::create(&mount_of(root)?)?,
+			SnapshotKind::Lvm => lvm::create(&mount_of(root)?)?,
+		};
+		println!("took a {} snapshot of {}", kind, root.display());
+		Ok(Snapshot {
+			kind,
+			original: root.to_path_buf(),
+			path,
+			id,
+		})
+	}
+}
+
+impl Drop for Snapshot {
+	fn drop(&mut self) {
+		let removed = match self.kind {
+			SnapshotKind::Vss => vss::remove(&self.id),
+			SnapshotKind::Btrfs => btrfs::remove(&self.path),
+			SnapshotKind::Zfs => zfs::remove(&self.id),
+			SnapshotKind::Lvm => lvm::remove(&self.id, &self.path),
+		};
+		if let Err(e) = removed {
+			eprintln!(
+				"Removing the snapshot of {} failed: {}",
+				self.original.display(),
+				e
+			);
+		}
+	}
+}
+
+/// Reads sources from snapshots of them taken when it's created, while
+/// they're still recorded under their own paths. A snapshot is taken of
+/// each volume or filesystem the sources are on, and all are removed
+/// again when this is dropped.
+pub struct SnapshotSource {
+	sources: Vec<String>,
+	snapshots: Vec<Snapshot>,
+}
+
+impl SnapshotSource {
+	pub fn take(kind: SnapshotKind, sources: &[&str]) -> io::Result<SnapshotSource> {
+		let mut snapshot_source = SnapshotSource {
+			sources: Vec::new(),
+			snapshots: Vec::new(),
+		};
+		for source in sources {
+			let source = fs::canonicalize(source)?;
+			let root = kind.root_of(&source)?;
+			if !snapshot_source.snapshots.iter().any(|s| s.original == root) {
+				snapshot_source.snapshots.push(Snapshot::take(kind, &root)?);
+			}
+			snapshot_source
+				.sources
+				.push(source.to_string_lossy().into_owned());
+		}
+		Ok(snapshot_source)
+	}
+
+	/// The sources, as absolute paths for backing up through this
+	pub fn sources(&self) -> Vec<&str> {
+		self.sources.iter().map(String::as_str).collect()
+	}
+
+	fn in_snapshot(&self, path: &Path) -> io::Result<PathBuf> {
+		self.snapshots
+			.iter()
+			.find_map(|snapshot| path_in_snapshot(&snapshot.original, &snapshot.path, path))
+			.ok_or_else(|| {
+				io::Error::new(
+					io::ErrorKind::NotFound,
+					format!("{} isn't in any of the snapshots taken", path.display()),
+				)
+			})
+	}
+}
+
+impl SourceBackend for SnapshotSource {
+	fn list(&self, path: &Path) -> io::Result<Vec<SourceEntry>> {
+		LocalSource.list(&self.in_snapshot(path)?)
+	}
+
+	fn open(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+		LocalSource.open(&self.in_snapshot(path)?)
+	}
+
+	fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+		fs::canonicalize(path)
+	}
+
+	fn permissions(&self, path: &Path) -> io::Result<Option<Permissions>> {
+		LocalSource.permissions(&self.in_snapshot(path)?)
+	}
+}
+
+/// Where `path`, under `original`, is found in a snapshot of it at
+/// `snapshot`
+fn path_in_snapshot(original: &Path, snapshot: &Path, path: &Path) -> Option<PathBuf> {
+	let within = path.strip_prefix(original).ok()?;
+	Some(match within.as_os_str().is_empty() {
+		true => snapshot.to_path_buf(),
+		false => snapshot.join(within),
+	})
+}
+
+/// A mounted filesystem, as /proc/self/mountinfo lists it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mount {
+	pub mount_point: PathBuf,
+	pub fs_type: String,
+	/// The device or, for ZFS, dataset mounted
+	pub source: String,
+}
+
+/// The filesystem an absolute path is on
+#[cfg(target_os = "linux")]
+fn mount_of(path: &Path) -> io::Result<Mount> {
+	let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+	mount_containing(&mountinfo, path)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mount_of(path: &Path) -> io::Result<Mount> {
+	Err(io::Error::new(
+		io::ErrorKind::Unsupported,
+		format!(
+			"finding the filesystem {} is on is not supported on this platform",
+			path.display()
+		),
+	))
+}
+
+/// The mount in the contents of /proc/self/mountinfo with the longest mount
+/// point containing `path`, the last listed where one hides another
+fn mount_containing(mountinfo: &str, path: &Path) -> io::Result<Mount> {
+	let mut found: Option<(&str, Mount)> = None;
+	for line in mountinfo.lines() {
+		let Some((fields, rest)) = line.split_once(" - ") else {
+			continue;
+		};
+		let fields: Vec<&str> = fields.split(' ').collect();
+		let rest: Vec<&str> = rest.split(' ').collect();
+		let ([_, _, _, root, mount_point, ..], [fs_type, source, ..]) = (&fields[..], &rest[..])
+		else {
+			continue;
+		};
+		let mount_point = PathBuf::from(unescape_mount_point(mount_point));
+		let longer = found
+			.as_ref()
+			.is_none_or(|(_, mount)| mount_point.starts_with(&mount.mount_point));
+		if path.starts_with(&mount_point) && longer {
+			let mount = Mount {
+				mount_point,
+				fs_type: fs_type.to_string(),
+				source: unescape_mount_point(source),
+			};
+			found = Some((*root, mount));
+		}
+	}
+	match found {
+		Some(("/", mount)) => Ok(mount),
+		Some(_) => Err(io::Error::new(
+			io::ErrorKind::Unsupported,
+			format!(
+				"{} is on a bind mount, so its filesystem can't be snapshotted",
+				path.display()
+			),
+		)),
+		None => Err(io::Error::new(
+			io::ErrorKind::NotFound,
+			format!("no filesystem is mounted at {}", path.display()),
+		)),
+	}
+}
+
+/// Runs a command taking a snapshot or removing one, giving its output
+pub(crate) fn run_command(command: &mut Command) -> io::Result<String> {
+	let program = command.get_program().to_string_lossy().into_owned();
+	let output = command.output().map_err(|e| match e.kind() {
+		io::ErrorKind::NotFound => io::Error::new(e.kind(), format!("{} isn't installed", program)),
+		_ => e,
+	})?;
+	if !output.status.success() {
+		return Err(io::Error::other(format!(
+			"{} failed: {}",
+			program,
+			String::from_utf8_lossy(&output.stderr).trim()
+		)));
+	}
+	Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// A name for snapshots this process takes, unique while it runs
+pub(crate) fn snapshot_name() -> String {
+	format!("disk-hog-backup-{}", std::process::id())
+}
+
+/// The root of the volume an absolute path is on, e.g. `C:\` for
+/// `C:\Users`, or just `/` where there are no drives
+fn volume_root(path: &Path) -> PathBuf {
+	path.components()
+		.take_while(|c| matches!(c, Component::Prefix(_) | Component::RootDir))
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_finds_paths_in_snapshots() {
+		let snapshot = Path::new("/snapshots/home-1");
+		assert_eq!(
+			path_in_snapshot(
+				Path::new("/home"),
+				snapshot,
+				Path::new("/home/susie/notes.txt")
+			),
+			Some(PathBuf::from("/snapshots/home-1/susie/notes.txt"))
+		);
+		assert_eq!(
+			path_in_snapshot(Path::new("/home"), snapshot, Path::new("/home")),
+			Some(PathBuf::from("/snapshots/home-1"))
+		);
+		assert_eq!(
+			path_in_snapshot(Path::new("/home"), snapshot, Path::new("/homes/sammy")),
+			None
+		);
+		assert_eq!(volume_root(Path::new("/home/susie")), PathBuf::from("/"));
+	}
+
+	const MOUNTINFO: &str = "\
+22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/mapper/vg0-root rw
+45 22 0:41 / /srv/data rw,relatime shared:30 - zfs tank/data rw,xattr
+46 45 0:42 / /srv/data/photos rw,relatime shared:31 - zfs tank/data/photos rw,xattr
+97 22 8:17 /backups /srv/backups rw,relatime shared:50 - exfat /dev/sdb1 rw
+";
+
+	#[test]
+	fn test_finds_mounts_of_paths() {
+		let mount = mount_containing(MOUNTINFO, Path::new("/srv/data/photos/2024")).unwrap();
+		assert_eq!(
+			mount,
+			Mount {
+				mount_point: PathBuf::from("/srv/data/photos"),
+				fs_type: "zfs".to_string(),
+				source: "tank/data/photos".to_string(),
+			}
+		);
+		let mount = mount_containing(MOUNTINFO, Path::new("/srv/database")).unwrap();
+		assert_eq!(mount.source, "/dev/mapper/vg0-root");
+		let err = mount_containing(MOUNTINFO, Path::new("/srv/backups/laptop")).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+	}
+}
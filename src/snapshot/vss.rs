@@ -0,0 +1,100 @@
+use crate::snapshot::run_command;
+use std::io;
+use std::path::{Component, Path, PathBuf, Prefix};
+use std::process::Command;
+
+// Shadow copies are made and removed through PowerShell's CIM cmdlets, so
+// nothing beyond what comes with Windows is needed. Both need an elevated
+// prompt, or a service running as an administrator.
+
+/// Makes a shadow copy of the volume at `root`, e.g. `C:\`, giving where
+/// it can be read and its ID for removing it
+pub fn create(root: &Path) -> io::Result<(PathBuf, String)> {
+	if !cfg!(windows) {
+		return Err(io::Error::new(
+			io::ErrorKind::Unsupported,
+			"VSS snapshots are only available on Windows",
+		));
+	}
+	let volume = volume_name(root)?;
+	let output = run_powershell(&format!(
+		"$result = Invoke-CimMethod -ClassName Win32_ShadowCopy -MethodName Create \
+		-Arguments @{{ Volume = '{}'; Context = 'ClientAccessible' }}
+		if ($result.ReturnValue -ne 0) {{ throw \"Win32_ShadowCopy.Create returned $($result.ReturnValue)\" }}
+		$shadow = Get-CimInstance -ClassName Win32_ShadowCopy -Filter \"ID = '$($result.ShadowID)'\"
+		\"$($shadow.ID)|$($shadow.DeviceObject)\"",
+		volume.replace('\'', "''")
+	))?;
+	parse_created(&output)
+}
+
+/// Removes the shadow copy with the ID `create` gave
+pub fn remove(id: &str) -> io::Result<()> {
+	run_powershell(&format!(
+		"Get-CimInstance -ClassName Win32_ShadowCopy -Filter \"ID = '{}'\" | Remove-CimInstance",
+		id.replace('\'', "")
+	))?;
+	Ok(())
+}
+
+fn run_powershell(script: &str) -> io::Result<String> {
+	run_command(
+		Command::new("powershell")
+			.args(["-NoProfile", "-NonInteractive", "-Command"])
+			.arg(format!("$ErrorActionPreference = 'Stop'\n{}", script)),
+	)
+}
+
+/// The volume to shadow copy as VSS names it, e.g. `C:\`
+fn volume_name(root: &Path) -> io::Result<String> {
+	match root.components().next() {
+		Some(Component::Prefix(prefix)) => match prefix.kind() {
+			Prefix::Disk(letter) | Prefix::VerbatimDisk(letter) => {
+				Ok(format!("{}:\\", letter as char))
+			}
+			_ => Err(io::Error::new(
+				io::ErrorKind::Unsupported,
+				format!("{} isn't on a drive VSS can snapshot", root.display()),
+			)),
+		},
+		_ => Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			format!("{} isn't on a drive", root.display()),
+		)),
+	}
+}
+
+/// Reads the `ID|DeviceObject` line the creating script prints, giving a
+/// path to the device's root and the ID
+fn parse_created(output: &str) -> io::Result<(PathBuf, String)> {
+	let line = output.lines().rev().find(|line| !line.trim().is_empty());
+	match line.and_then(|line| line.trim().split_once('|')) {
+		Some((id, device)) if !id.is_empty() && !device.is_empty() => {
+			Ok((PathBuf::from(format!("{}\\", device)), id.to_string()))
+		}
+		_ => Err(io::Error::new(
+			io::ErrorKind::InvalidData,
+			format!("unexpected output making a shadow copy: {}", output.trim()),
+		)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_reads_created_shadow_copies() {
+		let (path, id) = parse_created(
+			"{5C2D1E0B-6F3A-4C1E-9D8B-2A7F1C3E4B5D}|\\\\?\\GLOBALROOT\\Device\\HarddiskVolumeShadowCopy7\r\n",
+		)
+		.unwrap();
+		assert_eq!(id, "{5C2D1E0B-6F3A-4C1E-9D8B-2A7F1C3E4B5D}");
+		assert_eq!(
+			path,
+			PathBuf::from("\\\\?\\GLOBALROOT\\Device\\HarddiskVolumeShadowCopy7\\")
+		);
+		assert!(parse_created("").is_err());
+		assert!(volume_name(Path::new("relative")).is_err());
+	}
+}
@@ -0,0 +1,33 @@
+use crate::snapshot::{run_command, snapshot_name, Mount};
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Snapshots the dataset mounted as `mount`, giving where the snapshot
+/// can be read, in the dataset's `.zfs/snapshot` folder, and its name
+pub fn create(mount: &Mount) -> io::Result<(PathBuf, String)> {
+	if mount.fs_type != "zfs" {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			format!("{} isn't a ZFS dataset", mount.mount_point.display()),
+		));
+	}
+	let name = snapshot_name();
+	let id = format!("{}@{}", mount.source, name);
+	run_command(Command::new("zfs").arg("snapshot").arg(&id))?;
+	let path = mount.mount_point.join(".zfs/snapshot").join(name);
+	Ok((path, id))
+}
+
+/// Destroys the snapshot named `id`, e.g. `tank/home@disk-hog-backup-123`
+pub fn remove(id: &str) -> io::Result<()> {
+	// never the dataset itself
+	if !id.contains('@') {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			format!("{} isn't a snapshot", id),
+		));
+	}
+	run_command(Command::new("zfs").arg("destroy").arg(id))?;
+	Ok(())
+}
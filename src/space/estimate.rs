@@ -0,0 +1,117 @@
+use crate::backup_sets::hash_catalog::HashCatalog;
+use crate::error::BackupError;
+use crate::space::filesystem::filesystem_space;
+use crate::storage::source::SourceBackend;
+use std::io;
+use std::path::Path;
+
+/// Total size of the files in a folder, following symlinks the same way copying does
+pub fn estimate_size(source_backend: &dyn SourceBackend, folder: &Path) -> io::Result<u64> {
+	let mut total = 0;
+	for entry in source_backend.list(folder)? {
+		total += match entry.is_dir {
+			true => estimate_size(source_backend, &folder.join(&entry.name))?,
+			false => entry.size,
+		};
+	}
+	Ok(total)
+}
+
+/// How much a backup of a folder copies
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SizeEstimate {
+	/// All the files
+	pub total: u64,
+	/// Leaving out the files earlier sets have unchanged copies of, which
+	/// are linked rather than copied
+	pub new: u64,
+}
+
+/// The size of the files in a folder, following symlinks the same way
+/// copying does, and how much of it the catalog's sets don't have.
+/// `within` is where in the set the folder goes.
+pub fn estimate_backup_size(
+	source_backend: &dyn SourceBackend,
+	folder: &Path,
+	within: &Path,
+	catalog: Option<&HashCatalog>,
+) -> io::Result<SizeEstimate> {
+	let mut estimate = SizeEstimate::default();
+	for entry in source_backend.list(folder)? {
+		let path = within.join(&entry.name);
+		if entry.is_dir {
+			let inner =
+				estimate_backup_size(source_backend, &folder.join(&entry.name), &path, catalog)?;
+			estimate.total += inner.total;
+			estimate.new += inner.new;
+			continue;
+		}
+		estimate.total += entry.size;
+		let unchanged = catalog.and_then(|catalog| {
+			catalog.unchanged(&path.to_string_lossy(), entry.size, entry.modified)
+		});
+		if unchanged.is_none() {
+			estimate.new += entry.size;
+		}
+	}
+	Ok(estimate)
+}
+
+/// Fails if the destination filesystem can't hold `required` more bytes.
+/// Platforms where free space can't be read are let through.
+pub fn check_free_space(dest: &Path, required: u64) -> io::Result<()> {
+	match filesystem_space(dest) {
+		Ok(space) => ensure_space(dest, required, space.free),
+		Err(e) if e.kind() == io::ErrorKind::Unsupported => Ok(()),
+		Err(e) => Err(e),
+	}
+}
+
+fn ensure_space(dest: &Path, required: u64, available: u64) -> io::Result<()> {
+	if required > available {
+		return Err(BackupError::DestinationFull {
+			path: dest.to_path_buf(),
+			source: io::Error::new(
+				io::ErrorKind::StorageFull,
+				format!(
+					"backup needs about {} bytes but only {} bytes are free",
+					required, available
+				),
+			),
+		}
+		.into());
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::local::LocalSource;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+	use std::fs;
+
+	#[test]
+	fn test_estimates_size() -> io::Result<()> {
+		let folder = create_tmp_folder("orig")?;
+		let deep = Path::new(&folder).join("thats/deep");
+		fs::create_dir_all(&deep)?;
+		fs::write(Path::new(&folder).join("top.txt"), "12345")?;
+		fs::write(deep.join("testfile.txt"), "backmeup susie")?;
+
+		assert_eq!(estimate_size(&LocalSource, Path::new(&folder))?, 19);
+		Ok(())
+	}
+
+	#[test]
+	fn test_ensure_space() {
+		let dest = Path::new("/backups");
+		assert!(ensure_space(dest, 10, 10).is_ok());
+		let err = ensure_space(dest, 11, 10).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+		assert!(matches!(
+			BackupError::from(err),
+			BackupError::DestinationFull { path, .. } if path == dest
+		));
+	}
+}
@@ -0,0 +1,54 @@
+use std::io;
+use std::path::Path;
+
+pub struct FilesystemSpace {
+	pub total: u64,
+	/// Space available to unprivileged users, which excludes root-reserved blocks
+	pub free: u64,
+}
+
+#[cfg(unix)]
+pub fn filesystem_space(path: &Path) -> io::Result<FilesystemSpace> {
+	use std::ffi::CString;
+	use std::os::unix::ffi::OsStrExt;
+
+	let c_path = CString::new(path.as_os_str().as_bytes())?;
+	// SAFETY: statvfs is plain old data, and is only read after the call succeeds
+	let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+	if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+		return Err(io::Error::last_os_error());
+	}
+	// field types vary between platforms
+	#[allow(clippy::unnecessary_cast)]
+	let block_size = stat.f_frsize as u64;
+	#[allow(clippy::unnecessary_cast)]
+	Ok(FilesystemSpace {
+		total: stat.f_blocks as u64 * block_size,
+		free: stat.f_bavail as u64 * block_size,
+	})
+}
+
+#[cfg(not(unix))]
+pub fn filesystem_space(_path: &Path) -> io::Result<FilesystemSpace> {
+	Err(io::Error::new(
+		io::ErrorKind::Unsupported,
+		"checking free space is not supported on this platform",
+	))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	#[test]
+	fn test_reads_filesystem_space() -> io::Result<()> {
+		let folder = create_tmp_folder("space")?;
+
+		let space = filesystem_space(Path::new(&folder))?;
+
+		assert!(space.total > 0);
+		assert!(space.free <= space.total);
+		Ok(())
+	}
+}
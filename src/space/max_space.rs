@@ -0,0 +1,294 @@
+use crate::backup_sets::backup_set::list_sets_by_status;
+use crate::backup_sets::last_known_good::clear_last_known_good;
+use crate::backup_sets::retention::RetentionPolicy;
+use crate::backup_sets::seal::remove_set;
+use crate::backup_sets::trash::{empty_trash, trash_is_empty};
+use crate::error::BackupError;
+use crate::parsing::percentage::parse_percentage;
+use crate::parsing::size::parse_size;
+use crate::space::filesystem::filesystem_space;
+use crate::space::usage::folder_usage;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpaceLimit {
+	Bytes(u64),
+	/// Percentage of the destination filesystem's capacity, so the same
+	/// setting suits differently sized drives
+	Percent(f64),
+}
+
+impl SpaceLimit {
+	/// Works out the limit in bytes for the filesystem holding `dest`
+	pub fn resolve(&self, dest: &Path) -> io::Result<u64> {
+		match self {
+			SpaceLimit::Bytes(bytes) => Ok(*bytes),
+			SpaceLimit::Percent(percent) => {
+				let total = filesystem_space(dest)?.total;
+				Ok((total as f64 * percent / 100.0) as u64)
+			}
+		}
+	}
+}
+
+impl fmt::Display for SpaceLimit {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			SpaceLimit::Bytes(bytes) => write!(f, "{}B", bytes),
+			SpaceLimit::Percent(percent) => write!(f, "{}%", percent),
+		}
+	}
+}
+
+/// A percentage like `80%`, a size like `500MB` or `1.5TiB`, or for
+/// backwards compatibility a bare whole number of GB
+impl FromStr for SpaceLimit {
+	type Err = String;
+
+	fn from_str(value: &str) -> Result<Self, Self::Err> {
+		if value.ends_with('%') {
+			return parse_percentage(value).map(SpaceLimit::Percent);
+		}
+		if let Ok(gb) = value.parse::<u64>() {
+			return gb
+				.checked_mul(1_000_000_000)
+				.map(SpaceLimit::Bytes)
+				.ok_or_else(|| format!("{} GB is too big", value));
+		}
+		parse_size(value).map(SpaceLimit::Bytes)
+	}
+}
+
+/// Deletes the oldest sets until `required` more bytes fit in the
+/// destination without its total usage exceeding `max_space`.
+/// The newest `policy.last` complete sets (at least one) and sets the policy
+/// protects are never deleted; if the target can't be reached without them
+/// this fails with `StorageFull`.
+pub fn make_room(
+	dest: &str,
+	max_space: u64,
+	required: u64,
+	policy: &RetentionPolicy,
+) -> io::Result<Vec<String>> {
+	let used = folder_usage(Path::new(dest))?.total;
+	let deletions = delete_oldest_sets_until(dest, "stay within max space", policy, |freed| {
+		Ok(used.saturating_sub(freed) + required <= max_space)
+	})?;
+	if !deletions.satisfied {
+		return Err(cant_make_room(
+			dest,
+			format!(
+				"can't fit {} more bytes: {} of max space {} bytes is used by sets that must be kept",
+				required,
+				used.saturating_sub(deletions.freed),
+				max_space
+			),
+			deletions.pinned,
+		));
+	}
+	Ok(deletions.deleted)
+}
+
+pub(crate) struct Deletions {
+	pub deleted: Vec<String>,
+	/// Bytes freed by the deleted sets
+	pub freed: u64,
+	/// Pinned or tagged sets that would otherwise have been deleted
+	pub pinned: usize,
+	pub satisfied: bool,
+}
+
+/// Deletes sets oldest first until `satisfied` (given the bytes freed so far) is true.
+/// The trash is emptied before any sets are deleted, then abandoned incomplete sets.
+/// The newest `policy.last` complete sets (at least one), incomplete sets newer
+/// than them and sets the policy protects are never deleted.
+pub(crate) fn delete_oldest_sets_until<F>(
+	dest: &str,
+	reason: &str,
+	policy: &RetentionPolicy,
+	mut satisfied: F,
+) -> io::Result<Deletions>
+where
+	F: FnMut(u64) -> io::Result<bool>,
+{
+	let sets = list_sets_by_status(dest)?;
+	let mut complete = sets.complete;
+	// the newest set is always kept
+	complete.truncate(complete.len().saturating_sub(policy.last.max(1)));
+	let sets = sets.abandoned.into_iter().chain(complete);
+
+	let mut deletions = Deletions {
+		deleted: Vec::new(),
+		freed: 0,
+		pinned: 0,
+		satisfied: false,
+	};
+	if !satisfied(0)? && !trash_is_empty(dest)? {
+		println!("emptying trash to {}", reason);
+		deletions.freed += empty_trash(dest)?;
+	}
+	for set in sets {
+		if satisfied(deletions.freed)? {
+			deletions.satisfied = true;
+			return Ok(deletions);
+		}
+		if policy.protects(dest, &set) {
+			deletions.pinned += 1;
+			continue;
+		}
+		let set_folder = Path::new(dest).join(&set);
+		let freed = folder_usage(&set_folder)?.exclusive;
+		println!(
+			"deleting set {} to {}, freeing {} bytes",
+			set, reason, freed
+		);
+		remove_set(&set_folder)?;
+		clear_last_known_good(dest, &set)?;
+		deletions.freed += freed;
+		deletions.deleted.push(set);
+	}
+	deletions.satisfied = satisfied(deletions.freed)?;
+	Ok(deletions)
+}
+
+pub(crate) fn cant_make_room(dest: &str, mut message: String, pinned: usize) -> io::Error {
+	if pinned > 0 {
+		message.push_str(&format!(" ({} of them pinned or tagged to keep)", pinned));
+	}
+	BackupError::DestinationFull {
+		path: PathBuf::from(dest),
+		source: io::Error::new(io::ErrorKind::StorageFull, message),
+	}
+	.into()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backup_sets::backup_set::SetStatus;
+	use crate::backup_sets::pin::pin_set;
+	use crate::backup_sets::trash::move_to_trash;
+	use crate::test_helpers::test_helpers::{create_set, create_tmp_folder};
+	use std::fs;
+
+	#[test]
+	fn test_parses_space_limit() {
+		assert_eq!("80%".parse(), Ok(SpaceLimit::Percent(80.0)));
+		assert_eq!("2".parse(), Ok(SpaceLimit::Bytes(2_000_000_000)));
+		assert_eq!("500MB".parse(), Ok(SpaceLimit::Bytes(500_000_000)));
+		assert!("200%".parse::<SpaceLimit>().is_err());
+		assert!("lots".parse::<SpaceLimit>().is_err());
+	}
+
+	#[test]
+	fn test_resolves_percentage_of_disk() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let total = filesystem_space(Path::new(&dest))?.total;
+
+		let limit = SpaceLimit::Percent(50.0).resolve(Path::new(&dest))?;
+
+		assert_eq!(limit, total / 2);
+		Ok(())
+	}
+
+	const OLDEST_SET: &str = "dhb-set-20000101-000000";
+	const MIDDLE_SET: &str = "dhb-set-20000102-000000";
+	const NEWEST_SET: &str = "dhb-set-20000103-000000";
+
+	fn create_sets() -> io::Result<String> {
+		let dest = create_tmp_folder("backups")?;
+		for set in [OLDEST_SET, MIDDLE_SET, NEWEST_SET] {
+			let set_folder = Path::new(&dest).join(set);
+			fs::create_dir_all(&set_folder)?;
+			fs::write(set_folder.join("data"), "1234567890")?;
+		}
+		Ok(dest)
+	}
+
+	#[test]
+	fn test_deletes_oldest_sets_first() -> io::Result<()> {
+		let dest = create_sets()?;
+
+		let deleted = make_room(&dest, 30, 10, &RetentionPolicy::default())?;
+
+		assert_eq!(deleted, vec![OLDEST_SET]);
+		assert!(Path::new(&dest).join(MIDDLE_SET).exists());
+		Ok(())
+	}
+
+	#[test]
+	fn test_empties_trash_first() -> io::Result<()> {
+		let dest = create_sets()?;
+		move_to_trash(&dest, OLDEST_SET)?;
+
+		let deleted = make_room(&dest, 30, 10, &RetentionPolicy::default())?;
+
+		assert!(deleted.is_empty());
+		assert!(trash_is_empty(&dest)?);
+		assert!(Path::new(&dest).join(MIDDLE_SET).exists());
+		Ok(())
+	}
+
+	#[test]
+	fn test_deletes_abandoned_sets_first() -> io::Result<()> {
+		let dest = create_sets()?;
+		let abandoned_set = "dhb-set-20000102-120000";
+		create_set(&dest, abandoned_set, SetStatus::Incomplete)?;
+		fs::write(
+			Path::new(&dest).join(abandoned_set).join("data"),
+			"1234567890",
+		)?;
+
+		let deleted = make_room(&dest, 40, 0, &RetentionPolicy::default())?;
+
+		assert_eq!(deleted, vec![abandoned_set]);
+		assert!(Path::new(&dest).join(OLDEST_SET).exists());
+		Ok(())
+	}
+
+	fn keep_last(last: usize) -> RetentionPolicy {
+		RetentionPolicy {
+			last,
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn test_keeps_newest_sets() -> io::Result<()> {
+		let dest = create_sets()?;
+
+		let err = make_room(&dest, 15, 0, &keep_last(2)).unwrap_err();
+
+		assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+		assert!(!Path::new(&dest).join(OLDEST_SET).exists());
+		assert!(Path::new(&dest).join(MIDDLE_SET).exists());
+		Ok(())
+	}
+
+	#[test]
+	fn test_never_deletes_newest_set() -> io::Result<()> {
+		let dest = create_sets()?;
+
+		let err = make_room(&dest, 15, 10, &RetentionPolicy::default()).unwrap_err();
+
+		assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+		assert!(Path::new(&dest).join(NEWEST_SET).exists());
+		Ok(())
+	}
+
+	#[test]
+	fn test_reports_pinned_sets_in_the_way() -> io::Result<()> {
+		let dest = create_sets()?;
+		pin_set(&dest, OLDEST_SET)?;
+
+		let err = make_room(&dest, 15, 0, &RetentionPolicy::default()).unwrap_err();
+
+		assert!(err.to_string().contains("1 of them pinned"), "{}", err);
+		assert!(Path::new(&dest).join(OLDEST_SET).exists());
+		assert!(!Path::new(&dest).join(MIDDLE_SET).exists());
+		Ok(())
+	}
+}
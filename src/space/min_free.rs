@@ -0,0 +1,74 @@
+use crate::backup_sets::retention::RetentionPolicy;
+use crate::space::filesystem::filesystem_space;
+use crate::space::max_space::{cant_make_room, delete_oldest_sets_until};
+use std::io;
+use std::path::Path;
+
+/// Deletes the oldest sets until the destination filesystem will still have
+/// `min_free` bytes free after `required` more bytes are written, for when
+/// the disk is shared with other data.
+/// The newest `policy.last` complete sets (at least one) and sets the policy
+/// protects are never deleted; if the target can't be reached without them
+/// this fails with `StorageFull`.
+pub fn make_free_space(
+	dest: &str,
+	min_free: u64,
+	required: u64,
+	policy: &RetentionPolicy,
+) -> io::Result<Vec<String>> {
+	let deletions = delete_oldest_sets_until(dest, "keep min free space", policy, |_| {
+		Ok(filesystem_space(Path::new(dest))?.free >= required.saturating_add(min_free))
+	})?;
+	if !deletions.satisfied {
+		let free = filesystem_space(Path::new(dest))?.free;
+		return Err(cant_make_room(
+			dest,
+			format!(
+				"can't write {} more bytes and leave {} bytes free: only {} bytes are free without deleting sets that must be kept",
+				required, min_free, free
+			),
+			deletions.pinned,
+		));
+	}
+	Ok(deletions.deleted)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+	use std::fs;
+
+	const OLD_SET: &str = "dhb-set-20000101-000000";
+	const NEW_SET: &str = "dhb-set-20000102-000000";
+
+	fn create_sets() -> io::Result<String> {
+		let dest = create_tmp_folder("backups")?;
+		for set in [OLD_SET, NEW_SET] {
+			fs::create_dir_all(Path::new(&dest).join(set))?;
+		}
+		Ok(dest)
+	}
+
+	#[test]
+	fn test_leaves_sets_when_enough_free() -> io::Result<()> {
+		let dest = create_sets()?;
+
+		let deleted = make_free_space(&dest, 0, 0, &RetentionPolicy::default())?;
+
+		assert!(deleted.is_empty());
+		Ok(())
+	}
+
+	#[test]
+	fn test_fails_when_disk_can_never_have_enough_free() -> io::Result<()> {
+		let dest = create_sets()?;
+
+		let err = make_free_space(&dest, u64::MAX, 0, &RetentionPolicy::default()).unwrap_err();
+
+		assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+		assert!(!Path::new(&dest).join(OLD_SET).exists());
+		assert!(Path::new(&dest).join(NEW_SET).exists());
+		Ok(())
+	}
+}
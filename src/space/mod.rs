@@ -0,0 +1,5 @@
+pub mod estimate;
+pub mod filesystem;
+pub mod max_space;
+pub mod min_free;
+pub mod usage;
@@ -0,0 +1,181 @@
+use crate::backup_sets::backup_set::list_sets;
+use crate::backup_sets::set_metadata::{read_metadata, SetMetadata, SetStats};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub struct Usage {
+	/// Size of every file in the folder added up, as `du --apparent-size` would
+	pub apparent: u64,
+	/// Space taken by the folder, counting hard-linked files once
+	pub total: u64,
+	/// Space that deleting the folder would free, i.e. excluding files also linked from elsewhere
+	pub exclusive: u64,
+}
+
+pub fn folder_usage(folder: &Path) -> io::Result<Usage> {
+	let mut files = HashMap::new();
+	collect_files(folder, &mut files)?;
+	Ok(usage_of(&files))
+}
+
+pub struct SetUsage {
+	pub set: String,
+	pub usage: Usage,
+	/// Space taken by this set and all older ones together, counting files
+	/// they share through hard links once
+	pub cumulative: u64,
+}
+
+/// Usage of each set in the destination, oldest first
+pub fn sets_usage(dest: &str) -> io::Result<Vec<SetUsage>> {
+	let mut seen = HashSet::new();
+	let mut cumulative = 0;
+	let mut sets = Vec::new();
+	for set in list_sets(dest)? {
+		let mut files = HashMap::new();
+		collect_files(&Path::new(dest).join(&set), &mut files)?;
+		let usage = usage_of(&files);
+		if cfg!(unix) {
+			for (identity, file) in &files {
+				if seen.insert(*identity) {
+					cumulative += file.size;
+				}
+			}
+		} else {
+			// identities are only unique within a set
+			cumulative += usage.total;
+		}
+		sets.push(SetUsage {
+			set,
+			usage,
+			cumulative,
+		});
+	}
+	Ok(sets)
+}
+
+/// What all the sets in the destination hold together, as recorded in their
+/// metadata. Sets without stats, such as unfinished ones, aren't counted.
+pub fn total_stats(dest: &str) -> io::Result<SetStats> {
+	let mut total = SetStats::default();
+	for set in list_sets(dest)? {
+		if let Ok(SetMetadata {
+			stats: Some(stats), ..
+		}) = read_metadata(&Path::new(dest).join(&set))
+		{
+			total.files += stats.files;
+			total.folders += stats.folders;
+			total.bytes += stats.bytes;
+			total.deduplicated_bytes += stats.deduplicated_bytes;
+		}
+	}
+	Ok(total)
+}
+
+fn usage_of(files: &HashMap<(u64, u64), FileLinks>) -> Usage {
+	let mut usage = Usage {
+		apparent: 0,
+		total: 0,
+		exclusive: 0,
+	};
+	for file in files.values() {
+		usage.apparent += file.size * file.links_seen;
+		usage.total += file.size;
+		if file.links_seen >= file.links {
+			usage.exclusive += file.size;
+		}
+	}
+	usage
+}
+
+struct FileLinks {
+	size: u64,
+	links: u64,
+	links_seen: u64,
+}
+
+fn collect_files(folder: &Path, files: &mut HashMap<(u64, u64), FileLinks>) -> io::Result<()> {
+	for entry in fs::read_dir(folder)? {
+		let entry = entry?;
+		let metadata = entry.metadata()?;
+		if metadata.is_dir() {
+			collect_files(&entry.path(), files)?;
+			continue;
+		}
+		let (identity, links) = file_identity(&metadata, files.len());
+		files
+			.entry(identity)
+			.or_insert(FileLinks {
+				size: metadata.len(),
+				links,
+				links_seen: 0,
+			})
+			.links_seen += 1;
+	}
+	Ok(())
+}
+
+#[cfg(unix)]
+fn file_identity(metadata: &fs::Metadata, _index: usize) -> ((u64, u64), u64) {
+	use std::os::unix::fs::MetadataExt;
+	((metadata.dev(), metadata.ino()), metadata.nlink())
+}
+
+// without inode numbers every file is treated as unique
+#[cfg(not(unix))]
+fn file_identity(_metadata: &fs::Metadata, index: usize) -> ((u64, u64), u64) {
+	((0, index as u64), 1)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	#[test]
+	fn test_counts_hard_links_once() -> io::Result<()> {
+		let folder = create_tmp_folder("usage")?;
+		let set = Path::new(&folder).join("set");
+		fs::create_dir_all(&set)?;
+		fs::write(set.join("own.txt"), "12345")?;
+		fs::write(set.join("shared.txt"), "1234567890")?;
+		fs::hard_link(set.join("shared.txt"), set.join("shared-again.txt"))?;
+		fs::hard_link(
+			set.join("shared.txt"),
+			Path::new(&folder).join("elsewhere.txt"),
+		)?;
+
+		let usage = folder_usage(&set)?;
+
+		assert_eq!(usage.apparent, 25);
+		assert_eq!(usage.total, 15);
+		assert_eq!(
+			usage.exclusive, 5,
+			"file linked outside the set isn't freed"
+		);
+		Ok(())
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_cumulative_usage_counts_shared_files_once() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let old_set = Path::new(&dest).join("dhb-set-20000101-000000");
+		let new_set = Path::new(&dest).join("dhb-set-20000102-000000");
+		fs::create_dir_all(&old_set)?;
+		fs::create_dir_all(&new_set)?;
+		fs::write(old_set.join("unchanged.txt"), "1234567890")?;
+		fs::hard_link(old_set.join("unchanged.txt"), new_set.join("unchanged.txt"))?;
+		fs::write(new_set.join("changed.txt"), "12345")?;
+
+		let usage = sets_usage(&dest)?;
+
+		assert_eq!(usage[0].cumulative, 10);
+		assert_eq!(usage[1].usage.total, 15);
+		assert_eq!(usage[1].usage.exclusive, 5);
+		assert_eq!(usage[1].cumulative, 15);
+		Ok(())
+	}
+}
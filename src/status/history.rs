@@ -0,0 +1,255 @@
+use crate::notify::RunReport;
+use crate::progress::{self, Progress};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const HISTORY_FILE: &str = "runs.jsonl";
+const RUNNING_PREFIX: &str = "running-";
+/// Runs kept in the history, older ones dropped once it's twice as long
+const KEPT_RUNS: usize = 500;
+/// How often a running backup's progress is written out
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A backup that's under way
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RunningBackup {
+	pub pid: u32,
+	pub destination: String,
+	pub started_at: DateTime<Utc>,
+	/// As of the last time it was written out
+	#[serde(default)]
+	pub progress: Progress,
+}
+
+/// Which backups are running and how recent ones went, kept in a folder
+/// of the user's state, `$XDG_STATE_HOME/disk-hog-backup` by default
+pub struct RunHistory {
+	folder: PathBuf,
+}
+
+/// Marks a backup as running until dropped, keeping its progress up to
+/// date meanwhile
+pub struct CurrentRun {
+	path: PathBuf,
+	publisher: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+impl Drop for CurrentRun {
+	fn drop(&mut self) {
+		if let Some((stop, publisher)) = self.publisher.take() {
+			drop(stop);
+			let _ = publisher.join();
+		}
+		let _ = fs::remove_file(&self.path);
+	}
+}
+
+impl RunHistory {
+	pub fn new(folder: &Path) -> RunHistory {
+		RunHistory {
+			folder: folder.to_path_buf(),
+		}
+	}
+
+	/// The history in the default state folder
+	pub fn open_default() -> io::Result<RunHistory> {
+		Ok(RunHistory::new(&default_state_folder()?))
+	}
+
+	/// Records that this process is backing up to the destination
+	pub fn start_run(&self, destination: &str) -> io::Result<CurrentRun> {
+		fs::create_dir_all(&self.folder)?;
+		let mut running = RunningBackup {
+			pid: std::process::id(),
+			destination: destination.to_string(),
+			started_at: Utc::now(),
+			progress: progress::current(),
+		};
+		let path = self
+			.folder
+			.join(format!("{}{}.json", RUNNING_PREFIX, running.pid));
+		write_running(&path, &running)?;
+		let (stop, stopped) = mpsc::channel();
+		let publishing = path.clone();
+		let publisher = thread::spawn(move || {
+			while stopped.recv_timeout(PROGRESS_INTERVAL) == Err(RecvTimeoutError::Timeout) {
+				let progress = progress::current();
+				if progress != running.progress {
+					running.progress = progress;
+					// the status page does without it until the next go
+					let _ = write_running(&publishing, &running);
+				}
+			}
+		});
+		Ok(CurrentRun {
+			path,
+			publisher: Some((stop, publisher)),
+		})
+	}
+
+	/// Backups running now. Those whose process has gone without saying
+	/// they'd finished are left out.
+	pub fn running(&self) -> io::Result<Vec<RunningBackup>> {
+		let entries = match fs::read_dir(&self.folder) {
+			Ok(entries) => entries,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+			Err(e) => return Err(e),
+		};
+		let mut running = Vec::new();
+		for entry in entries {
+			let entry = entry?;
+			if !entry
+				.file_name()
+				.to_string_lossy()
+				.starts_with(RUNNING_PREFIX)
+			{
+				continue;
+			}
+			// it may finish while being read
+			let Ok(contents) = fs::read_to_string(entry.path()) else {
+				continue;
+			};
+			if let Ok(backup) = serde_json::from_str::<RunningBackup>(&contents) {
+				if process_exists(backup.pid) {
+					running.push(backup);
+				}
+			}
+		}
+		running.sort_by_key(|backup| backup.started_at);
+		Ok(running)
+	}
+
+	/// Adds a finished run to the history
+	pub fn record(&self, report: &RunReport) -> io::Result<()> {
+		fs::create_dir_all(&self.folder)?;
+		let path = self.folder.join(HISTORY_FILE);
+		let mut line = serde_json::to_string(report).map_err(io::Error::other)?;
+		line.push('\n');
+		OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&path)?
+			.write_all(line.as_bytes())?;
+		let runs = self.read_all()?;
+		if runs.len() > 2 * KEPT_RUNS {
+			let mut kept = String::new();
+			for run in &runs[runs.len() - KEPT_RUNS..] {
+				kept.push_str(&serde_json::to_string(run).map_err(io::Error::other)?);
+				kept.push('\n');
+			}
+			let temp_path = self.folder.join(format!("{}.tmp", HISTORY_FILE));
+			fs::write(&temp_path, kept)?;
+			fs::rename(temp_path, path)?;
+		}
+		Ok(())
+	}
+
+	/// The last `count` runs, newest first
+	pub fn recent(&self, count: usize) -> io::Result<Vec<RunReport>> {
+		let mut runs = self.read_all()?;
+		runs.reverse();
+		runs.truncate(count);
+		Ok(runs)
+	}
+
+	fn read_all(&self) -> io::Result<Vec<RunReport>> {
+		let contents = match fs::read_to_string(self.folder.join(HISTORY_FILE)) {
+			Ok(contents) => contents,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+			Err(e) => return Err(e),
+		};
+		// a line cut short by a crash is skipped rather than losing the rest
+		Ok(contents
+			.lines()
+			.filter_map(|line| serde_json::from_str(line).ok())
+			.collect())
+	}
+}
+
+/// Writes a running backup's file, renaming it into place so it's never
+/// read part written
+fn write_running(path: &Path, running: &RunningBackup) -> io::Result<()> {
+	let temp_path = path.with_extension("json.tmp");
+	fs::write(
+		&temp_path,
+		serde_json::to_string(running).map_err(io::Error::other)?,
+	)?;
+	fs::rename(temp_path, path)
+}
+
+/// Where the user's state is kept, `$XDG_STATE_HOME/disk-hog-backup` or
+/// under `~/.local/state`
+pub fn default_state_folder() -> io::Result<PathBuf> {
+	let state_home = match env::var_os("XDG_STATE_HOME") {
+		Some(state_home) => PathBuf::from(state_home),
+		None => match env::var_os("HOME") {
+			Some(home) => Path::new(&home).join(".local").join("state"),
+			None => {
+				return Err(io::Error::new(
+					io::ErrorKind::NotFound,
+					"neither $XDG_STATE_HOME nor $HOME is set",
+				))
+			}
+		},
+	};
+	Ok(state_home.join("disk-hog-backup"))
+}
+
+#[cfg(unix)]
+fn process_exists(pid: u32) -> bool {
+	// SAFETY: signal 0 only checks the process is there
+	let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+	result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_exists(_pid: u32) -> bool {
+	true
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	fn report(destination: &str) -> RunReport {
+		RunReport {
+			destination: destination.to_string(),
+			set_name: None,
+			started_at: Utc::now(),
+			finished_at: Utc::now(),
+			stats: None,
+			error: None,
+			warnings: Vec::new(),
+			attempt: None,
+			set_count: None,
+			free_bytes: None,
+		}
+	}
+
+	#[test]
+	fn test_keeps_run_history() -> io::Result<()> {
+		let folder = create_tmp_folder("state")?;
+		let history = RunHistory::new(Path::new(&folder));
+		assert!(history.recent(5)?.is_empty());
+
+		let current = history.start_run("/media/usb")?;
+		assert_eq!(history.running()?.len(), 1);
+		history.record(&report("/media/usb"))?;
+		drop(current);
+		history.record(&report("/media/nas"))?;
+
+		assert!(history.running()?.is_empty());
+		let recent = history.recent(5)?;
+		assert_eq!(recent.len(), 2);
+		assert_eq!(recent[0].destination, "/media/nas");
+		Ok(())
+	}
+}
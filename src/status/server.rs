@@ -0,0 +1,242 @@
+use crate::notify::metrics::render_history_metrics;
+use crate::notify::RunReport;
+use crate::progress::Progress;
+use crate::status::history::{RunHistory, RunningBackup};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::io;
+use tiny_http::{Header, Request, Response, Server};
+
+/// What the status page shows
+#[derive(Clone, Debug, Serialize)]
+pub struct Status {
+	pub running: Vec<RunningBackup>,
+	/// Newest first
+	pub recent: Vec<RunReport>,
+	/// When the next scheduled backup starts, if anything schedules them
+	pub next_run: Option<DateTime<Utc>>,
+}
+
+pub fn current_status(
+	history: &RunHistory,
+	count: usize,
+	next_run: Option<DateTime<Utc>>,
+) -> io::Result<Status> {
+	Ok(Status {
+		running: history.running()?,
+		recent: history.recent(count)?,
+		next_run,
+	})
+}
+
+/// Serves the status of backups, as a page at `/`, as JSON at
+/// `/status.json` and as Prometheus metrics at `/metrics`, until the
+/// process ends. `next_run` is asked each time.
+pub fn serve_status(
+	listen: &str,
+	history: &RunHistory,
+	count: usize,
+	next_run: &dyn Fn() -> Option<DateTime<Utc>>,
+) -> io::Result<()> {
+	let server = Server::http(listen).map_err(|e| {
+		io::Error::new(
+			io::ErrorKind::AddrNotAvailable,
+			format!("can't listen on {}: {}", listen, e),
+		)
+	})?;
+	for request in server.incoming_requests() {
+		// one client going away mustn't stop the server
+		let _ = respond(request, history, count, next_run());
+	}
+	Ok(())
+}
+
+fn respond(
+	request: Request,
+	history: &RunHistory,
+	count: usize,
+	next_run: Option<DateTime<Utc>>,
+) -> io::Result<()> {
+	let body = match request.url() {
+		"/" => current_status(history, count, next_run)
+			.map(|status| (status_page(&status), "text/html; charset=utf-8")),
+		"/status.json" => current_status(history, count, next_run).and_then(|status| {
+			let json = serde_json::to_string_pretty(&status).map_err(io::Error::other)?;
+			Ok((json, "application/json"))
+		}),
+		// the same metrics the textfile written after each run has
+		"/metrics" => history.recent(usize::MAX).map(|mut runs| {
+			runs.reverse();
+			(render_history_metrics(&runs), "text/plain; version=0.0.4")
+		}),
+		_ => return request.respond(Response::from_string("not found").with_status_code(404)),
+	};
+	let (body, content_type) = match body {
+		Ok(body) => body,
+		Err(e) => {
+			return request.respond(Response::from_string(e.to_string()).with_status_code(500))
+		}
+	};
+	let header = Header::from_bytes("Content-Type", content_type).unwrap();
+	request.respond(Response::from_string(body).with_header(header))
+}
+
+/// Says what a running backup's doing and how far it's got, like
+/// "copying: 12 files, 3400 of 10000 bytes (34%)"
+fn describe_progress(progress: &Progress) -> String {
+	let mut description = format!(
+		"{}: {} files, {}",
+		progress.stage, progress.files_done, progress.bytes_done
+	);
+	match (progress.bytes_estimated, progress.percent()) {
+		(Some(estimated), Some(percent)) => {
+			let _ = write!(description, " of {} bytes ({:.0}%)", estimated, percent);
+		}
+		_ => description.push_str(" bytes"),
+	}
+	description
+}
+
+fn status_page(status: &Status) -> String {
+	let mut page = String::from(
+		"<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+		<meta http-equiv=\"refresh\" content=\"30\"><title>disk-hog-backup</title></head><body>\n",
+	);
+	let _ = writeln!(page, "<h1>Backups</h1>");
+	if status.running.is_empty() {
+		let _ = writeln!(page, "<p>No backup is running.</p>");
+	}
+	for running in &status.running {
+		let _ = writeln!(
+			page,
+			"<p>Backing up to {} since {} (process {})</p>",
+			escape(&running.destination),
+			running.started_at.to_rfc3339(),
+			running.pid
+		);
+		let _ = writeln!(page, "<p>{}</p>", describe_progress(&running.progress));
+	}
+	if let Some(next_run) = status.next_run {
+		let _ = writeln!(page, "<p>Next backup at {}</p>", next_run.to_rfc3339());
+	}
+	let _ = writeln!(page, "<h2>Recent runs</h2>\n<table>");
+	let _ = writeln!(
+		page,
+		"<tr><th>Finished</th><th>Destination</th><th>Set</th><th>Outcome</th></tr>"
+	);
+	for run in &status.recent {
+		let mut outcome = match (&run.error, run.warnings.first()) {
+			(Some(error), _) => format!("failed: {}", error),
+			(None, Some(warning)) => format!("warning: {}", warning),
+			(None, None) => "succeeded".to_string(),
+		};
+		if let Some(attempt) = run.attempt.filter(|attempt| *attempt > 1) {
+			outcome = format!("{} (attempt {})", outcome, attempt);
+		}
+		let _ = writeln!(
+			page,
+			"<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+			run.finished_at.to_rfc3339(),
+			escape(&run.destination),
+			escape(run.set_name.as_deref().unwrap_or("")),
+			escape(&outcome)
+		);
+	}
+	page.push_str("</table>\n</body></html>\n");
+	page
+}
+
+fn escape(text: &str) -> String {
+	text.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::progress::Stage;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+	use std::path::Path;
+	use std::thread;
+
+	#[test]
+	fn test_serves_status() -> io::Result<()> {
+		let folder = create_tmp_folder("state")?;
+		let history = RunHistory::new(Path::new(&folder));
+		history.record(&RunReport {
+			destination: "/media/<usb>".to_string(),
+			set_name: None,
+			started_at: Utc::now(),
+			finished_at: Utc::now(),
+			stats: None,
+			error: Some("disk full".to_string()),
+			warnings: Vec::new(),
+			attempt: None,
+			set_count: None,
+			free_bytes: None,
+		})?;
+		let server = Server::http("127.0.0.1:0").map_err(io::Error::other)?;
+		let address = server.server_addr().to_ip().unwrap();
+		let serving = thread::spawn(move || {
+			for _ in 0..3 {
+				let request = server.recv().unwrap();
+				respond(request, &history, 10, None).unwrap();
+			}
+		});
+
+		let get = |path: &str| {
+			ureq::get(&format!("http://{}{}", address, path))
+				.call()
+				.map_err(io::Error::other)?
+				.body_mut()
+				.read_to_string()
+				.map_err(io::Error::other)
+		};
+		let json: serde_json::Value = serde_json::from_str(&get("/status.json")?)?;
+		let page = get("/")?;
+		let metrics = get("/metrics")?;
+		serving.join().unwrap();
+
+		assert_eq!(json["recent"][0]["error"], "disk full");
+		assert!(json["running"].as_array().unwrap().is_empty());
+		assert!(page.contains("/media/&lt;usb&gt;"));
+		assert!(page.contains("failed: disk full"));
+		assert!(
+			metrics.contains("disk_hog_backup_last_run_success{destination=\"/media/<usb>\"} 0\n")
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_shows_progress_of_running_backups() {
+		let status = Status {
+			running: vec![RunningBackup {
+				pid: 42,
+				destination: "/media/usb".to_string(),
+				started_at: Utc::now(),
+				progress: Progress {
+					stage: Stage::Copying,
+					files_done: 12,
+					bytes_done: 3400,
+					bytes_estimated: Some(10000),
+				},
+			}],
+			recent: Vec::new(),
+			next_run: None,
+		};
+		let page = status_page(&status);
+		let json = serde_json::to_value(&status).unwrap();
+
+		assert!(page.contains("copying: 12 files, 3400 of 10000 bytes (34%)"));
+		assert_eq!(json["running"][0]["progress"]["stage"], "copying");
+		assert_eq!(json["running"][0]["progress"]["files_done"], 12);
+		assert_eq!(json["running"][0]["progress"]["bytes_estimated"], 10000);
+		assert_eq!(
+			describe_progress(&Progress::default()),
+			"preparing: 0 files, 0 bytes"
+		);
+	}
+}
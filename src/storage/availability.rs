@@ -0,0 +1,62 @@
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a destination that isn't there is looked for again
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Keeps trying `probe` until it succeeds or `limit` has passed, e.g. to
+/// wait for a network share or removable drive a scheduled backup found
+/// missing. `retrying` is told each failure that will be tried again.
+/// Gives the last failure once out of time.
+pub fn wait_until_available<T>(
+	mut probe: impl FnMut() -> io::Result<T>,
+	limit: Duration,
+	interval: Duration,
+	mut retrying: impl FnMut(&io::Error),
+) -> io::Result<T> {
+	let deadline = Instant::now() + limit;
+	loop {
+		match probe() {
+			Ok(found) => return Ok(found),
+			Err(e) if Instant::now() + interval <= deadline => {
+				retrying(&e);
+				thread::sleep(interval);
+			}
+			Err(e) => return Err(e),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_retries_until_available() {
+		let mut attempts = 0;
+		let mut retries = 0;
+		let found = wait_until_available(
+			|| {
+				attempts += 1;
+				match attempts {
+					3 => Ok("mounted"),
+					_ => Err(io::Error::from(io::ErrorKind::NotFound)),
+				}
+			},
+			Duration::from_secs(1),
+			Duration::from_millis(1),
+			|_| retries += 1,
+		);
+		assert_eq!(found.unwrap(), "mounted");
+		assert_eq!(retries, 2);
+
+		let gave_up = wait_until_available(
+			|| Err::<(), _>(io::Error::from(io::ErrorKind::NotFound)),
+			Duration::from_millis(20),
+			Duration::from_millis(5),
+			|_| {},
+		);
+		assert_eq!(gave_up.unwrap_err().kind(), io::ErrorKind::NotFound);
+	}
+}
@@ -0,0 +1,60 @@
+use std::fs::Permissions;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// A file or folder found by [StorageBackend::list]
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackendEntry {
+	pub name: String,
+	pub is_dir: bool,
+}
+
+/// Where sets are written
+pub trait StorageBackend {
+	/// Creates a folder, failing with `AlreadyExists` if it's there, so
+	/// two backups can't both claim the same one
+	fn create_dir(&self, path: &Path) -> io::Result<()>;
+
+	/// Creates a folder and any missing parents
+	fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+	fn exists(&self, path: &Path) -> io::Result<bool>;
+
+	/// Creates or truncates a file to write into. What's written is only
+	/// sure to be stored once the writer has been flushed.
+	fn create_file(&self, path: &Path) -> io::Result<Box<dyn Write>>;
+
+	/// Opens a file to write onto the end of, e.g. to finish one an
+	/// interrupted upload cut short
+	fn append_file(&self, path: &Path) -> io::Result<Box<dyn Write>>;
+
+	/// How many bytes a file holds, failing with `NotFound` if it's not there
+	fn file_size(&self, path: &Path) -> io::Result<u64>;
+
+	/// Renames a file or folder. Sets are finalized this way, so it must be
+	/// atomic.
+	fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+	/// What's in a folder, in no particular order
+	fn list(&self, path: &Path) -> io::Result<Vec<BackendEntry>>;
+
+	fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+	/// Deletes a folder and everything in it
+	fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+
+	/// Makes `link` another name for the file at `original`, so stored
+	/// contents can be shared between sets. Backends that can't fail with
+	/// `Unsupported`, and the contents get written again.
+	fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+		let _ = (original, link);
+		Err(io::ErrorKind::Unsupported.into())
+	}
+
+	/// Gives a copied file its source's permissions, where the backend keeps
+	/// them
+	fn set_permissions(&self, path: &Path, permissions: Permissions) -> io::Result<()> {
+		let _ = (path, permissions);
+		Ok(())
+	}
+}
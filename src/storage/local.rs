@@ -0,0 +1,139 @@
+use crate::storage::backend::{BackendEntry, StorageBackend};
+use crate::storage::source::{SourceBackend, SourceEntry};
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// A destination that's a folder on a local or mounted filesystem
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LocalStorage;
+
+impl StorageBackend for LocalStorage {
+	fn create_dir(&self, path: &Path) -> io::Result<()> {
+		fs::create_dir(path)
+	}
+
+	fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+		fs::create_dir_all(path)
+	}
+
+	fn exists(&self, path: &Path) -> io::Result<bool> {
+		path.try_exists()
+	}
+
+	fn create_file(&self, path: &Path) -> io::Result<Box<dyn Write>> {
+		Ok(Box::new(File::create(path)?))
+	}
+
+	fn append_file(&self, path: &Path) -> io::Result<Box<dyn Write>> {
+		Ok(Box::new(OpenOptions::new().append(true).open(path)?))
+	}
+
+	fn file_size(&self, path: &Path) -> io::Result<u64> {
+		Ok(fs::metadata(path)?.len())
+	}
+
+	fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+		fs::rename(from, to)
+	}
+
+	fn list(&self, path: &Path) -> io::Result<Vec<BackendEntry>> {
+		fs::read_dir(path)?
+			.map(|entry| {
+				let entry = entry?;
+				Ok(BackendEntry {
+					name: entry.file_name().to_string_lossy().into_owned(),
+					is_dir: entry.file_type()?.is_dir(),
+				})
+			})
+			.collect()
+	}
+
+	fn remove_file(&self, path: &Path) -> io::Result<()> {
+		fs::remove_file(path)
+	}
+
+	fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+		fs::remove_dir_all(path)
+	}
+
+	fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+		fs::hard_link(original, link)
+	}
+
+	fn set_permissions(&self, path: &Path, permissions: Permissions) -> io::Result<()> {
+		fs::set_permissions(path, permissions)
+	}
+}
+
+/// Sources that are folders on a local or mounted filesystem
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LocalSource;
+
+impl SourceBackend for LocalSource {
+	fn list(&self, path: &Path) -> io::Result<Vec<SourceEntry>> {
+		fs::read_dir(path)?
+			.map(|entry| {
+				let entry = entry?;
+				// following symlinks, as copying does
+				let metadata = fs::metadata(entry.path())?;
+				Ok(SourceEntry {
+					name: entry.file_name(),
+					is_dir: metadata.is_dir(),
+					size: if metadata.is_dir() { 0 } else { metadata.len() },
+					modified: metadata.modified().ok(),
+				})
+			})
+			.collect()
+	}
+
+	fn open(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+		Ok(Box::new(BufReader::new(File::open(path)?)))
+	}
+
+	fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+		fs::canonicalize(path)
+	}
+
+	fn permissions(&self, path: &Path) -> io::Result<Option<Permissions>> {
+		Ok(Some(fs::metadata(path)?.permissions()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+
+	#[test]
+	fn test_writes_through_backend() -> io::Result<()> {
+		let dest = create_tmp_folder("backups")?;
+		let folder = Path::new(&dest).join("set");
+		let backend: &dyn StorageBackend = &LocalStorage;
+
+		backend.create_dir(&folder)?;
+		let err = backend.create_dir(&folder).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+		let mut file = backend.create_file(&folder.join("testfile.txt"))?;
+		file.write_all(b"backmeup susie")?;
+		file.flush()?;
+		drop(file);
+		backend.rename(&folder, &Path::new(&dest).join("renamed"))?;
+
+		let renamed = Path::new(&dest).join("renamed");
+		assert_eq!(
+			backend.list(&renamed)?,
+			vec![BackendEntry {
+				name: "testfile.txt".to_string(),
+				is_dir: false,
+			}]
+		);
+		assert_eq!(
+			fs::read_to_string(renamed.join("testfile.txt"))?,
+			"backmeup susie"
+		);
+		backend.remove_dir_all(&renamed)?;
+		assert!(!backend.exists(&renamed)?);
+		Ok(())
+	}
+}
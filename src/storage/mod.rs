@@ -0,0 +1,12 @@
+pub mod availability;
+pub mod backend;
+pub mod local;
+pub mod sftp;
+pub mod source;
+pub mod throttle;
+
+// Backups write into their destination through a StorageBackend, so the
+// copy logic doesn't care whether sets end up in a local folder or
+// somewhere else. Paths given to a backend are paths in the destination as
+// the backend understands them, for a local folder just filesystem paths.
+// Files being backed up are read the same way through a SourceBackend.
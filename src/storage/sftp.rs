@@ -0,0 +1,431 @@
+use crate::storage::backend::{BackendEntry, StorageBackend};
+use crate::storage::source::{SourceBackend, SourceEntry};
+use ssh2::{CheckResult, KnownHostFileKind, OpenFlags, OpenType, RenameFlags, Session, Sftp};
+use std::env;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, UNIX_EPOCH};
+
+pub const SFTP_SCHEME: &str = "sftp://";
+
+const DEFAULT_PORT: u16 = 22;
+
+/// Keys tried, in `~/.ssh`, when ssh-agent has none the server accepts
+const KEY_FILE_NAMES: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// Each read or write is a round trip to the server, so files are sent and
+/// fetched in pieces this big, which libssh2 pipelines
+const BUFFER_SIZE: usize = 256 * 1024;
+
+/// A destination given as `sftp://[user@]host[:port]/path`. The user
+/// defaults to the local one, and the path is absolute on the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SftpLocation {
+	pub user: String,
+	pub host: String,
+	pub port: u16,
+	pub path: String,
+	/// Private key to log in with before trying ssh-agent and the keys in
+	/// `~/.ssh`
+	pub identity_file: Option<PathBuf>,
+}
+
+impl SftpLocation {
+	pub fn is_sftp_url(destination: &str) -> bool {
+		destination.starts_with(SFTP_SCHEME)
+	}
+
+	/// Whether a source is given scp style, as `[user@]host:path`. Local
+	/// paths with a colon have a slash before it, and Windows drive letters
+	/// are a single letter.
+	pub fn is_scp_style(source: &str) -> bool {
+		match source.split_once(':') {
+			Some((host, path)) => host.len() > 1 && !host.contains(['/', '\\']) && !path.is_empty(),
+			None => false,
+		}
+	}
+
+	/// Parses an scp style `[user@]host:path`. Relative paths are from the
+	/// user's home folder on the server.
+	pub fn parse_scp_style(source: &str) -> Result<Self, String> {
+		if !Self::is_scp_style(source) {
+			return Err(format!("{} isn't a [user@]host:path", source));
+		}
+		let (authority, path) = source.split_once(':').unwrap();
+		let (user, host) = split_user(authority, source)?;
+		Ok(SftpLocation {
+			user,
+			host: host.to_string(),
+			port: DEFAULT_PORT,
+			path: path.to_string(),
+			identity_file: None,
+		})
+	}
+}
+
+/// The user before an `@`, or the local user
+fn split_user<'a>(authority: &'a str, location: &str) -> Result<(String, &'a str), String> {
+	let (user, host) = match authority.rsplit_once('@') {
+		Some((user, host)) => (user.to_string(), host),
+		None => (
+			env::var("USER")
+				.map_err(|_| format!("{} has no user, and $USER isn't set", location))?,
+			authority,
+		),
+	};
+	match user.is_empty() || host.is_empty() {
+		true => Err(format!("{} needs a user and host", location)),
+		false => Ok((user, host)),
+	}
+}
+
+impl FromStr for SftpLocation {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let rest = s
+			.strip_prefix(SFTP_SCHEME)
+			.ok_or_else(|| format!("{} isn't an {} URL", s, SFTP_SCHEME))?;
+		let (authority, path) = match rest.find('/') {
+			Some(slash) => rest.split_at(slash),
+			None => return Err(format!("{} has no path on the server", s)),
+		};
+		let (user, host_port) = split_user(authority, s)?;
+		let (host, port) = match host_port.rsplit_once(':') {
+			Some((host, port)) => (
+				host,
+				port.parse()
+					.map_err(|_| format!("{} isn't a valid port", port))?,
+			),
+			None => (host_port, DEFAULT_PORT),
+		};
+		if host.is_empty() {
+			return Err(format!("{} needs a user and host", s));
+		}
+		Ok(SftpLocation {
+			user,
+			host: host.to_string(),
+			port,
+			path: path.to_string(),
+			identity_file: None,
+		})
+	}
+}
+
+/// Writes sets to a folder on an SSH server over SFTP, or reads sources
+/// from one. The server is checked against `~/.ssh/known_hosts` as ssh
+/// would, and logged into with ssh-agent or a key in `~/.ssh` without a
+/// passphrase.
+pub struct SftpStorage {
+	location: SftpLocation,
+	sftp: Sftp,
+	// the SFTP channel needs the session kept open
+	_session: Session,
+}
+
+impl SftpStorage {
+	pub fn connect(location: &SftpLocation) -> io::Result<Self> {
+		let tcp = TcpStream::connect((location.host.as_str(), location.port))?;
+		let mut session = Session::new()?;
+		session.set_tcp_stream(tcp);
+		session.handshake()?;
+		check_host_key(&session, location)?;
+		authenticate(&session, location)?;
+		let sftp = session.sftp()?;
+		Ok(SftpStorage {
+			location: location.clone(),
+			sftp,
+			_session: session,
+		})
+	}
+}
+
+fn ssh_folder() -> io::Result<PathBuf> {
+	env::var_os("HOME")
+		.map(|home| Path::new(&home).join(".ssh"))
+		.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "$HOME isn't set"))
+}
+
+/// Refuses servers that aren't in known_hosts, or whose key has changed,
+/// rather than sending them the backup
+fn check_host_key(session: &Session, location: &SftpLocation) -> io::Result<()> {
+	let mut known_hosts = session.known_hosts()?;
+	let known_hosts_file = ssh_folder()?.join("known_hosts");
+	match known_hosts.read_file(&known_hosts_file, KnownHostFileKind::OpenSSH) {
+		Err(e) if known_hosts_file.exists() => return Err(e.into()),
+		_ => {}
+	}
+	let (key, _) = session
+		.host_key()
+		.ok_or_else(|| io::Error::other("the server sent no host key"))?;
+	match known_hosts.check_port(&location.host, location.port, key) {
+		CheckResult::Match => Ok(()),
+		CheckResult::Mismatch => Err(io::Error::new(
+			io::ErrorKind::PermissionDenied,
+			format!(
+				"the host key of {} doesn't match the one in {}",
+				location.host,
+				known_hosts_file.display()
+			),
+		)),
+		CheckResult::NotFound => Err(io::Error::new(
+			io::ErrorKind::PermissionDenied,
+			format!(
+				"{} isn't in {}, connect with ssh once to check and add its key",
+				location.host,
+				known_hosts_file.display()
+			),
+		)),
+		CheckResult::Failure => Err(io::Error::other(format!(
+			"couldn't check the host key of {}",
+			location.host
+		))),
+	}
+}
+
+fn authenticate(session: &Session, location: &SftpLocation) -> io::Result<()> {
+	let user = &location.user;
+	if let Some(identity_file) = &location.identity_file {
+		// failing here isn't an error yet, the agent may have a key
+		let _ = session.userauth_pubkey_file(user, None, identity_file, None);
+	}
+	if !session.authenticated() {
+		// nor the agent having no key the server takes
+		let _ = session.userauth_agent(user);
+	}
+	let ssh_folder = ssh_folder()?;
+	for name in KEY_FILE_NAMES {
+		if session.authenticated() {
+			break;
+		}
+		let key_file = ssh_folder.join(name);
+		if key_file.exists() {
+			let _ = session.userauth_pubkey_file(user, None, &key_file, None);
+		}
+	}
+	match session.authenticated() {
+		true => Ok(()),
+		false => Err(io::Error::new(
+			io::ErrorKind::PermissionDenied,
+			format!(
+				"couldn't log in as {} with ssh-agent or a key in {}",
+				user,
+				ssh_folder.display()
+			),
+		)),
+	}
+}
+
+impl StorageBackend for SftpStorage {
+	fn create_dir(&self, path: &Path) -> io::Result<()> {
+		match self.sftp.mkdir(path, 0o755) {
+			Ok(()) => Ok(()),
+			// SFTP only reports a generic failure, so look for what's there
+			Err(_) if self.exists(path)? => Err(io::ErrorKind::AlreadyExists.into()),
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+		if path.as_os_str().is_empty() || self.exists(path)? {
+			return Ok(());
+		}
+		if let Some(parent) = path.parent() {
+			self.create_dir_all(parent)?;
+		}
+		match self.create_dir(path) {
+			Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+			result => result,
+		}
+	}
+
+	fn exists(&self, path: &Path) -> io::Result<bool> {
+		match self.sftp.stat(path) {
+			Ok(_) => Ok(true),
+			Err(e) => match io::Error::from(e) {
+				e if e.kind() == io::ErrorKind::NotFound => Ok(false),
+				e => Err(e),
+			},
+		}
+	}
+
+	fn create_file(&self, path: &Path) -> io::Result<Box<dyn Write>> {
+		let file = self.sftp.create(path)?;
+		Ok(Box::new(BufWriter::with_capacity(BUFFER_SIZE, file)))
+	}
+
+	fn append_file(&self, path: &Path) -> io::Result<Box<dyn Write>> {
+		// writing at the end rather than with APPEND, which servers can ignore
+		let mut file = self
+			.sftp
+			.open_mode(path, OpenFlags::WRITE, 0o644, OpenType::File)?;
+		file.seek(SeekFrom::End(0))?;
+		Ok(Box::new(BufWriter::with_capacity(BUFFER_SIZE, file)))
+	}
+
+	fn file_size(&self, path: &Path) -> io::Result<u64> {
+		self.sftp.stat(path)?.size.ok_or_else(|| {
+			io::Error::other(format!("the server didn't give {}'s size", path.display()))
+		})
+	}
+
+	fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+		// without OVERWRITE, so a set can't replace another of the same name.
+		// OpenSSH renames folders with rename(2), which is atomic.
+		self.sftp
+			.rename(from, to, Some(RenameFlags::ATOMIC | RenameFlags::NATIVE))?;
+		Ok(())
+	}
+
+	fn list(&self, path: &Path) -> io::Result<Vec<BackendEntry>> {
+		Ok(self
+			.sftp
+			.readdir(path)?
+			.into_iter()
+			.map(|(child, stat)| BackendEntry {
+				name: child.file_name().unwrap().to_string_lossy().into_owned(),
+				is_dir: stat.is_dir(),
+			})
+			.collect())
+	}
+
+	fn remove_file(&self, path: &Path) -> io::Result<()> {
+		self.sftp.unlink(path)?;
+		Ok(())
+	}
+
+	fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+		for entry in StorageBackend::list(self, path)? {
+			let child = path.join(&entry.name);
+			match entry.is_dir {
+				true => self.remove_dir_all(&child)?,
+				false => self.remove_file(&child)?,
+			}
+		}
+		self.sftp.rmdir(path)?;
+		Ok(())
+	}
+
+	#[cfg(unix)]
+	fn set_permissions(&self, path: &Path, permissions: std::fs::Permissions) -> io::Result<()> {
+		use std::os::unix::fs::PermissionsExt;
+		let stat = ssh2::FileStat {
+			size: None,
+			uid: None,
+			gid: None,
+			perm: Some(permissions.mode()),
+			atime: None,
+			mtime: None,
+		};
+		self.sftp.setstat(path, stat)?;
+		Ok(())
+	}
+}
+
+impl SourceBackend for SftpStorage {
+	fn list(&self, path: &Path) -> io::Result<Vec<SourceEntry>> {
+		let mut entries = Vec::new();
+		for (child, stat) in self.sftp.readdir(path)? {
+			// following symlinks, as copying local sources does
+			let stat = match stat.file_type().is_symlink() {
+				true => self.sftp.stat(&child)?,
+				false => stat,
+			};
+			entries.push(SourceEntry {
+				name: child.file_name().unwrap().to_os_string(),
+				is_dir: stat.is_dir(),
+				size: if stat.is_dir() {
+					0
+				} else {
+					stat.size.unwrap_or(0)
+				},
+				// only whole seconds are given, so rounded up to be sure
+				// it's not taken for older than it is
+				modified: stat
+					.mtime
+					.map(|mtime| UNIX_EPOCH + Duration::from_secs(mtime + 1)),
+			});
+		}
+		Ok(entries)
+	}
+
+	fn open(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+		let file = self.sftp.open(path)?;
+		Ok(Box::new(BufReader::with_capacity(BUFFER_SIZE, file)))
+	}
+
+	fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+		Ok(self.sftp.realpath(path)?)
+	}
+
+	fn describe(&self, path: &Path) -> String {
+		format!(
+			"{}@{}:{}",
+			self.location.user,
+			self.location.host,
+			path.display()
+		)
+	}
+
+	#[cfg(unix)]
+	fn permissions(&self, path: &Path) -> io::Result<Option<std::fs::Permissions>> {
+		use std::os::unix::fs::PermissionsExt;
+		Ok(self
+			.sftp
+			.stat(path)?
+			.perm
+			.map(|perm| std::fs::Permissions::from_mode(perm & 0o7777)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parses_location() {
+		assert_eq!(
+			"sftp://susie@backups.example.com:2222/srv/backups".parse(),
+			Ok(SftpLocation {
+				user: "susie".to_string(),
+				host: "backups.example.com".to_string(),
+				port: 2222,
+				path: "/srv/backups".to_string(),
+				identity_file: None,
+			})
+		);
+		let location: SftpLocation = "sftp://susie@nas/backups".parse().unwrap();
+		assert_eq!(location.port, DEFAULT_PORT);
+		assert_eq!(location.path, "/backups");
+		assert!("sftp://susie@nas".parse::<SftpLocation>().is_err());
+		assert!("sftp://susie@nas:ssh/backups"
+			.parse::<SftpLocation>()
+			.is_err());
+		assert!("/backups".parse::<SftpLocation>().is_err());
+		assert!(SftpLocation::is_sftp_url("sftp://nas/backups"));
+		assert!(!SftpLocation::is_sftp_url("/mnt/sftp://"));
+	}
+
+	#[test]
+	fn test_parses_scp_style() {
+		assert_eq!(
+			SftpLocation::parse_scp_style("susie@server:/srv/data"),
+			Ok(SftpLocation {
+				user: "susie".to_string(),
+				host: "server".to_string(),
+				port: DEFAULT_PORT,
+				path: "/srv/data".to_string(),
+				identity_file: None,
+			})
+		);
+		assert_eq!(
+			SftpLocation::parse_scp_style("susie@server:data").map(|location| location.path),
+			Ok("data".to_string())
+		);
+		for local in ["/srv/data", "./odd:name", "C:\\data", "server:"] {
+			assert!(!SftpLocation::is_scp_style(local), "{}", local);
+		}
+	}
+}
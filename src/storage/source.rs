@@ -0,0 +1,40 @@
+use std::ffi::OsString;
+use std::fs::Permissions;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// A file or folder found by [SourceBackend::list]. Symlinks are followed,
+/// so a link to a folder is a folder.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceEntry {
+	pub name: OsString,
+	pub is_dir: bool,
+	/// Size of a file, 0 for folders
+	pub size: u64,
+	/// When a file was last changed, where the backend has it
+	pub modified: Option<SystemTime>,
+}
+
+/// Where the files being backed up are read from
+pub trait SourceBackend {
+	/// What's in a folder, in no particular order
+	fn list(&self, path: &Path) -> io::Result<Vec<SourceEntry>>;
+
+	/// Opens a file to read its contents
+	fn open(&self, path: &Path) -> io::Result<Box<dyn Read>>;
+
+	/// The absolute path of a source, symlinks resolved
+	fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+
+	/// How a source is shown and recorded in set metadata
+	fn describe(&self, path: &Path) -> String {
+		path.to_string_lossy().into_owned()
+	}
+
+	/// A file's permissions, for copies to keep, where the backend has them
+	fn permissions(&self, path: &Path) -> io::Result<Option<Permissions>> {
+		let _ = path;
+		Ok(None)
+	}
+}
@@ -0,0 +1,150 @@
+use crate::storage::backend::{BackendEntry, StorageBackend};
+use std::cell::RefCell;
+use std::fs::Permissions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Keeps what's written under a number of bytes a second, by sleeping once
+/// writes get ahead of it
+#[derive(Debug)]
+pub struct RateLimiter {
+	bytes_per_second: u64,
+	/// When what's been written so far would have finished at the limit
+	caught_up_at: Instant,
+}
+
+impl RateLimiter {
+	pub fn new(bytes_per_second: u64) -> RateLimiter {
+		RateLimiter {
+			bytes_per_second: bytes_per_second.max(1),
+			caught_up_at: Instant::now(),
+		}
+	}
+
+	/// Counts bytes just written, waiting as long as sending them takes at
+	/// the limit. Time spent not writing isn't saved up for a burst later.
+	pub fn wrote(&mut self, bytes: usize) {
+		let now = Instant::now();
+		let sending = Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64);
+		self.caught_up_at = self.caught_up_at.max(now) + sending;
+		thread::sleep(self.caught_up_at - now);
+	}
+}
+
+/// Wraps a backend so files are uploaded no faster than a limit shared by
+/// all of them, e.g. to leave room on a home connection for everything
+/// else. Everything but writing file contents goes through unchanged.
+pub struct ThrottledStorage<'a> {
+	backend: &'a dyn StorageBackend,
+	limiter: Rc<RefCell<RateLimiter>>,
+}
+
+impl<'a> ThrottledStorage<'a> {
+	pub fn new(backend: &'a dyn StorageBackend, bytes_per_second: u64) -> ThrottledStorage<'a> {
+		ThrottledStorage {
+			backend,
+			limiter: Rc::new(RefCell::new(RateLimiter::new(bytes_per_second))),
+		}
+	}
+}
+
+struct ThrottledWriter {
+	inner: Box<dyn Write>,
+	limiter: Rc<RefCell<RateLimiter>>,
+}
+
+impl Write for ThrottledWriter {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let written = self.inner.write(buf)?;
+		self.limiter.borrow_mut().wrote(written);
+		Ok(written)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+impl StorageBackend for ThrottledStorage<'_> {
+	fn create_dir(&self, path: &Path) -> io::Result<()> {
+		self.backend.create_dir(path)
+	}
+
+	fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+		self.backend.create_dir_all(path)
+	}
+
+	fn exists(&self, path: &Path) -> io::Result<bool> {
+		self.backend.exists(path)
+	}
+
+	fn create_file(&self, path: &Path) -> io::Result<Box<dyn Write>> {
+		Ok(Box::new(ThrottledWriter {
+			inner: self.backend.create_file(path)?,
+			limiter: Rc::clone(&self.limiter),
+		}))
+	}
+
+	fn append_file(&self, path: &Path) -> io::Result<Box<dyn Write>> {
+		Ok(Box::new(ThrottledWriter {
+			inner: self.backend.append_file(path)?,
+			limiter: Rc::clone(&self.limiter),
+		}))
+	}
+
+	fn file_size(&self, path: &Path) -> io::Result<u64> {
+		self.backend.file_size(path)
+	}
+
+	fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+		self.backend.rename(from, to)
+	}
+
+	fn list(&self, path: &Path) -> io::Result<Vec<BackendEntry>> {
+		self.backend.list(path)
+	}
+
+	fn remove_file(&self, path: &Path) -> io::Result<()> {
+		self.backend.remove_file(path)
+	}
+
+	fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+		self.backend.remove_dir_all(path)
+	}
+
+	fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+		self.backend.hard_link(original, link)
+	}
+
+	fn set_permissions(&self, path: &Path, permissions: Permissions) -> io::Result<()> {
+		self.backend.set_permissions(path, permissions)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::local::LocalStorage;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+	use std::fs;
+
+	#[test]
+	fn test_limits_upload_rate() -> io::Result<()> {
+		let folder = create_tmp_folder("throttled")?;
+		let storage = ThrottledStorage::new(&LocalStorage, 1 << 20);
+		let started = Instant::now();
+		for name in ["first", "second"] {
+			let mut file = storage.create_file(&Path::new(&folder).join(name))?;
+			file.write_all(&[0; 1 << 17])?;
+			file.flush()?;
+		}
+
+		// a quarter of a MiB at a MiB a second, over both files
+		assert!(started.elapsed() >= Duration::from_millis(240));
+		assert_eq!(fs::read(Path::new(&folder).join("second"))?.len(), 1 << 17);
+		Ok(())
+	}
+}
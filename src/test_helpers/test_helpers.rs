@@ -1,3 +1,7 @@
+use crate::backup::backup::{run, BackupOptions, BackupRequest};
+use crate::backup_sets::backup_set::SetStatus;
+use crate::backup_sets::set_metadata::{finish_metadata, write_metadata, SetMetadata};
+use crate::error::BackupError;
 use chrono::{DateTime, Utc};
 use rand::Rng;
 use std::env;
@@ -6,13 +10,41 @@ use std::io::{self, Read};
 use std::path::Path;
 
 pub fn create_tmp_folder(prefix: &str) -> io::Result<String> {
-	let mut rng = rand::thread_rng();
-	let random_suffix: u32 = rng.gen();
+	let mut rng = rand::rng();
+	let random_suffix: u32 = rng.random();
 	let dir = env::temp_dir().join(format!("dhb-{}-{}", prefix, random_suffix));
 	fs::create_dir_all(&dir)?;
 	Ok(dir.to_string_lossy().into_owned())
 }
 
+/// Backs up a single folder, returning the new set's name
+pub fn backup_folder(
+	source: &str,
+	dest: &str,
+	options: &BackupOptions,
+) -> Result<String, BackupError> {
+	let request = BackupRequest {
+		sources: vec![source.to_string()],
+		destination: dest.to_string(),
+		options: options.clone(),
+	};
+	Ok(run(&request)?.set_name)
+}
+
+/// Creates an empty set with metadata saying whether its backup finished
+pub fn create_set(dest: &str, set_name: &str, status: SetStatus) -> io::Result<()> {
+	let set_folder = Path::new(dest).join(set_name);
+	fs::create_dir_all(&set_folder)?;
+	write_metadata(
+		&set_folder,
+		&SetMetadata::new("/home", Utc::now(), Default::default()),
+	)?;
+	if status == SetStatus::Complete {
+		finish_metadata(&set_folder, Utc::now(), Default::default())?;
+	}
+	Ok(())
+}
+
 pub fn file_contents_matches(file1_path: &str, file2_path: &str) -> io::Result<bool> {
 	let file1_contents = read_contents(file1_path)?;
 	let file2_contents = read_contents(file2_path)?;
@@ -0,0 +1,174 @@
+use ::notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// Watches sources for changes through the platform's file notifications:
+/// inotify on Linux, FSEvents on macOS and ReadDirectoryChangesW on
+/// Windows
+pub struct SourceWatcher {
+	// stops watching when dropped
+	_watcher: RecommendedWatcher,
+	events: Receiver<::notify::Result<Event>>,
+	/// Folders whose changes don't count, like a destination inside a source
+	ignored: Vec<PathBuf>,
+}
+
+impl SourceWatcher {
+	/// Starts watching everything under the sources, apart from what's
+	/// under `ignored`
+	pub fn new(sources: &[&str], ignored: &[&str]) -> io::Result<SourceWatcher> {
+		let (sender, events) = mpsc::channel();
+		let mut watcher = ::notify::recommended_watcher(sender).map_err(watch_error)?;
+		for source in sources {
+			let source = fs::canonicalize(source)?;
+			watcher
+				.watch(&source, RecursiveMode::Recursive)
+				.map_err(|e| {
+					io::Error::other(format!("can't watch {}: {}", source.display(), e))
+				})?;
+		}
+		Ok(SourceWatcher {
+			_watcher: watcher,
+			events,
+			// a folder that isn't there yet can't have changes reported
+			ignored: ignored
+				.iter()
+				.map(|path| fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path)))
+				.collect(),
+		})
+	}
+
+	/// Waits until something in the sources changes and then until
+	/// nothing has for `quiet`, so a burst of changes like a build writing
+	/// thousands of files is taken together, giving the paths changed.
+	/// Changes that don't stop are given once `max_delay` has passed since
+	/// the first.
+	pub fn wait_for_changes(
+		&self,
+		quiet: Duration,
+		max_delay: Duration,
+	) -> io::Result<Vec<PathBuf>> {
+		let mut changed = Vec::new();
+		while changed.is_empty() {
+			let event = self.events.recv().map_err(|_| stopped())?;
+			self.add_changes(event, &mut changed)?;
+		}
+		let deadline = Instant::now().checked_add(max_delay);
+		loop {
+			let wait = match deadline {
+				Some(deadline) => quiet.min(deadline.saturating_duration_since(Instant::now())),
+				None => quiet,
+			};
+			match self.events.recv_timeout(wait) {
+				Ok(event) => self.add_changes(event, &mut changed)?,
+				Err(RecvTimeoutError::Timeout) => return Ok(changed),
+				Err(RecvTimeoutError::Disconnected) => return Err(stopped()),
+			}
+		}
+	}
+
+	fn add_changes(
+		&self,
+		event: ::notify::Result<Event>,
+		changed: &mut Vec<PathBuf>,
+	) -> io::Result<()> {
+		let event = event.map_err(watch_error)?;
+		// reading files, as the backup does, isn't a change
+		if event.kind.is_access() {
+			return Ok(());
+		}
+		for path in event.paths {
+			if !self.is_ignored(&path) && !changed.contains(&path) {
+				changed.push(path);
+			}
+		}
+		Ok(())
+	}
+
+	fn is_ignored(&self, path: &Path) -> bool {
+		self.ignored.iter().any(|ignored| path.starts_with(ignored))
+	}
+}
+
+fn stopped() -> io::Error {
+	io::Error::new(io::ErrorKind::BrokenPipe, "stopped getting file changes")
+}
+
+fn watch_error(e: ::notify::Error) -> io::Error {
+	io::Error::other(format!("watching for changes failed: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_helpers::test_helpers::create_tmp_folder;
+	use std::thread;
+
+	#[test]
+	fn test_reports_changed_files() -> io::Result<()> {
+		let source = fs::canonicalize(create_tmp_folder("watch")?)?;
+		let destination = source.join("backups");
+		fs::create_dir(&destination)?;
+		let watcher = SourceWatcher::new(
+			&[source.to_str().unwrap()],
+			&[destination.to_str().unwrap()],
+		)?;
+
+		let writer = {
+			let source = source.clone();
+			thread::spawn(move || {
+				thread::sleep(Duration::from_millis(100));
+				fs::write(destination.join("set"), "ignored").unwrap();
+				fs::write(source.join("notes.txt"), "changed").unwrap();
+			})
+		};
+		let changed = watcher.wait_for_changes(Duration::from_millis(500), Duration::MAX)?;
+		writer.join().unwrap();
+
+		assert_eq!(changed[0], source.join("notes.txt"));
+		assert!(changed
+			.iter()
+			.all(|path| !path.starts_with(source.join("backups"))));
+		Ok(())
+	}
+
+	#[test]
+	fn test_batches_bursts_of_changes() -> io::Result<()> {
+		let source = fs::canonicalize(create_tmp_folder("watch")?)?;
+		let watcher = SourceWatcher::new(&[source.to_str().unwrap()], &[])?;
+
+		let writer = {
+			let source = source.clone();
+			thread::spawn(move || {
+				for i in 0..5 {
+					thread::sleep(Duration::from_millis(100));
+					fs::write(source.join(format!("{}.o", i)), "built").unwrap();
+				}
+			})
+		};
+		let changed = watcher.wait_for_changes(Duration::from_millis(500), Duration::MAX)?;
+		writer.join().unwrap();
+		assert!(changed.contains(&source.join("4.o")), "{:?}", changed);
+
+		// writing that never stops still gets a backup by the deadline
+		let stop = source.join("stop");
+		let writer = {
+			let stop = stop.clone();
+			thread::spawn(move || {
+				while !stop.exists() {
+					fs::write(source.join("log"), "busy").unwrap();
+					thread::sleep(Duration::from_millis(50));
+				}
+			})
+		};
+		let started = Instant::now();
+		watcher.wait_for_changes(Duration::from_millis(500), Duration::from_secs(1))?;
+		assert!(started.elapsed() < Duration::from_secs(5));
+		fs::write(&stop, "")?;
+		writer.join().unwrap();
+		Ok(())
+	}
+}